@@ -0,0 +1,80 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Time the duplicate search over every pair of hashes, which compares them
+//! packed into words, against comparing every pair with [`ImageHash::dist`].
+//! Run with `cargo bench --bench distance [ENTRIES]`; the default is 20000
+//! entries, and 100000 takes a while the generic way.
+
+use image_duplicate::{HashDB, Header, ImageHash};
+use std::{env, hint::black_box, time::Instant};
+
+fn main() {
+    let n = env::args()
+        .skip(1)
+        .find_map(|x| x.parse().ok())
+        .unwrap_or(20_000);
+    // 64-bit hashes fill one word, and 144-bit ones leave part of the last
+    // of three unused.
+    for hash_size in [8, 12] {
+        let bytes = (hash_size * hash_size / 8) as usize;
+        let mut state = hash_size as u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        };
+        let mut hashdb = HashDB::new();
+        hashdb.set_header(Header {
+            hash_size,
+            ..Header::default()
+        });
+        let mut hashes = Vec::with_capacity(n);
+        for i in 0..n {
+            let hash: Vec<u8> = (0..bytes).map(|_| next()).collect();
+            let hash: ImageHash =
+                image_hasher::ImageHash::from_bytes(&hash).unwrap().into();
+            hashdb
+                .insert(format!("/images/{i}.png"), hash.clone())
+                .unwrap();
+            hashes.push(hash);
+        }
+        // A threshold this large skips the index and compares every pair.
+        let threshold = hash_size * hash_size / 4;
+
+        let start = Instant::now();
+        let packed = hashdb.find_duplicates(threshold, true, &()).len();
+        let packed_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut generic = 0;
+        for (i, a) in hashes.iter().enumerate() {
+            for b in &hashes[i + 1..] {
+                generic += usize::from(black_box(a.dist(b)) < threshold);
+            }
+        }
+        let generic_time = start.elapsed();
+
+        assert_eq!(packed, generic);
+        println!(
+            "{n} entries, {}-bit hashes: packed {packed_time:.2?}, generic \
+             {generic_time:.2?} ({:.1}x)",
+            bytes * 8,
+            generic_time.as_secs_f64() / packed_time.as_secs_f64()
+        );
+    }
+}
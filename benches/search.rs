@@ -0,0 +1,85 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Time the duplicate search, which looks up the neighbors of each hash in
+//! an index where that beats comparing every pair, on a synthetic database
+//! of clustered 64-bit hashes, against comparing every pair one at a time.
+//! Run with `cargo bench --bench search [ENTRIES]`; the default is 50000.
+
+use image_duplicate::{HashDB, ImageHash};
+use std::{env, time::Instant};
+
+fn main() {
+    let n = env::args()
+        .skip(1)
+        .find_map(|x| x.parse().ok())
+        .unwrap_or(50_000);
+    let mut state = 1u64;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    // A quarter of the hashes are random and the rest a few bits off one of
+    // those, as edited copies of photos would be.
+    let mut hashes: Vec<u64> = Vec::with_capacity(n);
+    for i in 0..n {
+        let hash = match i % 4 {
+            0 => next(),
+            _ => {
+                let mut hash = hashes[i - i % 4];
+                for _ in 0..next() % 12 {
+                    hash ^= 1 << (next() % 64);
+                }
+                hash
+            }
+        };
+        hashes.push(hash);
+    }
+    let hashes: Vec<ImageHash> = hashes
+        .iter()
+        .map(|x| image_hasher::ImageHash::from_bytes(&x.to_le_bytes()))
+        .map(|x| x.unwrap().into())
+        .collect();
+    let mut hashdb = HashDB::new();
+    for (i, hash) in hashes.iter().enumerate() {
+        hashdb
+            .insert(format!("/images/{i}.png"), hash.clone())
+            .unwrap();
+    }
+
+    let start = Instant::now();
+    let mut within = [0; 65];
+    for (i, a) in hashes.iter().enumerate() {
+        for b in &hashes[i + 1..] {
+            within[a.dist(b) as usize] += 1;
+        }
+    }
+    let every_pair = start.elapsed();
+    println!("{n} entries: comparing every pair takes {every_pair:.2?}");
+
+    for threshold in [1, 5, 9, 13] {
+        let start = Instant::now();
+        let pairs = hashdb.find_duplicates(threshold, true, &()).len();
+        let elapsed = start.elapsed();
+        assert_eq!(pairs, within[..threshold as usize].iter().sum::<usize>());
+        println!(
+            "threshold {threshold}: {pairs} pairs in {elapsed:.2?} ({:.1}x)",
+            every_pair.as_secs_f64() / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,1012 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Removing duplicate images, by hand or by policy, and logging what was
+//! removed. [`Remover`] forms the main interface.
+
+use crate::{
+    hashdb::{HashDB, Pair, escape_path},
+    jpeg,
+};
+use clap::ValueEnum;
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fmt::Display,
+    fs::{self, File, FileTimes, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use walkdir::WalkDir;
+
+/// Which image of a group to keep when resolving duplicates automatically.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum Policy {
+    /// Keep the largest file
+    KeepLargest,
+    /// Keep the most recently modified file
+    KeepNewest,
+    /// Keep the least recently modified file
+    KeepOldest,
+    /// Keep the file whose path sorts first
+    KeepFirstAlphabetical,
+    /// Keep the image with the most pixels, then the JPEG saved at the
+    /// highest quality, then the largest file
+    KeepBestQuality,
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.to_possible_value() {
+            Some(x) => f.write_str(x.get_name()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What to do with a removed image.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Destination {
+    /// Move it to the trash.
+    #[cfg(feature = "trash")]
+    Trash,
+    /// Move it into a directory.
+    MoveTo(PathBuf),
+    /// Delete it permanently.
+    Delete,
+}
+
+/// Who decided to remove an image.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Reason {
+    /// The user, while reviewing.
+    Manual,
+    /// A policy, while resolving automatically.
+    Auto(Policy),
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::Manual => write!(f, "manual"),
+            Reason::Auto(policy) => write!(f, "auto:{policy}"),
+        }
+    }
+}
+
+/// What "removing" a duplicate means. [`FileRemover`] is the implementation
+/// used by the program; embedders can supply their own, e.g. to update a
+/// catalog as well as moving the file. Removers are sent to a worker thread
+/// by the GUI, so that slow removals do not block it.
+pub trait Remover: std::fmt::Debug + Send {
+    /// Remove `file` as a duplicate of `kept`.
+    fn remove(
+        &mut self,
+        file: &Path,
+        kept: &Path,
+        reason: Reason,
+    ) -> Result<(), ActionError>;
+
+    /// Undo the most recent removal that has not been undone yet. Not every
+    /// removal can be undone; by default, none can.
+    fn undo_last(&mut self) -> Result<(), ActionError> {
+        Err(ActionError::CannotUndo)
+    }
+
+    /// Something about the last removal worth showing besides the removal
+    /// itself, such as metadata copied into the kept image. Taken at most
+    /// once; by default, there is never anything.
+    fn take_note(&mut self) -> Option<String> {
+        None
+    }
+}
+
+/// A removal done by a [`FileRemover`], remembered so it can be undone.
+#[derive(Debug)]
+struct Removal {
+    file: PathBuf,
+    kept: PathBuf,
+    reason: Reason,
+    /// Where the file went, if it was moved into a directory.
+    moved_to: Option<PathBuf>,
+}
+
+/// Removes image files and appends a line per removal to an action log. The
+/// log is tab-separated: the Unix time, the [`Reason`] (`manual` or
+/// `auto:<POLICY>`), the action (`trash`, `move`, `delete`, `restore`, or
+/// `exif` for metadata copied into the kept image), the removed path, and the
+/// kept path, with paths escaped as in the database listing.
+#[derive(Debug)]
+pub struct FileRemover {
+    destination: Destination,
+    dry_run: bool,
+    log: Option<(PathBuf, File)>,
+    done: Vec<Removal>,
+    /// Copy EXIF metadata into the kept image before removing.
+    merge_exif: bool,
+    /// Keep times and extended attributes when moving by copying.
+    preserve: bool,
+    note: Option<String>,
+}
+
+impl FileRemover {
+    /// Create a remover. With `dry_run`, nothing is removed or logged; the
+    /// removals are only printed.
+    pub fn new(
+        destination: Destination,
+        dry_run: bool,
+        log: Option<&Path>,
+    ) -> Result<Self, ActionError> {
+        let log = match (dry_run, log) {
+            (false, Some(file)) => {
+                if let Some(dir) = file.parent() {
+                    fs::create_dir_all(dir).map_err(ActionError::io(dir))?;
+                }
+                let log = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(file)
+                    .map_err(ActionError::io(file))?;
+                Some((file.to_path_buf(), log))
+            }
+            _ => None,
+        };
+        Ok(FileRemover {
+            destination,
+            dry_run,
+            log,
+            done: Vec::new(),
+            merge_exif: false,
+            preserve: true,
+            note: None,
+        })
+    }
+
+    /// Whether images moved to another filesystem, which means copying them,
+    /// keep their access and modification times and, on Unix, their extended
+    /// attributes, such as Finder tags or ratings. On by default; reading
+    /// extended attributes can be slow on some filesystems.
+    pub fn preserve_metadata(mut self, preserve: bool) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Before removing an image, copy its EXIF metadata into the kept image
+    /// where that lacks it, using `exiftool`. See [`copy_exif`].
+    pub fn merge_exif(mut self, merge: bool) -> Self {
+        self.merge_exif = merge;
+        self
+    }
+
+    /// Append a line to the action log, if there is one.
+    fn log(
+        &mut self,
+        reason: Reason,
+        action: &str,
+        file: &Path,
+        kept: &Path,
+    ) -> Result<(), ActionError> {
+        if let Some((path, log)) = &mut self.log {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |x| x.as_secs());
+            writeln!(
+                log,
+                "{time}\t{reason}\t{action}\t{}\t{}",
+                escape_path(file),
+                escape_path(kept)
+            )
+            .map_err(ActionError::io(path))?;
+        }
+        Ok(())
+    }
+}
+
+impl Remover for FileRemover {
+    fn remove(
+        &mut self,
+        file: &Path,
+        kept: &Path,
+        reason: Reason,
+    ) -> Result<(), ActionError> {
+        let (action, doing) = match &self.destination {
+            #[cfg(feature = "trash")]
+            Destination::Trash => ("trash", "Trashing"),
+            Destination::MoveTo(_) => ("move", "Moving"),
+            Destination::Delete => ("delete", "Deleting"),
+        };
+        let mut removal = Removal {
+            file: file.to_path_buf(),
+            kept: kept.to_path_buf(),
+            reason,
+            moved_to: None,
+        };
+        let merge = self.merge_exif && lacks_exif(file, kept);
+        if self.dry_run {
+            if merge {
+                eprintln!("Would copy EXIF from {file:?} into {kept:?}");
+            }
+            eprintln!("Would {action} {file:?} (keeping {kept:?})");
+            self.done.push(removal);
+            return Ok(());
+        }
+        if merge {
+            copy_exif(file, kept)?;
+            let note = format!("Copied EXIF from {file:?} into {kept:?}");
+            eprintln!("{note}");
+            self.note = Some(note);
+            self.log(reason, "exif", file, kept)?;
+        }
+        eprintln!("{doing} {file:?} (keeping {kept:?})");
+
+        match &self.destination {
+            #[cfg(feature = "trash")]
+            Destination::Trash => trash::delete(file)?,
+            Destination::MoveTo(dir) => {
+                removal.moved_to = Some(move_into(file, dir, self.preserve)?)
+            }
+            Destination::Delete => {
+                fs::remove_file(file).map_err(ActionError::io(file))?
+            }
+        }
+
+        self.log(reason, action, file, kept)?;
+        self.done.push(removal);
+        Ok(())
+    }
+
+    /// Undo the last removal by moving the file back from the directory or,
+    /// where the trash can be listed (Windows and Freedesktop systems), by
+    /// restoring it from the trash. Deletions cannot be undone.
+    fn undo_last(&mut self) -> Result<(), ActionError> {
+        let Some(removal) = self.done.pop() else {
+            return Err(ActionError::NothingToUndo);
+        };
+        let file = removal.file.clone();
+        if self.dry_run {
+            eprintln!("Would restore {file:?}");
+            return Ok(());
+        }
+
+        let result = match (&self.destination, &removal.moved_to) {
+            _ if file.exists() => Err(ActionError::Exists(file.clone())),
+            (Destination::MoveTo(_), Some(from)) => {
+                move_file(from, &file, self.preserve)
+            }
+            #[cfg(feature = "trash")]
+            (Destination::Trash, _) => restore_from_trash(&file),
+            _ => Err(ActionError::CannotUndo),
+        };
+        if let Err(e) = result {
+            self.done.push(removal);
+            return Err(e);
+        }
+        eprintln!("Restored {file:?}");
+        self.log(removal.reason, "restore", &file, &removal.kept)
+    }
+
+    fn take_note(&mut self) -> Option<String> {
+        self.note.take()
+    }
+}
+
+/// Image formats that `exiftool` can write EXIF metadata into.
+const EXIF_SUFFIXES: [&str; 5] = ["jpg", "jpeg", "jxl", "png", "webp"];
+
+/// Tags never copied by [`copy_exif`], with their names in `exiftool`, since
+/// they describe the pixels of the copy they came from rather than the photo.
+/// Copying the orientation of an unrotated original into an exported copy
+/// that was already rotated would turn it twice.
+const EXIF_SKIPPED: [(exif::Tag, &str); 5] = [
+    (exif::Tag::Orientation, "Orientation"),
+    (exif::Tag::PixelXDimension, "ExifImageWidth"),
+    (exif::Tag::PixelYDimension, "ExifImageHeight"),
+    (exif::Tag::ImageWidth, "ImageWidth"),
+    (exif::Tag::ImageLength, "ImageHeight"),
+];
+
+/// Tags of the main image in the EXIF metadata of a file, less those in
+/// [`EXIF_SKIPPED`]. Empty if the file has none.
+fn exif_tags(file: &Path) -> HashSet<exif::Tag> {
+    let Ok(input) = File::open(file) else {
+        return HashSet::new();
+    };
+    match exif::Reader::new()
+        .read_from_container(&mut io::BufReader::new(input))
+    {
+        Ok(exif) => exif
+            .fields()
+            .filter(|x| x.ifd_num == exif::In::PRIMARY)
+            .map(|x| x.tag)
+            .filter(|x| EXIF_SKIPPED.iter().all(|(tag, _)| tag != x))
+            .collect(),
+        Err(_) => HashSet::new(),
+    }
+}
+
+/// Whether `from` has EXIF tags that `into` lacks, and `into` is in a format
+/// that can hold them.
+fn lacks_exif(from: &Path, into: &Path) -> bool {
+    let writable = into
+        .extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| EXIF_SUFFIXES.contains(&x.to_lowercase().as_str()));
+    writable && !exif_tags(from).is_subset(&exif_tags(into))
+}
+
+/// Whether `exiftool` can be run.
+pub(crate) fn has_exiftool() -> bool {
+    Command::new("exiftool")
+        .arg("-ver")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|x| x.success())
+}
+
+/// Copy the EXIF tags of `from` that `into` lacks into `into`, leaving the
+/// tags it has alone, using `exiftool`. The new file is written under a
+/// temporary name and renamed over `into`, so `into` is never left half
+/// written. It keeps the permissions and modification time of `into`.
+pub fn copy_exif(from: &Path, into: &Path) -> Result<(), ActionError> {
+    let Some(name) = into.file_name() else {
+        return Err(ActionError::io(into)(io::ErrorKind::InvalidInput.into()));
+    };
+    let mut tmp_name = OsString::from(format!(".{}.exif.", process::id()));
+    tmp_name.push(name);
+    let tmp = into.with_file_name(tmp_name);
+
+    let mut command = Command::new("exiftool");
+    command.args(["-q", "-m", "-P", "-wm", "cg", "-tagsFromFile"]);
+    // The thumbnail (IFD1) shows the other copy, so it stays behind too.
+    command.arg(from).args(["-exif:all", "--IFD1:all"]);
+    for (_, name) in EXIF_SKIPPED {
+        command.arg(format!("--{name}"));
+    }
+    command.arg("-o").arg(&tmp).arg(into);
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| ActionError::Exiftool(format!("{e}")))?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&tmp);
+        let message = String::from_utf8_lossy(&output.stderr);
+        return Err(ActionError::Exiftool(format!(
+            "could not copy EXIF from {from:?} into {into:?}: {}",
+            message.trim()
+        )));
+    }
+
+    let result = fs::metadata(into)
+        .and_then(|x| fs::set_permissions(&tmp, x.permissions()))
+        .and_then(|_| fs::rename(&tmp, into));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result.map_err(ActionError::io(into))
+}
+
+/// Restore a file from the trash. If it was trashed several times, the most
+/// recent one is restored.
+#[cfg(all(
+    feature = "trash",
+    any(
+        target_os = "windows",
+        all(
+            unix,
+            not(target_os = "macos"),
+            not(target_os = "ios"),
+            not(target_os = "android")
+        )
+    )
+))]
+fn restore_from_trash(file: &Path) -> Result<(), ActionError> {
+    let item = trash::os_limited::list()?
+        .into_iter()
+        .filter(|x| x.original_path() == file)
+        .max_by_key(|x| x.time_deleted);
+    match item {
+        Some(item) => Ok(trash::os_limited::restore_all([item])?),
+        None => Err(ActionError::io(file)(io::ErrorKind::NotFound.into())),
+    }
+}
+
+/// Stand-in for [`restore_from_trash`] where the trash cannot be listed.
+#[cfg(all(
+    feature = "trash",
+    not(any(
+        target_os = "windows",
+        all(
+            unix,
+            not(target_os = "macos"),
+            not(target_os = "ios"),
+            not(target_os = "android")
+        )
+    ))
+))]
+fn restore_from_trash(_file: &Path) -> Result<(), ActionError> {
+    Err(ActionError::CannotUndo)
+}
+
+/// Give a copy the access and modification times and, on Unix, the extended
+/// attributes of the original. Permissions are already copied along with the
+/// contents. What cannot be kept, e.g. because the filesystem of the copy
+/// has no extended attributes, is reported as a warning.
+fn copy_metadata(from: &Path, to: &Path) {
+    let times = fs::metadata(from).and_then(|meta| {
+        let times = FileTimes::new()
+            .set_accessed(meta.accessed()?)
+            .set_modified(meta.modified()?);
+        File::options().write(true).open(to)?.set_times(times)
+    });
+    if let Err(e) = times {
+        eprintln!("Warning: could not keep the times of {from:?}: {e}");
+    }
+
+    #[cfg(unix)]
+    {
+        let attrs = xattr::list(from).and_then(|names| {
+            for name in names {
+                if let Some(value) = xattr::get(from, &name)? {
+                    xattr::set(to, &name, &value).map_err(|e| {
+                        io::Error::new(e.kind(), format!("{name:?}: {e}"))
+                    })?;
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = attrs {
+            eprintln!(
+                "Warning: could not keep the extended attributes of \
+                 {from:?}: {e}"
+            );
+        }
+    }
+}
+
+/// Move a file, copying and deleting it if it cannot be renamed. Refuses to
+/// overwrite an existing file. With `preserve`, a copy keeps the times and
+/// extended attributes of the file.
+fn move_file(
+    from: &Path,
+    to: &Path,
+    preserve: bool,
+) -> Result<(), ActionError> {
+    if to.exists() {
+        return Err(ActionError::Exists(to.to_path_buf()));
+    }
+    // Renaming fails across filesystems; copy and delete instead.
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to).map_err(ActionError::io(to))?;
+        if preserve {
+            copy_metadata(from, to);
+        }
+        fs::remove_file(from).map_err(ActionError::io(from))?;
+    }
+    Ok(())
+}
+
+/// Move a file into a directory, keeping its name, as by [`move_file`].
+/// Returns the new path.
+fn move_into(
+    file: &Path,
+    dir: &Path,
+    preserve: bool,
+) -> Result<PathBuf, ActionError> {
+    let Some(name) = file.file_name() else {
+        return Err(ActionError::io(file)(io::ErrorKind::InvalidInput.into()));
+    };
+    let target = dir.join(name);
+    if target.exists() {
+        return Err(ActionError::Exists(target));
+    }
+    fs::create_dir_all(dir).map_err(ActionError::io(dir))?;
+    move_file(file, &target, preserve)?;
+    Ok(target)
+}
+
+/// Housekeeping files that operating systems leave in image folders.
+const JUNK_FILES: [&str; 3] = [".DS_Store", "Thumbs.db", "desktop.ini"];
+
+/// Remove the directories under `root` that are empty, deepest first, so that
+/// directories containing only empty directories go too. `root` itself is
+/// kept, and directories on other filesystems are not entered. With `junk`,
+/// directories holding nothing but [`JUNK_FILES`] count as empty, and those
+/// files are deleted with them. With `dry_run`, the directories are only
+/// printed. Returns the number of directories removed.
+pub fn prune_empty_dirs(
+    root: &Path,
+    junk: bool,
+    dry_run: bool,
+) -> Result<usize, ActionError> {
+    let mut empty: HashSet<PathBuf> = HashSet::new();
+    let mut removed = 0;
+    let walk = WalkDir::new(root)
+        .min_depth(1)
+        .same_file_system(true)
+        .contents_first(true);
+    for entry in walk {
+        let entry = match entry {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Skipping: {e}");
+                continue;
+            }
+        };
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+        let dir = entry.path();
+        let mut junk_files = Vec::new();
+        let mut is_empty = true;
+        for child in fs::read_dir(dir).map_err(ActionError::io(dir))? {
+            let child = child.map_err(ActionError::io(dir))?;
+            let path = child.path();
+            let is_junk = junk
+                && child.file_type().is_ok_and(|x| x.is_file())
+                && JUNK_FILES.iter().any(|x| child.file_name() == *x);
+            match (is_junk, empty.contains(&path)) {
+                (true, _) => junk_files.push(path),
+                (false, true) => (),
+                (false, false) => {
+                    is_empty = false;
+                    break;
+                }
+            }
+        }
+        if !is_empty {
+            continue;
+        }
+
+        match dry_run {
+            true => eprintln!("Would remove empty directory {dir:?}"),
+            false => {
+                for file in &junk_files {
+                    fs::remove_file(file).map_err(ActionError::io(file))?;
+                }
+                fs::remove_dir(dir).map_err(ActionError::io(dir))?;
+                eprintln!("Removed empty directory {dir:?}");
+            }
+        }
+        empty.insert(dir.to_path_buf());
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Largest difference in aspect ratio, as a fraction of the smaller image's
+/// height, between an image and a resized copy of it.
+const ASPECT_TOLERANCE: f64 = 0.01;
+
+/// Which of two images, given by their dimensions, is a downscaled copy of
+/// the other, such as a web export of a photo. That is the smaller one, if
+/// both of its sides are smaller by the same factor, give or take a pixel of
+/// rounding or [`ASPECT_TOLERANCE`]. Returns its index, as the one to remove.
+pub(crate) fn resized_variant(dims: [(u32, u32); 2]) -> Option<usize> {
+    let [(w_1, h_1), (w_2, h_2)] = dims;
+    let small = match (w_1 < w_2 && h_1 < h_2, w_2 < w_1 && h_2 < h_1) {
+        (true, _) => 0,
+        (_, true) => 1,
+        _ => return None,
+    };
+    let (w_big, h_big) = dims[1 - small];
+    let (w_small, h_small) = dims[small];
+    if w_small == 0 || h_small == 0 {
+        return None;
+    }
+
+    // Height of the big image scaled to the width of the small one.
+    let expected = h_big as f64 * w_small as f64 / w_big as f64;
+    let slack = (h_small as f64 * ASPECT_TOLERANCE).max(1.0);
+    match (h_small as f64 - expected).abs() <= slack {
+        true => Some(small),
+        false => None,
+    }
+}
+
+/// Group images connected by the given pairs.
+pub(crate) fn groups<'a>(pairs: &[(&'a Path, &'a Path)]) -> Vec<Vec<&'a Path>> {
+    fn find(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+
+    let mut index: HashMap<&Path, usize> = HashMap::new();
+    let mut names = Vec::new();
+    let mut parent = Vec::new();
+    for &(a, b) in pairs {
+        let mut id = |x: &'a Path| {
+            *index.entry(x).or_insert_with(|| {
+                names.push(x);
+                parent.push(parent.len());
+                parent.len() - 1
+            })
+        };
+        let (a, b) = (id(a), id(b));
+        let (a, b) = (find(&mut parent, a), find(&mut parent, b));
+        parent[a] = b;
+    }
+
+    let mut groups: HashMap<usize, Vec<&Path>> = HashMap::new();
+    for (i, name) in names.into_iter().enumerate() {
+        let root = find(&mut parent, i);
+        groups.entry(root).or_default().push(name);
+    }
+    let mut groups: Vec<_> = groups.into_values().collect();
+    for group in &mut groups {
+        group.sort_unstable();
+    }
+    groups.sort_unstable();
+    groups
+}
+
+/// Choose the image of a group to keep: among the images under the first of
+/// the `prefer` directories that has any, or among all of them if none has,
+/// the one chosen by `policy`. Ties go to the path that sorts first. Sizes
+/// and dimensions come from `hashdb` where it has them.
+fn keeper<'a>(
+    hashdb: &HashDB,
+    group: &[&'a Path],
+    policy: Policy,
+    prefer: &[PathBuf],
+) -> &'a Path {
+    let rank = |x: &Path| {
+        prefer
+            .iter()
+            .position(|dir| x.starts_with(dir))
+            .unwrap_or(prefer.len())
+    };
+    let best = group.iter().map(|x| rank(x)).min().unwrap_or(0);
+    let group: Vec<&Path> =
+        group.iter().copied().filter(|x| rank(x) == best).collect();
+
+    let size = |x: &Path| match hashdb.metadata(x) {
+        Some(meta) => meta.size,
+        None => fs::metadata(x).map_or(0, |m| m.len()),
+    };
+    let time = |x: &Path| {
+        fs::metadata(x)
+            .and_then(|m| m.modified())
+            .unwrap_or(UNIX_EPOCH)
+    };
+    // `group` is sorted. `max_by_key` returns the last of equal elements,
+    // hence the reversal.
+    let first = group[0];
+    match policy {
+        Policy::KeepLargest => group
+            .iter()
+            .rev()
+            .max_by_key(|x| size(x))
+            .copied()
+            .unwrap_or(first),
+        Policy::KeepNewest => group
+            .iter()
+            .rev()
+            .max_by_key(|x| time(x))
+            .copied()
+            .unwrap_or(first),
+        Policy::KeepOldest => group
+            .iter()
+            .min_by_key(|x| time(x))
+            .copied()
+            .unwrap_or(first),
+        Policy::KeepFirstAlphabetical => first,
+        Policy::KeepBestQuality => {
+            // Images that are not JPEGs lose nothing to compression.
+            let quality = |x: &Path| jpeg::quality(x).unwrap_or(100);
+            let pixels = |x: &Path| {
+                let dims = match hashdb.metadata(x) {
+                    Some(meta) => Some((meta.width, meta.height)),
+                    None => image::image_dimensions(x).ok(),
+                };
+                dims.map_or(0, |(w, h)| w as u64 * h as u64)
+            };
+            group
+                .iter()
+                .rev()
+                .max_by_key(|x| (pixels(x), quality(x), size(x)))
+                .copied()
+                .unwrap_or(first)
+        }
+    }
+}
+
+/// Decide which images to remove under `policy`. Pairs further apart than
+/// `max_distance` are ignored; the rest are grouped, one image of each group
+/// is kept, and the others are removed if they are within `max_distance` of
+/// the kept one. Images under the `prefer` directories, which must be
+/// canonicalized like database entries, are kept over the others, in the
+/// order given. Returns `(removed, kept)` pairs.
+pub fn resolve(
+    hashdb: &HashDB,
+    duplicates: &[Pair],
+    policy: Policy,
+    max_distance: u32,
+    prefer: &[PathBuf],
+) -> Vec<Pair> {
+    let close: Vec<(&Path, &Path)> = duplicates
+        .iter()
+        .filter(|(a, b)| {
+            hashdb.distance(a, b).is_some_and(|d| d <= max_distance)
+        })
+        .map(|(a, b)| (&**a, &**b))
+        .collect();
+
+    let mut removals = Vec::new();
+    for group in groups(&close) {
+        let kept = keeper(hashdb, &group, policy, prefer);
+        for &x in &group {
+            if x != kept
+                && hashdb.distance(x, kept).is_some_and(|d| d <= max_distance)
+            {
+                removals.push((x.into(), kept.into()));
+            }
+        }
+    }
+    removals
+}
+
+/// Errors that can happen when removing images.
+#[derive(Debug, Error)]
+pub enum ActionError {
+    /// The destination of a move is already taken.
+    #[error("Will not overwrite {0:?}")]
+    Exists(PathBuf),
+
+    /// Wrapper around [`std::io::Error`], with the path involved.
+    #[error("IO Error: {path:?}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    /// There is no removal left to undo.
+    #[error("Nothing to undo")]
+    NothingToUndo,
+
+    /// The last removal cannot be undone.
+    #[error("The last removal cannot be undone")]
+    CannotUndo,
+
+    /// `exiftool` could not be run, or failed.
+    #[error("exiftool: {0}")]
+    Exiftool(String),
+
+    /// An error from a custom [`Remover`].
+    #[error("{0}")]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+
+    /// Wrapper around [`trash::Error`].
+    #[cfg(feature = "trash")]
+    #[error("Trash error: {0}")]
+    TrashError(#[from] trash::Error),
+}
+
+impl ActionError {
+    /// Wrap an IO error on `path`, for use with [`Result::map_err`].
+    fn io<P: AsRef<Path>>(path: P) -> impl FnOnce(io::Error) -> Self {
+        move |source| ActionError::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory for test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("image-duplicate-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A remover that only records what it is asked to remove.
+    #[derive(Debug, Default)]
+    struct MockRemover {
+        calls: Vec<(PathBuf, PathBuf, Reason)>,
+    }
+
+    impl Remover for MockRemover {
+        fn remove(
+            &mut self,
+            file: &Path,
+            kept: &Path,
+            reason: Reason,
+        ) -> Result<(), ActionError> {
+            self.calls
+                .push((file.to_path_buf(), kept.to_path_buf(), reason));
+            Ok(())
+        }
+
+        fn undo_last(&mut self) -> Result<(), ActionError> {
+            self.calls
+                .pop()
+                .map(|_| ())
+                .ok_or(ActionError::NothingToUndo)
+        }
+    }
+
+    /// A database of images with 64-bit hashes having the given bits set,
+    /// and the pairs of it closer than `threshold`.
+    fn database(
+        images: &[(&str, &[u32])],
+        threshold: u32,
+    ) -> (HashDB, Vec<Pair>) {
+        let mut hashdb = HashDB::new();
+        for (name, bits) in images {
+            let mut hash = [0u8; 8];
+            for bit in *bits {
+                hash[*bit as usize / 8] |= 1 << (bit % 8);
+            }
+            let hash = image_hasher::ImageHash::from_bytes(&hash).unwrap();
+            hashdb.insert(*name, hash.into()).unwrap();
+        }
+        let pairs = hashdb.find_duplicates(threshold, false, &());
+        (hashdb, pairs)
+    }
+
+    /// Resolve `pairs` under `policy` and remove the images chosen with
+    /// `remover`, as `--auto` does.
+    fn auto(
+        remover: &mut dyn Remover,
+        hashdb: &HashDB,
+        pairs: &[Pair],
+        policy: Policy,
+        max_distance: u32,
+        prefer: &[PathBuf],
+    ) {
+        for (file, kept) in resolve(hashdb, pairs, policy, max_distance, prefer)
+        {
+            remover.remove(&file, &kept, Reason::Auto(policy)).unwrap();
+        }
+    }
+
+    #[test]
+    fn resolve_removes_within_distance() {
+        // b is 1 from a and 2 from c, which is 3 from a; d is far from all.
+        let (hashdb, pairs) = database(
+            &[
+                ("/images/a.png", &[]),
+                ("/images/b.png", &[0]),
+                ("/images/c.png", &[0, 1, 2]),
+                ("/images/d.png", &[10, 20, 30, 40, 50, 60]),
+            ],
+            4,
+        );
+        let policy = Policy::KeepFirstAlphabetical;
+        let call = |file: &str, kept: &str| {
+            (file.into(), kept.into(), Reason::Auto(policy))
+        };
+
+        let mut remover = MockRemover::default();
+        auto(&mut remover, &hashdb, &pairs, policy, 2, &[]);
+        assert_eq!(remover.calls, [call("/images/b.png", "/images/a.png")]);
+
+        let mut remover = MockRemover::default();
+        auto(&mut remover, &hashdb, &pairs, policy, 3, &[]);
+        assert_eq!(
+            remover.calls,
+            [
+                call("/images/b.png", "/images/a.png"),
+                call("/images/c.png", "/images/a.png"),
+            ]
+        );
+        remover.undo_last().unwrap();
+        assert_eq!(remover.calls.len(), 1);
+
+        let mut remover = MockRemover::default();
+        auto(&mut remover, &hashdb, &pairs, policy, 0, &[]);
+        assert!(remover.calls.is_empty());
+        assert!(matches!(
+            remover.undo_last(),
+            Err(ActionError::NothingToUndo)
+        ));
+    }
+
+    #[test]
+    fn resolve_keeps_preferred() {
+        let (hashdb, pairs) = database(
+            &[
+                ("/images/a.png", &[]),
+                ("/images/b.png", &[0]),
+                ("/images/keep/c.png", &[1]),
+            ],
+            3,
+        );
+        let policy = Policy::KeepFirstAlphabetical;
+        let mut remover = MockRemover::default();
+        let prefer = [PathBuf::from("/images/keep")];
+        auto(&mut remover, &hashdb, &pairs, policy, 2, &prefer);
+        let kept = Path::new("/images/keep/c.png");
+        assert_eq!(
+            remover.calls,
+            [
+                ("/images/a.png".into(), kept.into(), Reason::Auto(policy)),
+                ("/images/b.png".into(), kept.into(), Reason::Auto(policy)),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_keeps_largest() {
+        let dir = temp_dir("keep-largest");
+        let names = ["a.png", "b.png", "c.png"].map(|x| dir.join(x));
+        for (name, size) in names.iter().zip([10, 30, 20]) {
+            fs::write(name, vec![0; size]).unwrap();
+        }
+        let [a, b, c] = names.each_ref().map(|x| x.to_str().unwrap());
+        let (hashdb, pairs) = database(&[(a, &[]), (b, &[0]), (c, &[1])], 3);
+        let policy = Policy::KeepLargest;
+        let mut remover = MockRemover::default();
+        auto(&mut remover, &hashdb, &pairs, policy, 2, &[]);
+        let removed: Vec<_> = remover
+            .calls
+            .iter()
+            .map(|(file, kept, _)| (file.as_path(), kept.as_path()))
+            .collect();
+        assert_eq!(
+            removed,
+            [(Path::new(a), Path::new(b)), (Path::new(c), Path::new(b))]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn moves_keep_extended_attributes() {
+        use std::time::Duration;
+
+        let dir = temp_dir("move-xattr");
+        let modified = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let image = |name: &str| {
+            let file = dir.join("from").join(name);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, b"image").unwrap();
+            File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+            xattr::set(&file, "user.rating", b"5").map(|_| file)
+        };
+        let Ok(file) = image("a.jpg") else {
+            // The filesystem has no extended attributes to keep.
+            fs::remove_dir_all(dir).unwrap();
+            return;
+        };
+        let moved = move_into(&file, &dir.join("to"), true).unwrap();
+        assert!(!file.exists());
+        assert_eq!(xattr::get(&moved, "user.rating").unwrap().unwrap(), b"5");
+
+        // What a move across filesystems falls back to.
+        let file = image("b.jpg").unwrap();
+        let copy = dir.join("to").join("b.jpg");
+        fs::copy(&file, &copy).unwrap();
+        copy_metadata(&file, &copy);
+        assert_eq!(xattr::get(&copy, "user.rating").unwrap().unwrap(), b"5");
+        assert_eq!(fs::metadata(&copy).unwrap().modified().unwrap(), modified);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,574 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Defaults for command line options, read from a TOML configuration file and
+//! from the environment. [`Config`] forms the main interface.
+//!
+//! Keys of the file are long option names, with either dashes or underscores:
+//!
+//! ```toml
+//! recursive = true
+//! threshold = 6
+//! db = "~/photo.db"
+//! ```
+//!
+//! The same options can be set with environment variables named after them,
+//! e.g. `IMAGE_DUPLICATE_THRESHOLD=6` or `IMAGE_DUPLICATE_NO_DUMP=true`.
+//!
+//! A profile bundles option values for a kind of image. Besides the
+//! [`PROFILES`] built in, the file can define its own under `[profiles.NAME]`,
+//! with the same keys as the file itself:
+//!
+//! ```toml
+//! [profiles.scans]
+//! threshold = 4
+//! min-pair-size = "1M"
+//! ```
+//!
+//! Options given on the command line win over the chosen profile, which wins
+//! over the environment, which wins over the file.
+
+use clap::{ArgMatches, Command, parser::ValueSource};
+use std::{
+    collections::HashSet,
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use toml::{Table, Value};
+
+/// Prefix of environment variables that set options.
+const ENV_PREFIX: &str = "IMAGE_DUPLICATE_";
+
+/// Key of the table of profiles in the file.
+const PROFILES_KEY: &str = "profiles";
+
+/// A built-in profile.
+#[derive(Clone, Copy, Debug)]
+pub struct Profile {
+    pub name: &'static str,
+    pub about: &'static str,
+    /// Option values, by long option name.
+    pub options: &'static [(&'static str, &'static str)],
+}
+
+/// Built-in profiles.
+pub const PROFILES: [Profile; 3] = [
+    Profile {
+        name: "photos",
+        about: "camera photos; frames of a burst are not duplicates",
+        options: &[("threshold", "8"), ("ignore-bursts", "true")],
+    },
+    Profile {
+        name: "screenshots",
+        about: "screenshots and scans, whose layouts look alike even when \
+                their contents differ",
+        options: &[("threshold", "3")],
+    },
+    Profile {
+        name: "art",
+        about: "drawings and edits, whose copies are often recolored or \
+                cropped",
+        options: &[("threshold", "14")],
+    },
+];
+
+/// Description of the built-in profiles and their settings, for the help.
+pub fn profiles_help() -> String {
+    let mut help = String::from(
+        "Use the option values of profile NAME where they are not given on \
+         the command line. Profiles win over the environment and the \
+         configuration file, which can define its own under \
+         [profiles.NAME]. Built-in profiles:\n",
+    );
+    for profile in PROFILES {
+        let options: Vec<String> = profile
+            .options
+            .iter()
+            .map(|(k, v)| match *v {
+                "true" => format!("--{k}"),
+                v => format!("--{k} {v}"),
+            })
+            .collect();
+        help.push_str(&format!(
+            "\n{}: {}\n    {}\n",
+            profile.name,
+            profile.about,
+            options.join(" ")
+        ));
+    }
+    help
+}
+
+/// A parsed configuration file.
+#[derive(Debug, Default)]
+pub struct Config {
+    path: PathBuf,
+    table: Table,
+}
+
+/// Default location of the configuration file,
+/// `$XDG_CONFIG_HOME/image-duplicate/config.toml` on Linux.
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|x| x.join("image-duplicate").join("config.toml"))
+}
+
+/// Name of the environment variable for an option.
+fn env_var(long: &str) -> String {
+    format!("{ENV_PREFIX}{}", long.to_uppercase().replace('-', "_"))
+}
+
+/// Replace a leading `~/` with the home directory.
+fn expand_home(value: &str) -> String {
+    match (value.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).to_string_lossy().into(),
+        _ => value.to_owned(),
+    }
+}
+
+/// Value of an option in a table of the file, whose keys may use
+/// underscores for dashes.
+fn lookup<'a>(table: &'a Table, long: &str) -> Option<(&'a String, &'a Value)> {
+    table
+        .iter()
+        .find(|(k, _)| k.as_str() == long || k.replace('_', "-") == long)
+}
+
+/// Interpret an environment variable as a flag.
+fn parse_flag(key: &str, value: &str) -> Result<bool, ConfigError> {
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "" | "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(ConfigError::InvalidValue(
+            key.to_owned(),
+            "expected true or false".into(),
+        )),
+    }
+}
+
+/// Turn a value of the file into option values.
+fn file_values(key: &str, value: &Value) -> Result<Vec<String>, ConfigError> {
+    match value {
+        Value::String(x) => Ok(vec![expand_home(x)]),
+        Value::Integer(x) => Ok(vec![x.to_string()]),
+        Value::Float(x) => Ok(vec![x.to_string()]),
+        Value::Boolean(x) => Ok(vec![x.to_string()]),
+        Value::Array(xs) => {
+            let mut values = Vec::new();
+            for x in xs {
+                match x {
+                    Value::Array(_) | Value::Table(_) => {
+                        return Err(ConfigError::InvalidValue(
+                            key.to_owned(),
+                            "nested arrays and tables are not options".into(),
+                        ));
+                    }
+                    x => values.extend(file_values(key, x)?),
+                }
+            }
+            Ok(values)
+        }
+        Value::Datetime(_) | Value::Table(_) => Err(ConfigError::InvalidValue(
+            key.to_owned(),
+            "expected a string, number, boolean, or array".into(),
+        )),
+    }
+}
+
+impl Config {
+    /// Read a configuration file.
+    pub fn from_file<P: AsRef<Path>>(file: P) -> Result<Self, ConfigError> {
+        let path = file.as_ref().to_path_buf();
+        let text = match fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(e) => return Err(ConfigError::IOError(path, e)),
+        };
+        match text.parse::<Table>() {
+            Ok(table) => Ok(Config { path, table }),
+            Err(e) => Err(ConfigError::ParseError(path, e)),
+        }
+    }
+
+    /// Load the configuration file given, or the one named by
+    /// `IMAGE_DUPLICATE_CONFIG`, or the default one. A missing default file is
+    /// the same as an empty one.
+    pub fn load(file: Option<&Path>) -> Result<Self, ConfigError> {
+        if let Some(file) = file {
+            return Self::from_file(file);
+        }
+        if let Some(file) = env::var_os(env_var("config")) {
+            return Self::from_file(file);
+        }
+        match default_path() {
+            Some(file) if file.is_file() => Self::from_file(file),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// Print a warning for every key, including those of profiles, that is not
+    /// the long name of an option of the command or one of its subcommands.
+    pub fn warn_unknown(&self, cmd: &Command) {
+        for warning in self.unknown(cmd) {
+            eprintln!("Warning: {warning}");
+        }
+    }
+
+    /// What [`warn_unknown`][Config::warn_unknown] warns about.
+    fn unknown(&self, cmd: &Command) -> Vec<String> {
+        fn longs<'a>(cmd: &'a Command, known: &mut HashSet<&'a str>) {
+            known.extend(cmd.get_arguments().filter_map(|x| x.get_long()));
+            for sub in cmd.get_subcommands() {
+                longs(sub, known);
+            }
+        }
+
+        let mut known = HashSet::new();
+        longs(cmd, &mut known);
+        let warning = |key: &str, place: String| {
+            (!known.contains(key.replace('_', "-").as_str()))
+                .then(|| format!("unknown key {key:?} in {place}"))
+        };
+        let mut warnings = Vec::new();
+        for (key, value) in &self.table {
+            match (key.as_str(), value) {
+                (PROFILES_KEY, Value::Table(profiles)) => {
+                    for (name, profile) in profiles {
+                        let Value::Table(profile) = profile else {
+                            warnings.push(format!(
+                                "profile {name:?} in {:?} is not a table",
+                                self.path
+                            ));
+                            continue;
+                        };
+                        for key in profile.keys() {
+                            warnings.extend(warning(
+                                key,
+                                format!("profile {name:?}"),
+                            ));
+                        }
+                    }
+                }
+                (key, _) => {
+                    warnings.extend(warning(key, format!("{:?}", self.path)))
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Value of an option in the file.
+    fn get(&self, long: &str) -> Option<(&String, &Value)> {
+        lookup(&self.table, long)
+    }
+
+    /// Name of the profile chosen on the command line, in the environment, or
+    /// in the file, if any.
+    fn profile_name(&self, matches: &ArgMatches) -> Option<String> {
+        if let Ok(Some(name)) = matches.try_get_one::<String>("profile") {
+            return Some(name.clone());
+        }
+        if let Ok(name) = env::var(env_var("profile")) {
+            return Some(name);
+        }
+        match self.get("profile") {
+            Some((_, Value::String(name))) => Some(name.clone()),
+            _ => None,
+        }
+    }
+
+    /// Option values of a profile defined in the file, or else of a built-in
+    /// one.
+    fn profile(&self, name: &str) -> Result<Table, ConfigError> {
+        let defined = match self.table.get(PROFILES_KEY) {
+            Some(Value::Table(profiles)) => profiles.get(name),
+            _ => None,
+        };
+        if let Some(Value::Table(profile)) = defined {
+            return Ok(profile.clone());
+        }
+        match PROFILES.iter().find(|x| x.name == name) {
+            Some(profile) => Ok(profile
+                .options
+                .iter()
+                .map(|(k, v)| (k.to_string(), Value::String(v.to_string())))
+                .collect()),
+            None => Err(ConfigError::UnknownProfile(name.to_owned())),
+        }
+    }
+
+    /// Command line arguments that supply the options of `cmd` that were not
+    /// given on the command line in `matches`, taking each from the chosen
+    /// profile if it sets it, from the environment if it is set there, and
+    /// from the file otherwise. Options that conflict with one given on the
+    /// command line are left out.
+    pub fn args(
+        &self,
+        cmd: &Command,
+        matches: &ArgMatches,
+    ) -> Result<Vec<OsString>, ConfigError> {
+        let given: Vec<_> = cmd
+            .get_arguments()
+            .filter(|x| {
+                matches.value_source(x.get_id().as_str())
+                    == Some(ValueSource::CommandLine)
+            })
+            .collect();
+
+        let profile = match self.profile_name(matches) {
+            Some(name) => self.profile(&name)?,
+            None => Table::new(),
+        };
+
+        let mut args = Vec::new();
+        for arg in cmd.get_arguments() {
+            let Some(long) = arg.get_long() else { continue };
+            if matches!(long, "config" | "help" | "version")
+                || given.iter().any(|x| {
+                    x.get_id() == arg.get_id()
+                        || cmd.get_arg_conflicts_with(x).contains(&arg)
+                        || cmd.get_arg_conflicts_with(arg).contains(x)
+                })
+            {
+                continue;
+            }
+
+            let var = env_var(long);
+            let values = match (lookup(&profile, long), env::var(&var)) {
+                (Some((key, x)), _) => file_values(key, x)?,
+                (None, Ok(x)) => match arg.get_action().takes_values() {
+                    true => vec![x],
+                    false => vec![parse_flag(&var, &x)?.to_string()],
+                },
+                (None, Err(_)) => match self.get(long) {
+                    Some((key, x)) => file_values(key, x)?,
+                    None => continue,
+                },
+            };
+
+            match arg.get_action().takes_values() {
+                true => args.extend(
+                    values.iter().map(|x| format!("--{long}={x}").into()),
+                ),
+                false => match values.as_slice() {
+                    [x] if x == "true" => args.push(format!("--{long}").into()),
+                    [x] if x == "false" => (),
+                    _ => {
+                        return Err(ConfigError::InvalidValue(
+                            long.to_owned(),
+                            "expected true or false".into(),
+                        ));
+                    }
+                },
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Errors that can happen when reading the configuration.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// A value of the wrong type for its option.
+    #[error("Invalid value for {0}: {1}")]
+    InvalidValue(String, String),
+
+    /// Wrapper around [`std::io::Error`].
+    #[error("Could not read {0:?}: {1}")]
+    IOError(PathBuf, std::io::Error),
+
+    /// No profile has the name given.
+    #[error(
+        "Unknown profile {0:?}; the built-in profiles are photos, \
+         screenshots, and art"
+    )]
+    UnknownProfile(String),
+
+    /// Wrapper around [`toml::de::Error`].
+    #[error("Could not parse {0:?}: {1}")]
+    ParseError(PathBuf, toml::de::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Args;
+    use clap::CommandFactory;
+    use std::sync::Mutex;
+
+    /// Held by tests that read or set the environment, which is shared by
+    /// the whole process.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// A configuration file in a fresh directory for test `name`.
+    fn config(name: &str, text: &str) -> Config {
+        let dir = env::temp_dir()
+            .join(format!("image-duplicate-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.toml");
+        fs::write(&file, text).unwrap();
+        let config = Config::from_file(&file);
+        fs::remove_dir_all(dir).unwrap();
+        config.unwrap()
+    }
+
+    /// Arguments `config` adds to the command line `list ARGS`.
+    fn list_args(
+        config: &Config,
+        args: &[&str],
+    ) -> Result<Vec<String>, ConfigError> {
+        let cmd = Args::command();
+        let sub = cmd.find_subcommand("list").unwrap();
+        let matches = sub
+            .clone()
+            .try_get_matches_from(
+                std::iter::once("list").chain(args.iter().copied()),
+            )
+            .unwrap();
+        let args = config.args(sub, &matches)?;
+        Ok(args.into_iter().map(|x| x.into_string().unwrap()).collect())
+    }
+
+    #[test]
+    fn file_values() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let config = config(
+            "config-values",
+            "threshold = 6\nno_dump = true\nrecursive = false\n\
+             exclude = [\"*.tmp\", \"cache\"]\n",
+        );
+        let args = list_args(&config, &["photos"]).unwrap();
+        assert_eq!(
+            args,
+            [
+                "--exclude=*.tmp",
+                "--exclude=cache",
+                "--no-dump",
+                "--threshold=6"
+            ]
+        );
+    }
+
+    #[test]
+    fn precedence() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let config = config(
+            "config-precedence",
+            "threshold = 6\nrecursive = true\n\n\
+             [profiles.mine]\nthreshold = 2\n",
+        );
+        let threshold = |args: &[&str]| {
+            list_args(&config, args).unwrap().into_iter().find(|x| {
+                x.starts_with("--threshold") || x.starts_with("--similarity")
+            })
+        };
+        let set = |key, value: Option<&str>| {
+            // SAFETY: tests that touch the environment hold `ENV`.
+            unsafe {
+                match value {
+                    Some(value) => env::set_var(key, value),
+                    None => env::remove_var(key),
+                }
+            }
+        };
+
+        assert_eq!(threshold(&["photos"]).as_deref(), Some("--threshold=6"));
+        set("IMAGE_DUPLICATE_THRESHOLD", Some("5"));
+        assert_eq!(threshold(&["photos"]).as_deref(), Some("--threshold=5"));
+        set("IMAGE_DUPLICATE_PROFILE", Some("screenshots"));
+        assert_eq!(threshold(&["photos"]).as_deref(), Some("--threshold=3"));
+        assert_eq!(
+            threshold(&["--profile", "mine", "photos"]).as_deref(),
+            Some("--threshold=2")
+        );
+        // The command line wins, and so do options that conflict with the
+        // ones it gives.
+        assert_eq!(threshold(&["--threshold", "1", "photos"]), None);
+        assert_eq!(threshold(&["--similarity", "90", "photos"]), None);
+        set("IMAGE_DUPLICATE_PROFILE", None);
+        set("IMAGE_DUPLICATE_THRESHOLD", None);
+
+        // A flag in the environment can turn off one set in the file.
+        let args = list_args(&config, &["photos"]).unwrap();
+        assert!(args.contains(&"--recursive".into()));
+        set("IMAGE_DUPLICATE_RECURSIVE", Some("off"));
+        let args = list_args(&config, &["photos"]).unwrap();
+        assert!(!args.contains(&"--recursive".into()));
+        set("IMAGE_DUPLICATE_RECURSIVE", Some("maybe"));
+        let err = list_args(&config, &["photos"]).unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidValue(..)), "{err}");
+        set("IMAGE_DUPLICATE_RECURSIVE", None);
+
+        let err =
+            list_args(&config, &["--profile", "nope", "photos"]).unwrap_err();
+        assert!(matches!(err, ConfigError::UnknownProfile(_)), "{err}");
+    }
+
+    #[test]
+    fn bad_input() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let missing = env::temp_dir().join("image-duplicate-no-such-config");
+        let err = Config::from_file(&missing).unwrap_err();
+        assert!(matches!(err, ConfigError::IOError(..)), "{err}");
+
+        let dir = env::temp_dir()
+            .join(format!("image-duplicate-config-bad-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.toml");
+        fs::write(&file, "threshold = = 6").unwrap();
+        let err = Config::from_file(&file).unwrap_err();
+        assert!(matches!(err, ConfigError::ParseError(..)), "{err}");
+        fs::remove_dir_all(dir).unwrap();
+
+        for text in [
+            "recursive = \"maybe\"",
+            "recursive = 1",
+            "exclude = [[\"nested\"]]",
+            "threshold = 1979-05-27",
+            "threshold = { value = 6 }",
+        ] {
+            let config = config("config-bad-values", text);
+            let err = list_args(&config, &["photos"]).unwrap_err();
+            assert!(
+                matches!(err, ConfigError::InvalidValue(..)),
+                "{text}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let config = config(
+            "config-unknown",
+            "threshold = 6\nno_dump = true\nthreshhold = 6\n\n\
+             [profiles.mine]\nrecursive = true\ncolour = \"always\"\n\n\
+             [profiles]\nodd = 3\n",
+        );
+        let warnings = config.unknown(&Args::command());
+        assert_eq!(warnings.len(), 3, "{warnings:?}");
+        assert!(
+            warnings
+                .iter()
+                .any(|x| x.starts_with("unknown key \"threshhold\" in "))
+        );
+        assert!(
+            warnings
+                .contains(&"unknown key \"colour\" in profile \"mine\"".into())
+        );
+        assert!(warnings.iter().any(|x| x.starts_with("profile \"odd\"")));
+    }
+}
@@ -0,0 +1,297 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Running a command for each pair of similar images. [`Hook::parse`] and
+//! [`run`] form the main interface.
+
+use std::{
+    ffi::{OsStr, OsString},
+    io,
+    num::NonZeroUsize,
+    path::Path,
+    process::{Command, ExitStatus},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+};
+use thiserror::Error;
+
+/// Placeholders substituted in the arguments of a [`Hook`].
+pub const PLACEHOLDERS: [&str; 3] = ["{left}", "{right}", "{distance}"];
+
+/// Error type for hook commands.
+#[derive(Debug, Error)]
+pub enum ExecError {
+    /// The command could not be split into arguments.
+    #[error("Invalid --exec command: {0}")]
+    Parse(&'static str),
+
+    /// The command could not be started.
+    #[error("Could not run {0:?}: {1}")]
+    Spawn(String, io::Error),
+
+    /// The command failed with `--exec-fail-fast`.
+    #[error("Command for {left:?} and {right:?} failed: {status}")]
+    Failed {
+        left: String,
+        right: String,
+        status: ExitStatus,
+    },
+}
+
+/// A command to run for each pair, split into its program and arguments.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Hook(Vec<String>);
+
+impl Hook {
+    /// Split a command into words like a shell would, honoring single quotes,
+    /// double quotes, and backslashes, but without expanding anything.
+    /// Placeholders are substituted into the words later, so paths are passed
+    /// as whole arguments whatever characters they contain.
+    pub fn parse(template: &str) -> Result<Hook, ExecError> {
+        let mut words = Vec::new();
+        let mut word: Option<String> = None;
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => {
+                    words.extend(word.take());
+                }
+                '\'' => {
+                    let word = word.get_or_insert_default();
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => word.push(c),
+                            None => {
+                                return Err(ExecError::Parse(
+                                    "unterminated single quote",
+                                ));
+                            }
+                        }
+                    }
+                }
+                '"' => {
+                    let word = word.get_or_insert_default();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some(c @ ('"' | '\\')) => word.push(c),
+                                Some(c) => {
+                                    word.push('\\');
+                                    word.push(c);
+                                }
+                                None => {
+                                    return Err(ExecError::Parse(
+                                        "unterminated double quote",
+                                    ));
+                                }
+                            },
+                            Some(c) => word.push(c),
+                            None => {
+                                return Err(ExecError::Parse(
+                                    "unterminated double quote",
+                                ));
+                            }
+                        }
+                    }
+                }
+                '\\' => match chars.next() {
+                    Some(c) => word.get_or_insert_default().push(c),
+                    None => {
+                        return Err(ExecError::Parse("trailing backslash"));
+                    }
+                },
+                c => word.get_or_insert_default().push(c),
+            }
+        }
+        words.extend(word);
+
+        match words.is_empty() {
+            true => Err(ExecError::Parse("empty command")),
+            false => Ok(Hook(words)),
+        }
+    }
+
+    /// Command for a pair, with the placeholders substituted.
+    fn command(&self, left: &Path, right: &Path, distance: u32) -> Command {
+        let distance = distance.to_string();
+        let values = [left.as_os_str(), right.as_os_str(), distance.as_ref()];
+        let mut words = self.0.iter().map(|word| substitute(word, values));
+        let mut command = Command::new(words.next().expect("nonempty"));
+        command.args(words);
+        command
+    }
+}
+
+/// Substitute the placeholders in a word of a command. Paths go in as they
+/// are, so that names which are not UTF-8 reach the command intact.
+fn substitute(word: &str, values: [&OsStr; 3]) -> OsString {
+    let mut out = OsString::new();
+    let mut rest = word;
+    while let Some((at, i)) = PLACEHOLDERS
+        .iter()
+        .enumerate()
+        .filter_map(|(i, p)| Some((rest.find(p)?, i)))
+        .min()
+    {
+        out.push(&rest[..at]);
+        out.push(values[i]);
+        rest = &rest[at + PLACEHOLDERS[i].len()..];
+    }
+    out.push(rest);
+    out
+}
+
+/// Totals for a [`run`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ExecSummary {
+    /// Commands run.
+    pub ran: usize,
+    /// Commands that exited unsuccessfully.
+    pub failed: usize,
+}
+
+/// Run `hook` for each `(left, right, distance)` pair, with up to `jobs`
+/// commands at once. Commands inherit stdin, stdout, and stderr. Failed
+/// commands are counted and reported on stderr; with `fail_fast`, the first
+/// failure stops starting new commands and is returned as an error once the
+/// running ones finish. A command that cannot be started at all is always an
+/// error.
+pub fn run(
+    hook: &Hook,
+    pairs: &[(&Path, &Path, u32)],
+    jobs: NonZeroUsize,
+    fail_fast: bool,
+) -> Result<ExecSummary, ExecError> {
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let summary = Mutex::new(ExecSummary::default());
+    let error: Mutex<Option<ExecError>> = Mutex::new(None);
+
+    let worker = || {
+        while !stop.load(Ordering::Relaxed) {
+            let Some(&(left, right, distance)) =
+                pairs.get(next.fetch_add(1, Ordering::Relaxed))
+            else {
+                break;
+            };
+            let status = hook.command(left, right, distance).status();
+            let mut summary = summary.lock().unwrap();
+            match status {
+                Ok(status) if status.success() => summary.ran += 1,
+                Ok(status) => {
+                    summary.ran += 1;
+                    summary.failed += 1;
+                    let failure = ExecError::Failed {
+                        left: left.display().to_string(),
+                        right: right.display().to_string(),
+                        status,
+                    };
+                    match fail_fast {
+                        true => {
+                            stop.store(true, Ordering::Relaxed);
+                            error.lock().unwrap().get_or_insert(failure);
+                        }
+                        false => eprintln!("{failure}"),
+                    }
+                }
+                Err(e) => {
+                    stop.store(true, Ordering::Relaxed);
+                    error
+                        .lock()
+                        .unwrap()
+                        .get_or_insert(ExecError::Spawn(hook.0[0].clone(), e));
+                }
+            }
+        }
+    };
+    thread::scope(|s| {
+        for _ in 0..jobs.get().min(pairs.len()) {
+            s.spawn(worker);
+        }
+    });
+
+    match error.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(summary.into_inner().unwrap()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Words of `template`, which must parse.
+    fn words(template: &str) -> Vec<String> {
+        Hook::parse(template).unwrap().0
+    }
+
+    /// Why `template` does not parse.
+    fn error(template: &str) -> &'static str {
+        match Hook::parse(template) {
+            Err(ExecError::Parse(e)) => e,
+            other => panic!("{template:?} parsed as {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_quotes() {
+        assert_eq!(
+            words("  cmp  {left}\t{right} "),
+            ["cmp", "{left}", "{right}"]
+        );
+        assert_eq!(words(r#"echo 'a "b" \c'"#), ["echo", r#"a "b" \c"#]);
+        assert_eq!(words(r#"echo "a \"b\" \\ \c""#), ["echo", r#"a "b" \ \c"#]);
+        assert_eq!(words(r"echo a\ b \'"), ["echo", "a b", "'"]);
+        assert_eq!(words(r#"echo '' "" x'y'"z""#), ["echo", "", "", "xyz"]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(error("echo 'a"), "unterminated single quote");
+        assert_eq!(error(r#"echo "a"#), "unterminated double quote");
+        assert_eq!(error(r#"echo "a\"#), "unterminated double quote");
+        assert_eq!(error(r"echo a\"), "trailing backslash");
+        assert_eq!(error(" \t"), "empty command");
+    }
+
+    #[test]
+    fn substitution() {
+        let values = [OsStr::new("a b"), OsStr::new("c'd"), OsStr::new("7")];
+        assert_eq!(substitute("--a={left}", values), "--a=a b");
+        assert_eq!(
+            substitute("{right}{left}:{distance}{", values),
+            "c'da b:7{"
+        );
+        assert_eq!(substitute("{lef}", values), "{lef}");
+    }
+
+    /// Placeholders within a path are part of the path, not substituted
+    /// again.
+    #[test]
+    fn substitution_is_not_recursive() {
+        let hook = Hook::parse("cmp --x={left} {right}").unwrap();
+        let left = Path::new("{right}.png");
+        let right = Path::new("{distance}{left}.png");
+        let command = hook.command(left, right, 3);
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, ["--x={right}.png", "{distance}{left}.png"]);
+    }
+}
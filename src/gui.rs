@@ -14,44 +14,264 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::{
+    actions::{ActionError, Reason, Remover, resized_variant},
+    hashdb::{HashDB, HashDBError, Pair, border_box, open_image},
+    jpeg,
+    metadata::{Field, metadata_only},
+    progress::{Phase, ScanObserver, Summary},
+    review::{Decision, Review, ReviewError},
+};
 use fltk::{
-    app::{self, App, Receiver, Scheme},
+    app::{self, App, MouseWheel, Receiver, Scheme, Sender},
     button::Button,
-    enums::{ColorDepth, FrameType, Shortcut},
+    dialog, draw,
+    enums::{Color, ColorDepth, Event, FrameType, Key, Shortcut},
     frame::Frame,
     group::Flex,
     image::RgbImage,
+    misc::Progress as ProgressBar,
     prelude::*,
     window::Window,
 };
-use image::{DynamicImage, GenericImage};
-use std::{fs, path::Path};
+use image::{
+    DynamicImage, GenericImage, GenericImageView, imageops::FilterType,
+};
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    env,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver as LensReceiver, Sender as LensSender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
 use thiserror::Error;
-use trash;
 
 const THUMB_SIZE: u32 = 384;
 const FRAME_SIZE: i32 = (5 * THUMB_SIZE / 4) as i32;
 const BUTTON_SIZE: i32 = 40;
 const EXTRA_PADDING: i32 = 10;
+const LABEL_HEIGHT: i32 = 14;
+const LENS_SIZE: u32 = 192;
+const LENS_ZOOM: (u32, u32) = (2, 4);
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(50);
 
 /// Main GUI struct.
 #[derive(Debug)]
 pub struct GUI {
     app: App,
     win: Window,
+    sender: Sender<Message>,
     receiver: Receiver<Message>,
+    main: Flex,
+    loading: Flex,
+    status: Frame,
+    bar: ProgressBar,
+    preview: Flex,
+    progress: Arc<Mutex<Status>>,
+    cancel: Arc<AtomicBool>,
     frame_l: Frame,
     frame_r: Frame,
-    idx: usize,
-    duplicates: Vec<(String, String)>,
+    lens: Rc<RefCell<Lens>>,
+    lens_tx: LensSender<(usize, Option<[DynamicImage; 2]>)>,
+    lens_rx: LensReceiver<(usize, Option<[DynamicImage; 2]>)>,
+    /// Results of checking pairs on a worker thread.
+    compare_tx: mpsc::Sender<(usize, Comparison)>,
+    compare_rx: mpsc::Receiver<(usize, Comparison)>,
+    /// Whether the window label shows the result of such a check.
+    tagged: bool,
+    /// Whether images had their borders cropped before hashing.
+    trim_borders: bool,
+    /// Pairs of byte-for-byte identical files.
+    exact: HashSet<Pair>,
+    buttons: Flex,
+    button_l: Button,
+    button_r: Button,
+    removal_tx: mpsc::Sender<Removal>,
+    removal_rx: mpsc::Receiver<Removal>,
+    review: Review,
+    /// The remover, or `None` while a removal runs on a worker thread.
+    remover: Option<Box<dyn Remover>>,
+}
+
+/// What a worker thread found out about a pair.
+#[derive(Debug)]
+struct Comparison {
+    /// The kinds of metadata that differ, if the pixels are the same.
+    metadata: Option<Vec<Field>>,
+    /// Which images have borders that were cropped before hashing.
+    trimmed: [bool; 2],
 }
 
+/// The remover handed back by a removal worker, with the result.
+type Removal = (Box<dyn Remover>, std::result::Result<(), ActionError>);
+
 /// GUI Events
 #[derive(Clone, Copy, Debug)]
 enum Message {
     LeftPressed,
     CenterPressed,
     RightPressed,
+    UndoPressed,
+    LensToggled,
+    LensLoaded,
+    Compared,
+    RemovalDone,
+    Progressed,
+    CancelPressed,
+    ScanDone,
+}
+
+/// What the progress screen shows.
+#[derive(Debug, Default)]
+struct Status {
+    text: String,
+    /// Fraction of the current phase done, if known.
+    fraction: Option<f64>,
+    /// When the GUI was last told about a change.
+    sent: Option<Instant>,
+}
+
+/// Reports the progress of a scan to the progress screen of a [`GUI`], and
+/// passes everything on to another observer. Cancelling from the GUI cancels
+/// the scan. Call [`ScanProgress::done`] when the scan and anything after it is
+/// over.
+pub struct ScanProgress {
+    inner: Box<dyn ScanObserver>,
+    sender: Sender<Message>,
+    status: Arc<Mutex<Status>>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl ScanProgress {
+    /// Update the progress screen, at most every [`PROGRESS_INTERVAL`] unless
+    /// `force`.
+    fn update(&self, force: bool, f: impl FnOnce(&mut Status)) {
+        let mut status = self.status.lock().unwrap();
+        f(&mut status);
+        let now = Instant::now();
+        if force || status.sent.is_none_or(|x| now - x >= PROGRESS_INTERVAL) {
+            status.sent = Some(now);
+            self.sender.send(Message::Progressed);
+        }
+    }
+
+    /// Tell the GUI that the scan is over, so that it can start the review.
+    pub fn done(&self) {
+        self.sender.send(Message::ScanDone);
+    }
+}
+
+impl ScanObserver for ScanProgress {
+    fn database(&self, file: &Path, existing: bool) {
+        self.inner.database(file, existing);
+    }
+
+    fn phase(&self, phase: Phase) {
+        self.inner.phase(phase);
+        let text = match phase {
+            Phase::Load => "Reading database...",
+            Phase::Scan => "Scanning for images...",
+            Phase::Hash => "Hashing new images...",
+            Phase::Save => "Dumping database...",
+            Phase::Match => "Finding duplicate images...",
+        };
+        self.update(true, |x| {
+            x.text = text.into();
+            x.fraction = None;
+        });
+    }
+
+    fn hashing(&self, file: &Path, done: usize, total: usize) {
+        self.inner.hashing(file, done, total);
+        self.update(done == total, |x| {
+            x.text = format!("Hashing new images: {done} of {total}");
+            x.fraction = Some(done as f64 / total.max(1) as f64);
+        });
+    }
+
+    fn phase_finished(&self, phase: Phase, elapsed: Duration) {
+        self.inner.phase_finished(phase, elapsed);
+    }
+
+    fn scanned(&self, images: usize, new: usize) {
+        self.inner.scanned(images, new);
+    }
+
+    fn hashed(&self, file: &Path, elapsed: Duration) {
+        self.inner.hashed(file, elapsed);
+    }
+
+    fn checkpoint(&self, hashdb: &HashDB) {
+        self.inner.checkpoint(hashdb);
+    }
+
+    fn error(&self, file: &Path, error: &HashDBError) {
+        self.inner.error(file, error);
+    }
+
+    fn pruned(&self, removed: usize) {
+        self.inner.pruned(removed);
+    }
+
+    fn unreadable(&self, dir: &Path, error: &HashDBError) {
+        self.inner.unreadable(dir, error);
+    }
+
+    fn pair(&self, img_1: &Path, img_2: &Path, distance: u32) {
+        self.inner.pair(img_1, img_2, distance);
+    }
+
+    fn matching(&self, done: u64, total: u64, pairs: usize) {
+        self.inner.matching(done, total, pairs);
+        self.update(done == total, |x| {
+            x.text = format!("Finding duplicate images: {pairs} pairs so far");
+            x.fraction = Some(done as f64 / total.max(1) as f64);
+        });
+    }
+
+    fn finished(&self, summary: &Summary) {
+        self.inner.finished(summary);
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed) || self.inner.cancelled()
+    }
+}
+
+/// State of the magnifier lens, shared between both preview frames so that
+/// they show the same relative position.
+#[derive(Debug)]
+struct Lens {
+    enabled: bool,
+    zoom: u32,
+    /// Cursor position relative to the displayed image, in `0.0..=1.0`.
+    pos: Option<(f64, f64)>,
+    /// Dimensions of the displayed images.
+    dims: [(u32, u32); 2],
+    /// Full-resolution decodes of the displayed images, loaded on demand.
+    images: Option<[DynamicImage; 2]>,
+    loading: bool,
+}
+
+impl Default for Lens {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            zoom: LENS_ZOOM.0,
+            pos: None,
+            dims: [(1, 1); 2],
+            images: None,
+            loading: false,
+        }
+    }
 }
 
 /// Errors that may occur when dealing with [`GUI`].
@@ -65,13 +285,53 @@ pub enum GUIError {
     #[error("Image error: {0}")]
     ImageError(#[from] image::ImageError),
 
-    /// Wrapper around [`std::io::Error`]
-    #[error("IO error: {0}")]
-    IOError(#[from] std::io::Error),
+    /// Wrapper around [`image::ImageError`], with the file involved
+    #[error("Could not read {path:?}: {source}")]
+    Image {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+
+    /// Wrapper around [`ActionError`]
+    #[error("{0}")]
+    ActionError(#[from] ActionError),
+
+    /// Wrapper around [`ReviewError`]
+    #[error("{0}")]
+    ReviewError(#[from] ReviewError),
+}
 
-    /// Wrapper around [`trash::Error`]
-    #[error("Trash error: {0}")]
-    TrashError(#[from] trash::Error),
+impl GUIError {
+    /// Wrap an image error on `path`, for use with [`Result::map_err`].
+    fn image<P: AsRef<Path>>(
+        path: P,
+    ) -> impl FnOnce(image::ImageError) -> Self {
+        move |source| GUIError::Image {
+            path: path.as_ref().to_path_buf(),
+            source,
+        }
+    }
+}
+
+/// Whether there is a display to show the GUI on. On Unix systems other than
+/// macOS, that takes an X11 or Wayland display, as named by `DISPLAY` or
+/// `WAYLAND_DISPLAY`; elsewhere, a display is assumed.
+pub fn has_display() -> bool {
+    display_named(
+        env::var_os("DISPLAY").as_deref(),
+        env::var_os("WAYLAND_DISPLAY").as_deref(),
+    )
+}
+
+/// Whether `x11` or `wayland`, the values of `DISPLAY` and `WAYLAND_DISPLAY`,
+/// name a display, as [`has_display`] decides.
+fn display_named(x11: Option<&OsStr>, wayland: Option<&OsStr>) -> bool {
+    match cfg!(all(unix, not(target_os = "macos"))) {
+        true => [x11, wayland]
+            .iter()
+            .any(|x| x.is_some_and(|x| !x.is_empty())),
+        false => true,
+    }
 }
 
 /// Simple result wrapper.
@@ -81,7 +341,8 @@ pub type Result<T> = std::result::Result<T, GUIError>;
 /// image.
 fn load_image<P: AsRef<Path>>(file: P) -> Result<RgbImage> {
     assert!(file.as_ref().is_file());
-    let img = image::open(file)?
+    let img = open_image(&file)
+        .map_err(GUIError::image(&file))?
         .thumbnail(THUMB_SIZE, THUMB_SIZE)
         .to_rgba8();
     let mut embed = DynamicImage::new_rgb8(THUMB_SIZE, THUMB_SIZE);
@@ -104,23 +365,215 @@ fn load_image<P: AsRef<Path>>(file: P) -> Result<RgbImage> {
     )?)
 }
 
-fn display_image<P: AsRef<Path>>(f: &mut Frame, file: P) -> Result<()> {
-    let size = image::image_dimensions(&file)?;
-    let label = format!(
-        "{} {size:?}",
-        file.as_ref()
-            .file_name()
-            .expect("display_image should only receive valid filenames")
-            .to_string_lossy()
-    );
-    f.set_label(&label);
+/// Label of an image in a frame: its file name and dimensions, and the
+/// estimated quality if it is a JPEG.
+fn image_label<P: AsRef<Path>>(
+    file: P,
+    size: (u32, u32),
+    quality: Option<u8>,
+) -> String {
+    let name = file
+        .as_ref()
+        .file_name()
+        .expect("display_pair should only receive valid filenames")
+        .to_string_lossy();
+    match quality {
+        Some(quality) => format!("{name} {size:?} quality {quality}"),
+        None => format!("{name} {size:?}"),
+    }
+}
+
+/// Display an image in a frame, leaving the label to the caller. Returns the
+/// dimensions of the image.
+fn display_image<P: AsRef<Path>>(f: &mut Frame, file: P) -> Result<(u32, u32)> {
+    let size =
+        image::image_dimensions(&file).map_err(GUIError::image(&file))?;
     f.set_image(Some(load_image(&file)?));
-    Ok(())
+    Ok(size)
+}
+
+/// Area covered by an image of the given dimensions within a frame, as
+/// `(x, y, w, h)`. This mirrors how [`load_image`] embeds the thumbnail, with
+/// the label drawn underneath.
+fn content_rect(f: &Frame, (w, h): (u32, u32)) -> (i32, i32, i32, i32) {
+    let scale = THUMB_SIZE as f64 / w.max(h).max(1) as f64;
+    let cw = ((w as f64 * scale) as i32).max(1);
+    let ch = ((h as f64 * scale) as i32).max(1);
+    let x = f.x() + (f.w() - cw) / 2;
+    let y = f.y() + (f.h() - ch - LABEL_HEIGHT) / 2;
+    (x, y, cw, ch)
+}
+
+/// Draw the magnifier lens over one side of the preview.
+fn draw_lens(f: &Frame, lens: &Lens, side: usize) {
+    let (Some((u, v)), Some(images)) = (lens.pos, &lens.images) else {
+        return;
+    };
+    if !lens.enabled {
+        return;
+    }
+    let img = &images[side];
+    let (x, y, w, h) = content_rect(f, img.dimensions());
+
+    // Square region of the source image that the lens magnifies.
+    let scale = img.width() as f64 / w as f64;
+    let span = (LENS_SIZE as f64 * scale / lens.zoom as f64)
+        .min(img.width().min(img.height()) as f64)
+        .max(1.0);
+    let left = (u * img.width() as f64 - span / 2.0)
+        .clamp(0.0, img.width() as f64 - span);
+    let top = (v * img.height() as f64 - span / 2.0)
+        .clamp(0.0, img.height() as f64 - span);
+    let view = img
+        .crop_imm(left as u32, top as u32, span as u32, span as u32)
+        .resize_exact(LENS_SIZE, LENS_SIZE, FilterType::Triangle)
+        .to_rgb8();
+    let Ok(mut view) = RgbImage::new(
+        view.as_raw(),
+        LENS_SIZE as i32,
+        LENS_SIZE as i32,
+        ColorDepth::Rgb8,
+    ) else {
+        return;
+    };
+
+    let cx = x + (u * w as f64) as i32 - LENS_SIZE as i32 / 2;
+    let cy = y + (v * h as f64) as i32 - LENS_SIZE as i32 / 2;
+    draw::push_clip(f.x(), f.y(), f.w(), f.h());
+    view.draw(cx, cy, LENS_SIZE as i32, LENS_SIZE as i32);
+    draw::set_draw_color(Color::Black);
+    draw::draw_rect(cx, cy, LENS_SIZE as i32, LENS_SIZE as i32);
+    draw::pop_clip();
+}
+
+/// Hook up the magnifier lens to a preview frame.
+fn attach_lens(f: &mut Frame, lens: &Rc<RefCell<Lens>>, side: usize) {
+    let l = lens.clone();
+    f.draw(move |f| draw_lens(f, &l.borrow(), side));
+
+    let l = lens.clone();
+    f.handle(move |f, ev| match ev {
+        Event::Enter => true,
+        Event::Move => {
+            let mut lens = l.borrow_mut();
+            if lens.enabled {
+                let (x, y, w, h) = content_rect(f, lens.dims[side]);
+                let u = (app::event_x() - x) as f64 / w as f64;
+                let v = (app::event_y() - y) as f64 / h as f64;
+                lens.pos = ((0.0..=1.0).contains(&u)
+                    && (0.0..=1.0).contains(&v))
+                .then_some((u, v));
+                app::redraw();
+            }
+            true
+        }
+        Event::Leave => {
+            l.borrow_mut().pos = None;
+            app::redraw();
+            true
+        }
+        Event::MouseWheel => {
+            let mut lens = l.borrow_mut();
+            if !lens.enabled {
+                return false;
+            }
+            lens.zoom = match app::event_dy() {
+                MouseWheel::Up => lens.zoom + 1,
+                MouseWheel::Down => lens.zoom.saturating_sub(1),
+                _ => lens.zoom,
+            }
+            .clamp(LENS_ZOOM.0, LENS_ZOOM.1);
+            app::redraw();
+            true
+        }
+        _ => false,
+    });
 }
 
 impl GUI {
     /// Create a new GUI.
-    pub fn build(duplicates: Vec<(String, String)>) -> Result<Self> {
+    pub fn build(
+        duplicates: Vec<Pair>,
+        remover: Box<dyn Remover>,
+    ) -> Result<Self> {
+        Self::new(duplicates, Some(remover))
+    }
+
+    /// Create a new GUI for a scan that runs on another thread, and show its
+    /// progress right away. Returns the observer for the scan, which reports
+    /// to `inner` as well. Once the scan is done, [`GUI::wait_for_scan`]
+    /// returns, and [`GUI::review`] hands over the results.
+    pub fn scanning(
+        inner: Box<dyn ScanObserver>,
+    ) -> Result<(Self, ScanProgress)> {
+        let mut gui = Self::new(Vec::new(), None)?;
+        gui.preview.hide();
+        gui.loading.show();
+        gui.main.layout();
+        gui.win.show();
+        let progress = ScanProgress {
+            inner,
+            sender: gui.sender,
+            status: gui.progress.clone(),
+            cancel: gui.cancel.clone(),
+        };
+        Ok((gui, progress))
+    }
+
+    /// Show the progress of a scan until [`ScanProgress::done`] is called.
+    /// Returns whether the scan ran to completion, rather than being
+    /// cancelled or having its window closed.
+    pub fn wait_for_scan(&mut self) -> bool {
+        while self.app.wait() {
+            match self.receiver.recv() {
+                Some(Message::Progressed) => {
+                    let status = self.progress.lock().unwrap();
+                    self.status.set_label(&status.text);
+                    self.bar.set_value(status.fraction.unwrap_or(0.0));
+                    drop(status);
+                    self.win.redraw();
+                }
+                Some(Message::CancelPressed) => {
+                    self.cancel.store(true, Ordering::Relaxed);
+                    self.status.set_label("Cancelling...");
+                    self.loading.deactivate();
+                    self.win.redraw();
+                }
+                Some(Message::ScanDone) => {
+                    return !self.cancel.load(Ordering::Relaxed);
+                }
+                _ => (),
+            }
+        }
+        self.cancel.store(true, Ordering::Relaxed);
+        false
+    }
+
+    /// Switch from the progress screen to reviewing `duplicates`.
+    /// `trim_borders` tells whether their images had borders cropped before
+    /// hashing, as the database says, and `exact` which pairs are of
+    /// byte-for-byte identical files.
+    pub fn review(
+        &mut self,
+        duplicates: Vec<Pair>,
+        remover: Box<dyn Remover>,
+        trim_borders: bool,
+        exact: HashSet<Pair>,
+    ) {
+        self.review = Review::new(duplicates);
+        self.trim_borders = trim_borders;
+        self.exact = exact;
+        self.remover = Some(remover);
+        self.loading.hide();
+        self.preview.show();
+        self.main.layout();
+    }
+
+    /// Create the window, showing the review screen.
+    fn new(
+        duplicates: Vec<Pair>,
+        remover: Option<Box<dyn Remover>>,
+    ) -> Result<Self> {
         let (s, receiver) = app::channel();
         let app = App::default().with_scheme(Scheme::Base);
 
@@ -134,13 +587,42 @@ impl GUI {
         );
         win.make_resizable(true);
 
-        let mut main = Flex::default().column().size_of_parent();
+        let main = Flex::default().column().size_of_parent();
+
+        let lens = Rc::new(RefCell::new(Lens::default()));
+        let (lens_tx, lens_rx) = mpsc::channel();
+        let (removal_tx, removal_rx) = mpsc::channel();
+        let (compare_tx, compare_rx) = mpsc::channel();
 
+        let mut loading = Flex::default().column();
+        Frame::default();
+        let status = Frame::default().with_label("Starting...");
+        let mut bar = ProgressBar::default();
+        bar.set_minimum(0.0);
+        bar.set_maximum(1.0);
+        let mut cancel_row = Flex::default().row();
+        Frame::default();
+        let mut button_cancel = Button::default().with_label("Cancel");
+        button_cancel.emit(s, Message::CancelPressed);
+        Frame::default();
+        cancel_row.fixed(&button_cancel, FRAME_SIZE / 2);
+        cancel_row.end();
+        Frame::default();
+        loading.fixed(&status, BUTTON_SIZE);
+        loading.fixed(&bar, BUTTON_SIZE / 2);
+        loading.fixed(&cancel_row, BUTTON_SIZE);
+        loading.set_margins(FRAME_SIZE / 4, 0, FRAME_SIZE / 4, 0);
+        loading.end();
+        loading.hide();
+
+        let mut preview = Flex::default().column();
         let row1 = Flex::default().row();
         let mut frame_l = Frame::default();
         let mut frame_r = Frame::default();
         frame_l.set_frame(FrameType::EngravedBox);
         frame_r.set_frame(FrameType::EngravedBox);
+        attach_lens(&mut frame_l, &lens, 0);
+        attach_lens(&mut frame_r, &lens, 1);
         row1.end();
 
         let mut row2 = Flex::default().row();
@@ -156,69 +638,429 @@ impl GUI {
         row2.set_margins(5, 0, 5, 5);
         row2.end();
 
-        main.fixed(&row2, BUTTON_SIZE);
+        preview.fixed(&row2, BUTTON_SIZE);
+        preview.end();
 
         main.end();
 
         win.end();
 
+        // Z toggles the magnifier lens; U goes back to the previous pair,
+        // undoing its removal.
+        win.handle(move |_, ev| match ev {
+            Event::KeyDown | Event::Shortcut
+                if app::event_key() == Key::from_char('z') =>
+            {
+                s.send(Message::LensToggled);
+                true
+            }
+            Event::KeyDown | Event::Shortcut
+                if app::event_key() == Key::from_char('u') =>
+            {
+                s.send(Message::UndoPressed);
+                true
+            }
+            _ => false,
+        });
+
         Ok(Self {
             app,
             win,
+            sender: s,
             receiver,
+            main,
+            loading,
+            status,
+            bar,
+            preview,
+            progress: Arc::default(),
+            cancel: Arc::default(),
             frame_l,
             frame_r,
-            idx: 0,
-            duplicates,
+            lens,
+            lens_tx,
+            lens_rx,
+            compare_tx,
+            compare_rx,
+            tagged: false,
+            trim_borders: false,
+            exact: HashSet::new(),
+            buttons: row2,
+            button_l,
+            button_r,
+            removal_tx,
+            removal_rx,
+            review: Review::new(duplicates),
+            remover,
         })
     }
 
-    /// Run the GUI program. Consumes the program.
-    pub fn run(mut self) -> Result<()> {
-        self.win.show();
+    /// Show the current pair of images and reset the lens for them. When one
+    /// image is a downscaled copy of the other, it is labeled as such and
+    /// keeping the other is suggested; when both are JPEGs of the same size,
+    /// keeping the one saved at the higher quality is. Identical files are
+    /// noted in the window label.
+    fn display_pair(&mut self, img_1: &Path, img_2: &Path) -> Result<()> {
+        let dims = [
+            display_image(&mut self.frame_l, img_1)?,
+            display_image(&mut self.frame_r, img_2)?,
+        ];
+        let quality = [jpeg::quality(img_1), jpeg::quality(img_2)];
 
-        let (mut img_1, mut img_2) = match self.duplicates.get(self.idx) {
-            Some(dup) => (&dup.0, &dup.1),
-            None => return Ok(()),
+        let resized = resized_variant(dims);
+        let suggested = match (resized, quality) {
+            (Some(small), _) => Some((1 - small, "larger")),
+            (None, [Some(l), Some(r)]) if dims[0] == dims[1] && l != r => {
+                Some((usize::from(r > l), "better quality"))
+            }
+            _ => None,
         };
-        display_image(&mut self.frame_l, &img_1)?;
-        display_image(&mut self.frame_r, &img_2)?;
+        let frames = [(&mut self.frame_l, img_1), (&mut self.frame_r, img_2)];
+        for (i, (frame, img)) in frames.into_iter().enumerate() {
+            let label = image_label(img, dims[i], quality[i]);
+            match resized == Some(i) {
+                true => frame.set_label(&format!("{label} — resized copy")),
+                false => frame.set_label(&label),
+            }
+        }
+        let buttons = [
+            (&mut self.button_l, "1: Keep left"),
+            (&mut self.button_r, "3: Keep right"),
+        ];
+        for (i, (button, label)) in buttons.into_iter().enumerate() {
+            match suggested {
+                Some((x, why)) if x == i => {
+                    button.set_label(&format!("{label} ({why})"))
+                }
+                _ => button.set_label(label),
+            }
+        }
 
-        while self.app.wait() {
-            if let Some(msg) = self.receiver.recv() {
-                match msg {
-                    Message::LeftPressed => {
-                        eprintln!("Trashing \"{img_2}\"");
-                        trash::delete(&img_2)?;
-                    }
-                    Message::CenterPressed => {
-                        eprintln!("Keeping both images");
-                    }
-                    Message::RightPressed => {
-                        eprintln!("Trashing \"{img_1}\"");
-                        trash::delete(&img_1)?;
-                    }
+        if self.tagged {
+            self.win.set_label("");
+            self.tagged = false;
+        }
+        // Identical files need no decoding to tell that their pixels and
+        // metadata are the same.
+        let exact = self.exact.contains(&(img_1.into(), img_2.into()));
+        if exact {
+            self.win.set_label("Identical files");
+            self.tagged = true;
+        }
+        let pixels = dims[0] == dims[1] && !exact;
+        if pixels || self.trim_borders {
+            self.compare(img_1, img_2, pixels);
+        }
+
+        let mut lens = self.lens.borrow_mut();
+        lens.dims = dims;
+        lens.pos = None;
+        lens.images = None;
+        lens.loading = false;
+        let enabled = lens.enabled;
+        drop(lens);
+
+        if enabled {
+            self.load_lens();
+        }
+        Ok(())
+    }
+
+    /// Decode the current pair at full resolution in the background.
+    fn load_lens(&self) {
+        let Some((img_1, img_2)) = self.review.current().cloned() else {
+            return;
+        };
+        self.lens.borrow_mut().loading = true;
+
+        let idx = self.review.index();
+        let tx = self.lens_tx.clone();
+        let s = self.sender;
+        thread::spawn(move || {
+            let images = match open_image(&*img_1)
+                .and_then(|a| Ok([a, open_image(&*img_2)?]))
+            {
+                Ok(images) => Some(images),
+                Err(e) => {
+                    eprintln!("Could not load images for lens: {e}");
+                    None
                 }
+            };
+            let _ = tx.send((idx, images));
+            s.send(Message::LensLoaded);
+        });
+    }
 
-                self.idx += 1;
-                (img_1, img_2) = match self.duplicates.get(self.idx) {
-                    Some(dup) => (&dup.0, &dup.1),
-                    None => return Ok(()),
-                };
+    /// Check on a worker thread, since both need the images decoded in full,
+    /// whether the current pair differs only in metadata (with `pixels`),
+    /// and which of its images had borders cropped (if trimming is on).
+    fn compare(&self, img_1: &Path, img_2: &Path, pixels: bool) {
+        let idx = self.review.index();
+        let (img_1, img_2) = (img_1.to_owned(), img_2.to_owned());
+        let trim = self.trim_borders;
+        let tx = self.compare_tx.clone();
+        let s = self.sender;
+        thread::spawn(move || {
+            let metadata = match pixels {
+                true => metadata_only(&img_1, &img_2),
+                false => None,
+            };
+            let trimmed = [&img_1, &img_2].map(|x| {
+                trim && open_image(x).is_ok_and(|x| border_box(&x).is_some())
+            });
+            let _ = tx.send((idx, Comparison { metadata, trimmed }));
+            s.send(Message::Compared);
+        });
+    }
 
-                while !fs::exists(&img_1)? || !fs::exists(&img_2)? {
-                    self.idx += 1;
-                    (img_1, img_2) = match self.duplicates.get(self.idx) {
-                        Some(dup) => (&dup.0, &dup.1),
-                        None => return Ok(()),
-                    };
+    /// Note on the images which had borders cropped, and show in the window
+    /// label whether the current pair differs only in metadata, and in
+    /// which. Stale results are dropped.
+    fn receive_comparison(&mut self) {
+        for (idx, comparison) in self.compare_rx.try_iter() {
+            if idx != self.review.index() {
+                continue;
+            }
+            let frames = [&mut self.frame_l, &mut self.frame_r];
+            for (frame, trimmed) in frames.into_iter().zip(comparison.trimmed) {
+                if trimmed {
+                    let label = frame.label();
+                    frame.set_label(&format!("{label} — borders trimmed"));
                 }
+            }
+            let Some(fields) = comparison.metadata else {
+                continue;
+            };
+            let fields: Vec<String> =
+                fields.iter().map(|x| x.to_string()).collect();
+            match fields.is_empty() {
+                true => self.win.set_label("Pixel-identical"),
+                false => self.win.set_label(&format!(
+                    "Pixel-identical, metadata differs: {}",
+                    fields.join(", ")
+                )),
+            }
+            self.tagged = true;
+        }
+    }
+
+    /// Toggle the magnifier lens, loading the full-size images if needed.
+    fn toggle_lens(&mut self) {
+        let mut lens = self.lens.borrow_mut();
+        lens.enabled = !lens.enabled;
+        let load = lens.enabled && lens.images.is_none() && !lens.loading;
+        drop(lens);
 
-                display_image(&mut self.frame_l, &img_1)?;
-                display_image(&mut self.frame_r, &img_2)?;
+        if load {
+            self.load_lens();
+        }
+        self.win.redraw();
+    }
+
+    /// Accept finished full-size decodes for the current pair. Stale results
+    /// from pairs that have since been skipped are dropped.
+    fn receive_lens(&mut self) {
+        let mut lens = self.lens.borrow_mut();
+        for (idx, images) in self.lens_rx.try_iter() {
+            if idx == self.review.index() {
+                lens.images = images;
+                lens.loading = false;
+            }
+        }
+        drop(lens);
+        self.win.redraw();
+    }
+
+    /// Remove `file` as a duplicate of `kept` on a worker thread, so that a
+    /// slow trash or move does not block the event loop. The buttons are
+    /// disabled until [`Message::RemovalDone`] arrives, so removals never
+    /// overlap.
+    fn start_removal(&mut self, file: &Path, kept: &Path) {
+        let Some(mut remover) = self.remover.take() else {
+            return;
+        };
+        self.buttons.deactivate();
+        self.win.set_label("Removing…");
+        self.win.redraw();
+
+        let (file, kept) = (file.to_owned(), kept.to_owned());
+        let tx = self.removal_tx.clone();
+        let s = self.sender;
+        thread::spawn(move || {
+            let result = remover.remove(&file, &kept, Reason::Manual);
+            let _ = tx.send((remover, result));
+            s.send(Message::RemovalDone);
+        });
+    }
+
+    /// Take back the remover from a finished removal. Returns whether the
+    /// removal succeeded; failures are shown in a dialog.
+    fn finish_removal(&mut self) -> bool {
+        let Ok((mut remover, result)) = self.removal_rx.recv() else {
+            return false;
+        };
+        self.buttons.activate();
+        self.win.set_label("");
+        let note = remover.take_note();
+        self.remover = Some(remover);
+        match result {
+            Ok(()) => {
+                if let Some(note) = note {
+                    self.win.set_label(&note);
+                }
+                true
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                dialog::alert_default(&format!("{e}"));
                 self.win.redraw();
+                false
+            }
+        }
+    }
+
+    /// Carry out a decision on the current pair. Removals run on a worker
+    /// thread and are recorded once done; other decisions are recorded
+    /// right away. Returns whether the review moved on.
+    fn decide(&mut self, decision: Decision) -> Result<bool> {
+        if let Some((file, kept)) = self.review.removal(decision) {
+            let (file, kept) = (file.to_owned(), kept.to_owned());
+            self.start_removal(&file, &kept);
+            return Ok(false);
+        }
+        self.review.decide(decision)?;
+        Ok(true)
+    }
+
+    /// Go back to the last pair decided on, undoing its removal if there was
+    /// one. Returns whether the review moved.
+    fn undo(&mut self) -> bool {
+        let Some(last) = self.review.last() else {
+            eprintln!("Nothing to undo");
+            return false;
+        };
+        if last.removes() {
+            // Not every removal can be undone; stay put.
+            let remover = self.remover.as_mut().expect("not busy");
+            if let Err(e) = remover.undo_last() {
+                eprintln!("{e}");
+                return false;
+            }
+        }
+        self.review.undo();
+        true
+    }
+
+    /// Run the GUI program. Consumes the program. The decisions taken are
+    /// summed up at the end.
+    pub fn run(mut self) -> Result<()> {
+        self.win.show();
+
+        // The decision whose removal is running, if any.
+        let mut pending = None;
+        if let Some((img_1, img_2)) = self.review.current().cloned() {
+            self.display_pair(&img_1, &img_2)?;
+            while self.app.wait() {
+                let Some(msg) = self.receiver.recv() else {
+                    continue;
+                };
+                let busy = self.remover.is_none();
+                let decision = match msg {
+                    Message::LeftPressed
+                    | Message::CenterPressed
+                    | Message::RightPressed
+                    | Message::UndoPressed
+                        if busy =>
+                    {
+                        continue;
+                    }
+                    Message::LeftPressed => Decision::KeepLeft,
+                    Message::CenterPressed => Decision::KeepBoth,
+                    Message::RightPressed => Decision::KeepRight,
+                    Message::RemovalDone => {
+                        let decision = pending.take();
+                        if self.finish_removal()
+                            && let Some(decision) = decision
+                        {
+                            self.review.decide(decision)?;
+                            if !self.show_current()? {
+                                break;
+                            }
+                        }
+                        continue;
+                    }
+                    Message::UndoPressed => {
+                        if self.undo() {
+                            self.show_current()?;
+                        }
+                        continue;
+                    }
+                    Message::LensToggled => {
+                        self.toggle_lens();
+                        continue;
+                    }
+                    Message::LensLoaded => {
+                        self.receive_lens();
+                        continue;
+                    }
+                    Message::Compared => {
+                        self.receive_comparison();
+                        continue;
+                    }
+                    Message::Progressed
+                    | Message::CancelPressed
+                    | Message::ScanDone => continue,
+                };
+                match self.decide(decision)? {
+                    true if !self.show_current()? => break,
+                    true => (),
+                    false => pending = Some(decision),
+                }
             }
         }
+
+        // Let a removal still running when the window was closed finish.
+        if self.remover.is_none()
+            && self.finish_removal()
+            && let Some(decision) = pending
+        {
+            self.review.decide(decision)?;
+        }
+        eprintln!("{}", self.review.summary());
         Ok(())
     }
+
+    /// Show the current pair, if any. Returns whether there was one.
+    fn show_current(&mut self) -> Result<bool> {
+        let Some((img_1, img_2)) = self.review.current().cloned() else {
+            return Ok(false);
+        };
+        self.display_pair(&img_1, &img_2)?;
+        self.win.redraw();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn display_from_environment() {
+        use super::display_named;
+        use std::ffi::OsStr;
+
+        let cases = [
+            ([None, None], false),
+            ([Some(""), None], false),
+            ([None, Some("")], false),
+            ([Some(":0"), None], true),
+            ([None, Some("wayland-0")], true),
+            ([Some(""), Some("wayland-0")], true),
+        ];
+        for ([x11, wayland], expected) in cases {
+            let found =
+                display_named(x11.map(OsStr::new), wayland.map(OsStr::new));
+            assert_eq!(found, expected, "{x11:?}, {wayland:?}");
+        }
+    }
 }
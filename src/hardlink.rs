@@ -0,0 +1,305 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Replacing byte-identical images with hardlinks to one copy. [`hardlink`]
+//! forms the main interface.
+
+use crate::{
+    actions::groups,
+    hashdb::{HashDB, Pair},
+    script::ScriptRemover,
+};
+use std::{
+    collections::HashMap,
+    fs::{self, File, Metadata},
+    io,
+    os::unix::fs::MetadataExt,
+    path::Path,
+    process,
+};
+
+/// Totals for a [`hardlink`] run.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LinkSummary {
+    /// Groups of two or more identical files.
+    pub groups: usize,
+    /// Files replaced by hardlinks.
+    pub links: usize,
+    /// Bytes no longer taken up by separate copies.
+    pub bytes: u64,
+    /// Identical files left alone because they could not be linked.
+    pub skipped: usize,
+}
+
+/// A file to consider for linking.
+struct Candidate<'a> {
+    path: &'a Path,
+    meta: Metadata,
+}
+
+/// BLAKE3 checksum of a file's contents.
+fn checksum(path: &Path) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(hasher.finalize())
+}
+
+/// Sync a directory so that changes to its entries are durable.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Replace `target` with a hardlink to `keeper`. The link is made under a
+/// temporary name and renamed over `target`, so `target` is never missing.
+fn replace_with_link(keeper: &Path, target: &Path) -> io::Result<()> {
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let Some(name) = target.file_name() else {
+        return Err(io::ErrorKind::InvalidInput.into());
+    };
+    let mut tmp_name = name.to_os_string();
+    tmp_name.push(format!(".{}.link.tmp", process::id()));
+    let tmp = dir.join(tmp_name);
+
+    fs::hard_link(keeper, &tmp)?;
+    let result = sync_dir(dir)
+        .and_then(|_| fs::rename(&tmp, target))
+        .and_then(|_| sync_dir(dir));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Why `file` cannot be replaced by a link to `keeper`: it is on another
+/// filesystem, or it has changed since it was checksummed.
+fn obstacle(keeper: &Candidate, file: &Candidate) -> Option<String> {
+    if file.meta.dev() != keeper.meta.dev() {
+        return Some(format!("not on the filesystem of {:?}", keeper.path));
+    }
+    let unchanged = fs::symlink_metadata(file.path).is_ok_and(|x| {
+        x.ino() == file.meta.ino()
+            && x.len() == file.meta.len()
+            && x.modified().ok() == file.meta.modified().ok()
+    });
+    match unchanged {
+        true => None,
+        false => Some("file changed".to_owned()),
+    }
+}
+
+/// Replace byte-identical images with hardlinks. Candidates are the pairs at
+/// distance 0, since identical files always hash the same; within them, only
+/// files whose sizes and checksums match are linked, so images that merely
+/// look alike are never touched. Each set of identical files keeps the copy
+/// with the most links (the first path on ties), and files on a different
+/// filesystem from it are skipped. With `dry_run`, the links are only
+/// printed, and with `script`, they are added to it instead of being made.
+pub fn hardlink(
+    hashdb: &HashDB,
+    duplicates: &[Pair],
+    dry_run: bool,
+    mut script: Option<&mut ScriptRemover>,
+) -> LinkSummary {
+    let exact: Vec<(&Path, &Path)> = duplicates
+        .iter()
+        .filter(|(a, b)| hashdb.distance(a, b) == Some(0))
+        .map(|(a, b)| (&**a, &**b))
+        .collect();
+
+    let mut summary = LinkSummary::default();
+    for group in groups(&exact) {
+        let mut identical: HashMap<(u64, blake3::Hash), Vec<Candidate>> =
+            HashMap::new();
+        for path in group {
+            let meta = match fs::symlink_metadata(path) {
+                Ok(meta) if meta.is_file() => meta,
+                Ok(_) => continue,
+                Err(e) => {
+                    eprintln!("Skipping {path:?}: {e}");
+                    continue;
+                }
+            };
+            let sum = match checksum(path) {
+                Ok(sum) => sum,
+                Err(e) => {
+                    eprintln!("Skipping {path:?}: {e}");
+                    continue;
+                }
+            };
+            identical
+                .entry((meta.len(), sum))
+                .or_default()
+                .push(Candidate { path, meta });
+        }
+
+        let mut identical: Vec<_> = identical.into_values().collect();
+        identical.sort_by(|a, b| a[0].path.cmp(b[0].path));
+        for mut files in identical {
+            if files.len() < 2 {
+                continue;
+            }
+            summary.groups += 1;
+            files.sort_by(|a, b| {
+                b.meta.nlink().cmp(&a.meta.nlink()).then(a.path.cmp(b.path))
+            });
+            let (keeper, rest) = files.split_first().expect("two or more");
+
+            for file in rest {
+                if file.meta.dev() == keeper.meta.dev()
+                    && file.meta.ino() == keeper.meta.ino()
+                {
+                    continue;
+                }
+                if let Some(reason) = obstacle(keeper, file) {
+                    eprintln!("Skipping {:?}: {reason}", file.path);
+                    summary.skipped += 1;
+                    continue;
+                }
+
+                if let Some(script) = script.as_deref_mut() {
+                    script.link(file.path, keeper.path);
+                } else if dry_run {
+                    eprintln!(
+                        "Would link {:?} to {:?}",
+                        file.path, keeper.path
+                    );
+                } else if let Err(e) = replace_with_link(keeper.path, file.path)
+                {
+                    eprintln!("Skipping {:?}: {e}", file.path);
+                    summary.skipped += 1;
+                    continue;
+                } else {
+                    eprintln!("Linked {:?} to {:?}", file.path, keeper.path);
+                }
+
+                summary.links += 1;
+                if file.meta.nlink() == 1 {
+                    summary.bytes += file.meta.len();
+                }
+            }
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// An empty directory for test `name`.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("image-duplicate-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A candidate for `path` as it is now.
+    fn candidate(path: &Path) -> Candidate<'_> {
+        let meta = fs::symlink_metadata(path).unwrap();
+        Candidate { path, meta }
+    }
+
+    #[test]
+    fn links_identical_files() {
+        let dir = temp_dir("hardlink");
+        let files = [
+            ("a.png", "same"),
+            ("b.png", "same"),
+            ("c.png", "same, but longer"),
+            ("d.png", "sama"),
+            ("e.png", "same"),
+        ];
+        let mut hashdb = HashDB::new();
+        let hash = image_hasher::ImageHash::from_bytes(&[0; 8]).unwrap();
+        let paths: Vec<Arc<Path>> = files
+            .iter()
+            .map(|(name, contents)| {
+                let path = dir.join(name);
+                fs::write(&path, contents).unwrap();
+                hashdb.insert(&path, hash.clone().into()).unwrap();
+                Arc::from(path)
+            })
+            .collect();
+        let duplicates: Vec<Pair> = paths[1..]
+            .iter()
+            .map(|x| (paths[0].clone(), x.clone()))
+            .collect();
+        let ino = |x: &str| fs::metadata(dir.join(x)).unwrap().ino();
+
+        let summary = hardlink(&hashdb, &duplicates, true, None);
+        assert_eq!((summary.groups, summary.links), (1, 2));
+        assert_ne!(ino("a.png"), ino("b.png"));
+
+        let summary = hardlink(&hashdb, &duplicates, false, None);
+        assert_eq!(
+            summary,
+            LinkSummary {
+                groups: 1,
+                links: 2,
+                bytes: 8,
+                skipped: 0,
+            }
+        );
+        assert_eq!(ino("a.png"), ino("b.png"));
+        assert_eq!(ino("a.png"), ino("e.png"));
+        assert_ne!(ino("a.png"), ino("c.png"));
+        assert_ne!(ino("a.png"), ino("d.png"));
+        for (name, contents) in files {
+            assert_eq!(fs::read_to_string(dir.join(name)).unwrap(), contents);
+        }
+
+        // Already linked files are left as they are.
+        let summary = hardlink(&hashdb, &duplicates, false, None);
+        assert_eq!((summary.groups, summary.links), (1, 0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn changed_files_are_not_linked() {
+        let dir = temp_dir("hardlink-changed");
+        let (kept, file) = (dir.join("kept.png"), dir.join("file.png"));
+        fs::write(&kept, "same").unwrap();
+        fs::write(&file, "same").unwrap();
+        let (kept, file) = (candidate(&kept), candidate(&file));
+        assert_eq!(obstacle(&kept, &file), None);
+
+        fs::write(file.path, "changed").unwrap();
+        assert_eq!(obstacle(&kept, &file).as_deref(), Some("file changed"));
+
+        fs::remove_file(file.path).unwrap();
+        assert_eq!(obstacle(&kept, &file).as_deref(), Some("file changed"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// procfs is never on the filesystem of the temporary directory.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn other_filesystems_are_not_linked() {
+        let dir = temp_dir("hardlink-device");
+        let file = dir.join("file.png");
+        fs::write(&file, "same").unwrap();
+        let kept = candidate(Path::new("/proc/self/stat"));
+        let reason = obstacle(&kept, &candidate(&file)).unwrap();
+        assert!(reason.starts_with("not on the filesystem"), "{reason}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
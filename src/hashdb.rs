@@ -17,34 +17,125 @@
 //! Structs and methods for dealing with a database of image hashes. [`HashDB`]
 //! forms the main interface. `HashDB` is backed by a [`HashMap`] and supports
 //! hashing image files as well as reading and writing to Zlib'd
-//! [MessagePack][`rmp`].
+//! [MessagePack][`rmp`] behind a versioned header.
 
-use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
-use image_hasher::HasherConfig;
-use permutator::LargeCombinationIterator;
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use crate::{
+    actions::groups,
+    output::shuffle,
+    progress::{Phase, ScanObserver},
+};
+use clap::ValueEnum;
+use flate2::{
+    Compression, Decompress, FlushDecompress, Status, read::ZlibDecoder,
+    write::ZlibEncoder,
+};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use image::{
+    AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat, ImageReader,
+    Limits, RgbaImage,
+    codecs::{gif::GifDecoder, webp::WebPDecoder},
+};
+use image_hasher::{HashAlg, HasherConfig};
+use rayon::{
+    ThreadPoolBuilder,
+    iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator},
+};
+use rmp::Marker;
 use rmp_serde::{Serializer, config::BytesMode};
 use serde::{
     Deserialize, Serialize,
     de::{self, Visitor},
 };
 use std::{
+    borrow::Cow,
     collections::{HashMap, HashSet},
+    ffi::OsString,
     fmt::Display,
-    fs,
+    fs::{self, File},
     hash::Hash,
-    io::Write,
-    path::Path,
+    io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Write},
+    num::NonZeroUsize,
+    ops::AddAssign,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 use thiserror::Error;
 use walkdir::WalkDir;
 
-const SUFFIXES: [&str; 7] = ["bmp", "gif", "jpg", "jpeg", "jxl", "png", "webp"];
+/// Extensions of the image files a scan looks at unless told otherwise.
+pub const IMAGE_EXTENSIONS: [&str; 16] = [
+    "bmp", "gif", "jpg", "jpeg", "jxl", "pam", "pbm", "pgm", "png", "pnm",
+    "ppm", "qoi", "tga", "tif", "tiff", "webp",
+];
+
+/// Width and height of the perceptual hash, in bits, unless chosen
+/// otherwise.
+pub const DEFAULT_HASH_SIZE: u32 = 8;
+
+/// Smallest width and height of the perceptual hash.
+pub const MIN_HASH_SIZE: u32 = 4;
+
+/// Largest width and height of the perceptual hash.
+pub const MAX_HASH_SIZE: u32 = 64;
+
+/// Bytes a database file starts with, ahead of its format version.
+const MAGIC: &[u8; 8] = b"IMGDUPDB";
+
+/// Version of the database format, stored as a little-endian `u32` after
+/// [`MAGIC`]. The rest of the file is Zlib'd [MessagePack][`rmp`] of the
+/// [`Header`], which holds the settings the hashes were made with, and the
+/// entries. Fields added at the end of the header or of an entry do not
+/// change the version, since older files are read with defaults for them;
+/// any other change of layout does. Files from before there was a version
+/// start right with the compressed data.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Most bytes a database file may decompress to. Real databases take about
+/// 150 bytes per image, so this allows some seven million images.
+const MAX_DECODED_BYTES: u64 = 1 << 30;
+
+/// Most entries a database file may hold.
+const MAX_ENTRIES: usize = 10_000_000;
+
+/// Widest and tallest image that is decoded, in pixels.
+const MAX_IMAGE_SIDE: u32 = 1 << 16;
+
+/// Most bytes decoding one image may allocate, enough for 128 megapixels of
+/// RGBA. Files claiming to be larger, such as decompression bombs, fail to
+/// decode instead of exhausting memory.
+const MAX_DECODE_ALLOC: u64 = 512 << 20;
+
+/// Frames of an animation that are hashed: the first, the middle, and the
+/// last.
+const FRAMES: usize = 3;
+
+/// Most frames of an animation held at once while looking for the middle one.
+const MAX_HELD_FRAMES: u64 = 8;
+
+/// Most bytes in a stored hash, enough for the largest hash size.
+const MAX_HASH_BYTES: usize = (MAX_HASH_SIZE * MAX_HASH_SIZE / 8) as usize;
 
 /// Wrapper around [`image_hasher::ImageHash`] for serialization.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct ImageHash(image_hasher::ImageHash);
 
+impl ImageHash {
+    /// Hamming distance to another hash.
+    pub fn dist(&self, other: &ImageHash) -> u32 {
+        self.0.dist(&other.0)
+    }
+
+    /// Number of bits in the hash, and so the largest possible distance.
+    pub fn bits(&self) -> u32 {
+        self.0.as_bytes().len() as u32 * 8
+    }
+}
+
 impl From<image_hasher::ImageHash> for ImageHash {
     fn from(value: image_hasher::ImageHash) -> Self {
         Self(value)
@@ -69,6 +160,268 @@ impl<'de> Deserialize<'de> for ImageHash {
     }
 }
 
+/// Hashes packed into `u64` words, so that distances take an XOR and a
+/// popcount per word instead of going through [`ImageHash::dist`] byte by
+/// byte. Hashes that are not a whole number of words are padded with zeros,
+/// which never add to a distance.
+struct PackedHashes {
+    /// Bits per hash, not counting the padding.
+    bits: u32,
+    /// Words per hash.
+    words: usize,
+    /// The words of each hash in turn.
+    data: Vec<u64>,
+}
+
+impl PackedHashes {
+    /// Pack `hashes`, or return `None` if they differ in size.
+    fn new<'a, I>(hashes: I) -> Option<Self>
+    where
+        I: Iterator<Item = &'a ImageHash>,
+    {
+        let mut hashes = hashes.peekable();
+        let bytes = hashes.peek().map_or(8, |x| x.0.as_bytes().len());
+        if bytes == 0 {
+            return None;
+        }
+        let words = bytes.div_ceil(8);
+        let mut data = Vec::with_capacity(hashes.size_hint().0 * words);
+        for hash in hashes {
+            let hash = hash.0.as_bytes();
+            if hash.len() != bytes {
+                return None;
+            }
+            data.extend(hash.chunks(8).map(|x| {
+                let mut word = [0; 8];
+                word[..x.len()].copy_from_slice(x);
+                u64::from_le_bytes(word)
+            }));
+        }
+        Some(PackedHashes {
+            bits: bytes as u32 * 8,
+            words,
+            data,
+        })
+    }
+
+    /// Words of hash `i`.
+    fn hash(&self, i: usize) -> &[u64] {
+        &self.data[i * self.words..(i + 1) * self.words]
+    }
+
+    /// Distance between hashes `i` and `j`.
+    fn distance(&self, i: usize, j: usize) -> u32 {
+        self.hash(i)
+            .iter()
+            .zip(self.hash(j))
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum()
+    }
+
+    /// `width` bits of hash `i` from bit `start` on, at most 64.
+    fn bits(&self, i: usize, start: u32, width: u32) -> u64 {
+        let hash = self.hash(i);
+        let (word, bit) = ((start / 64) as usize, start % 64);
+        let mut value = hash[word] >> bit;
+        if bit + width > 64 {
+            value |= hash[word + 1] << (64 - bit);
+        }
+        match width {
+            64 => value,
+            _ => value & ((1 << width) - 1),
+        }
+    }
+
+    /// Set `out` to the distances from hash `i` to every hash after it, in
+    /// order. Going through a whole row at once lets the compiler vectorize
+    /// the loop.
+    fn distances_after(&self, i: usize, out: &mut Vec<u32>) {
+        out.clear();
+        let start = (i + 1) * self.words;
+        let hash = &self.data[i * self.words..start];
+        let rest = &self.data[start..];
+        match self.words {
+            1 => {
+                let hash = hash[0];
+                out.extend(rest.iter().map(|x| (x ^ hash).count_ones()));
+            }
+            _ => out.extend(rest.chunks_exact(self.words).map(|x| {
+                x.iter()
+                    .zip(hash)
+                    .map(|(a, b)| (a ^ b).count_ones())
+                    .sum::<u32>()
+            })),
+        }
+    }
+}
+
+/// Multi-index hashing over [`PackedHashes`], to find the hashes near one
+/// without comparing it with every other. Hashes are filed by the value of
+/// each of a few pieces of them. Two hashes at most `r` apart differ by at
+/// most `r / pieces` bits in one of the pieces, so the hashes near one are
+/// among those filed under a value at most that far from one of its pieces.
+struct ChunkIndex {
+    /// First bit of each piece.
+    chunks: Vec<u32>,
+    /// Bits in each piece, at most 64.
+    width: u32,
+    /// Bits a piece of a nearby hash may differ by.
+    radius: u32,
+    /// Bits in the number of the slot a value is filed under. Wider values
+    /// are hashed down to this, so a slot may also hold hashes with other
+    /// values, which the full comparison weeds out.
+    slot_bits: u32,
+    /// For each piece, where the hashes of each slot start in `filed`,
+    /// followed by the end of the last slot.
+    starts: Vec<Vec<u32>>,
+    /// For each piece, the hashes in order of slot.
+    filed: Vec<Vec<u32>>,
+}
+
+/// Most pieces a [`ChunkIndex`] cuts hashes into, to bound its memory.
+const MAX_CHUNKS: u32 = 32;
+
+/// Most bits a piece may be off by in a [`ChunkIndex`]; past that, looking up
+/// every value that close takes longer than comparing.
+const MAX_CHUNK_RADIUS: u32 = 3;
+
+impl ChunkIndex {
+    /// Index `packed` for finding hashes closer than `threshold`, or return
+    /// `None` if the threshold is so large relative to the hashes that
+    /// comparing every pair would be faster.
+    fn new(packed: &PackedHashes, threshold: u32) -> Option<Self> {
+        let n = packed.data.len() / packed.words;
+        let max_dist = threshold.checked_sub(1)?;
+        // About twice as many slots as hashes.
+        let n_bits = usize::BITS - n.leading_zeros() + 1;
+
+        // Pick the number of pieces that is expected to be fastest. Each
+        // look-up probes every value within the radius of a piece, costing
+        // about as much as four comparisons since it misses the cache, and
+        // finds about n / 2^slot_bits hashes under each if they are spread
+        // out. Pieces need not cover every bit, so they are at most a word.
+        let (cost, count) = (1..=packed.bits.min(MAX_CHUNKS))
+            .filter(|count| max_dist / count <= MAX_CHUNK_RADIUS)
+            .map(|count| {
+                let width = (packed.bits / count).min(64);
+                let probes: f64 =
+                    (0..=max_dist / count).map(|k| binomial(width, k)).sum();
+                let per_slot = n as f64 / 2f64.powi(width.min(n_bits) as i32);
+                (count as f64 * probes * (4.0 + per_slot), count)
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0))?;
+        // Comparing a whole row of hashes at once is cheaper per hash than
+        // comparing them one at a time.
+        if cost > n as f64 / 16.0 {
+            return None;
+        }
+
+        let width = (packed.bits / count).min(64);
+        let mut index = ChunkIndex {
+            chunks: (0..count).map(|k| k * (packed.bits / count)).collect(),
+            width,
+            radius: max_dist / count,
+            slot_bits: width.min(n_bits),
+            starts: Vec::with_capacity(count as usize),
+            filed: Vec::with_capacity(count as usize),
+        };
+        let slots = 1 << index.slot_bits;
+        for &start in &index.chunks {
+            let slot_of: Vec<usize> = (0..n)
+                .map(|i| index.slot(packed.bits(i, start, width)))
+                .collect();
+            let mut starts = vec![0u32; slots + 1];
+            for &slot in &slot_of {
+                starts[slot + 1] += 1;
+            }
+            for k in 1..starts.len() {
+                starts[k] += starts[k - 1];
+            }
+            let mut next = starts.clone();
+            let mut filed = vec![0; n];
+            for (i, &slot) in slot_of.iter().enumerate() {
+                filed[next[slot] as usize] = i as u32;
+                next[slot] += 1;
+            }
+            index.starts.push(starts);
+            index.filed.push(filed);
+        }
+        Some(index)
+    }
+
+    /// Slot a piece with the given value is filed under.
+    fn slot(&self, value: u64) -> usize {
+        match value >> self.slot_bits {
+            0 => value as usize,
+            _ => {
+                (value.wrapping_mul(0x9e3779b97f4a7c15)
+                    >> (64 - self.slot_bits)) as usize
+            }
+        }
+    }
+
+    /// Set `out` to the hashes after hash `i` that are closer to it than
+    /// `threshold`, with their distances, in no particular order. `seen`
+    /// holds a mark for each hash, none of them `i` yet, to compare each
+    /// hash only once.
+    fn matches_after(
+        &self,
+        packed: &PackedHashes,
+        i: usize,
+        threshold: u32,
+        seen: &mut [usize],
+        out: &mut Vec<(usize, u32)>,
+    ) {
+        out.clear();
+        let pieces = self.chunks.iter().zip(&self.starts).zip(&self.filed);
+        for ((&start, starts), filed) in pieces {
+            let value = packed.bits(i, start, self.width);
+            each_within(value, self.width, self.radius, &mut |probe| {
+                let slot = self.slot(probe);
+                let hashes =
+                    &filed[starts[slot] as usize..starts[slot + 1] as usize];
+                for &j in hashes {
+                    let j = j as usize;
+                    if j <= i || seen[j] == i {
+                        continue;
+                    }
+                    seen[j] = i;
+                    let dist = packed.distance(i, j);
+                    if dist < threshold {
+                        out.push((j, dist));
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Number of ways to choose `k` of `n` things, as a float since it is only
+/// an estimate of work.
+fn binomial(n: u32, k: u32) -> f64 {
+    (0..k).fold(1.0, |x, i| x * (n - i) as f64 / (i + 1) as f64)
+}
+
+/// Call `f` with every value of the low `width` bits that differs from
+/// `value` in at most `radius` of them, each once.
+fn each_within(value: u64, width: u32, radius: u32, f: &mut impl FnMut(u64)) {
+    fn flip(
+        value: u64,
+        from: u32,
+        width: u32,
+        radius: u32,
+        f: &mut impl FnMut(u64),
+    ) {
+        f(value);
+        if radius > 0 {
+            for b in from..width {
+                flip(value ^ (1 << b), b + 1, width, radius - 1, f);
+            }
+        }
+    }
+    flip(value, 0, width, radius, f);
+}
+
 /// Helper for deserializing [`ImageHash`].
 struct ImageHashVisitor;
 
@@ -86,6 +439,12 @@ impl<'de> Visitor<'de> for ImageHashVisitor {
     where
         E: de::Error,
     {
+        if v.len() > MAX_HASH_BYTES {
+            return Err(E::custom(format!(
+                "a hash of {} bytes, more than the limit of {MAX_HASH_BYTES}",
+                v.len()
+            )));
+        }
         match image_hasher::ImageHash::<Box<[u8]>>::from_bytes(v) {
             Ok(v) => Ok(v.into()),
             Err(_) => Err(E::invalid_value(
@@ -96,198 +455,4450 @@ impl<'de> Visitor<'de> for ImageHashVisitor {
     }
 }
 
-/// A database storing image hashes via an internal [`HashMap`] that pairs the
-/// canonicalized filename of the image with its perceptual hash.
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
-pub struct HashDB(HashMap<String, ImageHash>);
+/// A database entry: the perceptual hash of an image, when it was taken, and
+/// the state of its file when it was hashed.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct Entry {
+    hash: ImageHash,
+    /// Capture time from EXIF, in milliseconds since 1970 in the local time
+    /// of the camera, or `None` if unknown.
+    taken: Option<i64>,
+    /// Modification time and size of the file, or `None` if unknown.
+    stat: Option<Stat>,
+    /// Checksum of the file, or `None` if unknown.
+    checksum: Option<Checksum>,
+    /// Hashes of the image turned each way but upright, in the order of
+    /// [`Orientation::ALL`], if the database matches rotations; otherwise
+    /// empty.
+    turned: Vec<ImageHash>,
+    /// Width and height of the image in pixels, or `None` if unknown.
+    dimensions: Option<(u32, u32)>,
+    /// Hashes of the middle and last frames of an animated GIF or WebP
+    /// image, whose first frame is hashed in `hash`; empty for a still image.
+    frames: Vec<ImageHash>,
+}
+
+/// What the database knows about an image file besides its hash, as of when
+/// it was last hashed or its stat checked.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ImageMeta {
+    /// Width in pixels.
+    pub width: u32,
+    /// Height in pixels.
+    pub height: u32,
+    /// Size of the file in bytes.
+    pub size: u64,
+}
 
-fn has_image_suffix<P: AsRef<Path>>(file: P) -> bool {
-    // uhh...
-    match file.as_ref().extension() {
-        Some(x) => match x.to_str() {
-            Some(x) => SUFFIXES.contains(&x),
-            None => false,
-        },
-        None => false,
+impl Entry {
+    /// Distance to the image of `other`, and how that image is turned to
+    /// match this one. Without turned hashes, only the upright images are
+    /// compared. Both images are turned in turn, so that the distance is the
+    /// same either way around. Two animations are compared frame by frame,
+    /// upright: the distance is the median of those between their first,
+    /// middle, and last frames, so that one frame that differs, such as a
+    /// shared title card, neither makes nor breaks a match. An animation and
+    /// a still image are compared by the first frame of the animation.
+    fn distance(&self, other: &Entry) -> (u32, Orientation) {
+        if self.is_animated() && other.is_animated() {
+            let mut dists: Vec<u32> = [(&self.hash, &other.hash)]
+                .into_iter()
+                .chain(self.frames.iter().zip(&other.frames))
+                .map(|(a, b)| a.dist(b))
+                .collect();
+            dists.sort_unstable();
+            return (dists[(dists.len() - 1) / 2], Orientation::Upright);
+        }
+        let turned = Orientation::ALL[1..].iter();
+        let theirs = turned
+            .clone()
+            .zip(&other.turned)
+            .map(|(&o, x)| (self.hash.dist(x), o));
+        let ours = turned
+            .zip(&self.turned)
+            .map(|(&o, x)| (x.dist(&other.hash), o.inverse()));
+        [(self.hash.dist(&other.hash), Orientation::Upright)]
+            .into_iter()
+            .chain(theirs)
+            .chain(ours)
+            .min_by_key(|x| x.0)
+            .unwrap_or_default()
+    }
+
+    /// Whether the image is an animation, with hashes of more than one frame.
+    fn is_animated(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    /// Smallest distance from the image turned any way to `hash`.
+    fn distance_to(&self, hash: &ImageHash) -> u32 {
+        self.turned
+            .iter()
+            .fold(self.hash.dist(hash), |min, x| min.min(x.dist(hash)))
     }
 }
 
-fn hash_image<P: AsRef<Path>>(
-    file: P,
-) -> Result<(String, ImageHash), HashDBError> {
-    let hasher = HasherConfig::new().to_hasher();
+/// BLAKE3 checksum of the contents of an image file, to tell byte-for-byte
+/// copies apart from images that merely look the same.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct Checksum([u8; 32]);
+
+impl Serialize for Checksum {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Checksum {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(ChecksumVisitor)
+    }
+}
+
+/// Helper for deserializing [`Checksum`].
+struct ChecksumVisitor;
+
+impl Visitor<'_> for ChecksumVisitor {
+    type Value = Checksum;
 
-    let image = match image::open(&file) {
-        Ok(i) => Ok(i),
-        Err(e) => {
-            Err(HashDBError::ImageError(format!("{:?}", file.as_ref()), e))
+    fn expecting(
+        &self,
+        formatter: &mut std::fmt::Formatter,
+    ) -> std::fmt::Result {
+        formatter.write_str("a checksum of 32 bytes")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        match v.try_into() {
+            Ok(x) => Ok(Checksum(x)),
+            Err(_) => Err(E::invalid_length(v.len(), &self)),
         }
-    }?;
+    }
+}
 
-    let temp = image
-        .resize(256, 256, image_hasher::FilterType::Nearest)
-        .blur(3.0);
+/// Modification time and size of an image file, to tell whether it has
+/// changed since it was hashed.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct Stat {
+    /// Nanoseconds since 1970.
+    modified: i64,
+    size: u64,
+}
 
-    let name = file.as_ref().canonicalize()?.to_string_lossy().into_owned();
-    let hash = hasher.hash_image(&temp);
+impl Stat {
+    /// Stat of a file, or `None` if its metadata cannot be read or the
+    /// filesystem does not keep modification times.
+    fn of<P: AsRef<Path>>(file: P) -> Option<Stat> {
+        let meta = fs::metadata(file).ok()?;
+        let modified = match meta.modified().ok()?.duration_since(UNIX_EPOCH) {
+            Ok(x) => x.as_nanos() as i64,
+            Err(e) => -(e.duration().as_nanos() as i64),
+        };
+        Some(Stat {
+            modified,
+            size: meta.len(),
+        })
+    }
+}
 
-    Ok((name, hash.into()))
+impl<'de> Deserialize<'de> for Entry {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(EntryVisitor)
+    }
 }
 
-impl HashDB {
-    /// Create a new hash database.
-    pub fn new() -> Self {
-        HashDB(HashMap::new())
+/// Helper for deserializing [`Entry`]. Databases written before capture
+/// times were stored hold bare hashes, which are read with no capture time,
+/// and those written before file stats, checksums, turned hashes,
+/// dimensions, or the frames of animations were stored are read with none.
+struct EntryVisitor;
+
+impl<'de> Visitor<'de> for EntryVisitor {
+    type Value = Entry;
+
+    fn expecting(
+        &self,
+        formatter: &mut std::fmt::Formatter,
+    ) -> std::fmt::Result {
+        formatter.write_str("an image hash, or a hash and a capture time")
     }
 
-    /// Read image files from the given directory. Add entries for any images
-    /// that do not exist the database. Then, remove entries from the database
-    /// that no longer have any corresponding images on the filesystem.
-    pub fn read_dir<P: AsRef<Path>>(
-        &mut self,
-        root: P,
-    ) -> Result<(), HashDBError> {
-        // I have to clone the keys from the DB because if I use references, It
-        // borrows the database and I can't insert any new entries.
-        let db_images: HashSet<String> =
-            self.0.keys().map(|x| x.clone()).collect();
-
-        let fs_images: HashSet<String> = fs::read_dir(&root)?
-            .filter_map(|x| x.ok())
-            .filter_map(|x| {
-                let p = x.path();
-                match has_image_suffix(&p) {
-                    true => p.canonicalize().ok(),
-                    false => None,
-                }
-            })
-            .map(|x| x.to_string_lossy().into_owned())
-            .collect();
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(Entry {
+            hash: ImageHashVisitor.visit_bytes(v)?,
+            taken: None,
+            stat: None,
+            checksum: None,
+            turned: Vec::new(),
+            dimensions: None,
+            frames: Vec::new(),
+        })
+    }
 
-        // Images on filesystem but not in DB - Add to DB
-        let hashes: Vec<(String, ImageHash)> = fs_images
-            .difference(&db_images)
-            .par_bridge()
-            .map(|img| hash_image(img))
-            .collect::<Result<Vec<_>, _>>()?;
-        for (name, hash) in hashes {
-            self.0.insert(name, hash);
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        let hash = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let taken = seq.next_element::<Option<i64>>()?.flatten();
+        let stat = seq.next_element::<Option<Stat>>()?.flatten();
+        let checksum = seq.next_element::<Option<Checksum>>()?.flatten();
+        let turned = seq.next_element()?.unwrap_or_default();
+        let dimensions = seq.next_element::<Option<(u32, u32)>>()?.flatten();
+        let frames = seq.next_element()?.unwrap_or_default();
+        Ok(Entry {
+            hash,
+            taken,
+            stat,
+            checksum,
+            turned,
+            dimensions,
+            frames,
+        })
+    }
+}
+
+/// Settings stored at the start of a database file: those that change how its
+/// images are hashed, and the scan it was last written by. Databases written
+/// before there were any hold none, and are read with the defaults.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Header {
+    /// Near-uniform borders are cropped away before hashing.
+    pub trim_borders: bool,
+    /// The scan the database was last written by, if any, so that it can be
+    /// repeated.
+    pub scan: Option<ScanSettings>,
+    /// Perceptual hash algorithm the images are hashed with.
+    pub algorithm: Algorithm,
+    /// Width and height of the hashes, in bits.
+    pub hash_size: u32,
+    /// Images are also hashed rotated and mirrored, so that turned copies
+    /// match.
+    pub match_rotations: bool,
+    /// Names are stored relative to the directory the database is scanned
+    /// from, so that it still applies once the directory moves, such as on a
+    /// drive mounted elsewhere. See [`HashDB::set_root`].
+    pub relative: bool,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Header {
+            trim_borders: false,
+            scan: None,
+            algorithm: Algorithm::default(),
+            hash_size: DEFAULT_HASH_SIZE,
+            match_rotations: false,
+            relative: false,
         }
+    }
+}
 
-        // Images in DB but not on filesystem - Remove from DB
-        for file in db_images.difference(&fs_images) {
-            self.0.remove(file);
+impl Header {
+    /// Number of bits in a hash of the configured size, and so the largest
+    /// possible distance between two hashes. Double-gradient hashes hold
+    /// fewer; [`ImageHash::bits`] tells for sure.
+    pub fn hash_bits(&self) -> u32 {
+        self.hash_size * self.hash_size
+    }
+
+    /// Check that a header read from a file makes sense.
+    fn check(&self) -> Result<(), String> {
+        match (MIN_HASH_SIZE..=MAX_HASH_SIZE).contains(&self.hash_size) {
+            true => Ok(()),
+            false => Err(format!(
+                "a hash size of {}, outside of {MIN_HASH_SIZE} to \
+                 {MAX_HASH_SIZE}",
+                self.hash_size
+            )),
         }
+    }
+}
 
-        Ok(())
+/// Perceptual hash algorithm. Hashes made with different algorithms cannot
+/// be compared.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    Eq,
+    PartialEq,
+    Serialize,
+    ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+    /// Compare neighbouring pixels along rows
+    #[default]
+    Gradient,
+    /// Compare neighbouring pixels along columns
+    VertGradient,
+    /// Compare neighbouring pixels along both rows and columns
+    DoubleGradient,
+    /// Compare pixels with the mean
+    Mean,
+    /// Compare blocks of pixels with the median, which suits line art
+    Blockhash,
+    /// Compare the low frequencies of a discrete cosine transform with their
+    /// mean, which suits photos and scans
+    Dct,
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.to_possible_value() {
+            Some(x) => f.write_str(x.get_name()),
+            None => Ok(()),
+        }
     }
+}
 
-    /// [`read_dir`][HashDB::read_dir] but scan the directory recursively. This
-    /// could be combined with `read_dir` via a recursive flag or whatever, but
-    /// no.
-    pub fn read_dir_recursive<P: AsRef<Path>>(
-        &mut self,
-        root: P,
-    ) -> Result<(), HashDBError> {
-        let db_images: HashSet<String> =
-            self.0.keys().map(|x| x.clone()).collect();
+/// One of the eight ways to turn an image by rotating it in steps of 90° and
+/// mirroring it. Serialized by the name it displays with.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Orientation {
+    /// As it is.
+    #[default]
+    Upright,
+    /// Rotated 90° clockwise.
+    Rotate90,
+    /// Rotated 180°.
+    Rotate180,
+    /// Rotated 90° counterclockwise.
+    Rotate270,
+    /// Mirrored left to right.
+    Mirror,
+    /// Mirrored left to right, then rotated 90° clockwise.
+    MirrorRotate90,
+    /// Mirrored left to right, then rotated 180°; that is, flipped upside
+    /// down.
+    MirrorRotate180,
+    /// Mirrored left to right, then rotated 90° counterclockwise.
+    MirrorRotate270,
+}
 
-        let fs_images: HashSet<String> = WalkDir::new(&root)
-            .into_iter()
-            .filter_map(|x| x.ok())
-            .filter_map(|x| {
-                let p = x.path();
-                match has_image_suffix(&p) && p.is_file() {
-                    true => p.canonicalize().ok(),
-                    false => None,
-                }
-            })
-            .map(|x| x.to_string_lossy().into_owned())
-            .collect();
+impl Orientation {
+    /// Every orientation, upright first.
+    pub const ALL: [Orientation; 8] = [
+        Orientation::Upright,
+        Orientation::Rotate90,
+        Orientation::Rotate180,
+        Orientation::Rotate270,
+        Orientation::Mirror,
+        Orientation::MirrorRotate90,
+        Orientation::MirrorRotate180,
+        Orientation::MirrorRotate270,
+    ];
 
-        // Images on filesystem but not in DB - Add to DB
-        let hashes: Vec<(String, ImageHash)> = fs_images
-            .difference(&db_images)
-            .par_bridge()
-            .map(|img| hash_image(img))
-            .collect::<Result<Vec<_>, _>>()?;
-        for (name, hash) in hashes {
-            self.0.insert(name, hash);
+    /// The orientation that turns an image back. Mirrored orientations undo
+    /// themselves.
+    pub fn inverse(self) -> Self {
+        match self {
+            Orientation::Rotate90 => Orientation::Rotate270,
+            Orientation::Rotate270 => Orientation::Rotate90,
+            x => x,
         }
+    }
 
-        // Images in DB but not on filesystem - Remove from DB
-        for file in db_images.difference(&fs_images) {
-            self.0.remove(file);
+    /// Turn an image this way.
+    pub fn apply(self, image: &DynamicImage) -> DynamicImage {
+        match self {
+            Orientation::Upright => image.clone(),
+            Orientation::Rotate90 => image.rotate90(),
+            Orientation::Rotate180 => image.rotate180(),
+            Orientation::Rotate270 => image.rotate270(),
+            Orientation::Mirror => image.fliph(),
+            Orientation::MirrorRotate90 => image.fliph().rotate90(),
+            Orientation::MirrorRotate180 => image.flipv(),
+            Orientation::MirrorRotate270 => image.fliph().rotate270(),
         }
+    }
+}
 
-        Ok(())
+impl Serialize for Orientation {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
     }
+}
 
-    /// Search through all pairs of images in the database for all images that
-    /// have a Hamming distance (according to [`image_hasher::ImageHash::dist`])
-    /// below the given threshold.
-    pub fn find_duplicates(&self, threshold: u32) -> Vec<(String, String)> {
-        let entries: Vec<(&String, &ImageHash)> = self.0.iter().collect();
-        LargeCombinationIterator::new(&entries, 2)
-            .filter_map(|comb| {
-                let (name_1, hash_1) = *comb[0];
-                let (name_2, hash_2) = *comb[1];
-                if hash_1.0.dist(&hash_2.0) < threshold {
-                    Some((name_1.clone(), name_2.clone()))
-                } else {
-                    None
-                }
-            })
-            .collect()
+impl Display for Orientation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Orientation::Upright => "upright",
+            Orientation::Rotate90 => "rotate-90",
+            Orientation::Rotate180 => "rotate-180",
+            Orientation::Rotate270 => "rotate-270",
+            Orientation::Mirror => "mirror",
+            Orientation::MirrorRotate90 => "mirror-rotate-90",
+            Orientation::MirrorRotate180 => "mirror-rotate-180",
+            Orientation::MirrorRotate270 => "mirror-rotate-270",
+        })
     }
+}
 
-    /// Write the database to a Zlib'd [MessagePack][rmp] file.
-    pub fn to_file<P: AsRef<Path>>(&self, file: P) -> Result<(), HashDBError> {
-        // Use this method over `rmp_serde::to_vec` to avoid overhead on packing
-        // bytes. (If this breaks decoding, maybe live with the overhead?)
-        let mut buf: Vec<u8> = Vec::new();
-        self.serialize(
-            &mut Serializer::new(&mut buf).with_bytes(BytesMode::ForceAll),
-        )?;
-        let mut z = ZlibEncoder::new(Vec::new(), Compression::default());
-        z.write_all(&buf)?;
-        fs::write(file, z.finish()?)?;
-        Ok(())
+/// How a directory was scanned into a database.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ScanSettings {
+    /// Canonical path of the directory scanned.
+    #[serde(with = "dir_names")]
+    pub root: PathBuf,
+    /// The directory was scanned recursively.
+    pub recursive: bool,
+    /// Most levels of directories the recursive scan looked into, if it was
+    /// limited.
+    pub max_depth: Option<usize>,
+    /// The scan stayed on the filesystem of the directory.
+    pub one_file_system: bool,
+    /// Similarity threshold last searched with, if any.
+    pub threshold: Option<u32>,
+    /// Glob patterns of paths left out of the scan.
+    pub exclude: Vec<String>,
+    /// Extensions of image files, as given to
+    /// [`ScanFilter::with_extensions`], or `None` for the usual ones.
+    pub extensions: Option<Vec<String>>,
+    /// Canonical paths of the directories scanned along with `root`.
+    #[serde(with = "dir_names")]
+    pub more_roots: Vec<PathBuf>,
+    /// Canonical path of the directory the others were searched against,
+    /// which was scanned along with them.
+    #[serde(with = "dir_names")]
+    pub against: Option<PathBuf>,
+}
+
+impl ScanSettings {
+    /// Canonical paths of all directories scanned, `root` first and
+    /// `against` last.
+    pub fn roots(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.root)
+            .chain(&self.more_roots)
+            .chain(&self.against)
     }
+}
 
-    /// Read a database from a Zlib'd [MessagePack][rmp] file.
-    pub fn from_file<P: AsRef<Path>>(file: P) -> Result<Self, HashDBError> {
-        Ok(rmp_serde::from_read(ZlibDecoder::new(
-            fs::read(file)?.as_slice(),
-        ))?)
+/// How far [`HashDB::read_dirs`] looks into the directories it is given.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Depth {
+    /// Most levels to look into each directory, where 1 is only the images
+    /// directly in it, or `None` for everything under it.
+    pub max_depth: Option<usize>,
+    /// Do not descend into other filesystems mounted under the directories.
+    pub one_file_system: bool,
+}
+
+impl Depth {
+    /// Only the images directly in each directory.
+    pub const FLAT: Depth = Depth::max(1);
+    /// Everything under each directory.
+    pub const RECURSIVE: Depth = Depth {
+        max_depth: None,
+        one_file_system: false,
+    };
+    /// Everything under each directory on the same filesystem as it.
+    pub const ONE_FILE_SYSTEM: Depth = Depth {
+        max_depth: None,
+        one_file_system: true,
+    };
+
+    /// Images at most `levels` levels under each directory: 2 is those
+    /// directly in it and in its subdirectories.
+    pub const fn max(levels: usize) -> Depth {
+        Depth {
+            max_depth: Some(levels),
+            one_file_system: false,
+        }
     }
 }
 
-impl Display for HashDB {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for (k, v) in self.0.iter() {
-            write!(f, "{}\t{k}\n", v.0.to_base64())?;
+/// A database storing image hashes via an internal [`HashMap`] that pairs the
+/// canonicalized filename of the image with its perceptual hash and capture
+/// time, along with the [`Header`] its hashes were made with.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HashDB {
+    header: Header,
+    entries: HashMap<PathBuf, Entry>,
+    /// Threads for parallel work, or `None` for Rayon's global pool. Not
+    /// stored in the file.
+    jobs: Option<NonZeroUsize>,
+    /// Largest image file to hash, in bytes. Not stored in the file.
+    max_file_size: Option<u64>,
+    /// Images to hash between checkpoints, or `None` for none. Not stored
+    /// in the file.
+    checkpoint: Option<NonZeroUsize>,
+    /// Directory the database is scanned from, which relative names are
+    /// resolved against. Not stored in the file.
+    root: Option<PathBuf>,
+}
+
+/// Written as the header followed by the map of entries.
+impl Serialize for HashDB {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let names = Names {
+            entries: &self.entries,
+            root: self.relative_root(),
+        };
+        (&self.header, names).serialize(serializer)
+    }
+}
+
+/// The entries of a [`HashDB`], for serializing them with their names as
+/// [`NameRef`]s, relative to `root` if there is one.
+struct Names<'a> {
+    entries: &'a HashMap<PathBuf, Entry>,
+    root: Option<&'a Path>,
+}
+
+impl Serialize for Names<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_map(self.entries.iter().map(|(k, v)| {
+            let name = self
+                .root
+                .and_then(|root| relative_name(k, root))
+                .map_or(Cow::Borrowed(k.as_path()), Cow::Owned);
+            (NameRef(name), v)
+        }))
+    }
+}
+
+/// `path` relative to `root`, with `/` between its components on every
+/// system so that a drive can move between systems too, or `None` if it is
+/// not under `root`.
+fn relative_name(path: &Path, root: &Path) -> Option<PathBuf> {
+    let mut name = OsString::new();
+    for (i, part) in path.strip_prefix(root).ok()?.components().enumerate() {
+        if i > 0 {
+            name.push("/");
         }
-        Ok(())
+        name.push(part);
     }
+    Some(name.into())
 }
 
-/// Errors that can happen when dealing with [`HashDB`].
-#[derive(Debug, Error)]
-pub enum HashDBError {
-    /// Wrapper around [`rmp_serde::decode::Error`].
-    #[error("Could not decode database: {0}")]
-    DecodeError(#[from] rmp_serde::decode::Error),
+/// A name written by [`relative_name`], resolved against `root`.
+fn resolve_name(name: &Path, root: &Path) -> PathBuf {
+    let mut path = root.to_path_buf();
+    path.extend(name.components());
+    path
+}
 
-    /// Wrapper around [`rmp_serde::encode::Error`].
-    #[error("Could not encode database: {0}")]
-    EncodeError(#[from] rmp_serde::encode::Error),
+/// The name of an entry as written to a database file: a string if the path
+/// is valid Unicode, as nearly all are, and otherwise the bytes the operating
+/// system holds it as, so that every path reads back as it was and no two
+/// are written alike.
+struct NameRef<'a>(Cow<'a, Path>);
 
-    /// Wrapper around [`image::ImageError`].
-    #[error("Could not read {0}: {1}")]
-    ImageError(String, image::ImageError),
+impl Serialize for NameRef<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self.0.to_str() {
+            Some(name) => serializer.serialize_str(name),
+            None => serializer.serialize_bytes(&path_bytes(&self.0)),
+        }
+    }
+}
+
+/// The name of an entry read from a database file, as written by
+/// [`NameRef`].
+struct Name(PathBuf);
+
+/// Serialization of the directories in [`ScanSettings`] as [`NameRef`]s and
+/// [`Name`]s, so that they read back as they were like entry names do.
+mod dir_names {
+    use super::{Name, NameRef};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::{borrow::Cow, path::PathBuf};
+
+    /// A directory, or some number of them.
+    pub(super) trait Dirs: Sized {
+        fn names(&self) -> impl Serialize;
+        fn from_names<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> Result<Self, D::Error>;
+    }
+
+    impl Dirs for PathBuf {
+        fn names(&self) -> impl Serialize {
+            NameRef(Cow::Borrowed(self))
+        }
 
-    /// Wrapper around [`std::io::Error`].
-    #[error("IO Error: {0}")]
-    IOError(#[from] std::io::Error),
+        fn from_names<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> Result<Self, D::Error> {
+            Name::deserialize(d).map(|x| x.0)
+        }
+    }
+
+    impl Dirs for Vec<PathBuf> {
+        fn names(&self) -> impl Serialize {
+            self.iter()
+                .map(|x| NameRef(Cow::Borrowed(x)))
+                .collect::<Vec<_>>()
+        }
+
+        fn from_names<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> Result<Self, D::Error> {
+            Vec::<Name>::deserialize(d)
+                .map(|x| x.into_iter().map(|x| x.0).collect())
+        }
+    }
+
+    impl Dirs for Option<PathBuf> {
+        fn names(&self) -> impl Serialize {
+            self.as_deref().map(|x| NameRef(Cow::Borrowed(x)))
+        }
+
+        fn from_names<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> Result<Self, D::Error> {
+            Option::<Name>::deserialize(d).map(|x| x.map(|x| x.0))
+        }
+    }
+
+    pub(super) fn serialize<T: Dirs, S: Serializer>(
+        dirs: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        dirs.names().serialize(serializer)
+    }
+
+    pub(super) fn deserialize<'de, T: Dirs, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        T::from_names(deserializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Name {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(NameVisitor).map(Name)
+    }
+}
+
+/// Helper for deserializing [`Name`].
+struct NameVisitor;
+
+impl Visitor<'_> for NameVisitor {
+    type Value = PathBuf;
+
+    fn expecting(
+        &self,
+        formatter: &mut std::fmt::Formatter,
+    ) -> std::fmt::Result {
+        formatter.write_str("an image name as a string or bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(PathBuf::from(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        bytes_path(v)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Bytes(v), &self))
+    }
+}
+
+/// The bytes a path that is not valid Unicode is written as: those of the
+/// `OsStr` on Unix, and its UTF-16 code units, little-endian, on Windows.
+fn path_bytes(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        path.as_os_str()
+            .encode_wide()
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        path.to_string_lossy().into_owned().into_bytes()
+    }
+}
+
+/// A path written as bytes by [`path_bytes`], or `None` if the bytes cannot
+/// be one here.
+fn bytes_path(bytes: &[u8]) -> Option<PathBuf> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStringExt;
+        let units: Vec<u16> = bytes
+            .chunks(2)
+            .map(|x| x.try_into().map(u16::from_le_bytes))
+            .collect::<Result<_, _>>()
+            .ok()?;
+        Some(PathBuf::from(std::ffi::OsString::from_wide(&units)))
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        String::from_utf8(bytes.to_vec()).ok().map(PathBuf::from)
+    }
+}
+
+impl<'de> Deserialize<'de> for HashDB {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(HashDBVisitor)
+    }
+}
+
+/// Helper for deserializing [`HashDB`]. Databases written before there was a
+/// header are a bare map of entries.
+struct HashDBVisitor;
+
+impl<'de> Visitor<'de> for HashDBVisitor {
+    type Value = HashDB;
+
+    fn expecting(
+        &self,
+        formatter: &mut std::fmt::Formatter,
+    ) -> std::fmt::Result {
+        formatter.write_str("a header and a map of image names to hashes")
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::MapAccess<'de>,
+    {
+        Ok(HashDB {
+            entries: EntriesVisitor.visit_map(map)?,
+            ..HashDB::default()
+        })
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        let header: Header = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        header.check().map_err(de::Error::custom)?;
+        let Entries(entries) = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        Ok(HashDB {
+            header,
+            entries,
+            ..HashDB::default()
+        })
+    }
+}
+
+/// The entries of a [`HashDB`], for deserializing them on their own.
+struct Entries(HashMap<PathBuf, Entry>);
+
+impl<'de> Deserialize<'de> for Entries {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(EntriesVisitor).map(Entries)
+    }
+}
+
+/// Helper for deserializing [`Entries`]. Rejects databases with more than
+/// [`MAX_ENTRIES`] entries, or with hashes of different sizes, without
+/// trusting the declared length for allocation.
+struct EntriesVisitor;
+
+impl<'de> Visitor<'de> for EntriesVisitor {
+    type Value = HashMap<PathBuf, Entry>;
+
+    fn expecting(
+        &self,
+        formatter: &mut std::fmt::Formatter,
+    ) -> std::fmt::Result {
+        formatter.write_str("a map of image names to hashes")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::MapAccess<'de>,
+    {
+        let too_many = |n| {
+            de::Error::custom(format!(
+                "{n} entries, more than the limit of {MAX_ENTRIES}"
+            ))
+        };
+        let declared = map.size_hint().unwrap_or(0);
+        if declared > MAX_ENTRIES {
+            return Err(too_many(declared));
+        }
+        let mut entries = HashMap::with_capacity(declared.min(1 << 16));
+        let mut hash_len = None;
+        while let Some((Name(name), entry)) = map.next_entry::<Name, Entry>()? {
+            check_entry(&name, &entry, &mut hash_len)
+                .map_err(de::Error::custom)?;
+            entries.insert(name, entry);
+            if entries.len() > MAX_ENTRIES {
+                return Err(too_many(entries.len()));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Check that an entry read from a file fits those before it, whose hashes
+/// are `hash_len` bytes long, if there were any.
+fn check_entry(
+    name: &Path,
+    entry: &Entry,
+    hash_len: &mut Option<usize>,
+) -> Result<(), String> {
+    let len = entry.hash.0.as_bytes().len();
+    match *hash_len {
+        Some(x) if x != len => {
+            return Err(format!("hashes of both {x} and {len} bytes"));
+        }
+        _ => *hash_len = Some(len),
+    }
+    if entry.turned.len() >= Orientation::ALL.len()
+        || entry.turned.iter().any(|x| x.0.as_bytes().len() != len)
+    {
+        return Err(format!("turned hashes of {name:?} do not fit its hash"));
+    }
+    if entry.frames.len() >= FRAMES
+        || entry.frames.iter().any(|x| x.0.as_bytes().len() != len)
+    {
+        return Err(format!("frame hashes of {name:?} do not fit its hash"));
+    }
+    Ok(())
+}
+
+/// A pair of similar images. Pairs share their paths rather than each having
+/// its own copy, since an image may be in many pairs.
+pub type Pair = (Arc<Path>, Arc<Path>);
+
+/// Which side of a search across directories an image is on: `Some(true)` on
+/// the right, `Some(false)` on the left, or `None` on neither.
+type Side<'a> = &'a dyn Fn(&Path) -> Option<bool>;
+
+/// How close the hashes of two images must be for them to count as similar.
+/// A `u32` converts to a [`Distance`][Threshold::Distance].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Threshold {
+    /// Images are similar when their hashes differ in fewer bits than this.
+    Distance(u32),
+    /// Images are similar when at least this percentage of the bits of their
+    /// hashes agree, whatever the size of the hashes. 100 only matches
+    /// identical hashes, and 0 matches everything.
+    Similarity(f64),
+}
+
+impl Threshold {
+    /// The threshold as a distance for hashes of `bits` bits: images are
+    /// similar when their distance is below it. A similarity allows the
+    /// largest whole number of differing bits that keeps at least that
+    /// percentage agreeing.
+    ///
+    /// ```
+    /// use image_duplicate::Threshold;
+    ///
+    /// // Only identical hashes.
+    /// assert_eq!(Threshold::Similarity(100.0).distance(64), 1);
+    /// // Everything, even hashes that differ in every bit.
+    /// assert_eq!(Threshold::Similarity(0.0).distance(64), 65);
+    /// // 6.4 bits may differ, so 6 do.
+    /// assert_eq!(Threshold::Similarity(90.0).distance(64), 7);
+    /// // Exactly 16 bits may differ.
+    /// assert_eq!(Threshold::Similarity(75.0).distance(64), 17);
+    /// assert_eq!(Threshold::Similarity(75.0).distance(256), 65);
+    /// // Just short of 100% still only matches identical 64-bit hashes.
+    /// assert_eq!(Threshold::Similarity(99.0).distance(64), 1);
+    /// assert_eq!(Threshold::Distance(9).distance(256), 9);
+    /// ```
+    pub fn distance(self, bits: u32) -> u32 {
+        match self {
+            Threshold::Distance(x) => x,
+            Threshold::Similarity(percent) => {
+                // Whole percentages subtract exactly, so a boundary such as
+                // 75% of 64 bits lands on 16 bits rather than just under it.
+                let differing = 100.0 - percent.clamp(0.0, 100.0);
+                (f64::from(bits) * differing / 100.0).floor() as u32 + 1
+            }
+        }
+    }
+}
+
+impl From<u32> for Threshold {
+    fn from(distance: u32) -> Self {
+        Threshold::Distance(distance)
+    }
+}
+
+impl Display for Threshold {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Threshold::Distance(x) => write!(f, "{x}"),
+            Threshold::Similarity(x) => write!(f, "{x}%"),
+        }
+    }
+}
+
+/// What a scan changed in a [`HashDB`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ScanReport {
+    /// Images hashed and added.
+    pub hashed: usize,
+    /// Names of the entries added.
+    pub added: Vec<PathBuf>,
+    /// Images that could not be hashed.
+    pub errors: usize,
+    /// Names of the entries hashed again because their files changed. These
+    /// are also counted in `hashed`.
+    pub changed: Vec<PathBuf>,
+    /// Entries removed because their images no longer exist.
+    pub removed: usize,
+    /// Directories that could not be read.
+    pub unreadable: usize,
+    /// Names of the entries left alone since their images are deeper under
+    /// the directories than the scan looked.
+    pub too_deep: Vec<PathBuf>,
+}
+
+impl AddAssign for ScanReport {
+    fn add_assign(&mut self, other: Self) {
+        self.hashed += other.hashed;
+        self.added.extend(other.added);
+        self.errors += other.errors;
+        self.changed.extend(other.changed);
+        self.removed += other.removed;
+        self.unreadable += other.unreadable;
+        self.too_deep.extend(other.too_deep);
+    }
+}
+
+/// What [`HashDB::merge`] changed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MergeReport {
+    /// Entries for images this database had none for.
+    pub added: usize,
+    /// Entries replaced by those of the other database, which were hashed
+    /// from newer files.
+    pub replaced: usize,
+    /// Entries for images both databases had, where this one's was kept.
+    pub kept: usize,
+}
+
+/// Which images [`HashDB::verify`] hashes again, beyond checking their
+/// stats.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct VerifyOptions {
+    /// Hash images whose modification time or size changed again, and only
+    /// count them as changed if their hashes did too, so that files which
+    /// were merely touched or copied pass.
+    pub rehash_changed: bool,
+    /// Hash this many images again, picked at random from those whose stats
+    /// have not changed, to catch edits that kept them.
+    pub sample: usize,
+    /// Seed for picking the sample, to pick the same images again.
+    pub seed: u64,
+}
+
+/// What [`HashDB::verify`] found. The lists are sorted.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+    /// Entries checked: those for images under the root.
+    pub checked: usize,
+    /// Entries not checked, since their images are outside the root.
+    pub skipped: usize,
+    /// Images hashed again.
+    pub rehashed: usize,
+    /// Names of the entries whose images no longer exist.
+    pub missing: Vec<PathBuf>,
+    /// Names of the entries whose images changed since they were hashed.
+    pub changed: Vec<PathBuf>,
+    /// Names of the entries whose images exist but could not be read.
+    pub unreadable: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+    /// Whether every entry checked still matches its image.
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty()
+            && self.changed.is_empty()
+            && self.unreadable.is_empty()
+    }
+}
+
+impl AddAssign for VerifyReport {
+    fn add_assign(&mut self, other: Self) {
+        self.checked += other.checked;
+        self.skipped += other.skipped;
+        self.rehashed += other.rehashed;
+        self.missing.extend(other.missing);
+        self.changed.extend(other.changed);
+        self.unreadable.extend(other.unreadable);
+        self.missing.sort();
+        self.changed.sort();
+        self.unreadable.sort();
+    }
+}
+
+/// The state of an image found by [`HashDB::verify`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Verdict {
+    Same,
+    Missing,
+    Changed,
+    Unreadable,
+}
+
+/// Directories that could not be read during a scan, and parts of the tree it
+/// left out on purpose. Their contents are unknown, so entries for images
+/// under them must not be removed.
+#[derive(Debug, Default)]
+struct Unreadable {
+    /// Canonicalized directories.
+    dirs: Vec<PathBuf>,
+    /// Whether a directory could not even be canonicalized, in which case
+    /// nothing is known to be gone.
+    unknown: bool,
+    count: usize,
+    /// Whether the scan left out parts of the tree on purpose, such as other
+    /// filesystems. Entries for images that were not found are then only
+    /// removed if they are known to be gone.
+    partial: bool,
+    /// Canonicalized directories that were only scanned so many levels deep.
+    shallow: Vec<(PathBuf, usize)>,
+}
+
+impl Unreadable {
+    /// Record and report a directory that could not be read.
+    fn add(
+        &mut self,
+        dir: &Path,
+        error: io::Error,
+        observer: &dyn ScanObserver,
+    ) {
+        observer.unreadable(dir, &HashDBError::io(dir)(error));
+        self.count += 1;
+        match dir.canonicalize() {
+            Ok(dir) => self.dirs.push(dir),
+            Err(_) => self.unknown = true,
+        }
+    }
+
+    /// Whether a file is deeper under the directories it is under than they
+    /// were scanned, so that it was not looked for.
+    fn too_deep(&self, file: &Path) -> bool {
+        let deeper: Vec<bool> = self
+            .shallow
+            .iter()
+            .filter_map(|(dir, max)| {
+                Some(file.strip_prefix(dir).ok()?.components().count() > *max)
+            })
+            .collect();
+        !deeper.is_empty() && deeper.iter().all(|&x| x)
+    }
+
+    /// Whether a file may be under one of the directories, or in a part of
+    /// the tree that was left out and still exist.
+    fn covers(&self, file: &Path) -> bool {
+        self.unknown
+            || self.dirs.iter().any(|x| file.starts_with(x))
+            || (self.partial && !is_gone(file))
+    }
+}
+
+/// Which files a scan looks at: those with an image extension, compared
+/// without regard to case, except for paths that match a glob pattern to
+/// exclude. Patterns are matched against the path relative to the scanned
+/// directory. A directory that matches is left out along with everything
+/// under it, as is one whose contents a pattern ending in `/**` matches, so
+/// that its tree is not even read.
+#[derive(Clone, Debug)]
+pub struct ScanFilter {
+    /// Lowercase extensions of image files.
+    extensions: HashSet<String>,
+    /// All the patterns, for files.
+    files: GlobSet,
+    /// The patterns and those ending in `/**` without it, for directories.
+    dirs: GlobSet,
+}
+
+impl Default for ScanFilter {
+    fn default() -> Self {
+        ScanFilter {
+            extensions: IMAGE_EXTENSIONS.iter().map(|&x| x.into()).collect(),
+            files: GlobSet::empty(),
+            dirs: GlobSet::empty(),
+        }
+    }
+}
+
+impl ScanFilter {
+    /// Look at files with the usual image extensions, leaving out those
+    /// matching the given glob patterns.
+    pub fn new<S: AsRef<str>>(exclude: &[S]) -> Result<Self, globset::Error> {
+        let mut files = GlobSetBuilder::new();
+        let mut dirs = GlobSetBuilder::new();
+        for pattern in exclude {
+            let pattern = pattern.as_ref();
+            files.add(Glob::new(pattern)?);
+            dirs.add(Glob::new(pattern)?);
+            if let Some(dir) = pattern.strip_suffix("/**")
+                && !dir.is_empty()
+            {
+                dirs.add(Glob::new(dir)?);
+            }
+        }
+        Ok(ScanFilter {
+            files: files.build()?,
+            dirs: dirs.build()?,
+            ..ScanFilter::default()
+        })
+    }
+
+    /// Change the extensions of image files. Extensions starting with `+`
+    /// are added to the usual ones, and those starting with `-` taken out of
+    /// them; the others replace them. A leading `.` and case are ignored.
+    pub fn with_extensions<S: AsRef<str>>(mut self, extensions: &[S]) -> Self {
+        let extensions: Vec<String> = extensions
+            .iter()
+            .map(|x| x.as_ref().to_lowercase())
+            .collect();
+        let bare = |x: &str| x.trim_start_matches('.').to_owned();
+        if extensions.iter().any(|x| !x.starts_with(['+', '-'])) {
+            self.extensions.clear();
+        }
+        for extension in &extensions {
+            match extension.strip_prefix('-') {
+                Some(x) => self.extensions.remove(&bare(x)),
+                None => self
+                    .extensions
+                    .insert(bare(extension.trim_start_matches('+'))),
+            };
+        }
+        self
+    }
+
+    /// Whether `file` has an image extension.
+    fn is_image(&self, file: &Path) -> bool {
+        file.extension()
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| self.extensions.contains(&x.to_lowercase()))
+    }
+
+    /// Whether to leave out `path`, which is relative to the scanned
+    /// directory.
+    fn excludes(&self, path: &Path, is_dir: bool) -> bool {
+        match is_dir {
+            true => self.dirs.is_match(path),
+            false => self.files.is_match(path),
+        }
+    }
+}
+
+/// Counting semaphore limiting how many images are decoded at once.
+struct Gate {
+    free: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A permit from a [`Gate`], given back when dropped.
+struct Permit<'a>(&'a Gate);
+
+impl Gate {
+    fn new(permits: usize) -> Self {
+        Gate {
+            free: Mutex::new(permits),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Wait for a permit.
+    fn acquire(&self) -> Permit<'_> {
+        let mut free = self.free.lock().unwrap_or_else(|e| e.into_inner());
+        while *free == 0 {
+            free = self.freed.wait(free).unwrap_or_else(|e| e.into_inner());
+        }
+        *free -= 1;
+        Permit(self)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Largest variance of the brightness of a row or column, on a scale of 0 to
+/// 255, for it to count as part of a uniform border. This leaves room for
+/// the noise of lossy compression.
+const BORDER_VARIANCE: f64 = 9.0;
+
+/// Largest fraction of the width or height trimmed from each side, so that
+/// an image that is mostly flat is not cropped down to a sliver.
+const MAX_BORDER: f64 = 0.25;
+
+/// Region of an image left after cropping near-uniform borders, such as
+/// letterbox bars or a frame added by an app, as `(x, y, width, height)`.
+/// Rows and columns at the edges whose brightness varies less than
+/// [`BORDER_VARIANCE`] are cropped, up to [`MAX_BORDER`] of the image on each
+/// side. Returns `None` if there is nothing to crop.
+pub(crate) fn border_box(image: &DynamicImage) -> Option<(u32, u32, u32, u32)> {
+    let luma = image.to_luma8();
+    let (w, h) = luma.dimensions();
+    let uniform = |pixels: &mut dyn Iterator<Item = u8>| {
+        let (mut n, mut sum, mut sq) = (0.0, 0.0, 0.0);
+        for x in pixels {
+            let x = x as f64;
+            n += 1.0;
+            sum += x;
+            sq += x * x;
+        }
+        let mean = sum / n;
+        sq / n - mean * mean < BORDER_VARIANCE
+    };
+    let row = |y| uniform(&mut (0..w).map(|x| luma.get_pixel(x, y)[0]));
+    let max_rows = (h as f64 * MAX_BORDER) as usize;
+    let top = (0..h).take(max_rows).take_while(|&y| row(y)).count() as u32;
+    let bottom =
+        (0..h).rev().take(max_rows).take_while(|&y| row(y)).count() as u32;
+
+    // Columns are checked only between the top and bottom borders, so that
+    // letterbox bars do not hide pillarbox bars.
+    let rows = top..h - bottom;
+    let col = |x| uniform(&mut rows.clone().map(|y| luma.get_pixel(x, y)[0]));
+    let max_cols = (w as f64 * MAX_BORDER) as usize;
+    let left = (0..w).take(max_cols).take_while(|&x| col(x)).count() as u32;
+    let right =
+        (0..w).rev().take(max_cols).take_while(|&x| col(x)).count() as u32;
+
+    match top + bottom + left + right {
+        0 => None,
+        _ => Some((left, top, w - left - right, h - top - bottom)),
+    }
+}
+
+/// Shrink a decoded image and hash it, cropping its borders first if the
+/// header says so. Files and in-memory images both go through this, so the
+/// same content always gets the same hash. `permit` is given back as soon as
+/// the full-size image is dropped. If the header matches rotations, the
+/// shrunk image is also hashed turned each way but upright, and those hashes
+/// are returned second.
+fn hash_decoded(
+    image: DynamicImage,
+    permit: Option<Permit<'_>>,
+    header: &Header,
+) -> (ImageHash, Vec<ImageHash>) {
+    let config =
+        HasherConfig::new().hash_size(header.hash_size, header.hash_size);
+    let hasher = match header.algorithm {
+        Algorithm::Gradient => config.hash_alg(HashAlg::Gradient),
+        Algorithm::VertGradient => config.hash_alg(HashAlg::VertGradient),
+        Algorithm::DoubleGradient => config.hash_alg(HashAlg::DoubleGradient),
+        Algorithm::Mean => config.hash_alg(HashAlg::Mean),
+        Algorithm::Blockhash => config.hash_alg(HashAlg::Blockhash),
+        Algorithm::Dct => config.hash_alg(HashAlg::Mean).preproc_dct(),
+    }
+    .to_hasher();
+
+    let image = match header.trim_borders.then(|| border_box(&image)) {
+        Some(Some((x, y, w, h))) => image.crop_imm(x, y, w, h),
+        _ => image,
+    };
+    let temp = image.resize(256, 256, image_hasher::FilterType::Nearest);
+    drop(image);
+    drop(permit);
+    let temp = temp.blur(3.0);
+    let turned = match header.match_rotations {
+        true => Orientation::ALL[1..]
+            .iter()
+            .map(|x| hasher.hash_image(&x.apply(&temp)).into())
+            .collect(),
+        false => Vec::new(),
+    };
+    (hasher.hash_image(&temp).into(), turned)
+}
+
+/// Canonicalized filename of an image, as used for database entries.
+fn entry_name<P: AsRef<Path>>(file: P) -> Result<PathBuf, HashDBError> {
+    file.as_ref().canonicalize().map_err(HashDBError::io(&file))
+}
+
+/// Decode an image file. Like [`image::open`], this goes by the extension,
+/// but files with an extension the `image` crate does not know, as added
+/// with [`ScanFilter::with_extensions`], go by their contents instead.
+pub(crate) fn open_image<P: AsRef<Path>>(
+    file: P,
+) -> image::ImageResult<DynamicImage> {
+    let reader = ImageReader::open(file)?;
+    let mut reader = match reader.format() {
+        Some(_) => reader,
+        None => reader.with_guessed_format()?,
+    };
+    reader.limits(decode_limits());
+    reader.decode()
+}
+
+/// Limits every image is decoded under, so that a huge or malicious file
+/// fails with [`image::ImageError::Limits`] rather than taking the process
+/// down.
+fn decode_limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_SIDE);
+    limits.max_image_height = Some(MAX_IMAGE_SIDE);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    limits
+}
+
+/// The middle and last frames of an animated GIF or WebP image, or `None` if
+/// `data` is not one. Frames are decoded in turn, holding every so many of
+/// them, twice as many apart whenever too many are held, so the middle frame
+/// is the one held nearest the middle. A damaged animation is sampled up to
+/// its first bad frame.
+fn later_frames(
+    data: &[u8],
+    format: Option<ImageFormat>,
+) -> Option<[DynamicImage; 2]> {
+    let frames = match format? {
+        ImageFormat::Gif => {
+            let mut decoder = GifDecoder::new(Cursor::new(data)).ok()?;
+            decoder.set_limits(decode_limits()).ok()?;
+            decoder.into_frames()
+        }
+        ImageFormat::WebP => {
+            let mut decoder = WebPDecoder::new(Cursor::new(data)).ok()?;
+            if !decoder.has_animation() {
+                return None;
+            }
+            decoder.set_limits(decode_limits()).ok()?;
+            decoder.into_frames()
+        }
+        _ => return None,
+    };
+    let mut held: Vec<(usize, RgbaImage)> = Vec::new();
+    let mut last: Option<(usize, RgbaImage)> = None;
+    let mut stride = 1;
+    for (i, frame) in frames.enumerate() {
+        let Ok(frame) = frame else { break };
+        let frame = frame.into_buffer();
+        // Hold no more than one image's worth of large frames.
+        let size = frame.as_raw().len().max(1) as u64;
+        let most = (MAX_DECODE_ALLOC / size).clamp(1, MAX_HELD_FRAMES);
+        if let Some(x) = last.replace((i, frame))
+            && x.0 % stride == 0
+        {
+            held.push(x);
+            if held.len() as u64 > most {
+                stride *= 2;
+                held.retain(|x| x.0 % stride == 0);
+            }
+        }
+    }
+    let (n, last) = last.filter(|x| x.0 > 0)?;
+    let middle = held.into_iter().min_by_key(|x| x.0.abs_diff(n / 2))?.1;
+    Some([middle.into(), last.into()])
+}
+
+/// Hashes of the middle and last frames of an animation in `data`, upright
+/// whether or not the header matches rotations; empty if it is not one.
+fn hash_frames(
+    data: &[u8],
+    format: Option<ImageFormat>,
+    gate: Option<&Gate>,
+    header: &Header,
+) -> Vec<ImageHash> {
+    let _permit = gate.map(Gate::acquire);
+    let upright = Header {
+        match_rotations: false,
+        ..header.clone()
+    };
+    later_frames(data, format)
+        .into_iter()
+        .flatten()
+        .map(|x| hash_decoded(x, None, &upright).0)
+        .collect()
+}
+
+/// Hash an image file, and checksum its contents on the same read. Returns
+/// the entry name of the file and an entry with its hashes, checksum, and
+/// dimensions, but no capture time or stat. Animations also get hashes of
+/// their middle and last frames. Files over `max_size` bytes, if given, are
+/// not read at all.
+fn hash_image<P: AsRef<Path>>(
+    file: P,
+    gate: Option<&Gate>,
+    max_size: Option<u64>,
+    header: &Header,
+) -> Result<(PathBuf, Entry), HashDBError> {
+    let error = |e| HashDBError::ImageError(file.as_ref().to_path_buf(), e);
+    if let Some(limit) = max_size {
+        let size = fs::metadata(&file).map_err(|e| error(e.into()))?.len();
+        if size > limit {
+            return Err(HashDBError::TooLarge {
+                path: file.as_ref().to_path_buf(),
+                size,
+                limit,
+            });
+        }
+    }
+    // Only the full-size image is gated; it is dropped once shrunk.
+    let permit = gate.map(Gate::acquire);
+    let data = fs::read(&file).map_err(|e| error(e.into()))?;
+    let checksum = Checksum(*blake3::hash(&data).as_bytes());
+    // Like `open_image`, go by the extension, or else by the contents.
+    let mut reader = ImageReader::new(Cursor::new(&data));
+    match ImageFormat::from_path(&file) {
+        Ok(format) => reader.set_format(format),
+        Err(_) => {
+            reader =
+                reader.with_guessed_format().map_err(|e| error(e.into()))?
+        }
+    }
+    reader.limits(decode_limits());
+    let format = reader.format();
+    let image = reader.decode().map_err(error)?;
+    let dimensions = Some((image.width(), image.height()));
+    let (hash, turned) = hash_decoded(image, permit, header);
+    // The other frames of an animation are decoded once the first is hashed,
+    // so that they are not held along with it.
+    let frames = hash_frames(&data, format, gate, header);
+    let entry = Entry {
+        hash,
+        taken: None,
+        stat: None,
+        checksum: Some(checksum),
+        turned,
+        dimensions,
+        frames,
+    };
+    Ok((entry_name(&file)?, entry))
+}
+
+/// Hash an image file with the settings of `header`. Returns the
+/// canonicalized filename, as used for database entries, and the hash.
+pub fn hash_file<P: AsRef<Path>>(
+    file: P,
+    header: &Header,
+) -> Result<(PathBuf, ImageHash), HashDBError> {
+    let (name, entry) = hash_image(file, None, None, header)?;
+    Ok((name, entry.hash))
+}
+
+/// Hash an image held in memory, such as an upload, in any format that can
+/// be read from a file. The hash is the same as for a file with the same
+/// content. Fails with [`HashDBError::NotAnImage`] if the bytes are not an
+/// image in a supported format.
+pub fn hash_bytes(
+    data: &[u8],
+    header: &Header,
+) -> Result<ImageHash, HashDBError> {
+    let decode = || {
+        let mut reader = ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .map_err(image::ImageError::IoError)?;
+        reader.limits(decode_limits());
+        reader.decode()
+    };
+    let image = decode().map_err(|e| match e {
+        image::ImageError::Decoding(_) | image::ImageError::Unsupported(_) => {
+            HashDBError::NotAnImage(e)
+        }
+        e => HashDBError::ImageError("image in memory".into(), e),
+    })?;
+    Ok(hash_decoded(image, None, header).0)
+}
+
+/// Days from 1970-01-01 to a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = match month <= 2 {
+        true => year - 1,
+        false => year,
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era =
+        year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Capture time of an image from its EXIF data, in milliseconds since 1970 in
+/// the local time of the camera. `None` if the image has no EXIF data or no
+/// usable date.
+fn capture_time<P: AsRef<Path>>(file: P) -> Option<i64> {
+    let file = File::open(file).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+    let ascii = |tag| match exif.get_field(tag, exif::In::PRIMARY) {
+        Some(exif::Field {
+            value: exif::Value::Ascii(x),
+            ..
+        }) => x.first().map(Vec::as_slice),
+        _ => None,
+    };
+
+    let (date, subsec) = match ascii(exif::Tag::DateTimeOriginal) {
+        Some(x) => (x, ascii(exif::Tag::SubSecTimeOriginal)),
+        None => (ascii(exif::Tag::DateTime)?, ascii(exif::Tag::SubSecTime)),
+    };
+    let mut time = exif::DateTime::from_ascii(date).ok()?;
+    if let Some(subsec) = subsec {
+        let _ = time.parse_subsec(subsec);
+    }
+
+    let days =
+        days_from_civil(time.year.into(), time.month.into(), time.day.into());
+    let seconds = days * 86400
+        + i64::from(time.hour) * 3600
+        + i64::from(time.minute) * 60
+        + i64::from(time.second);
+    Some(seconds * 1000 + i64::from(time.nanosecond.unwrap_or(0) / 1_000_000))
+}
+
+/// Whether an image is known to no longer exist: its path is missing or no
+/// longer a file. Paths that cannot be checked, e.g. for lack of permissions,
+/// are assumed to still exist.
+fn is_gone(file: &Path) -> bool {
+    match fs::metadata(file) {
+        Ok(x) => !x.is_file(),
+        Err(e) => e.kind() == io::ErrorKind::NotFound,
+    }
+}
+
+/// Whether two paths name the same file, such as spellings that differ only
+/// in case on a case-insensitive filesystem, or hardlinks: the same device and
+/// inode on Unix, or the same volume and file index on Windows. Paths that
+/// cannot be checked are assumed to be different files.
+fn same_file<P: AsRef<Path>, Q: AsRef<Path>>(a: P, b: Q) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let inode = |x: &Path| fs::metadata(x).map(|m| (m.dev(), m.ino())).ok();
+        let a = inode(a.as_ref());
+        a.is_some() && a == inode(b.as_ref())
+    }
+    #[cfg(windows)]
+    {
+        same_file::is_same_file(a, b).unwrap_or(false)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        // Canonical paths keep the case used on disk.
+        let a = a.as_ref().canonicalize().ok();
+        a.is_some() && a == b.as_ref().canonicalize().ok()
+    }
+}
+
+/// Whether the filesystem holding some images ignores case in names, judged by
+/// looking up the first image whose name has letters with their case swapped.
+/// Assumed not to if no name has any.
+fn ignores_case(images: &HashSet<PathBuf>) -> bool {
+    let swap = |c: char| match c.is_lowercase() {
+        true => c.to_uppercase().collect::<String>(),
+        false => c.to_lowercase().collect(),
+    };
+    for path in images {
+        let Some(name) = path.file_name().and_then(|x| x.to_str()) else {
+            continue;
+        };
+        let swapped: String = name.chars().map(swap).collect();
+        if swapped != name {
+            return same_file(path, path.with_file_name(swapped));
+        }
+    }
+    false
+}
+
+/// Outcome of hashing one image in [`hash_all`].
+enum Hashed {
+    /// The canonicalized filename and entry of the image.
+    Done(PathBuf, Entry),
+    /// The image could not be hashed.
+    Failed,
+    /// The run was cancelled before the image was hashed.
+    Skipped,
+}
+
+/// Hash images in parallel, reporting progress and errors to `observer`. At
+/// most `max_decodes` images are decoded at once, if given, and files over
+/// `max_size` bytes are reported as errors. `done` counts the images
+/// processed towards `total`, which may take several calls. Returns the
+/// outcome for each image, in order.
+fn hash_all(
+    files: &[&PathBuf],
+    (done, total): (&AtomicUsize, usize),
+    max_decodes: Option<NonZeroUsize>,
+    max_size: Option<u64>,
+    header: &Header,
+    observer: &dyn ScanObserver,
+) -> Vec<Hashed> {
+    let gate = max_decodes.map(|x| Gate::new(x.get()));
+    files
+        .into_par_iter()
+        .map(|img| {
+            if observer.cancelled() {
+                return Hashed::Skipped;
+            }
+            let start = Instant::now();
+            // Stat the file first, so that changes made while it is hashed
+            // are caught by the next scan.
+            let stat = Stat::of(img);
+            let hash = hash_image(img, gate.as_ref(), max_size, header);
+            match &hash {
+                Ok(_) => observer.hashed(img, start.elapsed()),
+                Err(e) => observer.error(img, e),
+            }
+            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+            observer.hashing(img, done, total);
+            match hash {
+                Ok((name, entry)) => {
+                    let entry = Entry {
+                        taken: capture_time(img),
+                        stat,
+                        ..entry
+                    };
+                    Hashed::Done(name, entry)
+                }
+                Err(_) => Hashed::Failed,
+            }
+        })
+        .collect()
+}
+
+/// Image files under `root` down to `depth`, as chosen by `filter`,
+/// canonicalized. Excluded directories are not descended into, and those
+/// that cannot be read are recorded in `unreadable`. Fails if `root` itself
+/// cannot be read.
+fn walk(
+    root: &Path,
+    depth: Depth,
+    filter: &ScanFilter,
+    unreadable: &mut Unreadable,
+    observer: &dyn ScanObserver,
+) -> Result<HashSet<PathBuf>, HashDBError> {
+    let mut root_error = None;
+    let images = WalkDir::new(root)
+        .max_depth(depth.max_depth.unwrap_or(usize::MAX))
+        .same_file_system(depth.one_file_system)
+        .into_iter()
+        .filter_entry(|x| {
+            x.depth() == 0
+                || !filter.excludes(
+                    x.path().strip_prefix(root).unwrap_or(x.path()),
+                    x.file_type().is_dir(),
+                )
+        })
+        .filter_map(|x| match x {
+            Ok(x) => Some(x),
+            Err(e) if e.depth() == 0 => {
+                root_error = e.into_io_error();
+                None
+            }
+            Err(e) => {
+                let dir = e.path().unwrap_or(root).to_path_buf();
+                let error = match e.into_io_error() {
+                    Some(e) => e,
+                    None => io::Error::other("filesystem loop"),
+                };
+                unreadable.add(&dir, error, observer);
+                None
+            }
+        })
+        .filter_map(|x| {
+            let p = x.path();
+            match filter.is_image(p) && p.is_file() {
+                true => p.canonicalize().ok(),
+                false => None,
+            }
+        })
+        .collect();
+    match root_error {
+        Some(e) => Err(HashDBError::io(root)(e)),
+        None => Ok(images),
+    }
+}
+
+impl HashDB {
+    /// Create a new hash database.
+    pub fn new() -> Self {
+        HashDB::default()
+    }
+
+    /// Run the parallel work of the database, such as hashing, on a pool of
+    /// `jobs` threads of its own, or on Rayon's global pool, one thread per
+    /// core, if `None`.
+    pub fn set_jobs(&mut self, jobs: Option<NonZeroUsize>) {
+        self.jobs = jobs;
+    }
+
+    /// Skip hashing image files larger than `max_file_size` bytes, reporting
+    /// them as errors with [`HashDBError::TooLarge`] without opening them.
+    /// `None` hashes files of any size.
+    pub fn set_max_file_size(&mut self, max_file_size: Option<u64>) {
+        self.max_file_size = max_file_size;
+    }
+
+    /// While a scan hashes new or changed images, pass the database to
+    /// [`ScanObserver::checkpoint`] after every `every` images, so that the
+    /// observer can write it out and an interrupted scan lose little work.
+    /// [`rehash`][HashDB::rehash] makes no checkpoints, since until it ends
+    /// the database holds hashes made with both the old and new settings.
+    /// `None` makes no checkpoints.
+    pub fn set_checkpoint(&mut self, every: Option<NonZeroUsize>) {
+        self.checkpoint = every;
+    }
+
+    /// Run `op` on the pool chosen by [`set_jobs`][HashDB::set_jobs]. If a
+    /// pool of that size cannot be started, the global pool is used instead.
+    fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        let pool = self.jobs.and_then(|jobs| {
+            ThreadPoolBuilder::new()
+                .num_threads(jobs.get())
+                .build()
+                .ok()
+        });
+        match pool {
+            Some(pool) => pool.install(op),
+            None => op(),
+        }
+    }
+
+    /// Read image files from the given directory, as chosen by `filter`. Add
+    /// entries for any images that do not exist the database, and if
+    /// `check_changes`, hash images whose modification time or size changed
+    /// since they were hashed again. Then, remove entries from the database
+    /// that no longer have any corresponding images on the filesystem, or
+    /// whose images are excluded. Entries for images in subdirectories are
+    /// left alone, since they were not looked for. Images that cannot be
+    /// hashed are reported to `observer` and skipped. At most `max_decodes`
+    /// images are decoded at once, if given. This is
+    /// [`read_dirs`][HashDB::read_dirs] with [`Depth::FLAT`].
+    pub fn read_dir<P: AsRef<Path>>(
+        &mut self,
+        root: P,
+        filter: &ScanFilter,
+        max_decodes: Option<NonZeroUsize>,
+        check_changes: bool,
+        observer: &dyn ScanObserver,
+    ) -> Result<ScanReport, HashDBError> {
+        self.read_dirs(
+            &[root],
+            Depth::FLAT,
+            filter,
+            max_decodes,
+            check_changes,
+            observer,
+        )
+    }
+
+    /// [`read_dir`][HashDB::read_dir] but scan the directory recursively, as
+    /// [`read_dirs`][HashDB::read_dirs] with [`Depth::RECURSIVE`].
+    /// Directories that cannot be read are reported to `observer`, and
+    /// entries for images under them are kept.
+    pub fn read_dir_recursive<P: AsRef<Path>>(
+        &mut self,
+        root: P,
+        filter: &ScanFilter,
+        max_decodes: Option<NonZeroUsize>,
+        check_changes: bool,
+        observer: &dyn ScanObserver,
+    ) -> Result<ScanReport, HashDBError> {
+        self.read_dirs(
+            &[root],
+            Depth::RECURSIVE,
+            filter,
+            max_decodes,
+            check_changes,
+            observer,
+        )
+    }
+
+    /// [`read_dir_recursive`][HashDB::read_dir_recursive] but do not descend
+    /// into other filesystems mounted under the directory, like `du -x`. On
+    /// Windows, this stops at other volumes. Entries for images on other
+    /// filesystems are kept as long as the images exist, since they were not
+    /// looked for. This is [`read_dirs`][HashDB::read_dirs] with
+    /// [`Depth::ONE_FILE_SYSTEM`].
+    pub fn read_dir_one_file_system<P: AsRef<Path>>(
+        &mut self,
+        root: P,
+        filter: &ScanFilter,
+        max_decodes: Option<NonZeroUsize>,
+        check_changes: bool,
+        observer: &dyn ScanObserver,
+    ) -> Result<ScanReport, HashDBError> {
+        self.read_dirs(
+            &[root],
+            Depth::ONE_FILE_SYSTEM,
+            filter,
+            max_decodes,
+            check_changes,
+            observer,
+        )
+    }
+
+    /// [`read_dir`][HashDB::read_dir] and its recursive variants over several
+    /// directories at once, as `depth` says. The images found under all of
+    /// them make up the scan, so an entry is only removed if its image is
+    /// under none of them; scanning the directories one by one would remove
+    /// the entries of each from the scan of the others. Patterns of `filter`
+    /// are matched against paths relative to the directory they are under.
+    ///
+    /// A scan limited to some depth leaves the entries for images deeper
+    /// under the directories alone, whether or not the images still exist,
+    /// since it did not look for them; a later deeper scan takes care of
+    /// them. They are listed in [`ScanReport::too_deep`], so that a search
+    /// of only what was scanned can leave them out. An image is only too
+    /// deep if it is too deep under every directory it is under.
+    ///
+    /// ```
+    /// # use image_duplicate::{Depth, HashDB, ScanFilter};
+    /// # use std::fs;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let dir = std::env::temp_dir()
+    ///     .join(format!("image-duplicate-depth-{}", std::process::id()));
+    /// fs::create_dir_all(dir.join("2024/edits"))?;
+    /// let dir = dir.canonicalize()?;
+    /// for name in ["a.png", "2024/b.png", "2024/edits/c.png"] {
+    ///     image::RgbImage::new(16, 16).save(dir.join(name))?;
+    /// }
+    /// let filter = ScanFilter::new::<&str>(&[])?;
+    /// let mut hashdb = HashDB::new();
+    /// hashdb.read_dirs(&[&dir], Depth::RECURSIVE, &filter, None, true, &())?;
+    /// assert_eq!(hashdb.len(), 3);
+    ///
+    /// // Two levels: the directory and its subdirectories, but not edits/.
+    /// let report =
+    ///     hashdb.read_dirs(&[&dir], Depth::max(2), &filter, None, true, &())?;
+    /// assert_eq!(report.removed, 0);
+    /// assert_eq!(report.too_deep, [dir.join("2024/edits/c.png")]);
+    /// assert_eq!(hashdb.len(), 3);
+    ///
+    /// // Images within the depth that are gone are still removed, and those
+    /// // below it are kept even if gone.
+    /// fs::remove_file(dir.join("2024/b.png"))?;
+    /// fs::remove_file(dir.join("2024/edits/c.png"))?;
+    /// let report =
+    ///     hashdb.read_dirs(&[&dir], Depth::max(2), &filter, None, true, &())?;
+    /// assert_eq!(report.removed, 1);
+    /// assert_eq!(hashdb.len(), 2);
+    ///
+    /// // A flat scan is one level deep.
+    /// let report =
+    ///     hashdb.read_dirs(&[&dir], Depth::FLAT, &filter, None, true, &())?;
+    /// assert_eq!((report.removed, report.too_deep.len()), (0, 1));
+    ///
+    /// // A full scan removes what is gone at any depth.
+    /// let depth = Depth::RECURSIVE;
+    /// let report =
+    ///     hashdb.read_dirs(&[&dir], depth, &filter, None, true, &())?;
+    /// assert_eq!(report.removed, 1);
+    /// assert_eq!(hashdb.len(), 1);
+    /// # fs::remove_dir_all(&dir)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn read_dirs<P: AsRef<Path>>(
+        &mut self,
+        roots: &[P],
+        depth: Depth,
+        filter: &ScanFilter,
+        max_decodes: Option<NonZeroUsize>,
+        check_changes: bool,
+        observer: &dyn ScanObserver,
+    ) -> Result<ScanReport, HashDBError> {
+        let start = Instant::now();
+        observer.phase(Phase::Scan);
+        let mut unreadable = Unreadable {
+            partial: depth.one_file_system,
+            ..Unreadable::default()
+        };
+        let mut fs_images = HashSet::new();
+        for root in roots {
+            let root = root.as_ref();
+            fs_images.extend(walk(
+                root,
+                depth,
+                filter,
+                &mut unreadable,
+                observer,
+            )?);
+            if let Some(max) = depth.max_depth {
+                let dir = root.canonicalize().map_err(HashDBError::io(root))?;
+                unreadable.shallow.push((dir, max));
+            }
+        }
+        observer.phase_finished(Phase::Scan, start.elapsed());
+
+        Ok(self.update(
+            fs_images,
+            &unreadable,
+            max_decodes,
+            check_changes,
+            observer,
+        ))
+    }
+
+    /// Bring the database in line with the images found on the filesystem.
+    /// Entries for images under unreadable directories are kept.
+    fn update(
+        &mut self,
+        fs_images: HashSet<PathBuf>,
+        unreadable: &Unreadable,
+        max_decodes: Option<NonZeroUsize>,
+        check_changes: bool,
+        observer: &dyn ScanObserver,
+    ) -> ScanReport {
+        let mut report = ScanReport::default();
+        if ignores_case(&fs_images) {
+            report.removed += self.fold_case(&fs_images);
+        }
+
+        // I have to clone the keys from the DB because if I use references, It
+        // borrows the database and I can't insert any new entries.
+        let db_images: HashSet<PathBuf> =
+            self.entries.keys().cloned().collect();
+
+        // Images on filesystem but not in DB - Add to DB
+        let start = Instant::now();
+        observer.phase(Phase::Hash);
+        let new: Vec<&PathBuf> = fs_images.difference(&db_images).collect();
+
+        // Images in both whose files changed - Hash again
+        let changed = match check_changes {
+            true => self.changed(&fs_images),
+            false => Vec::new(),
+        };
+        observer.scanned(fs_images.len(), new.len() + changed.len());
+        let files: Vec<&PathBuf> =
+            new.iter().copied().chain(&changed).collect();
+        let done = AtomicUsize::new(0);
+        let size = self.checkpoint.map_or(files.len(), |x| x.get()).max(1);
+        for (n, batch) in files.chunks(size).enumerate() {
+            let hashes = self.install(|| {
+                let max_size = self.max_file_size;
+                let progress = (&done, files.len());
+                hash_all(
+                    batch,
+                    progress,
+                    max_decodes,
+                    max_size,
+                    &self.header,
+                    observer,
+                )
+            });
+            let first = n * size;
+            for (i, (file, hash)) in (first..).zip(batch.iter().zip(hashes)) {
+                let is_new = i < new.len();
+                match hash {
+                    Hashed::Done(name, entry) => {
+                        match is_new {
+                            true => report.added.push(name.clone()),
+                            false => report.changed.push(name.clone()),
+                        }
+                        self.entries.insert(name, entry);
+                        report.hashed += 1;
+                    }
+                    Hashed::Failed => {
+                        // The old hash no longer matches what is in the file.
+                        if !is_new {
+                            self.entries.remove(*file);
+                        }
+                        report.errors += 1;
+                    }
+                    Hashed::Skipped => (),
+                }
+            }
+            // The last batch is written with the rest of the scan.
+            if first + batch.len() < files.len() && !observer.cancelled() {
+                observer.checkpoint(self);
+            }
+        }
+
+        // Images in DB but not on filesystem - Remove from DB
+        let mut pruned = 0;
+        for file in db_images.difference(&fs_images) {
+            if unreadable.too_deep(file) {
+                report.too_deep.push(file.clone());
+                continue;
+            }
+            if unreadable.covers(file) {
+                continue;
+            }
+            self.entries.remove(file);
+            pruned += 1;
+        }
+        observer.pruned(pruned);
+        report.removed += pruned;
+        report.unreadable = unreadable.count;
+        observer.phase_finished(Phase::Hash, start.elapsed());
+
+        report
+    }
+
+    /// Names of the entries for images in `fs_images` whose modification time
+    /// or size differ from when they were hashed. Entries with no stored
+    /// stat, from databases written before stats were kept, are given the
+    /// current one instead, since there is nothing to compare it with.
+    fn changed(&mut self, fs_images: &HashSet<PathBuf>) -> Vec<PathBuf> {
+        let current: Vec<(PathBuf, Option<Stat>)> = self.install(|| {
+            self.entries
+                .keys()
+                .filter(|x| fs_images.contains(*x))
+                .collect::<Vec<_>>()
+                .into_par_iter()
+                .map(|x| (x.clone(), Stat::of(x)))
+                .collect()
+        });
+        let mut changed = Vec::new();
+        for (name, now) in current {
+            let Some(entry) = self.entries.get_mut(&name) else {
+                continue;
+            };
+            match (entry.stat, now) {
+                (None, now) => entry.stat = now,
+                (Some(then), Some(now)) if then != now => changed.push(name),
+                _ => (),
+            }
+        }
+        changed
+    }
+
+    /// Rename entries whose names differ from those of images on a
+    /// case-insensitive filesystem only in case to the names found there, so
+    /// that one file never has two entries. Entries that would then clash are
+    /// merged. Returns the number of entries merged away. Names that are not
+    /// valid Unicode have no case to fold and are left alone.
+    fn fold_case(&mut self, fs_images: &HashSet<PathBuf>) -> usize {
+        let lower = |x: &Path| x.to_str().map(str::to_lowercase);
+        let on_disk: HashMap<String, &PathBuf> = fs_images
+            .iter()
+            .filter_map(|x| Some((lower(x)?, x)))
+            .collect();
+        let names: Vec<PathBuf> = self.entries.keys().cloned().collect();
+        let mut merged = 0;
+        for name in names {
+            let Some(&disk) = lower(&name).and_then(|x| on_disk.get(&x)) else {
+                continue;
+            };
+            if *disk == name {
+                continue;
+            }
+            if let Some(entry) = self.entries.remove(&name) {
+                match self.entries.contains_key(disk) {
+                    true => merged += 1,
+                    false => {
+                        self.entries.insert(disk.clone(), entry);
+                    }
+                }
+            }
+        }
+        merged
+    }
+
+    /// Hash every image in the database again, e.g. after the way hashes are
+    /// computed has changed. Entries whose images no longer exist are removed,
+    /// as are entries whose images can no longer be hashed; the latter are
+    /// reported to `observer`.
+    pub fn rehash(
+        &mut self,
+        max_decodes: Option<NonZeroUsize>,
+        observer: &dyn ScanObserver,
+    ) -> ScanReport {
+        let removed = self.prune();
+        observer.pruned(removed);
+        let mut report = ScanReport {
+            removed,
+            ..ScanReport::default()
+        };
+
+        let start = Instant::now();
+        observer.phase(Phase::Hash);
+        let names: Vec<PathBuf> = self.entries.keys().cloned().collect();
+        let files: Vec<&PathBuf> = names.iter().collect();
+        let hashes = self.install(|| {
+            let max_size = self.max_file_size;
+            let progress = (&AtomicUsize::new(0), files.len());
+            hash_all(
+                &files,
+                progress,
+                max_decodes,
+                max_size,
+                &self.header,
+                observer,
+            )
+        });
+        for (name, hash) in names.into_iter().zip(hashes) {
+            // Keep the name the entry had, whatever it canonicalizes to now.
+            match hash {
+                Hashed::Done(_, entry) => {
+                    self.entries.insert(name, entry);
+                    report.hashed += 1;
+                }
+                Hashed::Failed => {
+                    self.entries.remove(&name);
+                    report.errors += 1;
+                }
+                Hashed::Skipped => (),
+            }
+        }
+        observer.phase_finished(Phase::Hash, start.elapsed());
+        report
+    }
+
+    /// Number of entries in the database.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the database has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Add the entries of another database, such as one made on another
+    /// machine. Where both have an entry for the same path, the one hashed
+    /// from the file with the newer modification time wins; if either entry
+    /// has no stored stat, or the times are equal, this database's is kept.
+    /// Entries for images that do not exist here are added all the same, so
+    /// that a run without updating keeps them, and the next scan removes
+    /// them like those of any other deleted image. The two databases should
+    /// be [compatible][HashDB::check_compatible].
+    pub fn merge(&mut self, other: HashDB) -> MergeReport {
+        let mut report = MergeReport::default();
+        for (name, entry) in other.entries {
+            match self.entries.get_mut(&name) {
+                Some(ours) => {
+                    let modified = |x: &Entry| x.stat.map(|x| x.modified);
+                    match (modified(ours), modified(&entry)) {
+                        (Some(a), Some(b)) if b > a => {
+                            *ours = entry;
+                            report.replaced += 1;
+                        }
+                        _ => report.kept += 1,
+                    }
+                }
+                None => {
+                    self.entries.insert(name, entry);
+                    report.added += 1;
+                }
+            }
+        }
+        report
+    }
+
+    /// Add the entries of a read-only `seed` database that this one lacks, so
+    /// that a scan does not hash their images again. Returns the names of the
+    /// entries added, to take them out again with
+    /// [`remove_all`][HashDB::remove_all] before the database is written.
+    pub fn overlay(&mut self, seed: &HashDB) -> HashSet<PathBuf> {
+        let mut added = HashSet::new();
+        for (name, entry) in &seed.entries {
+            if !self.entries.contains_key(name) {
+                self.entries.insert(name.clone(), entry.clone());
+                added.insert(name.clone());
+            }
+        }
+        added
+    }
+
+    /// Remove the entries with the given names. Returns the number of them
+    /// that were present.
+    pub fn remove_all(&mut self, names: &HashSet<PathBuf>) -> usize {
+        names
+            .iter()
+            .filter(|x| self.entries.remove(*x).is_some())
+            .count()
+    }
+
+    /// Number of bits in the hashes of the database, or `None` if it is
+    /// empty.
+    pub fn hash_size(&self) -> Option<u32> {
+        let entry = self.entries.values().next()?;
+        Some(entry.hash.bits())
+    }
+
+    /// `threshold` as a distance for the hashes of the database, going by
+    /// the configured hash size while it is empty.
+    pub fn distance_for(&self, threshold: Threshold) -> u32 {
+        threshold.distance(self.hash_size().unwrap_or(self.header.hash_bits()))
+    }
+
+    /// Settings the hashes of the database are made with.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Set the directory the database is scanned from. If the [`Header`]
+    /// stores names relative, those read from a file are resolved against
+    /// `root`, and names are written relative to it again, failing with
+    /// [`HashDBError::OutsideRoot`] for any that are not under it. Names
+    /// resolved against another directory before move to `root`. A database
+    /// that stores absolute names only remembers it.
+    pub fn set_root<P: AsRef<Path>>(&mut self, root: P) {
+        let root = root.as_ref().to_path_buf();
+        if self.header.relative {
+            let old = self.root.take();
+            self.entries = self
+                .entries
+                .drain()
+                .map(|(name, entry)| {
+                    let name = match &old {
+                        Some(old) => name
+                            .strip_prefix(old)
+                            .map_or(name.clone(), Path::to_path_buf),
+                        None => name,
+                    };
+                    (resolve_name(&name, &root), entry)
+                })
+                .collect();
+        }
+        self.root = Some(root);
+    }
+
+    /// The directory names are written relative to, if the database stores
+    /// them so.
+    fn relative_root(&self) -> Option<&Path> {
+        self.root.as_deref().filter(|_| self.header.relative)
+    }
+
+    /// Whether the names of the database are relative ones read from a file
+    /// and not yet resolved with [`set_root`][HashDB::set_root].
+    fn unresolved(&self) -> bool {
+        self.header.relative && self.root.is_none()
+    }
+
+    /// Change the settings that images are hashed with from now on. Existing
+    /// entries keep their hashes, so this should go with a
+    /// [`rehash`][HashDB::rehash] unless the database is empty.
+    pub fn set_header(&mut self, header: Header) {
+        self.header = header;
+    }
+
+    /// Fail if the hashes of two databases have different sizes, or were made
+    /// with different settings, since they cannot be compared. That includes
+    /// one database matching rotations and the other not, and one holding
+    /// relative names that have not been [resolved][HashDB::set_root] while
+    /// the other holds absolute ones.
+    pub fn check_compatible(&self, other: &HashDB) -> Result<(), HashDBError> {
+        if self.unresolved() != other.unresolved() {
+            return Err(HashDBError::PathMismatch);
+        }
+        match (self.hash_size(), other.hash_size()) {
+            (Some(a), Some(b)) if a != b => Err(HashDBError::Mismatch(a, b)),
+            (Some(_), Some(_))
+                if self.header.hash_size != other.header.hash_size =>
+            {
+                Err(HashDBError::Mismatch(
+                    self.header.hash_bits(),
+                    other.header.hash_bits(),
+                ))
+            }
+            (Some(_), Some(_))
+                if self.header.algorithm != other.header.algorithm =>
+            {
+                Err(HashDBError::AlgorithmMismatch(
+                    self.header.algorithm,
+                    other.header.algorithm,
+                ))
+            }
+            (Some(_), Some(_))
+                if self.header.trim_borders != other.header.trim_borders =>
+            {
+                Err(HashDBError::TrimMismatch)
+            }
+            (Some(_), Some(_))
+                if self.header.match_rotations
+                    != other.header.match_rotations =>
+            {
+                Err(HashDBError::RotationMismatch)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Entries whose images no longer exist on the filesystem.
+    pub fn missing(&self) -> impl Iterator<Item = &Path> {
+        self.entries
+            .keys()
+            .map(PathBuf::as_path)
+            .filter(|x| is_gone(x))
+    }
+
+    /// Check the entries for images under `root` against the filesystem,
+    /// without changing the database: whether each image still exists, and
+    /// whether its modification time and size are those it was hashed with.
+    /// `options` picks images to hash again, to compare the hashes instead.
+    /// Entries outside `root`, such as those on a drive that is not mounted,
+    /// are skipped rather than reported missing. Entries with no stored stat,
+    /// from databases written before stats were kept, only count as changed
+    /// if hashed again.
+    pub fn verify<P: AsRef<Path>>(
+        &self,
+        root: P,
+        options: VerifyOptions,
+    ) -> VerifyReport {
+        let root = fs::canonicalize(&root)
+            .unwrap_or_else(|_| root.as_ref().to_path_buf());
+        let mut names: Vec<&PathBuf> = self
+            .entries
+            .keys()
+            .filter(|x| x.starts_with(&root))
+            .collect();
+        names.sort();
+        let check = |name: &PathBuf| match fs::metadata(name) {
+            Ok(x) if !x.is_file() => Verdict::Missing,
+            Ok(_) => match (self.entries[name].stat, Stat::of(name)) {
+                (Some(then), Some(now)) if then != now => Verdict::Changed,
+                _ => Verdict::Same,
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Verdict::Missing,
+            Err(_) => Verdict::Unreadable,
+        };
+        let mut verdicts: Vec<(&PathBuf, Verdict)> =
+            self.install(|| names.par_iter().map(|&x| (x, check(x))).collect());
+
+        // Pick the sample from the sorted names, so that it depends only on
+        // the seed.
+        let mut same: Vec<usize> = (0..verdicts.len())
+            .filter(|&i| verdicts[i].1 == Verdict::Same)
+            .collect();
+        shuffle(&mut same, options.seed);
+        same.truncate(options.sample);
+        let mut again: Vec<usize> = (0..verdicts.len())
+            .filter(|&i| {
+                options.rehash_changed && verdicts[i].1 == Verdict::Changed
+            })
+            .chain(same)
+            .collect();
+        again.sort_unstable();
+        let rehash = |name: &PathBuf| match hash_image(
+            name,
+            None,
+            self.max_file_size,
+            &self.header,
+        ) {
+            Ok((_, x)) if x.hash == self.entries[name].hash => Verdict::Same,
+            Ok(_) => Verdict::Changed,
+            Err(_) => Verdict::Unreadable,
+        };
+        let hashed: Vec<(usize, Verdict)> = self.install(|| {
+            again
+                .par_iter()
+                .map(|&i| (i, rehash(verdicts[i].0)))
+                .collect()
+        });
+        for &(i, verdict) in &hashed {
+            verdicts[i].1 = verdict;
+        }
+
+        let mut report = VerifyReport {
+            checked: verdicts.len(),
+            skipped: self.entries.len() - verdicts.len(),
+            rehashed: hashed.len(),
+            ..Default::default()
+        };
+        for (name, verdict) in verdicts {
+            let list = match verdict {
+                Verdict::Same => continue,
+                Verdict::Missing => &mut report.missing,
+                Verdict::Changed => &mut report.changed,
+                Verdict::Unreadable => &mut report.unreadable,
+            };
+            list.push(name.clone());
+        }
+        report
+    }
+
+    /// Remove entries whose images no longer exist on the filesystem. Returns
+    /// the number of entries removed.
+    pub fn prune(&mut self) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|name, _| !is_gone(name));
+        before - self.entries.len()
+    }
+
+    /// Search through all pairs of images in the database for all images that
+    /// have a Hamming distance (according to [`image_hasher::ImageHash::dist`])
+    /// below the given threshold, which is either a distance or a similarity
+    /// that is worked out for the hashes of the database; a plain number is a
+    /// distance. If the database matches rotations, the
+    /// distance is the smallest between one image upright and the other
+    /// turned any way. Two animations are compared by their first, middle,
+    /// and last frames, and an animation and a still image by the first frame
+    /// of the animation. Unless the threshold is large for the size of the
+    /// hashes, the hashes are indexed so that only those that could be close
+    /// are compared. Pairs are sorted by distance, then by path, with the
+    /// path that sorts first on the left, so the order is the same from run
+    /// to run. Two entries naming the same file, such as hardlinks, are only
+    /// paired if `same_files` is true.
+    pub fn find_duplicates(
+        &self,
+        threshold: impl Into<Threshold>,
+        same_files: bool,
+        observer: &dyn ScanObserver,
+    ) -> Vec<Pair> {
+        self.find_duplicates_with_distance(threshold, same_files, observer)
+            .into_iter()
+            .map(|(a, b, _)| (a, b))
+            .collect()
+    }
+
+    /// [`find_duplicates`][HashDB::find_duplicates], but with the distance
+    /// of each pair after its paths, so that callers need not look it up
+    /// again with [`distance`][HashDB::distance].
+    pub fn find_duplicates_with_distance(
+        &self,
+        threshold: impl Into<Threshold>,
+        same_files: bool,
+        observer: &dyn ScanObserver,
+    ) -> Vec<(Arc<Path>, Arc<Path>, u32)> {
+        let threshold = self.distance_for(threshold.into());
+        self.search(threshold, same_files, None, observer)
+    }
+
+    /// [`find_duplicates_with_distance`][HashDB::find_duplicates_with_distance],
+    /// but only pairing an image under one of the directories in `left` with
+    /// one under one of those in `right`, which is put on the right of the
+    /// pair. Pairs wholly on one side are left out, and so are images under
+    /// neither. An image under directories of both sides, where one side is
+    /// inside the other, is on the side of the deepest of them. The
+    /// directories should be canonical, like the names of the entries.
+    pub fn find_across<P: AsRef<Path>>(
+        &self,
+        left: &[P],
+        right: &[P],
+        threshold: impl Into<Threshold>,
+        same_files: bool,
+        observer: &dyn ScanObserver,
+    ) -> Vec<(Arc<Path>, Arc<Path>, u32)> {
+        let threshold = self.distance_for(threshold.into());
+        let roots: Vec<(&Path, bool)> = left
+            .iter()
+            .map(|x| (x.as_ref(), false))
+            .chain(right.iter().map(|x| (x.as_ref(), true)))
+            .collect();
+        let side = |img: &Path| {
+            roots
+                .iter()
+                .filter(|x| img.starts_with(x.0))
+                .max_by_key(|x| x.0.components().count())
+                .map(|x| x.1)
+        };
+        self.search(threshold, same_files, Some(&side), observer)
+    }
+
+    /// Search for pairs of similar images. With `side`, which tells whether
+    /// an image is on the right, on the left, or on neither side, only pairs
+    /// across the sides are kept, and those with the image on the right
+    /// second; otherwise the path that sorts first goes first.
+    fn search(
+        &self,
+        threshold: u32,
+        same_files: bool,
+        side: Option<Side>,
+        observer: &dyn ScanObserver,
+    ) -> Vec<(Arc<Path>, Arc<Path>, u32)> {
+        // Report every so many comparisons rather than every one; this loop
+        // is hot.
+        const STEP: u64 = 4096;
+
+        let entries: Vec<(&PathBuf, &Entry)> = self.entries.iter().collect();
+        let sides: Option<Vec<Option<bool>>> =
+            side.map(|side| entries.iter().map(|x| side(x.0)).collect());
+        // Shared copies of the names, made the first time an image is in a
+        // pair, so that each path is only copied once however many pairs it
+        // is in.
+        let mut names: Vec<Option<Arc<Path>>> = vec![None; entries.len()];
+        let mut name = |i: usize| {
+            names[i]
+                .get_or_insert_with(|| Arc::from(entries[i].0.as_path()))
+                .clone()
+        };
+        let n = entries.len() as u64;
+        let total = n * n.saturating_sub(1) / 2;
+        let mut done = 0;
+        let mut reported = 0;
+        let mut duplicates = Vec::new();
+
+        let start = Instant::now();
+        observer.phase(Phase::Match);
+
+        // The upright hashes come first, followed by the turned ones if any,
+        // with the image each of those belongs to.
+        let owners: Vec<usize> = entries
+            .iter()
+            .enumerate()
+            .flat_map(|(i, x)| x.1.turned.iter().map(move |_| i))
+            .collect();
+        let turned = !owners.is_empty();
+        let owner = |row: usize| match row.checked_sub(entries.len()) {
+            Some(k) => owners[k],
+            None => row,
+        };
+        // Distances to images before one, found by comparing them with its
+        // turned hashes, wait for it to come up so that each pair is reported
+        // once, at its smallest distance.
+        let mut pending: Vec<Vec<(usize, u32)>> = match turned {
+            true => vec![Vec::new(); entries.len()],
+            false => Vec::new(),
+        };
+
+        // Look the neighbors of each hash up in an index where that beats
+        // comparing it with every hash after it.
+        let packed = PackedHashes::new(
+            entries
+                .iter()
+                .map(|x| &x.1.hash)
+                .chain(entries.iter().flat_map(|x| &x.1.turned)),
+        );
+        let index = packed.as_ref().and_then(|x| ChunkIndex::new(x, threshold));
+        // The index only holds first frames, so pairs of animations are
+        // compared frame by frame on their own, each pair once. There are
+        // few enough animations to compare each with every other.
+        let animations: Vec<usize> = (0..entries.len())
+            .filter(|&i| entries[i].1.is_animated())
+            .collect();
+        let mut animated = vec![false; entries.len()];
+        let mut frame_matches: Vec<Vec<(usize, u32)>> =
+            vec![Vec::new(); entries.len()];
+        for (k, &i) in animations.iter().enumerate() {
+            animated[i] = true;
+            for &j in &animations[k + 1..] {
+                let dist = entries[i].1.distance(entries[j].1).0;
+                if dist < threshold {
+                    frame_matches[i].push((j, dist));
+                }
+            }
+        }
+
+        let rows = entries.len() + owners.len();
+        let mut seen = vec![usize::MAX; rows];
+        let mut distances: Vec<u32> = Vec::with_capacity(rows);
+        let mut matches: Vec<(usize, u32)> = Vec::new();
+
+        for (i, (_, entry_1)) in entries.iter().enumerate() {
+            match (&packed, &index) {
+                (Some(packed), Some(index)) => {
+                    index.matches_after(
+                        packed,
+                        i,
+                        threshold,
+                        &mut seen,
+                        &mut matches,
+                    );
+                }
+                (Some(packed), None) => {
+                    packed.distances_after(i, &mut distances);
+                    matches.clear();
+                    matches.extend(
+                        (i + 1..)
+                            .zip(&distances)
+                            .filter(|x| *x.1 < threshold)
+                            .map(|(j, &dist)| (j, dist)),
+                    );
+                }
+                (None, _) => {
+                    matches.clear();
+                    matches.extend(
+                        (i + 1..)
+                            .zip(&entries[i + 1..])
+                            .map(|(j, x)| (j, entry_1.distance(x.1).0))
+                            .filter(|x| x.1 < threshold),
+                    );
+                }
+            }
+            let mut ready;
+            let matches = match turned {
+                true => {
+                    for &(row, dist) in &matches {
+                        let j = owner(row);
+                        if j > i {
+                            pending[j].push((i, dist));
+                        } else if j < i {
+                            pending[i].push((j, dist));
+                        }
+                    }
+                    ready = std::mem::take(&mut pending[i]);
+                    ready.sort_unstable();
+                    ready.dedup_by_key(|x| x.0);
+                    &ready
+                }
+                false => &matches,
+            };
+            let matches = matches
+                .iter()
+                .filter(|x| !(animated[i] && animated[x.0]))
+                .chain(&frame_matches[i]);
+            for &(j, dist) in matches {
+                let (a, b) = match &sides {
+                    Some(sides) => match (sides[i], sides[j]) {
+                        (Some(false), Some(true)) => (i, j),
+                        (Some(true), Some(false)) => (j, i),
+                        _ => continue,
+                    },
+                    None => match entries[i].0 < entries[j].0 {
+                        true => (i, j),
+                        false => (j, i),
+                    },
+                };
+                // A file always hashes the same, so only identical hashes can
+                // be two names for one file.
+                if dist > 0
+                    || same_files
+                    || !same_file(entries[i].0, entries[j].0)
+                {
+                    let (a, b) = (name(a), name(b));
+                    observer.pair(&a, &b, dist);
+                    duplicates.push((dist, a, b));
+                }
+            }
+            done += (entries.len() - i - 1) as u64;
+            if done - reported >= STEP {
+                reported = done;
+                observer.matching(done, total, duplicates.len());
+                if observer.cancelled() {
+                    break;
+                }
+            }
+        }
+        observer.matching(total, total, duplicates.len());
+        duplicates.sort_unstable();
+        observer.phase_finished(Phase::Match, start.elapsed());
+        duplicates
+            .into_iter()
+            .map(|(dist, a, b)| (a, b, dist))
+            .collect()
+    }
+
+    /// Which of `pairs` are between frames of a burst of shots. Bursts are
+    /// groups of images taken at most `window` apart whose hashes are at most
+    /// `max_distance` apart, chained through such pairs. Copies of a photo
+    /// keep its capture time, so pairs taken at the same instant are never
+    /// burst frames. Images without a capture time are never in a burst.
+    pub fn bursts(
+        &self,
+        pairs: &[Pair],
+        window: Duration,
+        max_distance: u32,
+    ) -> Vec<bool> {
+        let gap = |(a, b): &Pair| match (self.taken(a), self.taken(b)) {
+            (Some(a), Some(b)) if a != b => Some(u128::from(a.abs_diff(b))),
+            _ => None,
+        };
+        let frames: Vec<(&Path, &Path)> = pairs
+            .iter()
+            .filter(|x| gap(x).is_some_and(|x| x <= window.as_millis()))
+            .filter(|(a, b)| {
+                self.distance(a, b).is_some_and(|x| x <= max_distance)
+            })
+            .map(|(a, b)| (&**a, &**b))
+            .collect();
+        let burst_of: HashMap<&Path, usize> = groups(&frames)
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, group)| group.into_iter().map(move |x| (x, i)))
+            .collect();
+
+        pairs
+            .iter()
+            .map(|x| {
+                gap(x).is_some()
+                    && burst_of
+                        .get(&*x.0)
+                        .is_some_and(|a| burst_of.get(&*x.1) == Some(a))
+            })
+            .collect()
+    }
+
+    /// Search for images in this database that have a Hamming distance below
+    /// `threshold` from an image in `other`. Returns `(ours, theirs)` pairs,
+    /// sorted like those of [`find_duplicates`][HashDB::find_duplicates].
+    /// Fails if the hashes of the two databases have different sizes, since
+    /// they cannot be compared.
+    pub fn find_cross(
+        &self,
+        other: &HashDB,
+        threshold: impl Into<Threshold>,
+    ) -> Result<Vec<Pair>, HashDBError> {
+        self.check_compatible(other)?;
+        let threshold = self.distance_for(threshold.into());
+
+        let mut duplicates = Vec::new();
+        let mut theirs: HashMap<&Path, Arc<Path>> = HashMap::new();
+        for (name_1, hash_1) in &self.entries {
+            let mut ours: Option<Arc<Path>> = None;
+            for (name_2, hash_2) in &other.entries {
+                let dist = hash_1.distance(hash_2).0;
+                if dist < threshold {
+                    let a =
+                        ours.get_or_insert_with(|| Arc::from(name_1.as_path()));
+                    let b = theirs
+                        .entry(name_2)
+                        .or_insert_with(|| Arc::from(name_2.as_path()));
+                    duplicates.push((dist, a.clone(), b.clone()));
+                }
+            }
+        }
+        duplicates.sort_unstable();
+        Ok(duplicates.into_iter().map(|(_, a, b)| (a, b)).collect())
+    }
+
+    /// Images in the database whose hashes have a Hamming distance below
+    /// `threshold` from `hash`, with their distances. If the database matches
+    /// rotations, the images may be turned any way. Sorted by distance, then
+    /// by path.
+    pub fn find_similar(
+        &self,
+        hash: &ImageHash,
+        threshold: impl Into<Threshold>,
+    ) -> Vec<(&Path, u32)> {
+        let threshold = self.distance_for(threshold.into());
+        let mut similar: Vec<(&Path, u32)> = self
+            .entries
+            .iter()
+            .map(|(name, x)| (name.as_path(), x.distance_to(hash)))
+            .filter(|(_, dist)| *dist < threshold)
+            .collect();
+        similar.sort_unstable_by_key(|&(name, dist)| (dist, name));
+        similar
+    }
+
+    /// [`find_similar`][HashDB::find_similar] for an image held in memory,
+    /// hashed with [`hash_bytes`].
+    pub fn find_similar_bytes(
+        &self,
+        data: &[u8],
+        threshold: impl Into<Threshold>,
+    ) -> Result<Vec<(&Path, u32)>, HashDBError> {
+        Ok(self.find_similar(&hash_bytes(data, &self.header)?, threshold))
+    }
+
+    /// Hash of an image in the database.
+    pub fn get<P: AsRef<Path>>(&self, img: P) -> Option<&ImageHash> {
+        self.entries.get(img.as_ref()).map(|x| &x.hash)
+    }
+
+    /// Whether the database has an entry for an image.
+    pub fn contains<P: AsRef<Path>>(&self, img: P) -> bool {
+        self.entries.contains_key(img.as_ref())
+    }
+
+    /// Names and hashes of all images in the database, in no particular
+    /// order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &ImageHash)> {
+        self.entries
+            .iter()
+            .map(|(name, x)| (name.as_path(), &x.hash))
+    }
+
+    /// Add or replace the entry for an image with a hash made elsewhere,
+    /// such as by [`hash_file`] with the same [`Header`], returning the hash
+    /// it replaces. The entry knows nothing else about the image, such as
+    /// its capture time or file size. Fails with [`HashDBError::Mismatch`]
+    /// if the hash is not the size of the others, and with
+    /// [`HashDBError::RotationsNeeded`] if the database matches rotations,
+    /// since those need hashes of the image turned each way; use
+    /// [`insert_file`][HashDB::insert_file] for such databases.
+    pub fn insert<P: Into<PathBuf>>(
+        &mut self,
+        img: P,
+        hash: ImageHash,
+    ) -> Result<Option<ImageHash>, HashDBError> {
+        if hash.bits() != self.header.hash_bits() {
+            return Err(HashDBError::Mismatch(
+                hash.bits(),
+                self.header.hash_bits(),
+            ));
+        }
+        if self.header.match_rotations {
+            return Err(HashDBError::RotationsNeeded);
+        }
+        let entry = Entry {
+            hash,
+            taken: None,
+            stat: None,
+            checksum: None,
+            turned: Vec::new(),
+            dimensions: None,
+            frames: Vec::new(),
+        };
+        Ok(self.entries.insert(img.into(), entry).map(|x| x.hash))
+    }
+
+    /// Hash an image file with the settings of the database and add or
+    /// replace its entry, as a scan would. Returns the name of the entry,
+    /// which is the canonicalized path of the file.
+    pub fn insert_file<P: AsRef<Path>>(
+        &mut self,
+        file: P,
+    ) -> Result<PathBuf, HashDBError> {
+        let stat = Stat::of(&file);
+        let (name, entry) =
+            hash_image(&file, None, self.max_file_size, &self.header)?;
+        let entry = Entry {
+            taken: capture_time(&name),
+            stat,
+            ..entry
+        };
+        self.entries.insert(name.clone(), entry);
+        Ok(name)
+    }
+
+    /// Remove the entry for an image, returning its hash.
+    pub fn remove<P: AsRef<Path>>(&mut self, img: P) -> Option<ImageHash> {
+        self.entries.remove(img.as_ref()).map(|x| x.hash)
+    }
+
+    /// Dimensions and file size of an image in the database, as stored when
+    /// it was hashed, so that they can be had without reading the file.
+    /// `None` if the image is missing, or was hashed before they were stored;
+    /// [`rehash`][HashDB::rehash] stores them.
+    pub fn metadata<P: AsRef<Path>>(&self, img: P) -> Option<ImageMeta> {
+        let entry = self.entries.get(img.as_ref())?;
+        let (width, height) = entry.dimensions?;
+        Some(ImageMeta {
+            width,
+            height,
+            size: entry.stat?.size,
+        })
+    }
+
+    /// Capture time of an image in the database, in milliseconds since 1970
+    /// in the local time of the camera, if known. Entries made before capture
+    /// times were stored have none until hashed again with
+    /// [`rehash`][HashDB::rehash].
+    pub fn taken<P: AsRef<Path>>(&self, img: P) -> Option<i64> {
+        self.entries.get(img.as_ref())?.taken
+    }
+
+    /// Hamming distance between the hashes of two images in the database, as
+    /// [`find_duplicates`][HashDB::find_duplicates] reckons it, or `None` if
+    /// either image is missing.
+    pub fn distance<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        img_1: P,
+        img_2: Q,
+    ) -> Option<u32> {
+        let entry = |x: &Path| self.entries.get(x);
+        Some(entry(img_1.as_ref())?.distance(entry(img_2.as_ref())?).0)
+    }
+
+    /// How the second of two images in the database is best turned to match
+    /// the first, or `None` if the database does not match rotations or
+    /// either image is missing.
+    pub fn orientation<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        img_1: P,
+        img_2: Q,
+    ) -> Option<Orientation> {
+        let entry = |x: &Path| self.entries.get(x);
+        match self.header.match_rotations {
+            true => {
+                Some(entry(img_1.as_ref())?.distance(entry(img_2.as_ref())?).1)
+            }
+            false => None,
+        }
+    }
+
+    /// Whether the files of two images in the database were byte-for-byte
+    /// the same when they were hashed. `false` if either image is missing or
+    /// was hashed before checksums were stored. Such pairs always have a
+    /// distance of 0, so they are among those found by
+    /// [`find_duplicates`][HashDB::find_duplicates].
+    pub fn identical<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        img_1: P,
+        img_2: Q,
+    ) -> bool {
+        let checksum = |x: &Path| self.entries.get(x).and_then(|x| x.checksum);
+        checksum(img_1.as_ref())
+            .is_some_and(|x| Some(x) == checksum(img_2.as_ref()))
+    }
+
+    /// Write the database to a file: [`FORMAT_VERSION`] behind magic bytes,
+    /// then Zlib'd [MessagePack][rmp]. The file it replaces is kept as its
+    /// [`backup_file`][HashDB::backup_file].
+    pub fn to_file<P: AsRef<Path>>(&self, file: P) -> Result<(), HashDBError> {
+        // The database is written under a temporary name and renamed over
+        // `file`, so a failed write never leaves a truncated database behind.
+        // `fs::rename` replaces an existing file on Windows as well.
+        let file = file.as_ref();
+        let mut tmp = file.as_os_str().to_owned();
+        tmp.push(format!(".{}.tmp", process::id()));
+        let tmp = PathBuf::from(tmp);
+
+        let result = self
+            .write_to(&tmp)
+            .and_then(|_| Self::back_up(file))
+            .and_then(|_| {
+                fs::rename(&tmp, file).map_err(HashDBError::io(file))
+            });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    /// Name of the copy of database `file` that [`to_file`][HashDB::to_file]
+    /// keeps from before the last write: `file` with `.bak` added.
+    pub fn backup_file<P: AsRef<Path>>(file: P) -> PathBuf {
+        let mut backup = file.as_ref().as_os_str().to_owned();
+        backup.push(".bak");
+        PathBuf::from(backup)
+    }
+
+    /// Keep the current contents of `file`, if any, as its backup. A hard
+    /// link leaves `file` in place until the new database is renamed over
+    /// it; filesystems without links get a copy. A damaged file is not kept,
+    /// so that the backup it was read from in its place survives.
+    fn back_up(file: &Path) -> Result<(), HashDBError> {
+        if !file.is_file() || !Self::intact(file) {
+            return Ok(());
+        }
+        let backup = Self::backup_file(file);
+        match fs::remove_file(&backup) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(HashDBError::io(&backup)(e));
+            }
+            _ => (),
+        }
+        fs::hard_link(file, &backup)
+            .or_else(|_| fs::copy(file, &backup).map(|_| ()))
+            .map_err(HashDBError::io(&backup))
+    }
+
+    /// Whether database `file` decompresses to the end of its stream,
+    /// checksum and all, which a write that was cut short does not.
+    fn intact(file: &Path) -> bool {
+        File::open(file)
+            .map_err(HashDBError::io(file))
+            .and_then(|input| compressed(BufReader::new(input), file))
+            .is_ok_and(|input| {
+                io::copy(&mut Inflate::new(input), &mut io::sink()).is_ok()
+            })
+    }
+
+    /// Stream the database straight into a new file, without holding the
+    /// whole encoding in memory.
+    fn write_to(&self, file: &Path) -> Result<(), HashDBError> {
+        let out = File::create(file).map_err(HashDBError::io(file))?;
+        let out = self.encode(BufWriter::new(out), file)?;
+        let out = out
+            .into_inner()
+            .map_err(|e| HashDBError::io(file)(e.into_error()))?;
+        out.sync_all().map_err(HashDBError::io(file))
+    }
+
+    /// Write the database to a stream in the format of
+    /// [`to_file`][HashDB::to_file]. Names are written as they are on disk,
+    /// so two names that differ only in bytes which are not valid UTF-8 stay
+    /// apart:
+    ///
+    /// ```
+    /// # #[cfg(unix)] {
+    /// use image::{ImageFormat, Rgb, RgbImage};
+    /// use image_duplicate::{HashDB, hash_bytes};
+    /// use std::{ffi::OsStr, io::Cursor, os::unix::ffi::OsStrExt, path::Path};
+    ///
+    /// let mut png = Cursor::new(Vec::new());
+    /// RgbImage::from_fn(32, 32, |x, y| Rgb([(x * 8) as u8, (y * 8) as u8, 0]))
+    ///     .write_to(&mut png, ImageFormat::Png)?;
+    /// let mut hashdb = HashDB::new();
+    /// let hash = hash_bytes(png.get_ref(), hashdb.header())?;
+    /// let latin1 = Path::new(OsStr::from_bytes(b"photos/caf\xe9.png"));
+    /// let other = Path::new(OsStr::from_bytes(b"photos/caf\xe8.png"));
+    /// hashdb.insert(latin1, hash.clone())?;
+    /// hashdb.insert(other, hash)?;
+    ///
+    /// let mut data = Vec::new();
+    /// hashdb.to_writer(&mut data)?;
+    /// let hashdb = HashDB::from_reader(&data[..])?;
+    /// assert_eq!(hashdb.len(), 2);
+    /// assert!(hashdb.contains(latin1) && hashdb.contains(other));
+    /// assert!(!hashdb.contains(&*latin1.to_string_lossy()));
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn to_writer<W: Write>(&self, writer: W) -> Result<(), HashDBError> {
+        let mut writer = self.encode(writer, Path::new("-"))?;
+        writer.flush().map_err(HashDBError::io("-"))
+    }
+
+    /// Encode the database into `writer`, returning it once the stream is
+    /// finished. `file` names the destination in errors.
+    fn encode<W: Write>(
+        &self,
+        writer: W,
+        file: &Path,
+    ) -> Result<W, HashDBError> {
+        if let Some(root) = self.relative_root()
+            && let Some(name) =
+                self.entries.keys().find(|x| !x.starts_with(root))
+        {
+            return Err(HashDBError::OutsideRoot {
+                path: name.clone(),
+                root: root.to_path_buf(),
+            });
+        }
+        let mut writer = writer;
+        writer
+            .write_all(MAGIC)
+            .and_then(|_| writer.write_all(&FORMAT_VERSION.to_le_bytes()))
+            .map_err(HashDBError::io(file))?;
+        let mut z = ZlibEncoder::new(writer, Compression::default());
+        // Use this method over `rmp_serde::encode::write` to avoid overhead on
+        // packing bytes. (If this breaks decoding, maybe live with the
+        // overhead?)
+        self.serialize(
+            &mut Serializer::new(&mut z).with_bytes(BytesMode::ForceAll),
+        )
+        .map_err(|e| HashDBError::EncodeError(file.to_path_buf(), e))?;
+        // Write errors that happen while finishing the stream would otherwise
+        // be lost when it is dropped.
+        z.finish().map_err(HashDBError::io(file))
+    }
+
+    /// Read a database from a file written by [`to_file`][HashDB::to_file],
+    /// or by a version from before the format was versioned. Fails with
+    /// [`HashDBError::Version`] if the file is in another format version.
+    /// Names stored [relative][Header::relative] stay so until the database
+    /// is given a [root][HashDB::set_root].
+    pub fn from_file<P: AsRef<Path>>(file: P) -> Result<Self, HashDBError> {
+        let file = file.as_ref();
+        let input = File::open(file).map_err(HashDBError::io(file))?;
+        Self::decode(input, file)
+    }
+
+    /// Read a database as [`from_file`][HashDB::from_file] does, falling back
+    /// to its [`backup_file`][HashDB::backup_file] if the file cannot be
+    /// read or decoded. The error from the file itself is returned along
+    /// with a database read from the backup, so that callers can warn about
+    /// it; if the backup cannot be read either, that error is returned
+    /// alone. Files in another format version do not fall back, since their
+    /// backups would be older still.
+    pub fn from_file_or_backup<P: AsRef<Path>>(
+        file: P,
+    ) -> Result<(Self, Option<HashDBError>), HashDBError> {
+        let file = file.as_ref();
+        match Self::from_file(file) {
+            Ok(hashdb) => Ok((hashdb, None)),
+            Err(e @ HashDBError::Version { .. }) => Err(e),
+            Err(e) => match Self::from_file(Self::backup_file(file)) {
+                Ok(hashdb) => Ok((hashdb, Some(e))),
+                Err(_) => Err(e),
+            },
+        }
+    }
+
+    /// Read what can still be read of a damaged database `file`: its header
+    /// and its entries up to the first that cannot be decoded, such as where
+    /// a write was cut short. Fails if the file is not a database in this
+    /// format version or its header cannot be read, but may return no
+    /// entries. Databases that are intact read in full, but
+    /// [`from_file`][HashDB::from_file] checks them more thoroughly.
+    pub fn salvage<P: AsRef<Path>>(file: P) -> Result<Self, HashDBError> {
+        let file = file.as_ref();
+        let input = File::open(file).map_err(HashDBError::io(file))?;
+        let input = compressed(BufReader::new(input), file)?;
+        // A stream that was cut short still yields what came before the cut.
+        let mut data = Vec::new();
+        let _ = ZlibDecoder::new(input)
+            .take(MAX_DECODED_BYTES)
+            .read_to_end(&mut data);
+
+        let mut input = data.as_slice();
+        let header = match input.first() {
+            Some(&x) if x == Marker::FixArray(2).to_u8() => {
+                input = &input[1..];
+                let header = Header::deserialize(
+                    &mut rmp_serde::Deserializer::new(&mut input),
+                )
+                .map_err(|e| HashDBError::DecodeError(file.to_path_buf(), e))?;
+                header
+                    .check()
+                    .map_err(|e| HashDBError::Corrupt(file.to_path_buf(), e))?;
+                header
+            }
+            // Databases from before there were headers are a bare map.
+            _ => Header::default(),
+        };
+        let mut hashdb = HashDB {
+            header,
+            ..HashDB::default()
+        };
+        let declared = rmp::decode::read_map_len(&mut input).unwrap_or(0);
+        let mut hash_len = None;
+        for _ in 0..(declared as usize).min(MAX_ENTRIES) {
+            let mut input = rmp_serde::Deserializer::new(&mut input);
+            let (Ok(Name(name)), Ok(entry)) = (
+                Name::deserialize(&mut input),
+                Entry::deserialize(&mut input),
+            ) else {
+                break;
+            };
+            if check_entry(&name, &entry, &mut hash_len).is_err() {
+                break;
+            }
+            hashdb.entries.insert(name, entry);
+        }
+        Ok(hashdb)
+    }
+
+    /// Read only the [`Header`] of a database file, without decoding its
+    /// entries.
+    pub fn read_header<P: AsRef<Path>>(file: P) -> Result<Header, HashDBError> {
+        let file = file.as_ref();
+        let input = File::open(file).map_err(HashDBError::io(file))?;
+        let input = compressed(BufReader::new(input), file)?;
+        let mut input = ZlibDecoder::new(input).take(MAX_DECODED_BYTES);
+        let marker = rmp::decode::read_marker(&mut input)
+            .map_err(|e| stream_error(file, e.0))?;
+        match marker {
+            Marker::FixArray(2) => {
+                let header = Header::deserialize(
+                    &mut rmp_serde::Deserializer::new(input),
+                )
+                .map_err(|e| HashDBError::DecodeError(file.to_path_buf(), e))?;
+                header
+                    .check()
+                    .map_err(|e| HashDBError::Corrupt(file.to_path_buf(), e))?;
+                Ok(header)
+            }
+            // Databases from before there were headers are a bare map.
+            Marker::FixMap(_) | Marker::Map16 | Marker::Map32 => {
+                Ok(Header::default())
+            }
+            _ => Err(HashDBError::Corrupt(
+                file.to_path_buf(),
+                "not a database".into(),
+            )),
+        }
+    }
+
+    /// Read a database from a stream in the format of
+    /// [`from_file`][HashDB::from_file].
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, HashDBError> {
+        Self::decode(reader, Path::new("-"))
+    }
+
+    /// Decode a database from `reader`. `file` names the source in errors.
+    fn decode<R: Read>(reader: R, file: &Path) -> Result<Self, HashDBError> {
+        Self::decode_within(reader, file, MAX_DECODED_BYTES)
+    }
+
+    /// Decode a database from `reader` as it is decompressed, failing if it
+    /// takes more than `limit` bytes. Lengths declared in the data are not
+    /// trusted: the decoder reads what is actually there before allocating
+    /// for it, and [`MAX_ENTRIES`] and [`MAX_HASH_BYTES`] cap the rest.
+    fn decode_within<R: Read>(
+        reader: R,
+        file: &Path,
+        limit: u64,
+    ) -> Result<Self, HashDBError> {
+        let reader = compressed(BufReader::new(reader), file)?;
+        let mut input = Inflate::new(reader).take(limit);
+        let hashdb = rmp_serde::from_read(&mut input).map_err(|e| {
+            match (input.limit(), e) {
+                (0, _) => HashDBError::Corrupt(
+                    file.to_path_buf(),
+                    format!("more than {limit} bytes decompressed"),
+                ),
+                (
+                    _,
+                    rmp_serde::decode::Error::InvalidMarkerRead(e)
+                    | rmp_serde::decode::Error::InvalidDataRead(e),
+                ) => stream_error(file, e),
+                (_, e) => HashDBError::DecodeError(file.to_path_buf(), e),
+            }
+        })?;
+        // Read to the end of the stream so that its checksum is checked;
+        // `Inflate` fails if the stream stops short of it.
+        io::copy(&mut input, &mut io::sink())
+            .map_err(|e| stream_error(file, e))?;
+        Ok(hashdb)
+    }
+}
+
+/// Check the magic bytes and format version at the start of database `file`,
+/// and return the compressed data that follows. Files from before there was
+/// a version are recognized by the Zlib header they start with, and read as
+/// they are.
+fn compressed<R: Read>(
+    mut reader: R,
+    file: &Path,
+) -> Result<io::Chain<Cursor<Vec<u8>>, R>, HashDBError> {
+    let mut start = Vec::with_capacity(MAGIC.len() + 4);
+    reader
+        .by_ref()
+        .take(MAGIC.len() as u64 + 4)
+        .read_to_end(&mut start)
+        .map_err(HashDBError::io(file))?;
+    let corrupt = |e: &str| HashDBError::Corrupt(file.to_path_buf(), e.into());
+    match start.strip_prefix(MAGIC) {
+        Some(version) => {
+            let found = version
+                .try_into()
+                .map(u32::from_le_bytes)
+                .map_err(|_| corrupt("no format version"))?;
+            if found != FORMAT_VERSION {
+                return Err(HashDBError::Version {
+                    path: file.to_path_buf(),
+                    found,
+                    expected: FORMAT_VERSION,
+                });
+            }
+            start.clear();
+        }
+        // A deflate stream with a window of at most 32 KiB, whose header
+        // checksum is right.
+        None if start.len() >= 2
+            && start[0] & 0x8f == 0x08
+            && u16::from_be_bytes([start[0], start[1]]) % 31 == 0 => {}
+        None => return Err(corrupt("not an image-duplicate database")),
+    }
+    Ok(Cursor::new(start).chain(reader))
+}
+
+/// Zlib decompressor that, unlike [`ZlibDecoder`], fails with
+/// [`io::ErrorKind::UnexpectedEof`] if its input ends before the stream does.
+/// `ZlibDecoder` takes such an input as the end of the stream, so a file cut
+/// short within the checksum at its end would go unnoticed.
+struct Inflate<R> {
+    reader: R,
+    state: Decompress,
+    done: bool,
+}
+
+impl<R: BufRead> Inflate<R> {
+    fn new(reader: R) -> Self {
+        Inflate {
+            reader,
+            state: Decompress::new(true),
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Read for Inflate<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.done && !buf.is_empty() {
+            let input = self.reader.fill_buf()?;
+            let eof = input.is_empty();
+            let (total_in, total_out) =
+                (self.state.total_in(), self.state.total_out());
+            let status = self
+                .state
+                .decompress(input, buf, FlushDecompress::None)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let read = (self.state.total_in() - total_in) as usize;
+            let written = (self.state.total_out() - total_out) as usize;
+            self.reader.consume(read);
+            match status {
+                Status::StreamEnd => self.done = true,
+                _ if written > 0 => (),
+                _ if eof => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "compressed stream cut short",
+                    ));
+                }
+                _ if read == 0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "compressed stream stalled",
+                    ));
+                }
+                _ => continue,
+            }
+            return Ok(written);
+        }
+        Ok(0)
+    }
+}
+
+/// Error for a failure to read the compressed stream of database `file`:
+/// malformed data makes it corrupt, anything else is an IO error.
+fn stream_error(file: &Path, e: io::Error) -> HashDBError {
+    match e.kind() {
+        io::ErrorKind::InvalidInput
+        | io::ErrorKind::InvalidData
+        | io::ErrorKind::UnexpectedEof => {
+            HashDBError::Corrupt(file.to_path_buf(), e.to_string())
+        }
+        _ => HashDBError::io(file)(e),
+    }
+}
+
+/// Escape backslashes, tabs, and line breaks so that a path fits on one
+/// tab-separated line, and bytes that are not UTF-8 as `\xNN` so that it
+/// is not mangled.
+pub(crate) fn escape_path(path: &Path) -> String {
+    let bytes = path.as_os_str().as_encoded_bytes();
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\t' => escaped.push_str("\\t"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                c => escaped.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            escaped.push_str(&format!("\\x{b:02x}"));
+        }
+    }
+    escaped
+}
+
+/// Undo [`escape_path`]. A backslash before anything else stays as it is,
+/// so that paths written by hand with single backslashes still read. Returns
+/// `None` if escaped bytes that are not UTF-8 cannot make a path here.
+pub(crate) fn unescape_path(text: &str) -> Option<PathBuf> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('\\') {
+        bytes.extend_from_slice(&rest.as_bytes()[..i]);
+        rest = &rest[i + 1..];
+        let hex = rest
+            .get(1..3)
+            .filter(|x| x.bytes().all(|x| x.is_ascii_hexdigit()))
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        let (byte, len) = match (rest.as_bytes().first(), hex) {
+            (Some(b'\\'), _) => (b'\\', 1),
+            (Some(b't'), _) => (b'\t', 1),
+            (Some(b'n'), _) => (b'\n', 1),
+            (Some(b'r'), _) => (b'\r', 1),
+            (Some(b'x'), Some(x)) => (x, 3),
+            _ => (b'\\', 0),
+        };
+        bytes.push(byte);
+        rest = &rest[len..];
+    }
+    bytes.extend_from_slice(rest.as_bytes());
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(PathBuf::from(std::ffi::OsStr::from_bytes(&bytes)))
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8(bytes).ok().map(PathBuf::from)
+    }
+}
+
+/// One line per entry, sorted by path: the base64 hash, a tab, and the path
+/// with backslashes, tabs, and line breaks escaped as `\\`, `\t`, `\n`, and
+/// `\r`, and bytes that are not UTF-8 as `\xNN`.
+impl Display for HashDB {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_unstable_by_key(|(k, _)| *k);
+        for (k, v) in entries {
+            writeln!(f, "{}\t{}", v.hash.0.to_base64(), escape_path(k))?;
+        }
+        Ok(())
+    }
+}
+
+/// Broad kinds of [`HashDBError`], for reporting to programs. The serialized
+/// names are stable.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// The file is not a valid image.
+    Decode,
+    /// The image format or one of its features is not supported.
+    Unsupported,
+    /// The image is too large to decode.
+    Limits,
+    /// The file could not be accessed for lack of permissions.
+    Permission,
+    /// The file no longer exists.
+    NotFound,
+    /// Any other IO error.
+    Io,
+    /// The database file could not be read or written.
+    Database,
+    /// Anything else.
+    Other,
+}
+
+impl From<io::ErrorKind> for ErrorCategory {
+    fn from(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::PermissionDenied => ErrorCategory::Permission,
+            io::ErrorKind::NotFound => ErrorCategory::NotFound,
+            _ => ErrorCategory::Io,
+        }
+    }
+}
+
+/// Errors that can happen when dealing with [`HashDB`].
+#[derive(Debug, Error)]
+pub enum HashDBError {
+    /// Wrapper around [`rmp_serde::decode::Error`], with the database file.
+    #[error("Database {0:?} appears corrupt or too large: {1}")]
+    DecodeError(PathBuf, rmp_serde::decode::Error),
+
+    /// A database file could not be decompressed, or is over the size limit.
+    #[error("Database {0:?} appears corrupt or too large: {1}")]
+    Corrupt(PathBuf, String),
+
+    /// A database file is in a format version this build cannot read.
+    #[error(
+        "Database {path:?} is in format version {found}, but this version of \
+         image-duplicate reads version {expected}"
+    )]
+    Version {
+        path: PathBuf,
+        found: u32,
+        expected: u32,
+    },
+
+    /// Wrapper around [`rmp_serde::encode::Error`], with the database file.
+    #[error("Could not encode database {0:?}: {1}")]
+    EncodeError(PathBuf, rmp_serde::encode::Error),
+
+    /// Two databases hold hashes of different sizes, in bits.
+    #[error(
+        "Databases have {0}-bit and {1}-bit hashes, which cannot be compared"
+    )]
+    Mismatch(u32, u32),
+
+    /// One database crops image borders before hashing and the other does
+    /// not.
+    #[error(
+        "One database was hashed with --trim-borders and the other without, \
+         so their hashes cannot be compared"
+    )]
+    TrimMismatch,
+
+    /// One database matches rotations and the other does not.
+    #[error(
+        "One database was hashed with --match-rotations and the other \
+         without, so their hashes cannot be compared"
+    )]
+    RotationMismatch,
+
+    /// A lone hash was given to a database that matches rotations, which
+    /// needs hashes of each image turned each way as well.
+    #[error(
+        "The database matches rotations, so images can only be added to it by \
+         hashing their files"
+    )]
+    RotationsNeeded,
+
+    /// Two databases were hashed with different algorithms.
+    #[error(
+        "Databases were hashed with the {0} and {1} algorithms, whose hashes \
+         cannot be compared"
+    )]
+    AlgorithmMismatch(Algorithm, Algorithm),
+
+    /// One database holds relative names not yet resolved against a
+    /// directory, and the other absolute ones.
+    #[error(
+        "One database stores paths relative to its directory and the other \
+         absolute paths, so their entries cannot be matched up"
+    )]
+    PathMismatch,
+
+    /// A database that stores names relative to a directory has an entry
+    /// outside of it.
+    #[error(
+        "{path:?} is not under {root:?}, which the database stores paths \
+         relative to"
+    )]
+    OutsideRoot { path: PathBuf, root: PathBuf },
+
+    /// Wrapper around [`image::ImageError`].
+    #[error("Could not read {0:?}: {1}")]
+    ImageError(PathBuf, image::ImageError),
+
+    /// Data given as an image is not one, or not in a supported format.
+    #[error("Not a readable image: {0}")]
+    NotAnImage(image::ImageError),
+
+    /// An image file is over the size limit set with
+    /// [`HashDB::set_max_file_size`].
+    #[error("{path:?} is {size} bytes, over the limit of {limit}")]
+    TooLarge {
+        path: PathBuf,
+        size: u64,
+        limit: u64,
+    },
+
+    /// Wrapper around [`std::io::Error`], with the path involved.
+    #[error("IO Error: {path:?}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+}
+
+impl HashDBError {
+    /// Broad kind of the error.
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            HashDBError::DecodeError(..)
+            | HashDBError::Corrupt(..)
+            | HashDBError::Version { .. }
+            | HashDBError::EncodeError(..)
+            | HashDBError::Mismatch(..)
+            | HashDBError::TrimMismatch
+            | HashDBError::RotationMismatch
+            | HashDBError::RotationsNeeded
+            | HashDBError::AlgorithmMismatch(..)
+            | HashDBError::PathMismatch
+            | HashDBError::OutsideRoot { .. } => ErrorCategory::Database,
+            HashDBError::ImageError(_, e) | HashDBError::NotAnImage(e) => {
+                match e {
+                    image::ImageError::Decoding(_) => ErrorCategory::Decode,
+                    image::ImageError::Unsupported(_) => {
+                        ErrorCategory::Unsupported
+                    }
+                    image::ImageError::Limits(_) => ErrorCategory::Limits,
+                    image::ImageError::IoError(e) => e.kind().into(),
+                    _ => ErrorCategory::Other,
+                }
+            }
+            HashDBError::TooLarge { .. } => ErrorCategory::Limits,
+            HashDBError::Io { source, .. } => source.kind().into(),
+        }
+    }
+
+    /// Wrap an IO error on `path`, for use with [`Result::map_err`].
+    fn io<P: AsRef<Path>>(path: P) -> impl FnOnce(io::Error) -> Self {
+        move |source| HashDBError::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database of `n` entries with distinct hashes.
+    fn database(n: usize) -> HashDB {
+        let bytes = (Header::default().hash_bits() / 8) as usize;
+        let mut hashdb = HashDB::new();
+        for i in 0..n {
+            let mut hash = vec![0; bytes];
+            hash[..4].copy_from_slice(&(i as u32).to_le_bytes());
+            let hash = image_hasher::ImageHash::from_bytes(&hash).unwrap();
+            hashdb
+                .insert(format!("/images/{i}.png"), ImageHash(hash))
+                .unwrap();
+        }
+        hashdb
+    }
+
+    fn encoded(hashdb: &HashDB) -> Vec<u8> {
+        let mut data = Vec::new();
+        hashdb.to_writer(&mut data).unwrap();
+        data
+    }
+
+    /// `data` Zlib'd after the magic bytes and format version, as a database
+    /// file would be.
+    fn wrapped(data: &[u8]) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        file.extend(FORMAT_VERSION.to_le_bytes());
+        let mut z = ZlibEncoder::new(file, Compression::default());
+        z.write_all(data).unwrap();
+        z.finish().unwrap()
+    }
+
+    /// `n` hashes of `bytes` bytes, a quarter of them random and the rest
+    /// a few bits off one of those, so that there are pairs at every small
+    /// distance.
+    fn clustered_hashes(seed: u64, n: usize, bytes: usize) -> Vec<ImageHash> {
+        let mut state = seed;
+        let mut next = || {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        };
+        let mut hashes: Vec<Vec<u8>> = Vec::with_capacity(n);
+        for i in 0..n {
+            let hash = match i % 4 {
+                0 => (0..bytes).map(|_| next() as u8).collect(),
+                _ => {
+                    let mut hash = hashes[i - i % 4].clone();
+                    for _ in 0..next() % 12 {
+                        let bit = (next() % (bytes as u64 * 8)) as usize;
+                        hash[bit / 8] ^= 1 << (bit % 8);
+                    }
+                    hash
+                }
+            };
+            hashes.push(hash);
+        }
+        hashes
+            .iter()
+            .map(|x| ImageHash(image_hasher::ImageHash::from_bytes(x).unwrap()))
+            .collect()
+    }
+
+    /// An empty directory for test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("image-duplicate-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn each_within_visits_each_value_once() {
+        for (width, radius) in [(1, 1), (8, 0), (8, 2), (21, 3), (64, 1)] {
+            let value = 0x5555_5555_5555_5555 & (u64::MAX >> (64 - width));
+            let mut seen = Vec::new();
+            each_within(value, width, radius, &mut |x| seen.push(x));
+            let expected: f64 = (0..=radius).map(|k| binomial(width, k)).sum();
+            assert_eq!(seen.len(), expected as usize);
+            assert!(seen.iter().all(|x| (x ^ value).count_ones() <= radius));
+            assert!(seen.iter().all(|x| x >> width.min(63) <= 1));
+            seen.sort_unstable();
+            seen.dedup();
+            assert_eq!(seen.len(), expected as usize);
+        }
+    }
+
+    #[test]
+    fn packed_distances_match_dist() {
+        // Whole words, and sizes that leave part of the last word unused.
+        for bytes in [8, 16, 32, 1, 2, 5, 9, 18] {
+            let hashes = clustered_hashes(bytes as u64, 200, bytes);
+            let packed = PackedHashes::new(hashes.iter()).unwrap();
+            assert_eq!(packed.bits, bytes as u32 * 8);
+            let mut row = Vec::new();
+            for i in 0..hashes.len() {
+                packed.distances_after(i, &mut row);
+                assert_eq!(row.len(), hashes.len() - i - 1);
+                for (j, &dist) in (i + 1..).zip(&row) {
+                    let expected = hashes[i].dist(&hashes[j]);
+                    assert_eq!(dist, expected, "{bytes} bytes");
+                    assert_eq!(packed.distance(i, j), expected);
+                }
+            }
+        }
+        let mixed = [clustered_hashes(0, 1, 8), clustered_hashes(0, 1, 9)];
+        assert!(PackedHashes::new(mixed.iter().flatten()).is_none());
+    }
+
+    #[test]
+    fn chunk_index_matches_brute_force() {
+        let mut indexed = 0;
+        for (n, bytes, thresholds) in [
+            (3000, 5, &[1, 3, 5][..]),
+            (3000, 8, &[1, 2, 3, 5]),
+            (3000, 18, &[3, 9]),
+            (3000, 32, &[9, 17]),
+            // Large enough for pieces that may be a bit or two off.
+            (10000, 2, &[2, 3]),
+            (10000, 8, &[9]),
+        ] {
+            let hashes = clustered_hashes(bytes as u64, n, bytes);
+            let packed = PackedHashes::new(hashes.iter()).unwrap();
+            for &threshold in thresholds {
+                let Some(index) = ChunkIndex::new(&packed, threshold) else {
+                    continue;
+                };
+                indexed += 1;
+                let mut seen = vec![usize::MAX; n];
+                let mut found = Vec::new();
+                // A sample of the rows; brute force is slow.
+                for i in (0..n).step_by(n / 300) {
+                    index.matches_after(
+                        &packed, i, threshold, &mut seen, &mut found,
+                    );
+                    found.sort_unstable();
+                    let expected: Vec<(usize, u32)> = (i + 1..n)
+                        .map(|j| (j, hashes[i].dist(&hashes[j])))
+                        .filter(|x| x.1 < threshold)
+                        .collect();
+                    assert_eq!(found, expected, "{bytes} bytes, {threshold}");
+                }
+            }
+        }
+        assert_eq!(indexed, 14);
+    }
+
+    #[test]
+    fn pairs_share_paths() {
+        let hashes = clustered_hashes(3, 3000, 8);
+        let mut hashdb = HashDB::new();
+        for (i, hash) in hashes.iter().enumerate() {
+            hashdb
+                .insert(format!("/images/{i}.png"), hash.clone())
+                .unwrap();
+        }
+        let pairs = hashdb.find_duplicates_with_distance(5, true, &());
+
+        let mut expected = Vec::new();
+        for (i, a) in hashes.iter().enumerate() {
+            for (j, b) in (i + 1..).zip(&hashes[i + 1..]) {
+                if a.dist(b) < 5 {
+                    let (a, b) = (
+                        PathBuf::from(format!("/images/{i}.png")),
+                        PathBuf::from(format!("/images/{j}.png")),
+                    );
+                    expected.push(match a < b {
+                        true => (a, b),
+                        false => (b, a),
+                    });
+                }
+            }
+        }
+        let mut found: Vec<(PathBuf, PathBuf)> = pairs
+            .iter()
+            .map(|x| (x.0.to_path_buf(), x.1.to_path_buf()))
+            .collect();
+        found.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(found, expected);
+
+        // Each image is stored once, however many pairs it is in.
+        let mut names: HashMap<&Path, &Arc<Path>> = HashMap::new();
+        for (a, b, _) in &pairs {
+            for name in [a, b] {
+                let first = names.entry(name).or_insert(name);
+                assert!(Arc::ptr_eq(first, name));
+            }
+        }
+        assert!(names.len() < pairs.len() * 2);
+    }
+
+    #[test]
+    fn find_duplicates_on_image_files() {
+        let dir = temp_dir("find-duplicates");
+        let mut state = 1u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        // Blocky images of random 8 by 8 patterns, each with two copies a
+        // few blocks off.
+        let mut files = Vec::new();
+        for i in 0..25 {
+            let pattern = next();
+            for k in 0..3 {
+                let mut pattern = pattern;
+                for _ in 0..k {
+                    pattern ^= 1 << (next() % 64);
+                }
+                let file = dir.join(format!("{i}-{k}.png"));
+                image::GrayImage::from_fn(16, 16, |x, y| {
+                    let bit = pattern >> (y / 2 * 8 + x / 2) & 1;
+                    [bit as u8 * 255].into()
+                })
+                .save(&file)
+                .unwrap();
+                files.push(file);
+            }
+        }
+        fs::copy(&files[0], dir.join("copy.png")).unwrap();
+        let mut hashdb = HashDB::new();
+        for file in &files {
+            hashdb.insert_file(file).unwrap();
+        }
+        hashdb.insert_file(dir.join("copy.png")).unwrap();
+        // The same file under another spelling is the same entry.
+        hashdb.insert_file(dir.join(".").join("0-0.png")).unwrap();
+        assert_eq!(hashdb.len(), files.len() + 1);
+
+        let entries: Vec<&Path> = hashdb.iter().map(|x| x.0).collect();
+        let packed =
+            PackedHashes::new(hashdb.entries.values().map(|x| &x.hash))
+                .unwrap();
+        assert!(ChunkIndex::new(&packed, 1).is_some());
+        for threshold in [1, 2, 5, 9] {
+            let pairs =
+                hashdb.find_duplicates_with_distance(threshold, true, &());
+            let mut found = HashSet::new();
+            for (a, b, dist) in &pairs {
+                assert_ne!(a, b, "self-pair");
+                assert!(found.insert((a.to_path_buf(), b.to_path_buf())));
+                assert!(!found.contains(&(b.to_path_buf(), a.to_path_buf())));
+                assert_eq!(hashdb.distance(a, b), Some(*dist));
+            }
+            let mut expected = HashSet::new();
+            for (k, a) in entries.iter().enumerate() {
+                for b in &entries[k + 1..] {
+                    if hashdb.distance(a, b).unwrap() < threshold {
+                        expected.insert(match a < b {
+                            true => (a.to_path_buf(), b.to_path_buf()),
+                            false => (b.to_path_buf(), a.to_path_buf()),
+                        });
+                    }
+                }
+            }
+            assert_eq!(found, expected, "threshold {threshold}");
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn qoi_tga_and_netpbm_images() {
+        let dir = temp_dir("formats");
+        let pixels = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+        let rgb = image::RgbImage::from_fn(2, 2, |x, y| {
+            pixels[(y * 2 + x) as usize].into()
+        });
+        rgb.save(dir.join("rgb.qoi")).unwrap();
+        rgb.save(dir.join("rgb.ppm")).unwrap();
+        // TGA rows run bottom up unless bit 5 of the descriptor is set.
+        for (name, descriptor, rows) in
+            [("down.tga", 0x20, [0, 1]), ("up.tga", 0, [1, 0])]
+        {
+            let mut tga = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            tga.extend([2, 0, 2, 0, 24, descriptor]);
+            for y in rows {
+                for [r, g, b] in &pixels[y * 2..y * 2 + 2] {
+                    tga.extend([b, g, r]);
+                }
+            }
+            fs::write(dir.join(name), tga).unwrap();
+        }
+        for name in ["rgb.qoi", "rgb.ppm", "down.tga", "up.tga"] {
+            let image = image::open(dir.join(name)).unwrap();
+            assert_eq!(image.to_rgb8(), rgb, "{name}");
+        }
+
+        // PBM samples are 1 for black; 16-bit PGM samples run up to maxval.
+        fs::write(dir.join("bits.pbm"), "P1\n2 2\n1 0\n0 1\n").unwrap();
+        let mut pgm = b"P5\n2 2\n1000\n".to_vec();
+        for x in [0u16, 1000, 1000, 0] {
+            pgm.extend(x.to_be_bytes());
+        }
+        fs::write(dir.join("deep.pgm"), pgm).unwrap();
+        for name in ["bits.pbm", "deep.pgm"] {
+            let image = image::open(dir.join(name)).unwrap().to_luma8();
+            assert_eq!(image.into_raw(), [0, 255, 255, 0], "{name}");
+        }
+
+        for file in fs::read_dir(&dir).unwrap() {
+            let file = file.unwrap().path();
+            assert!(ScanFilter::default().is_image(&file), "{file:?}");
+            hash_file(&file, &Header::default()).unwrap();
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn trimmed_letterbox_matches() {
+        let mut state = 7u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let pattern: Vec<u8> = (0..48).map(|_| next() as u8).collect();
+        let clean = image::GrayImage::from_fn(64, 48, |x, y| {
+            [pattern[(y / 8 * 8 + x / 8) as usize]].into()
+        });
+        // Black bars above and below, as a 4:3 image shown 1:1.
+        let letterboxed = image::GrayImage::from_fn(64, 64, |x, y| match y {
+            8..56 => *clean.get_pixel(x, y - 8),
+            _ => [0].into(),
+        });
+        let png = |image: &image::GrayImage| {
+            let mut data = Cursor::new(Vec::new());
+            image.write_to(&mut data, ImageFormat::Png).unwrap();
+            data.into_inner()
+        };
+        assert_eq!(
+            border_box(&letterboxed.clone().into()),
+            Some((0, 8, 64, 48))
+        );
+
+        let trim = Header {
+            trim_borders: true,
+            ..Header::default()
+        };
+        let a = hash_bytes(&png(&clean), &trim).unwrap();
+        let b = hash_bytes(&png(&letterboxed), &trim).unwrap();
+        assert!(a.dist(&b) < crate::DEFAULT_THRESHOLD, "{}", a.dist(&b));
+        let plain = Header::default();
+        let c = hash_bytes(&png(&clean), &plain).unwrap();
+        let d = hash_bytes(&png(&letterboxed), &plain).unwrap();
+        assert!(c.dist(&d) > a.dist(&b), "{}", c.dist(&d));
+    }
+
+    #[test]
+    fn excluded_paths() {
+        let dir = temp_dir("exclude");
+        let dir = dir.canonicalize().unwrap();
+        let names = [
+            "a.png",
+            "b.bmp",
+            "cache/c.png",
+            "sub/e.png",
+            "sub/g.bmp",
+            "sub/cache/d.png",
+            "sub/deep/cache/f.png",
+        ];
+        for name in names {
+            let file = dir.join(name);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            image::GrayImage::from_fn(8, 8, |x, y| [(x * y) as u8].into())
+                .save(file)
+                .unwrap();
+        }
+        let filter = ScanFilter::new(&["**/cache/**", "*.bmp"]).unwrap();
+        assert!(filter.excludes(Path::new("cache"), true));
+        assert!(filter.excludes(Path::new("sub/deep/cache"), true));
+        assert!(!filter.excludes(Path::new("sub/deep"), true));
+        let names_in = |hashdb: &HashDB| {
+            let mut names: Vec<String> = hashdb
+                .iter()
+                .map(|(x, _)| {
+                    x.strip_prefix(&dir).unwrap().to_string_lossy().into()
+                })
+                .collect();
+            names.sort();
+            names
+        };
+
+        let mut hashdb = HashDB::new();
+        hashdb.read_dir(&dir, &filter, None, false, &()).unwrap();
+        assert_eq!(names_in(&hashdb), ["a.png"]);
+
+        // Entries already under excluded paths go, as if the images were
+        // gone.
+        let mut hashdb = HashDB::new();
+        hashdb
+            .read_dir_recursive(&dir, &ScanFilter::default(), None, false, &())
+            .unwrap();
+        assert_eq!(hashdb.len(), names.len());
+        hashdb
+            .read_dir_recursive(&dir, &filter, None, false, &())
+            .unwrap();
+        assert_eq!(names_in(&hashdb), ["a.png", "sub/e.png"]);
+
+        // A scan that is not recursive leaves entries in subdirectories be.
+        // Exclusions apply only to the scan they are given for.
+        hashdb
+            .read_dir(
+                &dir,
+                &ScanFilter::new(&["*.png"]).unwrap(),
+                None,
+                false,
+                &(),
+            )
+            .unwrap();
+        assert_eq!(names_in(&hashdb), ["b.bmp", "sub/e.png"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let dir = temp_dir("metadata");
+        let file = dir.join("wide.png");
+        image::GrayImage::from_fn(12, 7, |x, y| [(x * y) as u8].into())
+            .save(&file)
+            .unwrap();
+        let mut hashdb = HashDB::new();
+        let name = hashdb.insert_file(&file).unwrap();
+        let meta = ImageMeta {
+            width: 12,
+            height: 7,
+            size: fs::metadata(&file).unwrap().len(),
+        };
+        assert_eq!(hashdb.metadata(&name), Some(meta));
+
+        let entry = &hashdb.entries[&name];
+        let data = rmp_serde::to_vec(entry).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Entry>(&data).unwrap(), *entry);
+        let read = HashDB::from_reader(&encoded(&hashdb)[..]).unwrap();
+        assert_eq!(read.metadata(&name), Some(meta));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn metadata_of_older_entries() {
+        // An entry from before dimensions were stored: a hash, a capture
+        // time, and a stat.
+        let mut data = Vec::new();
+        rmp::encode::write_array_len(&mut data, 3).unwrap();
+        rmp::encode::write_bin(&mut data, &[7; 8]).unwrap();
+        rmp::encode::write_nil(&mut data).unwrap();
+        rmp::encode::write_array_len(&mut data, 2).unwrap();
+        rmp::encode::write_sint(&mut data, 1_700_000_000).unwrap();
+        rmp::encode::write_uint(&mut data, 2048).unwrap();
+        let entry: Entry = rmp_serde::from_slice(&data).unwrap();
+        assert_eq!(entry.stat.map(|x| x.size), Some(2048));
+        assert_eq!(entry.dimensions, None);
+
+        let mut hashdb = HashDB::new();
+        hashdb.entries.insert("/images/old.png".into(), entry);
+        assert_eq!(hashdb.metadata("/images/old.png"), None);
+        let read = HashDB::from_reader(&encoded(&hashdb)[..]).unwrap();
+        assert_eq!(read.metadata("/images/old.png"), None);
+        assert_eq!(
+            read.entries[Path::new("/images/old.png")]
+                .stat
+                .unwrap()
+                .size,
+            2048
+        );
+    }
+
+    #[test]
+    fn case_spellings_of_one_file() {
+        let dir = temp_dir("case-spellings");
+        // A hardlink stands in for the other spelling of a name on a
+        // filesystem that ignores case.
+        let upper = dir.join("IMG_001.JPG");
+        let lower = dir.join("img_001.jpg");
+        fs::write(&upper, b"image").unwrap();
+        fs::hard_link(&upper, &lower).unwrap();
+        let mut hashdb = database(1);
+        let hash = hashdb.get("/images/0.png").unwrap().clone();
+        hashdb.insert(&upper, hash.clone()).unwrap();
+        hashdb.insert(&lower, hash).unwrap();
+
+        let pairs = hashdb.find_duplicates(1, false, &());
+        assert_eq!(pairs.len(), 2, "{pairs:?}");
+        assert!(pairs.iter().all(|(a, b)| !same_file(a, b)));
+        assert_eq!(hashdb.find_duplicates(1, true, &()).len(), 3);
+
+        let on_disk = HashSet::from([upper.clone()]);
+        assert!(ignores_case(&on_disk));
+        assert_eq!(hashdb.fold_case(&on_disk), 1);
+        assert_eq!(hashdb.len(), 2);
+        assert!(hashdb.contains(&upper) && !hashdb.contains(&lower));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_format_round_trip() {
+        let dir = temp_dir("file-format");
+        let file = dir.join("db");
+        let mut hashdb = HashDB::new();
+        let hash = image_hasher::ImageHash::from_bytes(&[7; 8]).unwrap();
+        hashdb.insert("/images/a.png", ImageHash(hash)).unwrap();
+        hashdb.to_file(&file).unwrap();
+
+        let data = fs::read(&file).unwrap();
+        assert_eq!(&data[..MAGIC.len()], MAGIC);
+        assert_eq!(
+            data[MAGIC.len()..MAGIC.len() + 4],
+            FORMAT_VERSION.to_le_bytes()
+        );
+        let read = HashDB::from_file(&file).unwrap();
+        assert_eq!(read.get("/images/a.png"), hashdb.get("/images/a.png"));
+        assert_eq!(read.header(), hashdb.header());
+        assert_eq!(HashDB::read_header(&file).unwrap(), *hashdb.header());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_format_legacy() {
+        let dir = temp_dir("file-format-legacy");
+        let file = dir.join("db");
+        // From before there was a version or a header: a bare map of names
+        // to hashes, Zlib'd.
+        let mut data = Vec::new();
+        rmp::encode::write_map_len(&mut data, 1).unwrap();
+        rmp::encode::write_str(&mut data, "/images/a.png").unwrap();
+        rmp::encode::write_bin(&mut data, &[7; 8]).unwrap();
+        let mut z = ZlibEncoder::new(Vec::new(), Compression::default());
+        z.write_all(&data).unwrap();
+        fs::write(&file, z.finish().unwrap()).unwrap();
+
+        let read = HashDB::from_file(&file).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read.get("/images/a.png").unwrap().0.as_bytes(), [7; 8]);
+        assert_eq!(*read.header(), Header::default());
+        assert_eq!(HashDB::read_header(&file).unwrap(), Header::default());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_format_bad_magic() {
+        let dir = temp_dir("file-format-magic");
+        let file = dir.join("db");
+        let mut hashdb = HashDB::new();
+        let hash = image_hasher::ImageHash::from_bytes(&[7; 8]).unwrap();
+        hashdb.insert("/images/a.png", ImageHash(hash)).unwrap();
+        hashdb.to_file(&file).unwrap();
+        let mut data = fs::read(&file).unwrap();
+        data[..MAGIC.len()].copy_from_slice(b"IMGDUPDX");
+        fs::write(&file, &data).unwrap();
+
+        for err in [
+            HashDB::from_file(&file).unwrap_err(),
+            HashDB::read_header(&file).unwrap_err(),
+        ] {
+            match err {
+                HashDBError::Corrupt(path, e) => {
+                    assert_eq!(path, file);
+                    assert_eq!(e, "not an image-duplicate database");
+                }
+                _ => panic!("{err}"),
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn backup_after_truncated_write() {
+        let dir = temp_dir("backup");
+        let file = dir.join("db");
+        let mut hashdb = HashDB::new();
+        let hash = image_hasher::ImageHash::from_bytes(&[1; 8]).unwrap();
+        hashdb.insert("/images/a.png", ImageHash(hash)).unwrap();
+        hashdb.to_file(&file).unwrap();
+        assert!(!HashDB::backup_file(&file).exists());
+        let hash = image_hasher::ImageHash::from_bytes(&[2; 8]).unwrap();
+        hashdb.insert("/images/b.png", ImageHash(hash)).unwrap();
+        hashdb.to_file(&file).unwrap();
+
+        // The latest write was cut short; the one before it is intact.
+        let data = fs::read(&file).unwrap();
+        fs::write(&file, &data[..data.len() / 2]).unwrap();
+        let (read, err) = HashDB::from_file_or_backup(&file).unwrap();
+        assert_eq!(read.len(), 1);
+        assert!(read.contains("/images/a.png"));
+        match err {
+            Some(HashDBError::Corrupt(path, _)) => assert_eq!(path, file),
+            _ => panic!("{err:?}"),
+        }
+
+        // Only the last byte of its checksum is missing: still not intact,
+        // so writing again keeps the backup rather than rotating it away.
+        fs::write(&file, &data[..data.len() - 1]).unwrap();
+        assert!(!HashDB::intact(&file));
+        let (mut read, err) = HashDB::from_file_or_backup(&file).unwrap();
+        assert_eq!(read.len(), 1);
+        assert!(matches!(err, Some(HashDBError::Corrupt(..))), "{err:?}");
+        let hash = image_hasher::ImageHash::from_bytes(&[3; 8]).unwrap();
+        read.insert("/images/c.png", ImageHash(hash)).unwrap();
+        read.to_file(&file).unwrap();
+        let backup = HashDB::from_file(HashDB::backup_file(&file)).unwrap();
+        assert_eq!(backup.len(), 1);
+        assert!(backup.contains("/images/a.png"));
+        assert_eq!(HashDB::from_file(&file).unwrap().len(), 2);
+
+        // With the backup gone too, the error is that of the file.
+        fs::write(&file, &data[..data.len() / 2]).unwrap();
+        fs::write(HashDB::backup_file(&file), b"").unwrap();
+        match HashDB::from_file_or_backup(&file) {
+            Err(HashDBError::Corrupt(path, _)) => assert_eq!(path, file),
+            x => panic!("{x:?}"),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn streamed_file_reads_whole() {
+        let dir = temp_dir("streamed");
+        for (name, hashdb) in
+            [("empty", HashDB::new()), ("full", database(500))]
+        {
+            let file = dir.join(name);
+            hashdb.to_file(&file).unwrap();
+            assert_eq!(fs::read(&file).unwrap(), encoded(&hashdb));
+
+            // Read as before writing was streamed: decompressed into memory
+            // in one go, then decoded from there.
+            let data = fs::read(&file).unwrap();
+            let mut inflated = Vec::new();
+            ZlibDecoder::new(&data[MAGIC.len() + 4..])
+                .read_to_end(&mut inflated)
+                .unwrap();
+            let read: HashDB = rmp_serde::from_slice(&inflated).unwrap();
+            assert_eq!(read.len(), hashdb.len());
+            assert!(
+                hashdb
+                    .iter()
+                    .all(|(name, hash)| read.get(name) == Some(hash))
+            );
+            assert_eq!(HashDB::from_file(&file).unwrap().len(), hashdb.len());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mismatched_header_survives_round_trip() {
+        let dir = temp_dir("mismatched-header");
+        let file = dir.join("db");
+        let mut hashdb = HashDB::new();
+        hashdb.set_header(Header {
+            hash_size: 16,
+            trim_borders: true,
+            ..Header::default()
+        });
+        for (i, hash) in clustered_hashes(1, 4, 32).into_iter().enumerate() {
+            hashdb.insert(format!("/images/{i}.png"), hash).unwrap();
+        }
+        hashdb.to_file(&file).unwrap();
+
+        let read = HashDB::from_file(&file).unwrap();
+        assert_eq!(read.header(), hashdb.header());
+        assert_eq!(HashDB::read_header(&file).unwrap(), *hashdb.header());
+        assert!(matches!(
+            read.check_compatible(&database(1)),
+            Err(HashDBError::Mismatch(256, 64))
+        ));
+        // A database with no entries yet takes on any settings.
+        assert!(read.check_compatible(&HashDB::new()).is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn errors_name_the_path() {
+        let dir = temp_dir("error-paths");
+        let missing = dir.join("missing.db");
+        let err = HashDB::from_file(&missing).unwrap_err();
+        assert!(
+            matches!(&err, HashDBError::Io { path, .. } if path == &missing)
+        );
+        assert!(err.to_string().contains(&format!("{missing:?}")));
+
+        let empty = dir.join("empty.db");
+        fs::write(&empty, b"").unwrap();
+        for err in [
+            HashDB::from_file(&empty).unwrap_err(),
+            HashDB::read_header(&empty).unwrap_err(),
+        ] {
+            assert!(err.to_string().contains(&format!("{empty:?}")), "{err}");
+        }
+
+        let unwritable = dir.join("no-such-dir").join("db");
+        let err = database(1).to_file(&unwritable).unwrap_err();
+        assert!(err.to_string().contains("no-such-dir"), "{err}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_directory_keeps_entries() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("unreadable");
+        let sub = dir.join("sub");
+        fs::create_dir(&sub).unwrap();
+        for file in [dir.join("a.png"), sub.join("b.png")] {
+            image::GrayImage::new(8, 8).save(file).unwrap();
+        }
+        let filter = ScanFilter::new::<&str>(&[]).unwrap();
+        let mut hashdb = HashDB::new();
+        let report = hashdb
+            .read_dir_recursive(&dir, &filter, None, false, &())
+            .unwrap();
+        assert_eq!((report.hashed, report.unreadable), (2, 0));
+
+        fs::set_permissions(&sub, fs::Permissions::from_mode(0o000)).unwrap();
+        // Permissions do not stop root, so there is nothing to test.
+        let enforced = fs::read_dir(&sub).is_err();
+        let report = hashdb.read_dir_recursive(&dir, &filter, None, false, &());
+        fs::set_permissions(&sub, fs::Permissions::from_mode(0o755)).unwrap();
+        if enforced {
+            let report = report.unwrap();
+            assert_eq!((report.unreadable, report.removed), (1, 0));
+            assert_eq!(hashdb.len(), 2);
+            assert!(hashdb.contains(sub.canonicalize().unwrap().join("b.png")));
+        }
+
+        // Once it is gone for real, so is its entry.
+        fs::remove_dir_all(&sub).unwrap();
+        let report = hashdb
+            .read_dir_recursive(&dir, &filter, None, false, &())
+            .unwrap();
+        assert_eq!((report.unreadable, report.removed), (0, 1));
+        assert_eq!(hashdb.len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn decode_round_trip() {
+        let hashdb = database(100);
+        let decoded = HashDB::from_reader(&encoded(&hashdb)[..]).unwrap();
+        assert_eq!(decoded.len(), 100);
+        assert_eq!(decoded.get("/images/42.png"), hashdb.get("/images/42.png"));
+    }
+
+    #[test]
+    fn scan_settings_round_trip() {
+        #[cfg(unix)]
+        let odd = {
+            use std::os::unix::ffi::OsStrExt;
+            PathBuf::from(std::ffi::OsStr::from_bytes(b"/images/caf\xe9"))
+        };
+        #[cfg(not(unix))]
+        let odd = PathBuf::from("/images/café");
+        let scan = ScanSettings {
+            root: "/images/a".into(),
+            more_roots: vec![odd.clone(), "/images/b".into()],
+            against: Some(odd),
+            ..ScanSettings::default()
+        };
+        let mut hashdb = database(1);
+        hashdb.set_header(Header {
+            scan: Some(scan.clone()),
+            ..Header::default()
+        });
+        let decoded = HashDB::from_reader(&encoded(&hashdb)[..]).unwrap();
+        assert_eq!(decoded.header().scan.as_ref(), Some(&scan));
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let data = encoded(&database(100));
+        // The last four bytes are the checksum of the stream, which is not
+        // needed to decode the entries before it.
+        let ends = (1..=4).map(|x| data.len() - x);
+        for len in [0, 4, MAGIC.len() + 2, data.len() / 2]
+            .into_iter()
+            .chain(ends)
+        {
+            let err = HashDB::from_reader(&data[..len]).unwrap_err();
+            assert_eq!(err.category(), ErrorCategory::Database, "{err}");
+        }
+    }
+
+    #[test]
+    fn escaped_paths_round_trip() {
+        for name in [
+            "/images/plain.png",
+            "/images/tab\there.png",
+            "/images/two\nlines\r.png",
+            "/images/back\\slash\\n.png",
+            "/images/\\x41.png",
+            "/images/trailing\\",
+        ] {
+            let escaped = escape_path(Path::new(name));
+            assert!(!escaped.contains(['\t', '\n', '\r']), "{escaped}");
+            assert_eq!(unescape_path(&escaped).unwrap(), Path::new(name));
+        }
+        #[cfg(unix)]
+        {
+            use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+            let latin1 = Path::new(OsStr::from_bytes(b"/images/caf\xe9.png"));
+            assert_eq!(escape_path(latin1), "/images/caf\\xe9.png");
+            assert_eq!(unescape_path("/images/caf\\xe9.png").unwrap(), latin1);
+        }
+        // Single backslashes before anything else are taken as they are.
+        assert_eq!(
+            unescape_path("C:\\photos\\a.jpg\\xz").unwrap(),
+            Path::new("C:\\photos\\a.jpg\\xz")
+        );
+    }
+
+    #[test]
+    fn inflate_needs_stream_end() {
+        let mut z = ZlibEncoder::new(Vec::new(), Compression::default());
+        z.write_all(&[7; 1000]).unwrap();
+        let data = z.finish().unwrap();
+        let inflate = |data: &[u8]| {
+            let mut out = Vec::new();
+            Inflate::new(data).read_to_end(&mut out).map(|_| out)
+        };
+        assert_eq!(inflate(&data).unwrap(), [7; 1000]);
+        for len in data.len() - 4..data.len() {
+            let err = inflate(&data[..len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{len}");
+        }
+    }
+
+    #[test]
+    fn decode_corrupt() {
+        let mut data = encoded(&database(100));
+        let middle = data.len() / 2;
+        data[middle..middle + 16].fill(0xa5);
+        let err = HashDB::from_reader(&data[..]).unwrap_err();
+        assert_eq!(err.category(), ErrorCategory::Database, "{err}");
+
+        let err = HashDB::from_reader(&b"not a database at all"[..]);
+        assert!(matches!(err, Err(HashDBError::Corrupt(..))));
+
+        let mut data = encoded(&database(1));
+        data[MAGIC.len()..MAGIC.len() + 4]
+            .copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let err = HashDB::from_reader(&data[..]);
+        assert!(matches!(err, Err(HashDBError::Version { .. })));
+
+        // Valid MessagePack, but not a database.
+        let err = HashDB::from_reader(&wrapped(&[0x93, 1, 2, 3])[..]);
+        assert!(matches!(err, Err(HashDBError::DecodeError(..))));
+    }
+
+    #[test]
+    fn decode_oversized() {
+        let data = encoded(&database(1000));
+        let err = HashDB::decode_within(&data[..], Path::new("db"), 1000)
+            .unwrap_err();
+        match err {
+            HashDBError::Corrupt(_, e) => {
+                assert_eq!(e, "more than 1000 bytes decompressed")
+            }
+            _ => panic!("{err}"),
+        }
+        assert!(
+            HashDB::decode_within(&data[..], Path::new("db"), 1 << 20).is_ok()
+        );
+
+        // A bare map declaring four billion entries, and a string declaring
+        // four gigabytes, neither of which are there.
+        let err =
+            HashDB::from_reader(&wrapped(&[0xdf, 0xff, 0xff, 0xff, 0xff])[..]);
+        assert!(matches!(err, Err(HashDBError::DecodeError(..))));
+        let err = HashDB::from_reader(
+            &wrapped(&[0x81, 0xdb, 0xff, 0xff, 0xff, 0xff, b'a'])[..],
+        );
+        assert_eq!(err.unwrap_err().category(), ErrorCategory::Database);
+    }
 }
@@ -0,0 +1,186 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Estimating the quality setting a JPEG was saved with from its quantization
+//! tables, which are read from the header without decoding the image.
+//! [`quality`] forms the main interface.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+};
+
+/// Luminance quantization table from Annex K of the JPEG standard, which
+/// libjpeg and most other encoders scale by the quality setting. The values
+/// are in zigzag order, as in files.
+const STANDARD_LUMINANCE: [u16; 64] = [
+    16, 11, 12, 14, 12, 10, 16, 14, 13, 14, 18, 17, 16, 19, 24, 40, 26, 24, 22,
+    22, 24, 49, 35, 37, 29, 40, 58, 51, 61, 60, 57, 51, 56, 55, 64, 72, 92, 78,
+    64, 68, 87, 69, 55, 56, 80, 109, 81, 87, 95, 98, 103, 104, 103, 62, 77,
+    113, 121, 112, 100, 120, 92, 101, 103, 99,
+];
+
+/// Largest value of a quantization table with 8-bit precision, which scaled
+/// values are clamped to.
+const MAX_VALUE: u16 = 255;
+
+/// Markers that stand alone, without a length or payload.
+fn is_standalone(marker: u8) -> bool {
+    matches!(marker, 0x01 | 0xd0..=0xd7)
+}
+
+/// Read the luminance quantization table of a JPEG, or the first table if
+/// there is none numbered 0. Returns `None` if the data is not a JPEG or has
+/// no tables before the image data.
+fn luminance_table<R: Read>(mut reader: R) -> io::Result<Option<Vec<u16>>> {
+    let mut buf = [0; 2];
+    reader.read_exact(&mut buf)?;
+    if buf != [0xff, 0xd8] {
+        return Ok(None);
+    }
+
+    let mut first = None;
+    loop {
+        reader.read_exact(&mut buf)?;
+        if buf[0] != 0xff {
+            return Ok(None);
+        }
+        let marker = buf[1];
+        match marker {
+            // Fill bytes before a marker.
+            0xff => continue,
+            x if is_standalone(x) => continue,
+            // Start of scan or end of image: the tables come before.
+            0xda | 0xd9 => return Ok(first),
+            _ => (),
+        }
+        reader.read_exact(&mut buf)?;
+        let len = u16::from_be_bytes(buf).saturating_sub(2) as usize;
+        let mut segment = vec![0; len];
+        reader.read_exact(&mut segment)?;
+        if marker != 0xdb {
+            continue;
+        }
+
+        // A DQT segment holds one or more tables, each with its precision
+        // and number in the first byte.
+        let mut rest = &segment[..];
+        while let Some((&info, tail)) = rest.split_first() {
+            let wide = info >> 4 != 0;
+            let size = match wide {
+                true => 128,
+                false => 64,
+            };
+            if tail.len() < size {
+                return Ok(first);
+            }
+            let table: Vec<u16> = match wide {
+                true => tail[..size]
+                    .chunks(2)
+                    .map(|x| u16::from_be_bytes([x[0], x[1]]))
+                    .collect(),
+                false => tail[..size].iter().map(|&x| x as u16).collect(),
+            };
+            if info & 0x0f == 0 {
+                return Ok(Some(table));
+            }
+            first.get_or_insert(table);
+            rest = &tail[size..];
+        }
+    }
+}
+
+/// Estimate the quality setting, from 1 to 100, that a JPEG was saved with,
+/// on the scale of libjpeg. The luminance table is compared with the standard
+/// one that libjpeg scales by the setting, so the estimate is close for
+/// libjpeg and its many derivatives, and rougher for encoders with tables of
+/// their own. Returns `None` if the file is not a JPEG or cannot be read.
+pub fn quality<P: AsRef<Path>>(file: P) -> Option<u8> {
+    let reader = BufReader::new(File::open(file).ok()?);
+    let table = luminance_table(reader).ok()??;
+    // Quality 100 scales every value down to the floor of 1.
+    if table.iter().all(|&x| x <= 1) {
+        return Some(100);
+    }
+
+    // libjpeg scales the standard table by 5000 / quality percent below
+    // quality 50 and by 200 - 2 * quality percent above it. Values clamped to
+    // the maximum no longer tell the scale, so they are left out, unless
+    // that leaves nothing.
+    let clamped = table.iter().all(|&x| x >= MAX_VALUE);
+    let (sum, standard) = table
+        .iter()
+        .zip(STANDARD_LUMINANCE)
+        .filter(|(x, _)| clamped || **x < MAX_VALUE)
+        .fold((0, 0), |(a, b), (&x, y)| (a + x as u32, b + y as u32));
+    let scale = sum as f64 * 100.0 / standard as f64;
+    let quality = match scale <= 100.0 {
+        true => (200.0 - scale) / 2.0,
+        false => 5000.0 / scale,
+    };
+    Some(quality.round().clamp(1.0, 100.0) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+    use std::fs;
+
+    #[test]
+    fn quality_of_known_encodes() {
+        let dir = std::env::temp_dir()
+            .join(format!("image-duplicate-jpeg-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let image = image::RgbImage::from_fn(32, 32, |x, y| {
+            [(x * 8) as u8, (y * 8) as u8, ((x + y) * 4) as u8].into()
+        });
+        for setting in [5, 25, 50, 75, 90, 100] {
+            let file = dir.join(format!("q{setting}.jpg"));
+            let mut data = Vec::new();
+            JpegEncoder::new_with_quality(&mut data, setting)
+                .encode_image(&image)
+                .unwrap();
+            fs::write(&file, data).unwrap();
+            let estimate = quality(&file).unwrap();
+            assert!(estimate.abs_diff(setting) <= 1, "{setting}: {estimate}");
+        }
+
+        let png = dir.join("image.png");
+        image.save(&png).unwrap();
+        assert_eq!(quality(&png), None);
+        assert_eq!(quality(dir.join("missing.jpg")), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn table_other_than_luminance() {
+        // Only a table numbered 1, at 16-bit precision, holding the standard
+        // luminance values doubled.
+        let mut data = vec![0xff, 0xd8, 0xff, 0xdb, 0, 2 + 1 + 128, 0x11];
+        for x in STANDARD_LUMINANCE {
+            data.extend((x * 2).to_be_bytes());
+        }
+        data.extend([0xff, 0xd9]);
+        let table = luminance_table(&data[..]).unwrap().unwrap();
+        assert_eq!(table[0], 32);
+        assert_eq!(table.len(), 64);
+
+        assert_eq!(luminance_table(&b"\x89PNG\r\n"[..]).unwrap(), None);
+    }
+}
@@ -15,90 +15,2558 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 //! The main image duplicate program.
+//!
+//! The hash database is also usable as a library, either directly through
+//! [`HashDB`] or through [`scan_and_find`], which runs the same scan pipeline
+//! as the program and returns its results. [`SharedDB`] lets other threads
+//! query a database while it is being updated. Building without the default
+//! `gui` feature leaves out the GUI and its dependencies; `review` then only
+//! works with `--no-gui`.
+//!
+//! A database can be loaded, queried, updated, and written back like this:
+//!
+//! ```no_run
+//! use image_duplicate::HashDB;
+//!
+//! # fn main() -> Result<(), image_duplicate::HashDBError> {
+//! let mut hashdb = HashDB::from_file("photos/.image_hash.db")?;
+//! let name = hashdb.insert_file("photos/new.jpg")?;
+//! for (other, distance) in hashdb.find_similar(hashdb.get(&name).unwrap(), 10)
+//! {
+//!     let (other, name) = (other.display(), name.display());
+//!     println!("{other} is {distance} bits from {name}");
+//! }
+//! hashdb.remove("photos/old.jpg");
+//! hashdb.to_file("photos/.image_hash.db")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Everything the library offers is exported from the crate root. It follows
+//! the version of the program: until 1.0, a minor release may change it, but
+//! a patch release does not. Database files are versioned on their own by
+//! [`FORMAT_VERSION`], and newer releases keep reading older files.
 
 use anyhow::{Result, anyhow};
-use clap::Parser;
-use gui::GUI;
-use hashdb::HashDB;
-use std::path::PathBuf;
+use clap::{
+    ArgGroup, ArgMatches, Command as ClapCommand, CommandFactory, Parser,
+    Subcommand, ValueEnum, ValueHint, builder::ArgPredicate,
+    parser::ValueSource,
+};
+use clap_complete::Shell;
+use config::Config;
+use output::Listing;
+use progress::{ErrorLog, Ndjson, Stderr, Timed};
+use std::{
+    collections::HashSet,
+    env,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process::{self, ExitCode},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
 
+pub use actions::{
+    ActionError, Destination, FileRemover, Policy, Reason, Remover, copy_exif,
+};
+pub use exec::{ExecError, ExecSummary, Hook};
+#[cfg(feature = "gui")]
+pub use gui::{GUI, GUIError, ScanProgress};
+pub use hashdb::{
+    Algorithm, DEFAULT_HASH_SIZE, Depth, ErrorCategory, FORMAT_VERSION, HashDB,
+    HashDBError, Header, IMAGE_EXTENSIONS, ImageHash, ImageMeta, MAX_HASH_SIZE,
+    MIN_HASH_SIZE, MergeReport, Orientation, Pair, ScanFilter, ScanReport,
+    ScanSettings, Threshold, VerifyOptions, VerifyReport, hash_bytes,
+    hash_file,
+};
+pub use output::{Color, Format, Sort};
+pub use pipeline::{
+    DEFAULT_THRESHOLD, DbLocation, ScanOptions, ScanOutcome, check_threshold,
+    db_file, scan_and_find,
+};
+pub use progress::{Phase, PhaseTime, ScanObserver, Summary};
+pub use review::{Decision, Review, ReviewError, ReviewSummary, run_script};
+pub use script::{ScriptFormat, ScriptRemover};
+pub use shared::SharedDB;
+
+mod actions;
+mod config;
+mod exec;
+#[cfg(feature = "gui")]
 mod gui;
+#[cfg(unix)]
+mod hardlink;
 mod hashdb;
+mod jpeg;
+mod metadata;
+mod output;
+mod pipeline;
+mod priority;
+mod progress;
+mod review;
+mod script;
+mod shared;
 
 /// GUI for scanning and handling visually similar images in a directory.
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
+#[command(after_help = AFTER_HELP)]
 pub struct Args {
-    /// Directory to scan for images
-    pub path: PathBuf,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/// Subcommands of the program.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Update the hash database for a directory without searching it
+    Scan(ScanArgs),
+
+    /// Review similar images in the GUI (default)
+    Review(ReviewArgs),
+
+    /// Print similar images without launching the GUI
+    List(ListArgs),
+
+    /// Print the distance between the hashes of two images
+    Compare(CompareArgs),
+
+    /// Inspect and maintain database files
+    #[command(subcommand)]
+    Db(DbCommand),
 
-    /// Location of database file (default: \<PATH\>/.image_hash.db)
-    #[arg(short = 'D', long)]
+    /// Print a completion script for SHELL to stdout
+    #[command(hide = true)]
+    Completions {
+        /// Shell to complete for
+        shell: Shell,
+    },
+}
+
+/// Options for reading a directory into the hash database.
+#[derive(Debug, clap::Args)]
+#[command(group(
+    ArgGroup::new("deep").args(["recursive", "max_depth"]).multiple(true)
+))]
+pub struct ScanArgs {
+    /// Directories to scan for images (default with --db: those the database
+    /// was last scanned from)
+    #[arg(value_name = "PATH", value_hint = ValueHint::DirPath)]
+    #[arg(required = false, required_unless_present = "db")]
+    #[arg(long_help = PATHS_HELP)]
+    pub paths: Vec<PathBuf>,
+
+    /// Location of database file, or - for stdin or stdout (default: chosen
+    /// by --db-location)
+    #[arg(short = 'D', long, value_hint = ValueHint::FilePath)]
     pub db: Option<PathBuf>,
 
+    /// Where to keep the database file when --db is not given
+    #[arg(long, value_enum, default_value_t, conflicts_with = "db")]
+    #[arg(long_help = DB_LOCATION_HELP)]
+    pub db_location: DbLocation,
+
+    /// Store paths in the database relative to PATH, so that it still works
+    /// when PATH is mounted elsewhere
+    #[arg(long)]
+    #[arg(long_help = RELATIVE_DB_HELP)]
+    pub relative_db: bool,
+
+    /// Also search the read-only database FILE, without hashing its images
+    /// again or writing its entries to the database file
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    #[arg(long_help = SEED_DB_HELP)]
+    pub seed_db: Option<PathBuf>,
+
+    /// Add the entries of the database FILE to the database file, such as
+    /// one made on another machine (may be given more than once)
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    #[arg(long_help = MERGE_HELP)]
+    pub merge: Vec<PathBuf>,
+
     /// Scan directory for images recursively
     #[arg(short = 'R', long)]
     pub recursive: bool,
 
+    /// Scan at most N levels of directories under PATH, where 1 is only the
+    /// images directly in PATH; implies --recursive
+    #[arg(long, value_name = "N", value_parser = parse_max_depth)]
+    #[arg(long_help = MAX_DEPTH_HELP)]
+    pub max_depth: Option<usize>,
+
+    /// With --recursive, do not descend into other filesystems mounted under
+    /// PATH
+    #[arg(short = 'x', long, requires = "deep")]
+    pub one_file_system: bool,
+
+    /// Leave out paths under PATH matching GLOB (may be given more than
+    /// once)
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    #[arg(long_help = EXCLUDE_HELP)]
+    pub exclude: Vec<String>,
+
+    /// Treat files with these extensions as images, compared without regard
+    /// to case; +EXT adds to the usual ones and -EXT takes one out
+    #[arg(long, value_name = "EXT", value_delimiter = ',')]
+    #[arg(allow_hyphen_values = true, long_help = EXTENSIONS_HELP)]
+    pub extensions: Option<Vec<String>>,
+
     /// Force rebuild hash database
     #[arg(short = 'b', long)]
     pub rebuild: bool,
 
+    /// Hash the images already in the database again, keeping their entries
+    #[arg(long, conflicts_with = "rebuild")]
+    pub rehash: bool,
+
+    /// Only hash images that are not in the database yet, not those whose
+    /// files changed since they were hashed
+    #[arg(long)]
+    #[arg(long_help = NO_STAT_HELP)]
+    pub no_stat: bool,
+
+    /// Crop near-uniform borders, such as letterbox bars, before hashing
+    #[arg(long)]
+    #[arg(long_help = TRIM_BORDERS_HELP)]
+    pub trim_borders: bool,
+
+    /// Also match copies that were rotated in steps of 90° or mirrored
+    #[arg(long)]
+    #[arg(long_help = MATCH_ROTATIONS_HELP)]
+    pub match_rotations: bool,
+
+    /// Perceptual hash algorithm (default: that of the database, or
+    /// gradient)
+    #[arg(long, value_enum, value_name = "NAME")]
+    #[arg(long_help = ALGORITHM_HELP)]
+    pub algorithm: Option<Algorithm>,
+
+    /// Width and height of hashes, in bits (default: those of the database,
+    /// or 8)
+    #[arg(long, value_name = "N", value_parser = parse_hash_size)]
+    #[arg(long_help = HASH_SIZE_HELP)]
+    pub hash_size: Option<u32>,
+
+    /// Decode at most N images at once (default: one per hashing thread)
+    #[arg(long, value_name = "N")]
+    #[arg(long_help = MAX_DECODES_HELP)]
+    pub max_concurrent_decodes: Option<NonZeroUsize>,
+
+    /// Skip image files larger than SIZE bytes (K, M, and G suffixes
+    /// allowed)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    #[arg(long_help = MAX_FILE_SIZE_HELP)]
+    pub max_file_size: Option<u64>,
+
+    /// Hash with N threads; 0 for one per core (default: 0)
+    #[arg(short = 'j', long, value_name = "N")]
+    #[arg(long_help = JOBS_HELP)]
+    pub jobs: Option<usize>,
+
+    /// Write the database after every N images hashed, so that an
+    /// interrupted scan resumes where it stopped; 0 for never (default: 0)
+    #[arg(long, value_name = "N")]
+    #[arg(long_help = CHECKPOINT_HELP)]
+    pub checkpoint: Option<usize>,
+
+    /// Lower the CPU and disk priority of the scan
+    #[arg(long, visible_alias = "nice")]
+    #[arg(long_help = LOW_PRIORITY_HELP)]
+    pub low_priority: bool,
+
+    /// Print the time taken by each phase
+    #[arg(long)]
+    pub timings: bool,
+
+    /// Write the images that could not be hashed to FILE as JSON
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    #[arg(long_help = ERRORS_JSON_HELP)]
+    pub errors_json: Option<PathBuf>,
+
+    /// Report progress on stdout
+    #[arg(long, value_enum, default_value_t)]
+    #[arg(long_help = PROGRESS_HELP)]
+    pub progress: Progress,
+
+    /// Read default options from FILE (default:
+    /// $XDG_CONFIG_HOME/image-duplicate/config.toml)
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub config: Option<PathBuf>,
+}
+
+/// How to report progress.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum Progress {
+    /// Status messages on stderr only
+    #[default]
+    None,
+    /// One JSON object per event on stdout
+    Ndjson,
+}
+
+/// Options for finding and printing similar images.
+#[derive(Debug, clap::Args)]
+pub struct ListArgs {
+    #[command(flatten)]
+    pub scan: ScanArgs,
+
     /// Do not dump hash database to file
     #[arg(short = 'd', long)]
     pub no_dump: bool,
 
+    /// Fail if the hash database cannot be dumped, instead of warning and
+    /// carrying on without it
+    #[arg(long, conflicts_with = "no_dump")]
+    pub require_dump: bool,
+
     /// Read database file only; do not update contents
-    #[arg(short = 'u', long, conflicts_with = "rebuild")]
+    #[arg(short = 'u', long, conflicts_with_all = ["rebuild", "rehash"])]
     pub no_update: bool,
 
-    /// Image similarity threshold
-    #[arg(short, long, default_value_t = 9)]
+    /// Check the database against the images under PATH instead of finding
+    /// similar images, without changing it
+    #[arg(long, conflicts_with_all = ["rebuild", "rehash", "only_new"])]
+    #[arg(long_help = VERIFY_HELP)]
+    pub verify: bool,
+
+    /// With --verify, also hash N images picked at random again to compare
+    /// their hashes
+    #[arg(long, value_name = "N", requires = "verify")]
+    pub verify_sample: Option<usize>,
+
+    /// With --verify, hash images whose modification time or size changed
+    /// again, and only report them if their hashes changed too
+    #[arg(long, requires = "verify")]
+    pub verify_rehash: bool,
+
+    /// Image similarity threshold; pairs closer than this match
+    #[arg(short, long, default_value_t = DEFAULT_THRESHOLD)]
+    #[arg(value_parser = parse_threshold)]
+    pub threshold: u32,
+
+    /// Image similarity as the percentage of hash bits that must agree, such
+    /// as 90%, instead of --threshold
+    #[arg(long, value_name = "PERCENT", conflicts_with = "threshold")]
+    #[arg(value_parser = parse_similarity, long_help = SIMILARITY_HELP)]
+    pub similarity: Option<f64>,
+
+    /// Also report pairs of paths that name the same file, such as hardlinks
+    #[arg(long)]
+    #[arg(long_help = INCLUDE_HARDLINKS_HELP)]
+    pub include_hardlinks: bool,
+
+    /// Only find pairs of an image in PATH with one in DIR, which goes on the
+    /// right
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    #[arg(long_help = AGAINST_HELP)]
+    pub against: Option<PathBuf>,
+
+    /// Use the option values of a profile (photos, screenshots, art, or one
+    /// defined in the configuration file)
+    #[arg(long, value_name = "NAME")]
+    #[arg(long_help = config::profiles_help())]
+    pub profile: Option<String>,
+
+    /// Only handle the first N pairs, closest first
+    #[arg(long, value_name = "N")]
+    #[arg(long_help = LIMIT_HELP)]
+    pub limit: Option<usize>,
+
+    /// Only handle pairs whose smaller file has at least SIZE bytes (K, M,
+    /// and G suffixes allowed)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub min_pair_size: Option<u64>,
+
+    /// Handle the pairs in random order instead of closest first
+    #[arg(long, conflicts_with = "sort")]
+    #[arg(long_help = SHUFFLE_HELP)]
+    pub shuffle: bool,
+
+    /// Seed for --shuffle, to get the same order again
+    #[arg(long, value_name = "N", requires = "shuffle")]
+    pub seed: Option<u64>,
+
+    /// Only handle pairs involving an image added by this scan
+    #[arg(long, conflicts_with = "no_update")]
+    pub only_new: bool,
+
+    /// Hide pairs of frames from the same burst of shots
+    #[arg(long, conflicts_with = "only_bursts")]
+    #[arg(long_help = BURSTS_HELP)]
+    pub ignore_bursts: bool,
+
+    /// Only handle pairs of frames from the same burst of shots, e.g. to pick
+    /// the best frame of each
+    #[arg(long)]
+    pub only_bursts: bool,
+
+    /// Longest time between two frames of a burst
+    #[arg(long, value_name = "SECONDS", default_value = "2")]
+    #[arg(value_parser = parse_seconds)]
+    pub burst_window: Duration,
+
+    /// Largest distance between two frames of a burst
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_THRESHOLD)]
+    pub burst_max_distance: u32,
+
+    #[command(flatten)]
+    pub actions: ActionArgs,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// Options for removing duplicate images.
+#[derive(Debug, clap::Args)]
+#[command(next_help_heading = "Actions")]
+#[command(group(ArgGroup::new("mode").args(["auto", "hardlink"])))]
+#[command(group(
+    ArgGroup::new("dry")
+        .args(["auto", "hardlink", "prune_empty_dirs"])
+        .multiple(true)
+))]
+pub struct ActionArgs {
+    /// Remove duplicates without asking, keeping one image of each group
+    /// chosen by POLICY
+    #[arg(long, value_enum, value_name = "POLICY")]
+    pub auto: Option<Policy>,
+
+    /// Only resolve pairs at most N apart automatically
+    #[arg(long, value_name = "N", default_value_t = 0, requires = "auto")]
+    pub auto_max_distance: u32,
+
+    /// With --auto, keep images under DIR over others; repeat to give
+    /// directories in order of preference
+    #[arg(long, value_name = "DIR", requires = "auto")]
+    #[arg(value_hint = ValueHint::DirPath, long_help = PREFER_HELP)]
+    pub prefer: Vec<PathBuf>,
+
+    /// Replace byte-identical images with hardlinks to one copy, without
+    /// asking
+    #[arg(long)]
+    pub hardlink: bool,
+
+    /// Print what --auto, --hardlink, or --prune-empty-dirs would do without
+    /// changing anything
+    #[arg(long, requires = "dry")]
+    pub dry_run: bool,
+
+    /// Move removed images into DIR instead of the trash
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub move_to: Option<PathBuf>,
+
+    /// With --move-to, do not keep the times and extended attributes of
+    /// images that have to be copied to another filesystem
+    #[arg(long, requires = "move_to")]
+    pub no_preserve: bool,
+
+    /// Delete removed images permanently instead of using the trash
+    #[arg(long, visible_alias = "no-trash", conflicts_with = "move_to")]
+    pub delete: bool,
+
+    /// Before removing an image, copy its EXIF metadata into the kept image
+    /// where that lacks it (requires exiftool)
+    #[arg(long, conflicts_with = "emit_script")]
+    #[arg(long_help = MERGE_EXIF_HELP)]
+    pub merge_exif: bool,
+
+    /// Append a line for each removed image to FILE (default:
+    /// $XDG_DATA_HOME/image-duplicate/actions.log)
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub log: Option<PathBuf>,
+
+    /// Afterwards, remove directories under PATH that are left empty
+    #[arg(long)]
+    pub prune_empty_dirs: bool,
+
+    /// With --prune-empty-dirs, treat directories holding only .DS_Store,
+    /// Thumbs.db, or desktop.ini as empty, and delete those files
+    #[arg(long, requires = "prune_empty_dirs")]
+    pub prune_junk: bool,
+
+    /// Run COMMAND for each pair, with {left}, {right}, and {distance}
+    /// replaced
+    #[arg(long, value_name = "COMMAND", value_parser = parse_hook)]
+    #[arg(long_help = EXEC_HELP)]
+    pub exec: Option<Hook>,
+
+    /// Stop at the first --exec command that fails
+    #[arg(long, requires = "exec")]
+    pub exec_fail_fast: bool,
+
+    /// Run up to N --exec commands at once
+    #[arg(long, value_name = "N", default_value = "1", requires = "exec")]
+    pub parallel_exec: NonZeroUsize,
+
+    /// Write what --auto or --hardlink would do to a shell script FILE
+    /// instead of doing it
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    #[arg(requires = "mode", conflicts_with = "dry_run")]
+    #[arg(long_help = EMIT_SCRIPT_HELP)]
+    pub emit_script: Option<PathBuf>,
+
+    /// Language of the --emit-script script
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t)]
+    #[arg(requires = "emit_script")]
+    pub emit_script_format: ScriptFormat,
+}
+
+/// Options for printing duplicate pairs.
+#[derive(Debug, clap::Args)]
+#[command(next_help_heading = "Output")]
+pub struct OutputArgs {
+    /// Output format [default: terminal when writing to a terminal,
+    /// otherwise plain]
+    #[arg(long, value_enum)]
+    #[arg(long_help = FORMAT_HELP)]
+    pub format: Option<Format>,
+
+    /// When to use colors in the terminal format
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t)]
+    pub color: Color,
+
+    /// Same as --format nul, for use with xargs -0
+    #[arg(long, conflicts_with = "format")]
+    pub print0: bool,
+
+    /// Write output to FILE instead of stdout, replacing it only once the
+    /// output is complete (- for stdout)
+    #[arg(short, long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub output: Option<PathBuf>,
+
+    /// Omit the header row from CSV output
+    #[arg(long)]
+    pub no_header: bool,
+
+    /// Order of the pairs
+    #[arg(long, value_enum, default_value_t)]
+    #[arg(long_help = SORT_HELP)]
+    pub sort: Sort,
+
+    /// Include at most N pairs in an HTML report
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    pub report_max_pairs: usize,
+
+    /// Also write the pairs as a Graphviz graph to FILE
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    #[arg(long_help = EXPORT_DOT_HELP)]
+    pub export_dot: Option<PathBuf>,
+
+    /// Leave groups of fewer than N images out of the graph
+    #[arg(long, value_name = "N", default_value_t = 2)]
+    #[arg(requires = "export_dot")]
+    pub dot_min_cluster: usize,
+
+    /// Color the edges of the graph by distance, as in the terminal format
+    #[arg(long, requires = "export_dot")]
+    pub dot_color: bool,
+
+    /// Print only the number of groups and redundant files, and an estimate
+    /// of the space they take
+    #[arg(long, conflicts_with = "print0")]
+    #[arg(long_help = SUMMARY_HELP)]
+    pub summary: bool,
+}
+
+/// Options for reviewing similar images.
+#[derive(Debug, clap::Args)]
+#[command(mut_arg("paths", |x| {
+    x.required(false)
+        .required_unless_present("pairs_file")
+        .default_value_if("pairs_file", ArgPredicate::IsPresent, ".")
+}))]
+pub struct ReviewArgs {
+    /// Print duplicate pairs like `list` instead of launching the GUI
+    #[arg(long, visible_alias = "list", long_help = NO_GUI_HELP)]
+    pub no_gui: bool,
+
+    /// Print the contents of the database and exit without scanning
+    #[arg(long)]
+    #[arg(conflicts_with_all = ["no_gui", "rebuild", "rehash", "progress"])]
+    #[arg(conflicts_with = "verify")]
+    pub print_db: bool,
+
+    /// Take the decisions on the pairs from FILE (- for stdin) instead of
+    /// the GUI
+    #[arg(long = "actions", value_name = "FILE")]
+    #[arg(value_hint = ValueHint::FilePath, long_help = ACTIONS_HELP)]
+    #[arg(conflicts_with_all = ["no_gui", "print_db", "summary"])]
+    pub decisions: Option<PathBuf>,
+
+    /// Review the pairs listed in FILE (- for stdin) instead of scanning for
+    /// similar images
+    #[arg(long, value_name = "FILE")]
+    #[arg(value_hint = ValueHint::FilePath, long_help = PAIRS_FILE_HELP)]
+    #[arg(conflicts_with_all = [
+        "no_gui", "print_db", "summary", "auto", "only_new", "ignore_bursts",
+        "only_bursts", "min_pair_size",
+    ])]
+    pub pairs_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub list: ListArgs,
+}
+
+/// Options for comparing two images.
+#[derive(Debug, clap::Args)]
+pub struct CompareArgs {
+    /// First image
+    #[arg(value_hint = ValueHint::FilePath)]
+    pub image_1: PathBuf,
+
+    /// Second image
+    #[arg(value_hint = ValueHint::FilePath)]
+    pub image_2: PathBuf,
+
+    /// Judge the images similar if their distance is below N
+    #[arg(short = 't', long, value_name = "N", value_parser = parse_threshold)]
+    pub threshold: Option<u32>,
+
+    /// Reuse hashes stored in this database file instead of decoding the
+    /// images (the file is not modified)
+    #[arg(short = 'D', long, value_hint = ValueHint::FilePath)]
+    pub db: Option<PathBuf>,
+
+    /// Crop near-uniform borders before hashing, as with `scan` (with --db,
+    /// the setting of the database is used)
+    #[arg(long, conflicts_with = "db")]
+    pub trim_borders: bool,
+
+    /// Perceptual hash algorithm, as with `scan` (with --db, that of the
+    /// database is used)
+    #[arg(long, value_enum, value_name = "NAME", conflicts_with = "db")]
+    pub algorithm: Option<Algorithm>,
+
+    /// Width and height of hashes, as with `scan` (with --db, those of the
+    /// database are used)
+    #[arg(long, value_name = "N", value_parser = parse_hash_size)]
+    #[arg(conflicts_with = "db")]
+    pub hash_size: Option<u32>,
+}
+
+/// Database maintenance commands.
+#[derive(Debug, Subcommand)]
+pub enum DbCommand {
+    /// Merge entries from other databases into a database file
+    Merge {
+        /// Database file to merge into
+        #[arg(value_hint = ValueHint::FilePath)]
+        db: PathBuf,
+
+        /// Database files to merge from
+        #[arg(required = true, value_hint = ValueHint::FilePath)]
+        others: Vec<PathBuf>,
+    },
+
+    /// Remove entries for images that no longer exist
+    Prune {
+        /// Database file
+        #[arg(value_hint = ValueHint::FilePath)]
+        db: PathBuf,
+    },
+
+    /// Print statistics about a database file
+    Stats {
+        /// Database file
+        #[arg(value_hint = ValueHint::FilePath)]
+        db: PathBuf,
+    },
+
+    /// Print the hash and path of every entry in a database file, sorted by
+    /// path, with tabs and line breaks in paths escaped
+    Export {
+        /// Database file
+        #[arg(value_hint = ValueHint::FilePath)]
+        db: PathBuf,
+    },
+
+    /// Print similar images found in two database files, one from each, such
+    /// as images on one drive that already exist on another
+    Cross(CrossArgs),
+}
+
+/// Options for comparing two database files.
+#[derive(Debug, clap::Args)]
+pub struct CrossArgs {
+    /// First database file; its images are on the left of each pair
+    #[arg(value_hint = ValueHint::FilePath)]
+    pub db_1: PathBuf,
+
+    /// Second database file; its images are on the right of each pair
+    #[arg(value_hint = ValueHint::FilePath)]
+    pub db_2: PathBuf,
+
+    /// Image similarity threshold; pairs closer than this match
+    #[arg(short, long, default_value_t = DEFAULT_THRESHOLD)]
+    #[arg(value_parser = parse_threshold)]
     pub threshold: u32,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+const AFTER_HELP: &str = "\
+Running `image-duplicate [OPTIONS] <PATH>` without a command is the same as \
+`image-duplicate review`.
+
+Options can also be set in the configuration file, using their long names as \
+keys, or in environment variables such as IMAGE_DUPLICATE_THRESHOLD. The \
+command line wins over the environment, which wins over the file.
+
+Exit status:
+  0  Success; no similar images were found by `list`, `review --no-gui`, or \
+`db cross`, or `compare` found the images similar
+  1  Failure, whether invalid usage, a runtime error, or an error in the GUI
+  2  Similar images were found by `list`, `review --no-gui`, or `db cross`, \
+`compare` found the images not similar, or `--verify` found the database out \
+of date";
+
+const VERIFY_HELP: &str = "\
+Check the database against the images under PATH instead of finding similar \
+images
+
+Each entry for an image under PATH is checked: whether the image still \
+exists, and whether its modification time and size are still those it was \
+hashed with. Entries for images elsewhere, such as on a backup drive that is \
+not mounted, are left out, so only the part that can be reached is checked. \
+--verify-sample and --verify-rehash hash images again to compare the hashes \
+too.
+
+The database is only read, as with --no-update and --no-dump. A summary and \
+the names of the entries found missing, changed, or unreadable are printed, \
+and the exit status is 2 if there are any.";
+
+const DB_LOCATION_HELP: &str = "\
+Where to keep the database file when --db is not given
+
+local: <PATH>/.image_hash.db.
+
+xdg: <DIR>/<HASH>.db, where DIR is $IMAGE_DUPLICATE_DB_DIR if set and \
+$XDG_DATA_HOME/image-duplicate otherwise, and HASH identifies the absolute \
+path of PATH, so a directory always maps to the same file.
+
+auto: xdg if $IMAGE_DUPLICATE_DB_DIR is set; otherwise local if that file \
+already exists or PATH is writable, and xdg if not.";
+
+const PREFER_HELP: &str = "\
+With --auto, keep the image under DIR over the others in its group, whatever \
+POLICY says. Repeat to give directories in order of preference, e.g. \
+`--prefer ~/Pictures/Curated --prefer ~/Pictures`. POLICY still decides \
+between images under the same directory, and between images under none of \
+them. Paths are compared after resolving symbolic links, so a symlinked view \
+of a directory counts as the directory itself. --dry-run and --emit-script \
+show the same choices.";
+
+const RELATIVE_DB_HELP: &str = "\
+Store paths in the database relative to PATH instead of as absolute paths, \
+so that a collection on a removable drive, mounted at /media/user/photos on \
+one machine and /Volumes/photos on another, is not hashed again on each. \
+The database remembers this, so later scans need not repeat it. With more \
+than one PATH, the others must be under the first. Keep the database in \
+PATH, as --db-location local does, or the data directory will not find it \
+at another mount point. An existing database of absolute paths is \
+converted, and `db` commands resolve relative paths against the directory \
+of the database file.";
+
+const SEED_DB_HELP: &str = "\
+Also search the database FILE, such as a master database shared by a team, \
+as if its entries were in the database file. FILE is only read: images it \
+has hashes for are not hashed again, and its entries are neither pruned nor \
+written to the database file, which only gets the images hashed locally. \
+Where both have an entry for the same image, the database file wins. FILE \
+must have hashes of the same size as the program makes.";
+
+const MERGE_HELP: &str = "\
+Add the entries of the database FILE to the database file before scanning, \
+so that images hashed elsewhere, such as on another machine with the same \
+collection, are not hashed again. Where both have an entry for the same \
+path, the one hashed from the file with the newer modification time wins, \
+and the database file's if that is unknown. Entries for images that do not \
+exist here are kept by a run with --no-update and removed by a scan like \
+those of deleted images, and entries whose files have another modification \
+time or size here are hashed again unless --no-stat is given. FILE must have \
+been hashed with the same settings. May be given more than once; `db merge` \
+does the same without scanning.";
+
+const ACTIONS_HELP: &str = "\
+Take the decisions on the pairs from FILE (- for stdin) instead of the GUI, \
+one per line, in the order the GUI would show the pairs: keep-left, \
+keep-right, keep-both, or skip. Removals, the action log, and the summary \
+are the same as in the GUI, and no display is needed. Blank lines and lines \
+starting with # are ignored. A line that is not a decision, or a removal \
+that fails, stops the review with the line number; running out of lines \
+leaves the remaining pairs untouched.";
+
+const PAIRS_FILE_HELP: &str = "\
+Review the pairs listed in FILE (- for stdin) instead of scanning for \
+similar images, e.g. pairs found by another program or saved by `list`. \
+FILE may be the output of `list` in the plain format (without --sort \
+savings) or in the json format; other formats, such as csv, are not read. \
+Otherwise each line is a pair: either the two paths and an optional distance \
+separated by tabs, with paths escaped as in plain output, or a JSON object \
+like {\"left\": \"a.jpg\", \"right\": \"b.jpg\", \"distance\": 3}. Blank \
+lines and lines starting with # are ignored. Pairs are reviewed closest \
+first, followed by those without a distance in the order listed. Pairs with \
+an image that does not exist are left out with a warning. The database is \
+neither read nor written, PATH may be left out, and the review works as usual \
+otherwise, with --actions, --limit, and --shuffle.";
+
+const ALGORITHM_HELP: &str = "\
+Perceptual hash algorithm to hash new images with. Which one works best \
+depends on the images: dct suits photos and scanned documents, and blockhash \
+suits line art. Hashes made with different algorithms cannot be compared, so \
+a database keeps the algorithm it was hashed with; giving another one for an \
+existing database takes --rehash (or --rebuild) to hash its images again. \
+Without this option, the algorithm of the database is used, or gradient for \
+a new one.";
+
+const HASH_SIZE_HELP: &str = "\
+Width and height of the perceptual hashes of new images, in bits, from 4 to \
+64. Larger hashes tell similar images apart better, at the cost of space and \
+search time; 16 makes hashes of 256 bits instead of 64. Like the algorithm, \
+the size is kept in the database, and changing it for an existing database \
+takes --rehash (or --rebuild).
+
+The threshold counts differing bits, so it has to grow with the hash: about \
+9 bits in 64 corresponds to 36 in 256. A threshold over half of the bits \
+matches most pairs and is warned about; one at or above the number of bits \
+is refused. --similarity gives it as a percentage that holds for any size.";
+
+const MAX_DEPTH_HELP: &str = "\
+Scan at most N levels of directories under PATH, where 1 is only the images \
+directly in PATH and 2 also those in its subdirectories; implies --recursive
+
+Entries for images deeper down, such as from an earlier scan without a limit, \
+are kept in the database whether or not the images still exist, since they \
+were not looked for, but they are left out of the search. A deeper scan \
+takes care of them again.";
+
+const EXCLUDE_HELP: &str = "\
+Leave out paths matching GLOB, relative to the scanned directory, such as \
+'**/.thumbnails' or '*.gif'. * and ? match any characters including /, ** \
+matches any number of directories, and {a,b} either alternative. A directory \
+that matches is not read at all, nor is one whose contents a pattern ending \
+in /** matches, such as '**/cache/**'. Entries for images that are left out \
+are removed from the database like those of deleted images. May be given \
+more than once. The patterns are stored in the database, and a scan with \
+--db alone uses them again unless --exclude is given.";
+
+const EXTENSIONS_HELP: &str = "\
+Treat files with the given extensions as images, as a comma-separated list \
+such as 'jpg,png'. Extensions are compared without regard to case, so a.JPG \
+is an image as much as a.jpg. Extensions starting with + are added to the \
+usual ones and those starting with - taken out of them, as in '+heic,-gif'. \
+The usual ones are bmp, gif, jpg, jpeg, jxl, pam, pbm, pgm, png, pnm, ppm, \
+qoi, tga, tif, tiff and webp.
+
+Files with an extension the image decoder does not know are decoded by their \
+contents; those it cannot decode at all, such as AVIF and HEIC without \
+system libraries, are reported as unreadable and skipped. The list is stored \
+in the database, and a scan with --db alone uses it again unless \
+--extensions is given.";
+
+const NO_STAT_HELP: &str = "\
+Only hash images that are not in the database yet. By default, the \
+modification time and size of every image already in the database are \
+compared with those it was hashed with, and images that were edited in place \
+are hashed again. That takes a stat call per image, which can be slow on \
+network filesystems; with this option, edited images keep their old hashes \
+until --rehash.
+
+Entries from databases written before file stats were stored take the stats \
+the images have at their next scan, without being hashed again.";
+
+const TRIM_BORDERS_HELP: &str = "\
+Crop near-uniform borders before hashing, so that a copy with black letterbox \
+bars or a plain frame added by some app still matches the original. Rows and \
+columns at the edges whose brightness barely varies are cropped, up to a \
+quarter of the image on each side. This changes every hash, so it is stored \
+in the database: an existing database needs --rehash to turn it on, and keeps \
+it until it is hashed again with --rehash alone or rebuilt. The GUI notes \
+which images had borders cropped.";
+
+const MATCH_ROTATIONS_HELP: &str = "\
+Also match copies that were rotated in steps of 90° or mirrored, such as a \
+photo turned by the camera or flipped by an editing app. Each image is hashed \
+in all eight orientations, and a pair is reported if its images are close in \
+any of them; list and review output tell which orientation matched. Hashing \
+takes somewhat longer, and searching up to eight times as long. Like \
+--trim-borders, this is stored in the database: an existing database needs \
+--rehash to turn it on, and keeps it until it is hashed again with --rehash \
+alone or rebuilt. A database that matches rotations cannot be compared with \
+one that does not.";
+
+const SIMILARITY_HELP: &str = "\
+Image similarity as the percentage of hash bits that must agree, such as 90% \
+(the % is optional), instead of --threshold. Unlike the threshold, which \
+counts differing bits, it means the same for any --hash-size: it is turned \
+into the largest number of differing bits that keeps at least that much of \
+the hashes of the database agreeing. 100% only matches identical hashes and \
+0% matches everything. The default threshold of 9 is about 87.5% for 64-bit \
+hashes.";
+
+const AGAINST_HELP: &str = "\
+Only find pairs of an image in PATH with one in DIR, such as new images \
+against an archive they may already be in, leaving out pairs wholly inside \
+either. DIR is scanned along with PATH, and its images are always on the \
+right of a pair, so `keep right` removes the copy in PATH. Symlinks in both \
+are resolved first, and an image under both, when one is inside the other, \
+belongs to the deeper of them. If DIR already has a database file of its own, \
+its hashes are used as with --seed-db. To compare two databases without \
+scanning, see `db cross`.";
+
+const INCLUDE_HARDLINKS_HELP: &str = "\
+Also report pairs of paths that name the same file, such as hardlinks made by \
+a backup tool, or names that differ only in case on a case-insensitive \
+filesystem. These are left out by default, since such a pair is one image \
+rather than two copies: deleting one path frees no space, and for names that \
+differ in case removes the image altogether. Files are compared by device and \
+inode on Unix, and by volume and file index on Windows.";
+
+const MAX_FILE_SIZE_HELP: &str = "\
+Skip image files larger than SIZE bytes without opening them, reporting them \
+like images that cannot be decoded (in the limits category of \
+--errors-json). K, M, and G suffixes are allowed, as in 200M. Whatever the \
+file size, images wider or taller than 65536 pixels, or that would take more \
+than 512 MiB to decode, are always skipped, so that a decompression bomb \
+cannot exhaust memory.";
+
+const PATHS_HELP: &str = "\
+Directories to scan for images. Several can be given, such as ~/Pictures and \
+an external drive, to find duplicates across them: all of them are scanned \
+into one database, and an entry is only removed once its image is under none \
+of them. The database is kept by the first directory unless --db is given, \
+which is best with several, so that scanning the first one alone later does \
+not remove the entries of the others. With --db and no directories, those \
+the database was last scanned from are scanned again.";
+
+const CHECKPOINT_HELP: &str = "\
+Write the database after every N images hashed, rather than only once the \
+scan is done, so that a scan that crashes or is interrupted with Ctrl-C \
+keeps the hashes made so far. The next run loads them and only hashes the \
+remaining images. Each checkpoint writes the whole database, so N should be \
+in the hundreds or thousands for a large database; 0 is the same as leaving \
+this out. No checkpoints are written with --no-dump, when the database goes \
+to stdout, or while --rehash hashes the images already in the database, \
+since until that ends the database holds hashes of two kinds.";
+
+const JOBS_HELP: &str = "\
+Hash images and check them for changes with N threads, instead of one per \
+core. On spinning disks a few threads can be faster than many, since reads \
+from all over the disk make it seek; on a laptop fewer threads keep the fans \
+down. 1 hashes one image at a time, and 0 is the same as leaving this out. \
+--max-concurrent-decodes limits memory rather than threads, and the two can \
+be combined.";
+
+const MAX_DECODES_HELP: &str = "\
+Decode at most N images at once while hashing. Each image is held at full \
+size only while it is decoded and shrunk, so this caps memory use on \
+directories of very large images; the other hashing threads wait for a turn. \
+Defaults to one per hashing thread, which is the number of CPUs unless \
+RAYON_NUM_THREADS says otherwise.";
+
+const LOW_PRIORITY_HELP: &str = "\
+Lower the priority of the scan so that it stays out of the way of other \
+programs: like nice and ionice -c 3 on Linux, the background band on macOS, \
+and the below normal priority class on Windows. If the priority cannot be \
+changed, a warning is printed and the scan runs anyway.";
+
+const ERRORS_JSON_HELP: &str = "\
+Write the images that could not be hashed to FILE when the scan finishes, as \
+a JSON array of objects sorted by path:
+
+    [{\"path\": \"...\", \"category\": \"decode\", \"message\": \"...\"}]
+
+\"category\" is one of decode, unsupported, limits, permission, not_found, \
+io, database, or other. \"message\" is for people and may change. The array \
+is empty, not missing, when every image was hashed.";
+
+const PROGRESS_HELP: &str = "\
+Report progress on stdout. Status messages always go to stderr.
+
+none: no progress reports.
+
+ndjson: one JSON object per line, each with a \"type\" field:
+
+    {\"type\": \"start\", \"schema\": 1}
+    {\"type\": \"phase\", \"phase\": \"load\"}
+    {\"type\": \"hash\", \"done\": 10, \"total\": 250, \"file\": \"...\"}
+    {\"type\": \"error\", \"file\": \"...\", \"message\": \"...\"}
+    {\"type\": \"unreadable\", \"dir\": \"...\", \"message\": \"...\"}
+    {\"type\": \"match\", \"done\": 4096, \"total\": 31125, \"pairs\": 2}
+    {\"type\": \"summary\", \"images\": 250, \"hashed\": 10, \"errors\": 0,
+     \"removed\": 1, \"unreadable\": 0, \"pairs\": 3}
+
+Phases are load, scan, hash, save, and match, in that order, and are skipped \
+when they do not apply. \"hash\" and \"match\" events are sent at most every \
+100 ms, plus once when the phase is complete. \"pairs\" in the summary is \
+null for `scan`. With --timings, the summary also has \"timings\", a list \
+of {\"phase\": ..., \"seconds\": ...} objects. Fields are only added, never \
+changed or removed, unless \"schema\" changes. With `list`, --output is \
+required so that the pairs do not mix with the events.";
+
+const SHUFFLE_HELP: &str = "\
+Handle the pairs in random order instead of closest first, so that a long \
+run of near-identical pairs does not come all at once. The pairs are \
+shuffled after every other filter and action and before --limit, so each run \
+with --limit samples the whole backlog. The seed is printed; pass it to \
+--seed to get the same order again.";
+
+const BURSTS_HELP: &str = "\
+Hide pairs of frames from the same burst of shots, which look alike without \
+being copies of each other. Images taken at most --burst-window seconds \
+apart, according to their EXIF capture time, and at most \
+--burst-max-distance apart form a burst, chained through such pairs. Pairs \
+taken at the same instant are kept, since copies of a photo keep its capture \
+time. --only-bursts does the opposite, handling only the burst frames.
+
+Capture times are stored in the database when images are hashed. Entries \
+made by older versions have none until --rehash.";
+
+const MERGE_EXIF_HELP: &str = "\
+Before removing an image, copy its EXIF metadata, such as the capture date \
+and GPS position, into the kept image where that lacks it, e.g. when the \
+larger copy went through an exporter that stripped it. Tags the kept image \
+already has are left alone, and the orientation, dimensions, and thumbnail \
+are never copied, since they describe the removed copy. The kept image is \
+written under a temporary name and renamed into place, keeping its \
+permissions and modification time. Formats that cannot hold EXIF (BMP and \
+GIF) are skipped. Copies are noted in the action log as \"exif\" and shown \
+while reviewing. This runs exiftool, which must be installed.";
+
+const EMIT_SCRIPT_HELP: &str = "\
+Write what --auto or --hardlink would do to a shell script FILE instead of \
+doing it, so that it can be reviewed, kept under version control, and run \
+later. Each removal or link becomes one command, preceded by a comment with \
+the kept file and the distance. Paths are quoted so that spaces, quotes, and \
+line breaks survive. The script asks for confirmation before doing anything, \
+and stops at the first command that fails. Links are only made if the two \
+files are still identical when the script runs.
+
+Removals are deletions, or moves with --move-to; the trash cannot be reached \
+from a script, so --auto requires --delete or --move-to. \
+--emit-script-format powershell writes a PowerShell script instead of a POSIX \
+shell one.";
+
+const EXEC_HELP: &str = "\
+Run COMMAND once for each pair that is left after the other actions, in \
+order, after the pairs are printed. COMMAND is split into words like a shell \
+would, with single quotes, double quotes, and backslashes, but nothing is \
+expanded and no shell is run. {left}, {right}, and {distance} are then \
+replaced in each word, so paths are passed as single arguments whatever they \
+contain:
+
+    --exec 'mv -n -- {right} /tmp/dupes/'
+
+Commands inherit stdin, stdout, and stderr. Failures are reported and \
+counted, and do not stop the run unless --exec-fail-fast is given. Use sh -c \
+'...' sh {left} {right} for shell features.";
+
+const LIMIT_HELP: &str = "\
+Only handle the first N pairs. Pairs are ordered by distance, then by path \
+(or as --sort says), after --auto and --hardlink have been applied, so a \
+backlog can be worked through in chunks over several runs.";
+
+const SORT_HELP: &str = "\
+Order of the pairs with --no-gui
+
+distance: closest pairs first.
+
+savings: pairs that would free the most space first, where the space is the \
+size of the smaller file. Pairs with a missing file come last. The savings of \
+each pair are shown as an extra column (a \"savings\" column in CSV), and \
+the total is printed at the end, or on stderr for CSV and NUL output. JSON \
+always has \"savings\" for each pair, and has \"total_savings\" with this \
+order.";
+
+const EXPORT_DOT_HELP: &str = "\
+Also write the pairs as an undirected Graphviz graph to FILE, for viewing \
+with e.g. `neato -Tsvg FILE > graph.svg`. Nodes are images labeled with their \
+file names, edges are pairs labeled with their distances, and closer pairs get \
+heavier edges. Each group of connected images is a cluster; --dot-min-cluster \
+leaves out small ones, and --dot-color colors edges green, orange, or red by \
+distance.";
+
+const NO_GUI_HELP: &str = "\
+Print duplicate pairs instead of launching the GUI, for headless servers and \
+scripts. This is the same listing as the `list` subcommand, in any --format: \
+plain is tab-separated and sorted so that it diffs cleanly, json is a \
+document with both paths and the distance of each pair, and csv is quoted \
+where paths need it. Messages go to stderr, so stdout holds only the pairs. \
+--list is another name for this option.";
+
+const SUMMARY_HELP: &str = "\
+Print only totals instead of the pairs: the number of groups of similar \
+images, the images in them, the redundant files (all but one per group), and \
+an estimate of the space freed by keeping only the largest file of each group, \
+computed from file sizes. The estimate is printed with how it was made, since \
+hardlinks and pairs that are not true duplicates make the real figure smaller. \
+--format json and csv carry the same figures. With `review`, implies --no-gui.";
+
+const FORMAT_HELP: &str = "\
+Output format for --no-gui
+
+Defaults to terminal when writing to a terminal, and to plain otherwise, so \
+that scripts always get plain output.
+
+plain: one pair per line, the two paths and their distance separated by \
+tabs, with the space the pair would free as a fourth column when it is shown. \
+The distance is left out of pairs not in the database. Backslashes, \
+tabs, and line breaks in paths are escaped as \\\\, \\t, \\n, and \\r, and \
+bytes that are not valid UTF-8 as \\xNN.
+
+digikam: one row per image in a pair, with the columns group, path, \
+distance, and tag, preceded by a header row unless --no-header is given, for \
+a script that tags the images in digiKam or another photo manager. Images \
+connected by pairs share a group number, counting from 1 in the order of the \
+pairs. \"distance\" is the smallest distance to another image of the group, \
+and \"tag\" is Duplicates/exact for distance 0, Duplicates/close for \
+distances below a third of the threshold, and Duplicates/similar otherwise. \
+Paths are absolute.
+
+terminal: aligned columns of distance and paths, with a blank line between \
+groups of connected pairs. Identical files show = for their distance, and \
+pairs matched turned end with the orientation, as in json. Colors follow \
+--color; with auto, they are used unless the NO_COLOR environment variable is \
+set. The layout may change between versions; use another format for scripts.
+
+json: a single document of the form
+
+    {
+      \"threshold\": 9,
+      \"root\": \"/absolute/scan/root\",
+      \"roots\": [\"/absolute/scan/root\"],
+      \"pairs\": [
+        {
+          \"distance\": 3,
+          \"exact\": false,
+          \"orientation\": null,
+          \"savings\": 12345,
+          \"files\": [
+            { \"path\": \"...\", \"lossy\": false, \"size\": 12345,
+              \"width\": 640, \"height\": 480 },
+            { ... }
+          ],
+          \"resized\": 1,
+          \"metadata_only\": [\"date\", \"gps\"]
+        }
+      ]
+    }
+
+\"root\" is the first directory scanned, and \"roots\" lists them all.
+\"exact\" is true when the files were byte-for-byte the same when they were \
+hashed, going by checksums stored in the database; images hashed by versions \
+without checksums count as different until they are hashed again.
+\"orientation\" is set when the database matches rotations, and tells how \
+the second file is turned to match the first: upright, rotate-90, \
+rotate-180, or rotate-270 for clockwise rotations, and mirror or \
+mirror-rotate-90 and so on for a left-to-right mirror followed by one.
+\"lossy\" is true when the path is not valid UTF-8 and is shown with bytes \
+replaced.
+\"size\" and \"savings\" are in bytes; \"savings\" is the size of the \
+smaller file. \"resized\" is the index in \"files\" of the image that is a \
+downscaled copy of the other, with the same aspect ratio and smaller sides, \
+and the one to remove; it is null when neither is. \"metadata_only\" is set \
+for pairs at distance 0 whose pixels are the same, and lists the kinds of EXIF \
+metadata that differ: date, gps, orientation, and other; it is empty when \
+none does, and null when the pixels differ. Images over 4 megapixels are \
+compared shrunk. Metadata that cannot be read is null.
+
+csv: one row per pair with the columns left, right, distance, left_size, \
+right_size, left_dimensions, right_dimensions, resized, metadata_only, \
+exact, and orientation, preceded by a header row unless --no-header is \
+given. Sizes are in bytes, dimensions are WxH, resized is left or right for \
+the side that is a downscaled copy of the other, metadata_only lists the \
+differing kinds of metadata separated by spaces, or none, exact is true or \
+false, and orientation is empty unless the database matches rotations, as in \
+json.
+
+nul: the two paths of each pair, each followed by a NUL byte, so that \
+`xargs -0 -n 2` gets one pair per call. Paths are written as they are on \
+disk, even those that are not valid UTF-8. --print0 is short for --format \
+nul.
+
+html: a single self-contained page for viewing in a browser, with \
+thumbnails, sizes, dimensions, and modification dates of the images. Similar \
+images are grouped, closest first, and can be filtered and reordered by \
+distance. Only the first --report-max-pairs pairs are included.";
+
+/// Parse and check a `--threshold` value against the largest hashes. The
+/// scan checks it again once it knows the hash size.
+fn parse_threshold(s: &str) -> Result<u32, String> {
+    let threshold = s.parse().map_err(|e| format!("{e}"))?;
+    check_threshold(threshold, MAX_HASH_SIZE * MAX_HASH_SIZE)?;
+    Ok(threshold)
+}
+
+/// Parse a `--similarity` percentage, with or without the `%`.
+fn parse_similarity(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let percent: f64 = s
+        .strip_suffix('%')
+        .unwrap_or(s)
+        .trim()
+        .parse()
+        .map_err(|e| format!("{e}"))?;
+    match (0.0..=100.0).contains(&percent) {
+        true => Ok(percent),
+        false => Err(format!("{percent}% is not from 0% to 100%")),
+    }
+}
+
+/// Check a `--exclude` pattern.
+fn parse_glob(s: &str) -> Result<String, String> {
+    match globset::Glob::new(s) {
+        Ok(_) => Ok(s.to_owned()),
+        Err(e) => Err(e.kind().to_string()),
+    }
+}
+
+/// Parse and check a `--max-depth` value.
+fn parse_max_depth(s: &str) -> Result<usize, String> {
+    match s.parse().map_err(|e| format!("{e}"))? {
+        0 => Err("use 1 or more; 1 is only the images directly in PATH".into()),
+        n => Ok(n),
+    }
+}
+
+/// Parse and check a `--hash-size` value.
+fn parse_hash_size(s: &str) -> Result<u32, String> {
+    let size = s.parse().map_err(|e| format!("{e}"))?;
+    match (MIN_HASH_SIZE..=MAX_HASH_SIZE).contains(&size) {
+        true => Ok(size),
+        false => Err(format!(
+            "use {MIN_HASH_SIZE} to {MAX_HASH_SIZE}, for hashes of \
+             {MIN_HASH_SIZE}x{MIN_HASH_SIZE} to {MAX_HASH_SIZE}x{MAX_HASH_SIZE} \
+             bits"
+        )),
+    }
+}
+
+/// Parse a size in bytes, with an optional K, M, or G suffix for powers of
+/// 1024 (optionally followed by "B" or "iB").
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let shift = match s[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        x => return Err(format!("unknown size suffix {x:?}")),
+    };
+    let n: u64 = digits.trim().parse().map_err(|e| format!("{e}"))?;
+    n.checked_mul(1 << shift).ok_or("size too large".into())
+}
+
+/// Parse a non-negative number of seconds.
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let seconds: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("{e}"))
+}
+
+/// Parse an `--exec` command.
+fn parse_hook(s: &str) -> Result<Hook, String> {
+    Hook::parse(s).map_err(|e| format!("{e}"))
+}
+
+/// Outcome of a successful run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    /// Nothing to report.
+    Clean,
+    /// Similar images were found and printed.
+    Duplicates,
+    /// The images compared were not similar.
+    Different,
+    /// The database was found out of date.
+    Stale,
+}
+
+impl Status {
+    /// Exit code for the outcome. Errors exit with 1, so that a finding
+    /// can be told from a failure.
+    pub fn exit_code(self) -> ExitCode {
+        match self {
+            Status::Clean => ExitCode::SUCCESS,
+            Status::Duplicates | Status::Different | Status::Stale => {
+                ExitCode::from(2)
+            }
+        }
+    }
+}
+
+/// An error in how the program was invoked, as opposed to one that happened
+/// while running.
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct UsageError(String);
+
+impl Args {
+    /// Parse the command line. For compatibility with the original interface,
+    /// a command line that does not start with a subcommand is parsed as
+    /// `review`. Options not given on the command line are taken from the
+    /// environment and the configuration file.
+    pub fn parse_args() -> Result<Self> {
+        let mut args = with_default_command(env::args_os());
+        let cmd = Args::command();
+        let matches = try_matches(&cmd, &args)?;
+        let Some((name, sub_matches)) = matches.subcommand() else {
+            return Ok(Self::try_parse_from(args)?);
+        };
+
+        let file = sub_matches.try_get_one::<PathBuf>("config").ok().flatten();
+        let config = Config::load(file.map(|x| x.as_path()))?;
+        config.warn_unknown(&cmd);
+
+        let sub = cmd
+            .find_subcommand(name)
+            .expect("matched subcommand should exist");
+        let defaults = config.args(sub, sub_matches)?;
+        args.splice(2..2, defaults);
+
+        let matches = try_matches(&cmd, &args)?;
+        if let Some((_, sub_matches)) = matches.subcommand() {
+            let stored = stored_args(sub, sub_matches)?;
+            args.splice(2..2, stored);
+        }
+        Ok(Self::try_parse_from(args)?)
+    }
+}
+
+/// Match `args` against `cmd`. Help and version are printed as usual, but
+/// other errors are returned, so that they exit like any other failure.
+fn try_matches(cmd: &ClapCommand, args: &[OsString]) -> Result<ArgMatches> {
+    cmd.clone()
+        .try_get_matches_from(args)
+        .or_else(|e| match e.use_stderr() {
+            true => Err(e.into()),
+            false => e.exit(),
+        })
+}
+
+/// Arguments that repeat the scan stored in the database file given with
+/// --db, when the directory to scan is not given. Options given on the
+/// command line or by the configuration win over the stored ones.
+fn stored_args(
+    cmd: &ClapCommand,
+    matches: &ArgMatches,
+) -> Result<Vec<OsString>> {
+    let has = |id: &str| cmd.get_arguments().any(|x| x.get_id() == id);
+    let given = |id: &str| {
+        has(id) && matches.value_source(id) == Some(ValueSource::CommandLine)
+    };
+    if !has("paths") || given("paths") || given("pairs_file") {
+        return Ok(Vec::new());
+    }
+    let Some(db) = matches.get_one::<PathBuf>("db") else {
+        return Ok(Vec::new());
+    };
+    if db == Path::new("-") {
+        Err(UsageError(
+            "--db - has no stored scan settings to read; give the directory \
+             to scan"
+                .into(),
+        ))?;
+    }
+    let header = HashDB::read_header(db).map_err(|e| {
+        UsageError(format!(
+            "Could not read the scan settings of {db:?} ({e}); give the \
+             directory to scan"
+        ))
+    })?;
+    let Some(scan) = header.scan else {
+        Err(UsageError(format!(
+            "{db:?} has no stored scan settings, since no scan has written it \
+             since they were added; give the directory to scan"
+        )))?
+    };
+
+    // Without --against, as for `scan`, the directory searched against is
+    // scanned like the others.
+    let mut args: Vec<OsString> = scan.roots().map(OsString::from).collect();
+    if let Some(against) = &scan.against
+        && has("against")
+    {
+        args.pop();
+        if !given("against") {
+            let mut arg = OsString::from("--against=");
+            arg.push(against);
+            args.push(arg);
+        }
+    }
+    if scan.recursive && !given("recursive") {
+        args.push("--recursive".into());
+        if let Some(depth) = scan.max_depth
+            && !given("max_depth")
+        {
+            args.push(format!("--max-depth={depth}").into());
+        }
+        if scan.one_file_system && !given("one_file_system") {
+            args.push("--one-file-system".into());
+        }
+    }
+    if let Some(threshold) = scan.threshold
+        && has("threshold")
+        && !given("threshold")
+        && !given("similarity")
+    {
+        args.push(format!("--threshold={threshold}").into());
+    }
+    if !given("exclude") {
+        for pattern in scan.exclude {
+            args.push(format!("--exclude={pattern}").into());
+        }
+    }
+    if let Some(extensions) = scan.extensions
+        && !given("extensions")
+    {
+        args.push(format!("--extensions={}", extensions.join(",")).into());
+    }
+    Ok(args)
+}
+
+/// Insert `review` into a command line that lacks a subcommand.
+fn with_default_command<I: IntoIterator<Item = OsString>>(
+    args: I,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = args.into_iter().collect();
+    let explicit = match args.get(1).and_then(|x| x.to_str()) {
+        Some("-h" | "--help" | "-V" | "--version" | "help") => true,
+        Some(x) => Args::command().find_subcommand(x).is_some(),
+        None => args.len() < 2,
+    };
+    if !explicit {
+        args.insert(1, "review".into());
+    }
+    args
 }
 
 /// Run the image duplicate program.
-pub fn run(args: &Args) -> Result<()> {
-    let path = args.path.clone();
-    if !path.is_dir() {
-        return Err(anyhow!("Directory not found: {path:?}"));
+pub fn run(args: &Args) -> Result<Status> {
+    match &args.command {
+        Command::Scan(args) => scan(args).map(|_| Status::Clean),
+        Command::Review(args) => review(args),
+        Command::List(args) => list(args),
+        Command::Compare(args) => compare(args),
+        Command::Db(cmd) => db(cmd),
+        Command::Completions { shell } => {
+            completions(*shell).map(|_| Status::Clean)
+        }
     }
+}
 
-    let db_file = match &args.db {
-        Some(path) => path.clone(),
-        None => path.join(".image_hash.db"),
+/// Progress reporting for a scan.
+fn observer(args: &ScanArgs) -> Box<dyn ScanObserver> {
+    let observer: Box<dyn ScanObserver> = match args.progress {
+        Progress::None => Box::new(Stderr),
+        Progress::Ndjson => Box::new(Ndjson::new()),
     };
-    eprintln!("Database file is {db_file:?}");
+    let observer: Box<dyn ScanObserver> = match &args.errors_json {
+        Some(file) => Box::new(ErrorLog::new(observer, file.clone())),
+        None => observer,
+    };
+    match args.timings {
+        true => Box::new(Timed::new(observer)),
+        false => observer,
+    }
+}
 
-    let mut hashdb = match db_file.is_file() && !args.rebuild {
-        true => {
-            eprintln!("Reading database file...");
-            HashDB::from_file(&db_file)?
+impl ScanArgs {
+    /// Whether the database will be written to stdout, if it is dumped.
+    fn db_to_stdout(&self, dump: bool) -> bool {
+        dump && self.db.as_deref() == Some(Path::new("-"))
+    }
+
+    /// Set up the process for scanning. This must run before the hashing
+    /// threads start.
+    fn prepare(&self) {
+        if self.low_priority {
+            priority::lower();
         }
-        false => {
-            eprintln!("Creating new database...");
-            HashDB::new()
+    }
+
+    /// Pipeline options for scanning without searching.
+    fn options(&self) -> ScanOptions {
+        ScanOptions {
+            roots: self.paths.clone(),
+            db: self.db.clone(),
+            db_location: self.db_location,
+            seed_db: self.seed_db.clone(),
+            merge: self.merge.clone(),
+            recursive: self.recursive || self.max_depth.is_some(),
+            max_depth: self.max_depth,
+            one_file_system: self.one_file_system,
+            exclude: self.exclude.clone(),
+            extensions: self.extensions.clone(),
+            rebuild: self.rebuild,
+            rehash: self.rehash,
+            trim_borders: self.trim_borders,
+            match_rotations: self.match_rotations,
+            algorithm: self.algorithm,
+            hash_size: self.hash_size,
+            update: true,
+            check_changes: !self.no_stat,
+            max_decodes: self.max_concurrent_decodes,
+            jobs: self.jobs.and_then(NonZeroUsize::new),
+            max_file_size: self.max_file_size,
+            checkpoint: self.checkpoint.and_then(NonZeroUsize::new),
+            dump: true,
+            require_dump: true,
+            threshold: None,
+            include_hardlinks: false,
+            against: None,
+            relative_db: self.relative_db,
+        }
+    }
+}
+
+impl ListArgs {
+    /// Pipeline options for scanning and searching.
+    fn options(&self) -> ScanOptions {
+        ScanOptions {
+            update: !self.no_update,
+            dump: !self.no_dump,
+            require_dump: self.require_dump,
+            threshold: Some(match self.similarity {
+                Some(x) => Threshold::Similarity(x),
+                None => Threshold::Distance(self.threshold),
+            }),
+            include_hardlinks: self.include_hardlinks,
+            against: self.against.clone(),
+            ..self.scan.options()
+        }
+    }
+}
+
+/// `scan` subcommand.
+fn scan(args: &ScanArgs) -> Result<()> {
+    if args.db_to_stdout(true) && args.progress == Progress::Ndjson {
+        Err(UsageError(
+            "--progress ndjson cannot be used when writing the database to \
+             stdout"
+                .into(),
+        ))?;
+    }
+    args.prepare();
+    scan_and_find(&args.options(), &*observer(args))?;
+    Ok(())
+}
+
+/// Run the pipeline for a command that searches for similar images.
+fn find(args: &ListArgs) -> Result<ScanOutcome> {
+    find_with(args, &*observer(&args.scan))
+}
+
+/// [`find`], reporting progress to `observer`.
+fn find_with(
+    args: &ListArgs,
+    observer: &dyn ScanObserver,
+) -> Result<ScanOutcome> {
+    args.scan.prepare();
+    let mut outcome = scan_and_find(&args.options(), observer)?;
+    if let Some(min) = args.min_pair_size {
+        let size = |x: &Path| fs::metadata(x).map_or(0, |m| m.len());
+        let before = outcome.duplicates.len();
+        outcome
+            .duplicates
+            .retain(|(a, b)| size(a).min(size(b)) >= min);
+        let hidden = before - outcome.duplicates.len();
+        if hidden > 0 {
+            eprintln!(
+                "Hiding {hidden} pairs with a file smaller than {min} bytes"
+            );
+        }
+    }
+    if args.only_new {
+        let added: HashSet<&Path> =
+            outcome.report.added.iter().map(PathBuf::as_path).collect();
+        let before = outcome.duplicates.len();
+        outcome
+            .duplicates
+            .retain(|(a, b)| added.contains(&**a) || added.contains(&**b));
+        let hidden = before - outcome.duplicates.len();
+        if hidden > 0 {
+            eprintln!("Hiding {hidden} pairs of previously scanned images");
+        }
+    }
+    if args.ignore_bursts || args.only_bursts {
+        let mut in_burst = outcome
+            .hashdb
+            .bursts(
+                &outcome.duplicates,
+                args.burst_window,
+                args.burst_max_distance,
+            )
+            .into_iter();
+        let before = outcome.duplicates.len();
+        outcome
+            .duplicates
+            .retain(|_| in_burst.next() == Some(args.only_bursts));
+        let hidden = before - outcome.duplicates.len();
+        if hidden > 0 {
+            eprintln!(
+                "Hiding {hidden} pairs {}",
+                match args.only_bursts {
+                    true => "outside of bursts",
+                    false => "of burst frames",
+                }
+            );
         }
+    }
+    Ok(outcome)
+}
+
+impl ActionArgs {
+    /// Remover that plans the actions for --emit-script, if given.
+    fn script(&self) -> Result<Option<ScriptRemover>> {
+        if self.emit_script.is_none() {
+            return Ok(None);
+        }
+        if self.auto.is_some() && self.move_to.is_none() && !self.delete {
+            Err(UsageError(
+                "--emit-script cannot use the trash; add --delete or \
+                 --move-to DIR"
+                    .into(),
+            ))?;
+        }
+        Ok(Some(ScriptRemover::new(
+            self.emit_script_format,
+            self.move_to.clone(),
+        )))
+    }
+
+    /// Write the planned actions to the --emit-script file.
+    fn write_script(
+        &self,
+        script: &ScriptRemover,
+        hashdb: &HashDB,
+    ) -> Result<()> {
+        let Some(file) = &self.emit_script else {
+            return Ok(());
+        };
+        let mut options = File::options();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o755);
+        let out = options
+            .open(file)
+            .map_err(|e| anyhow!("Could not write {file:?}: {e}"))?;
+        script
+            .write(BufWriter::new(out), hashdb)
+            .map_err(|e| anyhow!("Could not write {file:?}: {e}"))?;
+        eprintln!("Wrote {} actions to {file:?}", script.len());
+        Ok(())
+    }
+
+    /// Remover for the chosen destination and log.
+    fn remover(&self) -> Result<FileRemover> {
+        let destination = match (&self.move_to, self.delete) {
+            (Some(dir), _) => Destination::MoveTo(dir.clone()),
+            (None, true) => Destination::Delete,
+            #[cfg(feature = "trash")]
+            (None, false) => Destination::Trash,
+            #[cfg(not(feature = "trash"))]
+            (None, false) => Err(UsageError(
+                "This build has no trash support; use --move-to DIR or --delete"
+                    .into(),
+            ))?,
+        };
+        let log = match &self.log {
+            Some(file) => Some(file.clone()),
+            None => dirs::data_dir()
+                .map(|x| x.join("image-duplicate").join("actions.log")),
+        };
+        if self.merge_exif && !actions::has_exiftool() {
+            Err(UsageError("--merge-exif requires exiftool".into()))?;
+        }
+        Ok(FileRemover::new(destination, self.dry_run, log.as_deref())?
+            .merge_exif(self.merge_exif)
+            .preserve_metadata(!self.no_preserve))
+    }
+}
+
+/// Resolve duplicates automatically if --auto was given, and return the pairs
+/// that are left for review.
+fn auto_resolve(
+    args: &ActionArgs,
+    remover: &mut dyn Remover,
+    hashdb: &HashDB,
+    duplicates: Vec<Pair>,
+) -> Result<Vec<Pair>> {
+    let Some(policy) = args.auto else {
+        return Ok(duplicates);
     };
 
-    if !args.no_update {
-        eprintln!("Hashing images in {path:?}...");
-        match args.recursive {
-            true => hashdb.read_dir_recursive(path)?,
-            false => hashdb.read_dir(path)?,
+    let prefer = args
+        .prefer
+        .iter()
+        .map(|dir| {
+            dir.canonicalize().map_err(|e| {
+                UsageError(format!("Could not resolve --prefer {dir:?}: {e}"))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let removals = actions::resolve(
+        hashdb,
+        &duplicates,
+        policy,
+        args.auto_max_distance,
+        &prefer,
+    );
+    for (file, kept) in &removals {
+        remover.remove(file, kept, Reason::Auto(policy))?;
+    }
+
+    let removed: HashSet<&Path> = removals.iter().map(|(x, _)| &**x).collect();
+    Ok(duplicates
+        .into_iter()
+        .filter(|(a, b)| !removed.contains(&**a) && !removed.contains(&**b))
+        .collect())
+}
+
+/// Replace byte-identical images with hardlinks if --hardlink was given, and
+/// return the pairs that are not now the same file.
+#[cfg(unix)]
+fn link(
+    args: &ActionArgs,
+    hashdb: &HashDB,
+    duplicates: Vec<Pair>,
+    script: Option<&mut ScriptRemover>,
+) -> Result<Vec<Pair>> {
+    use std::os::unix::fs::MetadataExt;
+
+    if !args.hardlink {
+        return Ok(duplicates);
+    }
+
+    let planned = args.dry_run || script.is_some();
+    let summary = hardlink::hardlink(hashdb, &duplicates, args.dry_run, script);
+    eprintln!(
+        "{} {} files in {} groups of identical images, saving {} bytes",
+        match planned {
+            true => "Would link",
+            false => "Linked",
+        },
+        summary.links,
+        summary.groups,
+        summary.bytes
+    );
+    if summary.skipped > 0 {
+        eprintln!("Skipped {} files that could not be linked", summary.skipped);
+    }
+
+    let inode = |x: &Path| fs::metadata(x).map(|m| (m.dev(), m.ino())).ok();
+    Ok(duplicates
+        .into_iter()
+        .filter(|(a, b)| inode(a).is_none() || inode(a) != inode(b))
+        .collect())
+}
+
+/// Stand-in for [`link`] on systems without hardlink support.
+#[cfg(not(unix))]
+fn link(
+    args: &ActionArgs,
+    _hashdb: &HashDB,
+    duplicates: Vec<Pair>,
+    _script: Option<&mut ScriptRemover>,
+) -> Result<Vec<Pair>> {
+    match args.hardlink {
+        true => Err(UsageError("--hardlink is only supported on Unix".into()))?,
+        false => Ok(duplicates),
+    }
+}
+
+/// `list` subcommand.
+fn list(args: &ListArgs) -> Result<Status> {
+    if args.verify {
+        return verify(args);
+    }
+    args.output.check()?;
+    if args.scan.progress == Progress::Ndjson && args.output.file().is_none() {
+        Err(UsageError(
+            "--progress ndjson requires --output, since both write to stdout"
+                .into(),
+        ))?;
+    }
+    if args.scan.db_to_stdout(!args.no_dump)
+        && (args.output.file().is_none()
+            || args.scan.progress == Progress::Ndjson)
+    {
+        Err(UsageError(
+            "Writing the database to stdout requires --output and no \
+             --progress, since they would write to stdout too"
+                .into(),
+        ))?;
+    }
+
+    // Build the remover before scanning, so that a destination this build
+    // cannot handle is reported right away.
+    let mut script = args.actions.script()?;
+    let mut remover = match (args.actions.auto, &script) {
+        (Some(_), None) => Some(args.actions.remover()?),
+        _ => None,
+    };
+    let outcome = find(args)?;
+    let hashdb = &outcome.hashdb;
+    let duplicates = match (&mut script, &mut remover) {
+        (Some(script), _) => {
+            auto_resolve(&args.actions, script, hashdb, outcome.duplicates)?
         }
+        (None, Some(remover)) => {
+            auto_resolve(&args.actions, remover, hashdb, outcome.duplicates)?
+        }
+        (None, None) => outcome.duplicates,
+    };
+    let mut duplicates =
+        link(&args.actions, hashdb, duplicates, script.as_mut())?;
+    if let Some(script) = &script {
+        args.actions.write_script(script, hashdb)?;
+    }
+    args.actions.prune(&args.scan.paths)?;
+    if args.output.sort == Sort::Savings {
+        output::sort_by_savings(&mut duplicates);
     }
+    shuffle(args, &mut duplicates);
+    let duplicates = &limit(args, duplicates);
 
-    if !args.no_dump {
-        eprintln!("Dumping database to {db_file:?}...");
-        hashdb.to_file(&db_file)?;
+    args.output.write(&Listing {
+        threshold: outcome.threshold.unwrap_or(args.threshold),
+        roots: &args.scan.paths,
+        hashdb: &outcome.hashdb,
+        duplicates,
+        header: !args.output.no_header,
+        max_pairs: args.output.report_max_pairs,
+        savings: args.output.sort == Sort::Savings,
+        color: args.output.color(),
+    })?;
+    args.actions.exec(&outcome.hashdb, duplicates)?;
+
+    match duplicates.is_empty() {
+        true => Ok(Status::Clean),
+        false => Ok(Status::Duplicates),
     }
+}
 
-    eprintln!("Finding duplicate images...");
-    let duplicates = hashdb.find_duplicates(args.threshold);
+/// Write a file under a temporary name and rename it over `file`, so a failed
+/// run never leaves a partial file behind.
+fn write_atomic<T>(
+    file: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<T>,
+) -> Result<T> {
+    let mut tmp = file.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", process::id()));
+    let tmp = PathBuf::from(tmp);
+    let result = File::create(&tmp)
+        .and_then(|out| {
+            let mut out = BufWriter::new(out);
+            let value = write(&mut out)?;
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            Ok(value)
+        })
+        .and_then(|value| fs::rename(&tmp, file).map(|_| value));
+    result.map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        anyhow!("Could not write {file:?}: {e}")
+    })
+}
 
-    let gui = GUI::build(duplicates)?;
+impl OutputArgs {
+    /// Write a listing, or its totals with `--summary`, to the output file or
+    /// stdout, and the graph to the file given with `--export-dot`.
+    fn write(&self, listing: &Listing) -> Result<()> {
+        let format = self.format();
+        let write = |out: &mut dyn Write| match self.summary {
+            true => listing.write_summary(out, format),
+            false => listing.write(out, format),
+        };
+        match self.file() {
+            Some(file) => write_atomic(file, |out| write(out))?,
+            None => stdout_closed_ok(write(&mut io::stdout().lock()))?,
+        }
+
+        if let Some(file) = &self.export_dot {
+            let (images, groups) = write_atomic(file, |out| {
+                listing.write_dot(out, self.dot_min_cluster, self.dot_color)
+            })?;
+            eprintln!("Wrote {images} images in {groups} groups to {file:?}");
+        }
+        Ok(())
+    }
+
+    /// Check option combinations that clap cannot, before anything is
+    /// scanned.
+    fn check(&self) -> Result<()> {
+        if self.summary
+            && matches!(
+                self.format(),
+                Format::Html | Format::Nul | Format::Digikam
+            )
+        {
+            Err(UsageError(
+                "--summary supports the plain, terminal, json, and csv formats"
+                    .into(),
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Output file, or `None` for stdout.
+    fn file(&self) -> Option<&Path> {
+        self.output.as_deref().filter(|x| *x != Path::new("-"))
+    }
+
+    /// Output format, taking `--print0` into account. Without either, the
+    /// terminal format is used when writing to a terminal.
+    fn format(&self) -> Format {
+        match (self.print0, self.format) {
+            (true, _) => Format::Nul,
+            (false, Some(format)) => format,
+            (false, None) => match self.to_terminal() {
+                true => Format::Terminal,
+                false => Format::Plain,
+            },
+        }
+    }
+
+    /// Whether output goes to a terminal.
+    fn to_terminal(&self) -> bool {
+        self.file().is_none() && io::stdout().is_terminal()
+    }
+
+    /// Whether to use colors, following `--color` and NO_COLOR.
+    fn color(&self) -> bool {
+        match self.color {
+            Color::Always => true,
+            Color::Never => false,
+            Color::Auto => {
+                self.to_terminal()
+                    && env::var_os("NO_COLOR").is_none_or(|x| x.is_empty())
+            }
+        }
+    }
+}
+
+impl ActionArgs {
+    /// Remove the directories under `roots` left empty, if asked to.
+    fn prune(&self, roots: &[PathBuf]) -> Result<()> {
+        if self.prune_empty_dirs {
+            let mut removed = 0;
+            for root in roots {
+                removed += actions::prune_empty_dirs(
+                    root,
+                    self.prune_junk,
+                    self.dry_run,
+                )?;
+            }
+            match self.dry_run {
+                true => eprintln!("Would remove {removed} empty directories"),
+                false => eprintln!("Removed {removed} empty directories"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the `--exec` command for each pair.
+    fn exec(&self, hashdb: &HashDB, duplicates: &[Pair]) -> Result<()> {
+        let Some(hook) = &self.exec else {
+            return Ok(());
+        };
+        let pairs: Vec<(&Path, &Path, u32)> = duplicates
+            .iter()
+            .map(|(a, b)| {
+                let distance = hashdb.distance(a, b).unwrap_or_default();
+                (&**a, &**b, distance)
+            })
+            .collect();
+        let summary =
+            exec::run(hook, &pairs, self.parallel_exec, self.exec_fail_fast)?;
+        if summary.failed > 0 {
+            eprintln!(
+                "{} of {} --exec commands failed",
+                summary.failed, summary.ran
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Apply `--shuffle` to the pairs about to be handled.
+fn shuffle(args: &ListArgs, duplicates: &mut [Pair]) {
+    if !args.shuffle {
+        return;
+    }
+    let seed = args.seed.unwrap_or_else(clock_seed);
+    eprintln!("Shuffling pairs with seed {seed}");
+    output::shuffle(duplicates, seed);
+}
+
+/// A seed that differs from run to run, from the time and process ID.
+fn clock_seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_nanos() as u64);
+    nanos ^ u64::from(process::id()).rotate_left(32)
+}
+
+/// Apply `--limit` to the pairs about to be handled.
+fn limit(args: &ListArgs, mut duplicates: Vec<Pair>) -> Vec<Pair> {
+    if let Some(limit) = args.limit.filter(|&x| x < duplicates.len()) {
+        eprintln!("Reviewing {limit} of {} pairs", duplicates.len());
+        duplicates.truncate(limit);
+    }
+    duplicates
+}
+
+/// `--verify`: check the database against the images under each of the
+/// paths, and print what is out of date.
+fn verify(args: &ListArgs) -> Result<Status> {
+    let options = ScanOptions {
+        update: false,
+        dump: false,
+        threshold: None,
+        ..args.options()
+    };
+    let hashdb = scan_and_find(&options, &Stderr)?.hashdb;
+    let verify = VerifyOptions {
+        rehash_changed: args.verify_rehash,
+        sample: args.verify_sample.unwrap_or(0),
+        seed: clock_seed(),
+    };
+    // Leave out paths under others, so that no entry is checked twice.
+    let mut roots: Vec<PathBuf> = args
+        .scan
+        .paths
+        .iter()
+        .map(|x| x.canonicalize().unwrap_or_else(|_| x.clone()))
+        .collect();
+    roots.sort();
+    roots.dedup();
+    let mut report = VerifyReport::default();
+    for root in &roots {
+        if !roots.iter().any(|x| x != root && root.starts_with(x)) {
+            report += hashdb.verify(root, verify);
+        }
+    }
+    // Entries outside one path may be under another.
+    report.skipped = hashdb.len() - report.checked;
+
+    let write = |out: &mut dyn Write| -> io::Result<()> {
+        writeln!(out, "Checked:    {}", report.checked)?;
+        writeln!(out, "Skipped:    {}", report.skipped)?;
+        writeln!(out, "Rehashed:   {}", report.rehashed)?;
+        writeln!(out, "Missing:    {}", report.missing.len())?;
+        writeln!(out, "Changed:    {}", report.changed.len())?;
+        writeln!(out, "Unreadable: {}", report.unreadable.len())?;
+        for (what, names) in [
+            ("missing", &report.missing),
+            ("changed", &report.changed),
+            ("unreadable", &report.unreadable),
+        ] {
+            for name in names {
+                writeln!(out, "{what}\t{}", hashdb::escape_path(name))?;
+            }
+        }
+        Ok(())
+    };
+    stdout_closed_ok(write(&mut io::stdout().lock()))?;
+    Ok(match report.is_clean() {
+        true => Status::Clean,
+        false => Status::Stale,
+    })
+}
+
+/// Print every entry of a database to stdout.
+fn print_db(hashdb: &HashDB) -> Result<()> {
+    stdout_closed_ok(write!(io::stdout().lock(), "{hashdb}"))?;
+    Ok(())
+}
+
+/// Take stdout being closed by its reader, as with `list | head`, for the
+/// end of the output rather than a failure.
+fn stdout_closed_ok(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+/// `review` subcommand.
+fn review(args: &ReviewArgs) -> Result<Status> {
+    if args.print_db {
+        let options = ScanOptions {
+            update: false,
+            dump: false,
+            threshold: None,
+            ..args.list.scan.options()
+        };
+        print_db(&scan_and_find(&options, &Stderr)?.hashdb)?;
+        return Ok(Status::Clean);
+    }
+    if args.list.verify {
+        return verify(&args.list);
+    }
+
+    if args.no_gui || args.list.output.summary {
+        return list(&args.list);
+    }
+    if args.pairs_file.as_deref() == Some(Path::new("-"))
+        && args.decisions.as_deref() == Some(Path::new("-"))
+    {
+        Err(UsageError(
+            "--pairs-file - and --actions - cannot both read stdin".into(),
+        ))?;
+    }
+    #[cfg(feature = "gui")]
+    if args.decisions.is_none() && !gui::has_display() {
+        if args.pairs_file.is_some() {
+            Err(UsageError(
+                "No display found, since neither DISPLAY nor WAYLAND_DISPLAY \
+                 is set; add --actions to review the pairs without the GUI"
+                    .into(),
+            ))?;
+        }
+        eprintln!(
+            "No display found, since neither DISPLAY nor WAYLAND_DISPLAY is \
+             set; printing the pairs as with --no-gui instead"
+        );
+        return list(&args.list);
+    }
+
+    let output = &args.list.output;
+    if output.format.is_some()
+        || output.print0
+        || output.output.is_some()
+        || output.no_header
+        || output.sort != Sort::default()
+        || output.color != Color::default()
+        || output.export_dot.is_some()
+    {
+        Err(UsageError(
+            "--format, --print0, --output, --no-header, --sort, --color, and \
+             --export-dot require --no-gui"
+                .into(),
+        ))?;
+    }
+    if args.list.actions.hardlink
+        || args.list.actions.exec.is_some()
+        || args.list.actions.emit_script.is_some()
+    {
+        Err(UsageError(
+            "--hardlink, --exec, and --emit-script require --no-gui".into(),
+        ))?;
+    }
+
+    match &args.decisions {
+        Some(file) => review_script(args, file),
+        None => review_gui(args),
+    }
+}
+
+/// Pairs to review from a scan: those left by automatic resolution, shuffled
+/// and limited as asked for.
+fn review_queue(
+    args: &ListArgs,
+    remover: &mut dyn Remover,
+    outcome: ScanOutcome,
+) -> Result<Vec<Pair>> {
+    let mut duplicates = auto_resolve(
+        &args.actions,
+        remover,
+        &outcome.hashdb,
+        outcome.duplicates,
+    )?;
+    shuffle(args, &mut duplicates);
+    Ok(limit(args, duplicates))
+}
+
+/// Pairs to review from --pairs-file, shuffled and limited as asked for.
+fn pairs_file_queue(args: &ListArgs, file: &Path) -> Result<Vec<Pair>> {
+    let mut duplicates = review::read_pairs(open_input(file)?)?;
+    shuffle(args, &mut duplicates);
+    Ok(limit(args, duplicates))
+}
+
+/// Open `file` for reading, or stdin if it is `-`.
+fn open_input(file: &Path) -> Result<Box<dyn BufRead>> {
+    Ok(match file == Path::new("-") {
+        true => Box::new(io::stdin().lock()),
+        false => Box::new(BufReader::new(
+            File::open(file)
+                .map_err(|e| anyhow!("Could not open {file:?}: {e}"))?,
+        )),
+    })
+}
+
+/// Review similar images with decisions read from `file` instead of the GUI,
+/// with the same removals, action log, and summary.
+fn review_script(args: &ReviewArgs, file: &Path) -> Result<Status> {
+    if file == Path::new("-")
+        && args.pairs_file.is_none()
+        && args.list.scan.db.as_deref() == Some(Path::new("-"))
+        && !args.list.scan.rebuild
+    {
+        Err(UsageError(
+            "--actions - and --db - cannot both read stdin".into(),
+        ))?;
+    }
+    let input = open_input(file)?;
+    let mut remover = args.list.actions.remover()?;
+    let duplicates = match &args.pairs_file {
+        Some(pairs) => pairs_file_queue(&args.list, pairs)?,
+        None => {
+            let outcome = find(&args.list)?;
+            review_queue(&args.list, &mut remover, outcome)?
+        }
+    };
+
+    let mut review = Review::new(duplicates);
+    let result = review::run_script(&mut review, &mut remover, input);
+    eprintln!("{}", review.summary());
+    result?;
+    args.list.actions.prune(&args.list.scan.paths)?;
+    Ok(Status::Clean)
+}
+
+/// Review similar images in the GUI. The window comes up right away and
+/// shows the progress of the scan, which runs on another thread.
+#[cfg(feature = "gui")]
+fn review_gui(args: &ReviewArgs) -> Result<Status> {
+    let mut remover = args.list.actions.remover()?;
+
+    let start = std::time::Instant::now();
+    let (mut gui, progress) = GUI::scanning(observer(&args.list.scan))?;
+    if args.list.scan.timings {
+        eprintln!("GUI startup took {:.3}s", start.elapsed().as_secs_f64());
+    }
+    let (complete, scan) = std::thread::scope(|s| {
+        let scan = s.spawn(|| {
+            let result = match &args.pairs_file {
+                Some(file) => pairs_file_queue(&args.list, file)
+                    .map(|x| (x, false, HashSet::new())),
+                None => find_with(&args.list, &progress).and_then(|outcome| {
+                    if progress.cancelled() {
+                        return Ok((Vec::new(), false, HashSet::new()));
+                    }
+                    let trim_borders = outcome.hashdb.header().trim_borders;
+                    let exact = outcome
+                        .duplicates
+                        .iter()
+                        .filter(|(a, b)| outcome.hashdb.identical(a, b))
+                        .cloned()
+                        .collect();
+                    let duplicates =
+                        review_queue(&args.list, &mut remover, outcome)?;
+                    Ok((duplicates, trim_borders, exact))
+                }),
+            };
+            progress.done();
+            result
+        });
+        let complete = gui.wait_for_scan();
+        (complete, scan.join())
+    });
+    let (duplicates, trim_borders, exact) = match scan {
+        Ok(duplicates) => duplicates?,
+        Err(e) => std::panic::resume_unwind(e),
+    };
+    if !complete {
+        eprintln!("Scan cancelled");
+        return Ok(Status::Clean);
+    }
+
+    gui.review(duplicates, Box::new(remover), trim_borders, exact);
     gui.run()?;
+    args.list.actions.prune(&args.list.scan.paths)?;
+
+    Ok(Status::Clean)
+}
+
+/// Stand-in for [`review_gui`] when built without the GUI.
+#[cfg(not(feature = "gui"))]
+fn review_gui(_args: &ReviewArgs) -> Result<Status> {
+    Err(UsageError(
+        "This build has no GUI; use --no-gui or the list command".into(),
+    ))?
+}
+
+/// `compare` subcommand.
+fn compare(args: &CompareArgs) -> Result<Status> {
+    let hashdb = match &args.db {
+        Some(db) => Some(pipeline::resolve(HashDB::from_file(db)?, db)),
+        None => None,
+    };
+    let header = match &hashdb {
+        Some(hashdb) => hashdb.header().clone(),
+        None => Header {
+            trim_borders: args.trim_borders,
+            algorithm: args.algorithm.unwrap_or_default(),
+            hash_size: args.hash_size.unwrap_or(DEFAULT_HASH_SIZE),
+            ..Header::default()
+        },
+    };
+    let hash = |file: &PathBuf| -> Result<ImageHash> {
+        let stored = file
+            .canonicalize()
+            .ok()
+            .and_then(|x| hashdb.as_ref()?.get(x).cloned());
+        match stored {
+            Some(hash) => Ok(hash),
+            None => Ok(hash_file(file, &header)?.1),
+        }
+    };
+    let hash_1 = hash(&args.image_1)?;
+    let distance = hash_1.dist(&hash(&args.image_2)?);
+
+    let mut out = io::stdout().lock();
+    writeln!(out, "Distance:  {distance}")?;
+    writeln!(out, "Hash bits: {}", hash_1.bits())?;
+    let Some(threshold) = args.threshold else {
+        return Ok(Status::Clean);
+    };
+    match distance < threshold {
+        true => {
+            writeln!(out, "Verdict:   similar (threshold {threshold})")?;
+            Ok(Status::Clean)
+        }
+        false => {
+            writeln!(out, "Verdict:   not similar (threshold {threshold})")?;
+            Ok(Status::Different)
+        }
+    }
+}
+
+/// `db` subcommands.
+fn db(cmd: &DbCommand) -> Result<Status> {
+    match cmd {
+        DbCommand::Merge { db, others } => {
+            let mut hashdb = pipeline::resolve(pipeline::load(db)?, db);
+            for other in others {
+                eprintln!("Merging {other:?}...");
+                let other_db =
+                    pipeline::resolve(HashDB::from_file(other)?, other);
+                hashdb
+                    .check_compatible(&other_db)
+                    .map_err(|e| anyhow!("{db:?} and {other:?}: {e}"))?;
+                let merge = hashdb.merge(other_db);
+                eprintln!(
+                    "{} entries added, {} replaced by newer ones, {} kept",
+                    merge.added, merge.replaced, merge.kept
+                );
+            }
+            eprintln!("Dumping database to {db:?}...");
+            hashdb.to_file(db)?;
+        }
+        DbCommand::Prune { db } => {
+            let mut hashdb = pipeline::resolve(pipeline::load(db)?, db);
+            let removed = hashdb.prune();
+            eprintln!("Removed {removed} entries");
+            eprintln!("Dumping database to {db:?}...");
+            hashdb.to_file(db)?;
+        }
+        DbCommand::Stats { db } => {
+            let hashdb = pipeline::resolve(HashDB::from_file(db)?, db);
+            let mut out = io::stdout().lock();
+            writeln!(out, "File:      {}", db.display())?;
+            let size = fs::metadata(db)
+                .map_err(|e| anyhow!("Could not read {db:?}: {e}"))?
+                .len();
+            writeln!(out, "Size:      {size} bytes")?;
+            writeln!(out, "Entries:   {}", hashdb.len())?;
+            writeln!(out, "Missing:   {}", hashdb.missing().count())?;
+            let header = hashdb.header();
+            let yes_no = |x: bool| match x {
+                true => "yes",
+                false => "no",
+            };
+            writeln!(out, "Algorithm: {}", header.algorithm)?;
+            writeln!(
+                out,
+                "Hash size: {}x{}",
+                header.hash_size, header.hash_size
+            )?;
+            writeln!(out, "Trimmed:   {}", yes_no(header.trim_borders))?;
+            writeln!(out, "Rotations: {}", yes_no(header.match_rotations))?;
+            writeln!(out, "Relative:  {}", yes_no(header.relative))?;
+            match &header.scan {
+                Some(scan) => {
+                    for root in scan.roots() {
+                        writeln!(out, "Root:      {}", root.display())?;
+                    }
+                    writeln!(out, "Recursive: {}", yes_no(scan.recursive))?;
+                    if scan.recursive {
+                        if let Some(depth) = scan.max_depth {
+                            writeln!(out, "Max depth: {depth}")?;
+                        }
+                        writeln!(
+                            out,
+                            "One FS:    {}",
+                            yes_no(scan.one_file_system)
+                        )?;
+                    }
+                    match scan.threshold {
+                        Some(x) => writeln!(out, "Threshold: {x}")?,
+                        None => writeln!(out, "Threshold: none")?,
+                    }
+                    for pattern in &scan.exclude {
+                        writeln!(out, "Exclude:   {pattern}")?;
+                    }
+                    if let Some(extensions) = &scan.extensions {
+                        writeln!(out, "Images:    {}", extensions.join(","))?;
+                    }
+                }
+                None => writeln!(out, "Root:      not stored")?,
+            }
+        }
+        DbCommand::Export { db } => {
+            print_db(&pipeline::resolve(HashDB::from_file(db)?, db))?
+        }
+        DbCommand::Cross(args) => return cross(args),
+    }
+    Ok(Status::Clean)
+}
+
+/// `db cross` subcommand.
+fn cross(args: &CrossArgs) -> Result<Status> {
+    args.output.check()?;
+    let hashdb_1 =
+        pipeline::resolve(HashDB::from_file(&args.db_1)?, &args.db_1);
+    let hashdb_2 =
+        pipeline::resolve(HashDB::from_file(&args.db_2)?, &args.db_2);
+    let mut duplicates = hashdb_1
+        .find_cross(&hashdb_2, args.threshold)
+        .map_err(|e| anyhow!("{:?} and {:?}: {e}", args.db_1, args.db_2))?;
+    if let Some(bits) = hashdb_1.hash_size() {
+        check_threshold(args.threshold, bits)
+            .map_err(|e| UsageError(format!("Invalid threshold: {e}")))?;
+    }
+    if args.output.sort == Sort::Savings {
+        output::sort_by_savings(&mut duplicates);
+    }
+
+    // The listing looks up distances by path, so it needs both databases.
+    let mut hashdb = hashdb_1;
+    hashdb.merge(hashdb_2);
+    args.output.write(&Listing {
+        threshold: args.threshold,
+        roots: std::slice::from_ref(&args.db_1),
+        hashdb: &hashdb,
+        duplicates: &duplicates,
+        header: !args.output.no_header,
+        max_pairs: args.output.report_max_pairs,
+        savings: args.output.sort == Sort::Savings,
+        color: args.output.color(),
+    })?;
 
+    match duplicates.is_empty() {
+        true => Ok(Status::Clean),
+        false => Ok(Status::Duplicates),
+    }
+}
+
+/// `completions` subcommand.
+fn completions(shell: Shell) -> Result<()> {
+    let mut cmd = Args::command();
+    let name = cmd.get_name().to_owned();
+    clap_complete::generate(shell, &mut cmd, name, &mut io::stdout().lock());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+
+    /// Parse a command line as the binary does, without the environment or
+    /// a configuration file.
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        let args = std::iter::once("image-duplicate")
+            .chain(args.iter().copied())
+            .map(OsString::from);
+        Args::try_parse_from(with_default_command(args))
+    }
+
+    fn review(args: &[&str]) -> ReviewArgs {
+        match parse(args).unwrap().command {
+            Command::Review(args) => args,
+            x => panic!("{x:?}"),
+        }
+    }
+
+    #[test]
+    fn default_command_is_review() {
+        let args = review(&["photos"]);
+        assert_eq!(args.list.scan.paths, [PathBuf::from("photos")]);
+        assert!(!args.no_gui && !args.list.scan.recursive);
+
+        let args = review(&["-R", "--threshold", "4", "a", "b"]);
+        assert_eq!(args.list.scan.paths.len(), 2);
+        assert!(args.list.scan.recursive);
+        assert_eq!(args.list.threshold, 4);
+
+        assert!(review(&["--no-gui", "photos"]).no_gui);
+        assert!(review(&["review", "--list", "photos"]).no_gui);
+        assert_eq!(review(&["review", "photos"]).list.scan.paths.len(), 1);
+    }
+
+    #[test]
+    fn subcommands() {
+        let parsed = |args| parse(args).unwrap().command;
+        assert!(matches!(parsed(&["scan", "photos"]), Command::Scan(_)));
+        assert!(matches!(parsed(&["list", "photos"]), Command::List(_)));
+        assert!(matches!(
+            parsed(&["compare", "a.png", "b.png"]),
+            Command::Compare(_)
+        ));
+        assert!(matches!(
+            parsed(&["db", "stats", "photos.db"]),
+            Command::Db(DbCommand::Stats { .. })
+        ));
+        assert!(matches!(
+            parsed(&["db", "merge", "a.db", "b.db"]),
+            Command::Db(DbCommand::Merge { .. })
+        ));
+        // With --db, the paths default to those last scanned.
+        assert!(matches!(
+            parsed(&["list", "--db", "x.db"]),
+            Command::List(_)
+        ));
+    }
+
+    #[test]
+    fn usage_errors() {
+        let kind = |args| parse(args).unwrap_err().kind();
+        assert_eq!(
+            kind(&[]),
+            ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand
+        );
+        assert_eq!(kind(&["--help"]), ErrorKind::DisplayHelp);
+        assert_eq!(kind(&["--version"]), ErrorKind::DisplayVersion);
+        assert_eq!(kind(&["list"]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(
+            kind(&["compare", "a.png"]),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            kind(&["db", "merge", "a.db"]),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(kind(&["-x", "photos"]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(
+            kind(&["--no-gui", "--print-db", "photos"]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(
+            kind(&["list", "--bogus", "photos"]),
+            ErrorKind::UnknownArgument
+        );
+        assert_eq!(
+            kind(&["list", "--threshold", "many", "photos"]),
+            ErrorKind::ValueValidation
+        );
+    }
+}
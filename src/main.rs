@@ -14,14 +14,22 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use clap::Parser;
 use image_duplicate::{self, Args};
-use std::process;
+use std::process::ExitCode;
 
-fn main() {
-    let args = Args::parse();
-    if let Err(e) = image_duplicate::run(&args) {
-        eprintln!("{e}");
-        process::exit(1);
+fn main() -> ExitCode {
+    let args = match Args::parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match image_duplicate::run(&args) {
+        Ok(status) => status.exit_code(),
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
     }
 }
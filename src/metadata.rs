@@ -0,0 +1,160 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Telling apart images that have the same pixels but different metadata,
+//! such as a photo and the copy a messenger app exported with its EXIF tags
+//! stripped. [`metadata_only`] forms the main interface.
+
+use crate::hashdb::open_image;
+use image::{DynamicImage, GenericImageView, imageops::FilterType};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap, fmt::Display, fs::File, io::BufReader, path::Path,
+};
+
+/// Most pixels compared in full. Larger images are shrunk to this many first,
+/// which identical images survive unchanged, so that comparing stays cheap.
+const MAX_PIXELS: u64 = 4_000_000;
+
+/// Metadata that can differ between images with the same pixels.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    /// When the image was taken or changed.
+    Date,
+    /// Whether the image has a location.
+    Gps,
+    /// How the image is to be rotated or mirrored.
+    Orientation,
+    /// Any other EXIF tag.
+    Other,
+}
+
+impl Field {
+    /// Name of the field in structured output.
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Date => "date",
+            Field::Gps => "gps",
+            Field::Orientation => "orientation",
+            Field::Other => "other",
+        }
+    }
+}
+
+impl Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Field::Date => "date",
+            Field::Gps => "GPS",
+            Field::Orientation => "orientation",
+            Field::Other => "other EXIF",
+        })
+    }
+}
+
+/// EXIF fields of an image's main IFD and GPS IFD, as text by tag, sorted so
+/// that images can be compared. Empty if the image has none.
+fn exif_fields(file: &Path) -> BTreeMap<String, String> {
+    let Ok(input) = File::open(file) else {
+        return BTreeMap::new();
+    };
+    let Ok(exif) =
+        exif::Reader::new().read_from_container(&mut BufReader::new(input))
+    else {
+        return BTreeMap::new();
+    };
+    exif.fields()
+        .filter(|x| x.ifd_num == exif::In::PRIMARY)
+        .map(|x| (x.tag.to_string(), x.display_value().to_string()))
+        .collect()
+}
+
+/// Which kind of metadata an EXIF tag is.
+fn field_of(tag: &str) -> Field {
+    match tag {
+        "DateTime"
+        | "DateTimeOriginal"
+        | "DateTimeDigitized"
+        | "SubSecTime"
+        | "SubSecTimeOriginal"
+        | "SubSecTimeDigitized"
+        | "OffsetTime"
+        | "OffsetTimeOriginal"
+        | "OffsetTimeDigitized" => Field::Date,
+        "Orientation" => Field::Orientation,
+        x if x.starts_with("GPS") => Field::Gps,
+        _ => Field::Other,
+    }
+}
+
+/// Decode an image, shrunk if it has more than [`MAX_PIXELS`].
+fn decode(file: &Path) -> Option<DynamicImage> {
+    let image = open_image(file).ok()?;
+    let (w, h) = image.dimensions();
+    let pixels = w as u64 * h as u64;
+    if pixels <= MAX_PIXELS {
+        return Some(image);
+    }
+    let scale = (MAX_PIXELS as f64 / pixels as f64).sqrt();
+    let (w, h) = ((w as f64 * scale) as u32, (h as f64 * scale) as u32);
+    Some(image.resize_exact(w.max(1), h.max(1), FilterType::Triangle))
+}
+
+/// Whether two images have the same pixels. Images with more than
+/// [`MAX_PIXELS`] are compared shrunk, which can in principle miss a
+/// difference of a few pixels.
+fn same_pixels(file_1: &Path, file_2: &Path) -> bool {
+    let same_size = match (
+        image::image_dimensions(file_1),
+        image::image_dimensions(file_2),
+    ) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    };
+    if !same_size {
+        return false;
+    }
+    let (Some(a), Some(b)) = (decode(file_1), decode(file_2)) else {
+        return false;
+    };
+    match a.color() == b.color() {
+        true => a.as_bytes() == b.as_bytes(),
+        false => a.to_rgba16().as_raw() == b.to_rgba16().as_raw(),
+    }
+}
+
+/// Check whether two images differ only in their metadata. Returns `None` if
+/// their pixels differ or either cannot be read, and otherwise the kinds of
+/// EXIF metadata that differ, which may be none if the difference lies in
+/// metadata other than EXIF or in how the pixels were compressed. Identical
+/// pixels always get identical hashes, so only pairs at distance 0 are worth
+/// checking.
+pub fn metadata_only(file_1: &Path, file_2: &Path) -> Option<Vec<Field>> {
+    if !same_pixels(file_1, file_2) {
+        return None;
+    }
+    let (a, b) = (exif_fields(file_1), exif_fields(file_2));
+    let mut fields: Vec<Field> = a
+        .iter()
+        .filter(|(tag, value)| b.get(*tag) != Some(value))
+        .chain(b.iter().filter(|(tag, _)| !a.contains_key(*tag)))
+        .map(|(tag, _)| field_of(tag))
+        .collect();
+    fields.sort_unstable();
+    fields.dedup();
+    Some(fields)
+}
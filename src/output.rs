@@ -0,0 +1,1095 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Formats for printing duplicate pairs without the GUI. [`Listing`] collects
+//! everything needed to write the results in any [`Format`].
+
+use crate::{
+    actions::{groups, resized_variant},
+    hashdb::{HashDB, Orientation, Pair, escape_path, open_image},
+    metadata::{Field, metadata_only},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use clap::ValueEnum;
+use image::ImageFormat;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Cursor, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// Largest width and height of a thumbnail in an HTML report.
+const THUMBNAIL_SIZE: u32 = 160;
+
+/// Output format for duplicate pairs.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum Format {
+    /// Tab-separated paths, one pair per line
+    #[default]
+    Plain,
+    /// A single JSON document
+    Json,
+    /// Comma-separated values with distances and metadata
+    Csv,
+    /// A self-contained HTML report with thumbnails
+    Html,
+    /// NUL-terminated paths, two per pair
+    Nul,
+    /// Aligned columns with colors, grouped, for reading in a terminal
+    Terminal,
+    /// Groups of similar images as CSV, for tagging in digiKam
+    Digikam,
+}
+
+/// When to use colors in the terminal format.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum Color {
+    /// When writing to a terminal and NO_COLOR is not set
+    #[default]
+    Auto,
+    /// Always
+    Always,
+    /// Never
+    Never,
+}
+
+/// ANSI escape sequences for the terminal format.
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+
+/// Order of duplicate pairs in the output.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum Sort {
+    /// Closest pairs first
+    #[default]
+    Distance,
+    /// Pairs that would free the most space first
+    Savings,
+}
+
+/// Space freed by removing one image of a pair: the size of the smaller file,
+/// or `None` if either size is unknown.
+pub(crate) fn savings(img_1: &Path, img_2: &Path) -> Option<u64> {
+    let size = |x: &Path| fs::metadata(x).ok().map(|m| m.len());
+    Some(size(img_1)?.min(size(img_2)?))
+}
+
+/// Sort pairs by [`savings`], largest first, with unknown savings last. The
+/// sort is stable, so pairs with equal savings stay in distance order.
+pub(crate) fn sort_by_savings(duplicates: &mut [Pair]) {
+    duplicates.sort_by_cached_key(|(a, b)| std::cmp::Reverse(savings(a, b)));
+}
+
+/// Shuffle items, such as pairs, into an order that depends only on `seed`.
+/// This uses SplitMix64 rather than a random number crate; it is plenty for
+/// spreading out a review queue or picking a sample.
+pub(crate) fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+    // Fisher-Yates. The modulo bias is negligible for queue lengths.
+    for i in (1..items.len()).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+/// Information about one image of a duplicate pair.
+#[derive(Debug, Serialize)]
+struct FileInfo {
+    path: String,
+    lossy: bool,
+    size: Option<u64>,
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+impl FileInfo {
+    /// Information on an image, from the database if it has it, or else from
+    /// the file.
+    fn new(path: &Path, hashdb: &HashDB) -> Self {
+        let (size, dims) = match hashdb.metadata(path) {
+            Some(x) => (Some(x.size), Some((x.width, x.height))),
+            None => (
+                fs::metadata(path).ok().map(|m| m.len()),
+                image::image_dimensions(path).ok(),
+            ),
+        };
+        Self {
+            path: path.to_string_lossy().into_owned(),
+            // Paths that are not valid UTF-8 are shown with replacement
+            // characters, which name no file.
+            lossy: path.to_str().is_none(),
+            size,
+            width: dims.map(|d| d.0),
+            height: dims.map(|d| d.1),
+        }
+    }
+}
+
+impl FileInfo {
+    fn dimensions(&self) -> String {
+        match (self.width, self.height) {
+            (Some(w), Some(h)) => format!("{w}x{h}"),
+            _ => String::new(),
+        }
+    }
+}
+
+/// A scanned directory for JSON output, made absolute if possible.
+fn root_name(root: &Path) -> String {
+    match root.canonicalize() {
+        Ok(p) => p.to_string_lossy().into_owned(),
+        Err(_) => root.to_string_lossy().into_owned(),
+    }
+}
+
+/// Escape text for HTML.
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A small JPEG of an image as a `data:` URL, or `None` if the image cannot
+/// be read.
+fn thumbnail(path: &Path) -> Option<String> {
+    let image = open_image(path).ok()?;
+    let thumb = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+    drop(image);
+    let mut buf = Cursor::new(Vec::new());
+    thumb.write_to(&mut buf, ImageFormat::Jpeg).ok()?;
+    Some(format!(
+        "data:image/jpeg;base64,{}",
+        STANDARD.encode(buf.into_inner())
+    ))
+}
+
+/// Quote a CSV field if it contains anything that would break the row.
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_owned(),
+    }
+}
+
+/// Quote text as a Graphviz ID. Backslashes are doubled so that labels show
+/// them as they are rather than as escapes.
+fn dot_id(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Length of the directory two paths share, including its trailing
+/// separator.
+fn shared_dir(a: &str, b: &str) -> usize {
+    let common = a
+        .char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map_or(a.len().min(b.len()), |((i, _), _)| i);
+    a[..common]
+        .rfind(std::path::MAIN_SEPARATOR)
+        .map_or(0, |i| i + 1)
+}
+
+fn opt_string<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// One duplicate pair with its metadata.
+#[derive(Debug, Serialize)]
+struct Record {
+    distance: Option<u32>,
+    /// Whether the files are byte-for-byte the same.
+    exact: bool,
+    /// How the right image is turned to match the left one, if the database
+    /// matches rotations.
+    orientation: Option<Orientation>,
+    savings: Option<u64>,
+    files: [FileInfo; 2],
+    /// Index of the file that is a downscaled copy of the other, if any.
+    resized: Option<usize>,
+    /// The kinds of metadata that differ, if the pixels are the same.
+    metadata_only: Option<Vec<Field>>,
+}
+
+/// Top-level JSON document.
+#[derive(Debug, Serialize)]
+struct Document<'a> {
+    threshold: u32,
+    root: String,
+    roots: Vec<String>,
+    pairs: &'a [Record],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_savings: Option<u64>,
+}
+
+/// How [`Totals::reclaimable_bytes`] is estimated, printed along with it.
+const RECLAIMABLE_METHOD: &str = "sum of the file sizes in each group except \
+    the largest, as if only the largest file of each group were kept; files \
+    of unknown size are left out, and hardlinked copies or pairs that are not \
+    true duplicates make the real figure smaller";
+
+/// Totals over the groups of similar images, for `--summary`.
+#[derive(Debug, Serialize)]
+struct Totals {
+    threshold: u32,
+    root: String,
+    roots: Vec<String>,
+    groups: usize,
+    images: usize,
+    /// Images beyond the first of each group.
+    redundant_files: usize,
+    reclaimable_bytes: u64,
+    /// Images whose size could not be read.
+    unknown_sizes: usize,
+    method: &'static str,
+}
+
+/// Duplicate pairs found in a scan, ready to be written out.
+#[derive(Debug)]
+pub struct Listing<'a> {
+    pub threshold: u32,
+    /// Directories scanned, the first one standing for all of them where
+    /// only one fits.
+    pub roots: &'a [PathBuf],
+    pub hashdb: &'a HashDB,
+    pub duplicates: &'a [Pair],
+    pub header: bool,
+    /// Most pairs to include in an HTML report.
+    pub max_pairs: usize,
+    /// Show the space each pair would free, and the total.
+    pub savings: bool,
+    /// Use colors in the terminal format.
+    pub color: bool,
+}
+
+impl Listing<'_> {
+    /// Write the listing in the given format.
+    pub fn write<W: Write>(
+        &self,
+        mut out: W,
+        format: Format,
+    ) -> io::Result<()> {
+        match format {
+            Format::Plain if self.savings => {
+                for (img_1, img_2) in self.duplicates {
+                    writeln!(
+                        out,
+                        "{}\t{}\t{}\t{}",
+                        escape_path(img_1),
+                        escape_path(img_2),
+                        opt_string(self.hashdb.distance(img_1, img_2)),
+                        opt_string(savings(img_1, img_2))
+                    )?;
+                }
+                writeln!(out, "Potential savings: {} bytes", self.total())?;
+            }
+            Format::Plain => {
+                for (img_1, img_2) in self.duplicates {
+                    write!(
+                        out,
+                        "{}\t{}",
+                        escape_path(img_1),
+                        escape_path(img_2)
+                    )?;
+                    match self.hashdb.distance(img_1, img_2) {
+                        Some(x) => writeln!(out, "\t{x}")?,
+                        None => writeln!(out)?,
+                    }
+                }
+            }
+            Format::Json => {
+                let pairs = self.records(true);
+                let doc = Document {
+                    threshold: self.threshold,
+                    root: self.root_name(),
+                    roots: self.roots.iter().map(|x| root_name(x)).collect(),
+                    pairs: &pairs,
+                    total_savings: self
+                        .savings
+                        .then(|| pairs.iter().filter_map(|x| x.savings).sum()),
+                };
+                serde_json::to_writer_pretty(&mut out, &doc)?;
+                writeln!(out)?;
+            }
+            Format::Csv => {
+                if self.header {
+                    writeln!(
+                        out,
+                        "left,right,distance,left_size,right_size,\
+                         left_dimensions,right_dimensions,resized,\
+                         metadata_only,exact,orientation{}",
+                        match self.savings {
+                            true => ",savings",
+                            false => "",
+                        }
+                    )?;
+                }
+                for Record {
+                    distance,
+                    exact,
+                    orientation,
+                    savings,
+                    files: [l, r],
+                    resized,
+                    metadata_only,
+                } in self.records(true)
+                {
+                    write!(
+                        out,
+                        "{},{},{},{},{},{},{},{},{},{exact},{}",
+                        csv_field(&l.path),
+                        csv_field(&r.path),
+                        opt_string(distance),
+                        opt_string(l.size),
+                        opt_string(r.size),
+                        l.dimensions(),
+                        r.dimensions(),
+                        match resized {
+                            Some(0) => "left",
+                            Some(_) => "right",
+                            None => "",
+                        },
+                        match metadata_only {
+                            Some(x) if x.is_empty() => "none".into(),
+                            Some(x) => x
+                                .iter()
+                                .map(|x| x.name())
+                                .collect::<Vec<_>>()
+                                .join(" "),
+                            None => String::new(),
+                        },
+                        opt_string(orientation),
+                    )?;
+                    match self.savings {
+                        true => writeln!(out, ",{}", opt_string(savings))?,
+                        false => writeln!(out)?,
+                    }
+                }
+                // A total row would break the table.
+                if self.savings {
+                    eprintln!("Potential savings: {} bytes", self.total());
+                }
+            }
+            Format::Html => self.write_html(&mut out)?,
+            Format::Terminal => self.write_terminal(&mut out)?,
+            Format::Digikam => self.write_digikam(&mut out)?,
+            Format::Nul => {
+                for (img_1, img_2) in self.duplicates {
+                    for img in [img_1, img_2] {
+                        out.write_all(img.as_os_str().as_encoded_bytes())?;
+                        out.write_all(b"\0")?;
+                    }
+                }
+                if self.savings {
+                    eprintln!("Potential savings: {} bytes", self.total());
+                }
+            }
+        }
+        out.flush()
+    }
+
+    /// Write only the totals over the groups of similar images, with how the
+    /// reclaimable space was estimated. JSON and CSV carry the same figures;
+    /// any other format gets lines for reading.
+    pub fn write_summary<W: Write>(
+        &self,
+        mut out: W,
+        format: Format,
+    ) -> io::Result<()> {
+        let totals = self.totals();
+        match format {
+            Format::Json => {
+                serde_json::to_writer_pretty(&mut out, &totals)?;
+                writeln!(out)?;
+            }
+            Format::Csv => {
+                if self.header {
+                    writeln!(
+                        out,
+                        "threshold,groups,images,redundant_files,\
+                         reclaimable_bytes,unknown_sizes"
+                    )?;
+                }
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{}",
+                    totals.threshold,
+                    totals.groups,
+                    totals.images,
+                    totals.redundant_files,
+                    totals.reclaimable_bytes,
+                    totals.unknown_sizes
+                )?;
+            }
+            _ => {
+                writeln!(out, "Threshold:       {}", totals.threshold)?;
+                writeln!(out, "Groups:          {}", totals.groups)?;
+                writeln!(out, "Images:          {}", totals.images)?;
+                writeln!(out, "Redundant files: {}", totals.redundant_files)?;
+                writeln!(
+                    out,
+                    "Reclaimable:     {} bytes (estimated)",
+                    totals.reclaimable_bytes
+                )?;
+                if totals.unknown_sizes > 0 {
+                    writeln!(out, "Unknown sizes:   {}", totals.unknown_sizes)?;
+                }
+                writeln!(out, "Estimate: {}.", totals.method)?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Totals over the groups of images connected by the pairs, as in
+    /// automatic resolution.
+    fn totals(&self) -> Totals {
+        let pairs: Vec<(&Path, &Path)> =
+            self.duplicates.iter().map(|(a, b)| (&**a, &**b)).collect();
+        let groups = groups(&pairs);
+        let sizes: Vec<Vec<u64>> = groups
+            .par_iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .filter_map(|x| fs::metadata(x).ok().map(|m| m.len()))
+                    .collect()
+            })
+            .collect();
+        let images = groups.iter().map(Vec::len).sum::<usize>();
+        let known = sizes.iter().map(Vec::len).sum::<usize>();
+        let reclaimable_bytes = sizes
+            .iter()
+            .map(|x| x.iter().sum::<u64>() - x.iter().max().unwrap_or(&0))
+            .sum();
+        Totals {
+            threshold: self.threshold,
+            root: self.root_name(),
+            roots: self.roots.iter().map(|x| root_name(x)).collect(),
+            groups: groups.len(),
+            images,
+            redundant_files: images - groups.len(),
+            reclaimable_bytes,
+            unknown_sizes: images - known,
+            method: RECLAIMABLE_METHOD,
+        }
+    }
+
+    /// The first root for JSON output, made absolute if possible.
+    fn root_name(&self) -> String {
+        self.roots.first().map(|x| root_name(x)).unwrap_or_default()
+    }
+
+    /// Write aligned columns of distance, paths, and savings for reading in
+    /// a terminal. Pairs are grouped as in automatic resolution, with groups
+    /// in order of their first pair, and a blank line between groups. Pairs
+    /// of identical files show `=` for their distance. With `color`,
+    /// distances are colored by closeness, and the directory the two paths
+    /// of a pair share is dimmed.
+    fn write_terminal<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let paint = |code: &str, text: &str| match self.color {
+            true => format!("{code}{text}{RESET}"),
+            false => text.to_owned(),
+        };
+        let records = self.records(false);
+        let (group_of, count) = self.group_numbers();
+        let mut grouped: Vec<Vec<&Record>> = vec![Vec::new(); count];
+        for (record, (a, _)) in records.iter().zip(self.duplicates) {
+            grouped[group_of[&**a]].push(record);
+        }
+
+        let dist_width = records
+            .iter()
+            .map(|x| opt_string(x.distance).len())
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        for (i, group) in grouped.iter().enumerate() {
+            if i > 0 {
+                writeln!(out)?;
+            }
+            let width = |i: usize| {
+                group
+                    .iter()
+                    .map(|x| x.files[i].path.chars().count())
+                    .max()
+                    .unwrap_or(0)
+            };
+            let (left_width, right_width) = (width(0), width(1));
+            for Record {
+                distance,
+                exact,
+                orientation,
+                savings,
+                files: [l, r],
+                ..
+            } in group
+            {
+                let code = match distance {
+                    Some(d) if d * 3 < self.threshold => GREEN,
+                    Some(d) if d * 3 < self.threshold * 2 => YELLOW,
+                    Some(_) => RED,
+                    None => DIM,
+                };
+                let dist = match distance {
+                    _ if *exact => format!("{:>dist_width$}", "="),
+                    Some(d) => format!("{d:>dist_width$}"),
+                    None => format!("{:>dist_width$}", "-"),
+                };
+                let shared = shared_dir(&l.path, &r.path);
+                let path = |x: &str| {
+                    format!("{}{}", paint(DIM, &x[..shared]), &x[shared..])
+                };
+                let pad = left_width - l.path.chars().count();
+                write!(
+                    out,
+                    "{}  {}{:pad$}  {}",
+                    paint(code, &dist),
+                    path(&l.path),
+                    "",
+                    path(&r.path)
+                )?;
+                let pad = right_width - r.path.chars().count();
+                match (self.savings, savings) {
+                    (true, Some(x)) => write!(out, "{:pad$}  {x} bytes", "")?,
+                    (true, None) => write!(out, "{:pad$}  -", "")?,
+                    (false, _) => (),
+                }
+                match orientation {
+                    Some(Orientation::Upright) | None => writeln!(out)?,
+                    Some(x) => {
+                        writeln!(out, "  {}", paint(DIM, &x.to_string()))?
+                    }
+                }
+            }
+        }
+        if self.savings {
+            writeln!(out, "Potential savings: {} bytes", self.total())?;
+        }
+        Ok(())
+    }
+
+    /// Group images connected by the pairs, as in automatic resolution.
+    /// Returns the group number of each image, with groups numbered in order
+    /// of their first pair, and the number of groups.
+    fn group_numbers(&self) -> (HashMap<&Path, usize>, usize) {
+        let pairs: Vec<(&Path, &Path)> =
+            self.duplicates.iter().map(|(a, b)| (&**a, &**b)).collect();
+        let group_of: HashMap<&Path, usize> = groups(&pairs)
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, group)| group.into_iter().map(move |x| (x, i)))
+            .collect();
+        let mut number = HashMap::new();
+        for (a, _) in &pairs {
+            let len = number.len();
+            number.entry(group_of[a]).or_insert(len);
+        }
+        let numbers = group_of
+            .into_iter()
+            .map(|(x, group)| (x, number[&group]))
+            .collect();
+        (numbers, number.len())
+    }
+
+    /// Write groups of similar images as CSV for tagging in digiKam, or any
+    /// other photo manager: one row per image with its group, the smallest
+    /// distance to another image of the group, and a tag name. Tags are
+    /// `Duplicates/exact` for distance 0, `Duplicates/close` for distances
+    /// below a third of the threshold, and `Duplicates/similar` otherwise.
+    fn write_digikam<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let (group_of, _) = self.group_numbers();
+        let mut closest: HashMap<&Path, Option<u32>> = HashMap::new();
+        for (a, b) in self.duplicates {
+            let distance = self.hashdb.distance(a, b);
+            for x in [&**a, &**b] {
+                let d = closest.entry(x).or_insert(distance);
+                *d = match (*d, distance) {
+                    (Some(d), Some(e)) => Some(d.min(e)),
+                    (d, e) => d.or(e),
+                };
+            }
+        }
+        let mut rows: Vec<(usize, &Path, Option<u32>)> = closest
+            .into_iter()
+            .map(|(x, d)| (group_of[x], x, d))
+            .collect();
+        rows.sort_unstable();
+
+        if self.header {
+            writeln!(out, "group,path,distance,tag")?;
+        }
+        for (group, path, distance) in rows {
+            let tag = match distance {
+                Some(0) => "Duplicates/exact",
+                Some(d) if d * 3 < self.threshold => "Duplicates/close",
+                _ => "Duplicates/similar",
+            };
+            writeln!(
+                out,
+                "{},{},{},{tag}",
+                group + 1,
+                csv_field(&path.to_string_lossy()),
+                opt_string(distance)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write the pairs as an undirected Graphviz graph, for `dot` or `neato`.
+    /// Nodes are images labeled with their file names, and edges are pairs
+    /// labeled with their distances; closer pairs get heavier edges. Each
+    /// group, as in automatic resolution, is a cluster, and groups of fewer
+    /// than `min_cluster` images are left out. With `color`, edges are
+    /// colored by closeness like distances in the terminal format. Returns
+    /// the number of images and groups written.
+    pub fn write_dot<W: Write>(
+        &self,
+        mut out: W,
+        min_cluster: usize,
+        color: bool,
+    ) -> io::Result<(usize, usize)> {
+        let (group_of, count) = self.group_numbers();
+        let mut grouped: Vec<(Vec<&Path>, Vec<&Pair>)> =
+            vec![(Vec::new(), Vec::new()); count];
+        let mut seen = HashSet::new();
+        for pair in self.duplicates {
+            let group = &mut grouped[group_of[&*pair.0]];
+            for x in [&*pair.0, &*pair.1] {
+                if seen.insert(x) {
+                    group.0.push(x);
+                }
+            }
+            group.1.push(pair);
+        }
+        grouped.retain(|(images, _)| images.len() >= min_cluster);
+
+        writeln!(out, "graph duplicates {{")?;
+        writeln!(out, "    node [shape=box];")?;
+        for (i, (images, pairs)) in grouped.iter().enumerate() {
+            writeln!(out, "    subgraph cluster_{} {{", i + 1)?;
+            writeln!(out, "        label=\"{} images\";", images.len())?;
+            for image in images {
+                let id = dot_id(&image.to_string_lossy());
+                let name = image
+                    .file_name()
+                    .map_or(image.to_string_lossy(), |x| x.to_string_lossy());
+                writeln!(
+                    out,
+                    "        {id} [label={}, tooltip={id}];",
+                    dot_id(&name),
+                )?;
+            }
+            for (a, b) in pairs {
+                let distance = self.hashdb.distance(a, b);
+                let weight =
+                    distance.map_or(1, |d| self.threshold.saturating_sub(d));
+                write!(
+                    out,
+                    "        {} -- {} [label=\"{}\", weight={weight}",
+                    dot_id(&a.to_string_lossy()),
+                    dot_id(&b.to_string_lossy()),
+                    distance.map_or("?".into(), |d| d.to_string())
+                )?;
+                if color {
+                    let color = match distance {
+                        Some(d) if d * 3 < self.threshold => "forestgreen",
+                        Some(d) if d * 3 < self.threshold * 2 => "orange",
+                        Some(_) => "red",
+                        None => "gray",
+                    };
+                    write!(out, ", color={color}, fontcolor={color}")?;
+                }
+                writeln!(out, "];")?;
+            }
+            writeln!(out, "    }}")?;
+        }
+        writeln!(out, "}}")?;
+        out.flush()?;
+
+        let images = grouped.iter().map(|(images, _)| images.len()).sum();
+        Ok((images, grouped.len()))
+    }
+
+    /// Write a self-contained HTML report. Pairs are grouped as in automatic
+    /// resolution, and groups are ordered by the smallest distance within
+    /// them.
+    fn write_html<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let shown =
+            &self.duplicates[..self.duplicates.len().min(self.max_pairs)];
+        let pairs: Vec<(&Path, &Path)> =
+            shown.iter().map(|(a, b)| (&**a, &**b)).collect();
+        let distance = |a: &Path, b: &Path| self.hashdb.distance(a, b);
+
+        let mut groups: Vec<(Option<u32>, Vec<&Path>)> = groups(&pairs)
+            .into_iter()
+            .map(|group| {
+                let min = pairs
+                    .iter()
+                    .filter(|(a, _)| group.contains(a))
+                    .filter_map(|(a, b)| distance(a, b))
+                    .min();
+                (min, group)
+            })
+            .collect();
+        groups.sort_by_key(|(d, _)| d.unwrap_or(u32::MAX));
+
+        let files: Vec<&Path> =
+            groups.iter().flat_map(|(_, g)| g.iter().copied()).collect();
+        let thumbs: HashMap<&Path, Option<String>> =
+            files.par_iter().map(|&x| (x, thumbnail(x))).collect();
+
+        writeln!(out, "{}", HTML_HEAD)?;
+        writeln!(
+            out,
+            "<h1>Similar images in {}</h1>",
+            html_escape(
+                &self
+                    .roots
+                    .iter()
+                    .map(|x| x.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        )?;
+        write!(
+            out,
+            "<p>{} pairs in {} groups at threshold {}.",
+            shown.len(),
+            groups.len(),
+            self.threshold
+        )?;
+        if shown.len() < self.duplicates.len() {
+            write!(
+                out,
+                " {} more pairs were left out of this report.",
+                self.duplicates.len() - shown.len()
+            )?;
+        }
+        if self.savings {
+            write!(out, " Potential savings: {} bytes.", self.total())?;
+        }
+        writeln!(out, "</p>")?;
+        writeln!(out, "{}", HTML_CONTROLS)?;
+
+        writeln!(out, "<div id=\"groups\">")?;
+        for (min, group) in &groups {
+            writeln!(
+                out,
+                "<section class=\"group\" data-distance=\"{}\">",
+                min.map_or(-1, |x| x as i64)
+            )?;
+            writeln!(
+                out,
+                "<h2>Distance {}</h2>",
+                min.map_or("unknown".into(), |x| x.to_string())
+            )?;
+            for &file in group {
+                let info = FileInfo::new(file, self.hashdb);
+                let modified = fs::metadata(file)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs());
+                let path = html_escape(&file.to_string_lossy());
+                writeln!(out, "<figure>")?;
+                match thumbs.get(file).and_then(|x| x.as_deref()) {
+                    Some(src) => writeln!(out, "<img src=\"{src}\" alt=\"\">")?,
+                    None => writeln!(out, "<div class=\"missing\">?</div>")?,
+                }
+                writeln!(
+                    out,
+                    "<figcaption><span class=\"path\">{path}</span><br>\
+                     {} bytes, {}<br><time data-unix=\"{}\"></time>\
+                     </figcaption>",
+                    opt_string(info.size),
+                    info.dimensions(),
+                    opt_string(modified),
+                )?;
+                writeln!(out, "</figure>")?;
+            }
+            writeln!(out, "</section>")?;
+        }
+        writeln!(out, "</div>")?;
+        writeln!(out, "{}", HTML_TAIL)
+    }
+
+    /// Collect the metadata for each pair. With `pixels`, pairs at distance 0
+    /// are also decoded to check whether they differ only in metadata, which
+    /// is done in parallel. Identical files need no decoding for that.
+    fn records(&self, pixels: bool) -> Vec<Record> {
+        self.duplicates
+            .par_iter()
+            .map(|(img_1, img_2)| {
+                let files = [
+                    FileInfo::new(img_1, self.hashdb),
+                    FileInfo::new(img_2, self.hashdb),
+                ];
+                let dims = |x: &FileInfo| Some((x.width?, x.height?));
+                let resized = match (dims(&files[0]), dims(&files[1])) {
+                    (Some(l), Some(r)) => resized_variant([l, r]),
+                    _ => None,
+                };
+                let distance = self.hashdb.distance(img_1, img_2);
+                let exact = self.hashdb.identical(img_1, img_2);
+                let orientation = self.hashdb.orientation(img_1, img_2);
+                let metadata_only = match pixels && distance == Some(0) {
+                    true if exact => Some(Vec::new()),
+                    true => metadata_only(img_1, img_2),
+                    false => None,
+                };
+                Record {
+                    distance,
+                    exact,
+                    orientation,
+                    savings: savings(img_1, img_2),
+                    files,
+                    resized,
+                    metadata_only,
+                }
+            })
+            .collect()
+    }
+
+    /// Space that removing one image of every pair would free. Images in
+    /// several pairs are counted once per pair.
+    fn total(&self) -> u64 {
+        self.duplicates
+            .iter()
+            .filter_map(|(a, b)| savings(a, b))
+            .sum()
+    }
+}
+
+/// Start of an HTML report, up to the opening of the body.
+const HTML_HEAD: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>Similar images</title>
+<style>
+body { font-family: sans-serif; margin: 1em 2em; }
+.group { border-top: 1px solid #ccc; padding: 0.5em 0; }
+.group h2 { font-size: 1em; margin: 0.2em 0; }
+figure { display: inline-block; vertical-align: top; margin: 0.5em;
+         width: 180px; font-size: 0.8em; }
+figure img, .missing { display: block; max-width: 160px; max-height: 160px; }
+.missing { width: 160px; height: 120px; background: #eee; text-align: center;
+           line-height: 120px; }
+.path { word-break: break-all; }
+</style>
+</head>
+<body>"#;
+
+/// Sorting and filtering controls of an HTML report.
+const HTML_CONTROLS: &str = r#"<p>
+<label>Largest distance: <input id="max" type="number" min="0" value=""
+       placeholder="any"></label>
+<label>Order: <select id="order">
+<option value="asc">closest first</option>
+<option value="desc">furthest first</option>
+</select></label>
+</p>"#;
+
+/// End of an HTML report, with the script behind the controls.
+const HTML_TAIL: &str = r#"<script>
+const list = document.getElementById("groups");
+const groups = Array.from(list.children);
+const distance = (g) => Number(g.dataset.distance);
+function update() {
+  const max = document.getElementById("max").value;
+  const desc = document.getElementById("order").value === "desc";
+  groups.sort((a, b) => desc ? distance(b) - distance(a)
+                             : distance(a) - distance(b));
+  for (const g of groups) {
+    g.hidden = max !== "" && distance(g) > Number(max);
+    list.appendChild(g);
+  }
+}
+for (const t of document.querySelectorAll("time[data-unix]")) {
+  if (t.dataset.unix !== "") {
+    const date = new Date(Number(t.dataset.unix) * 1000);
+    t.dateTime = date.toISOString();
+    t.textContent = date.toLocaleString();
+  }
+}
+document.getElementById("max").addEventListener("input", update);
+document.getElementById("order").addEventListener("change", update);
+</script>
+</body>
+</html>"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Paths that need quoting or escaping in one format or another.
+    const AWKWARD: [&str; 5] = [
+        "/images/a, b.png",
+        "/images/\"quoted\".png",
+        "/images/two\nlines.png",
+        "/images/tab\there\\.png",
+        "/images/plain.png",
+    ];
+
+    fn write(format: Format) -> String {
+        let duplicates: Vec<Pair> = AWKWARD
+            .iter()
+            .zip(AWKWARD.iter().skip(1))
+            .map(|(a, b)| (Path::new(a).into(), Path::new(b).into()))
+            .collect();
+        write_pairs(format, &HashDB::new(), &duplicates)
+    }
+
+    fn write_pairs(
+        format: Format,
+        hashdb: &HashDB,
+        duplicates: &[Pair],
+    ) -> String {
+        let listing = Listing {
+            threshold: 10,
+            roots: &[PathBuf::from("/images")],
+            hashdb,
+            duplicates,
+            header: false,
+            max_pairs: 100,
+            savings: false,
+            color: false,
+        };
+        let mut out = Vec::new();
+        listing.write(&mut out, format).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_escaping() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a, b"), "\"a, b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+
+        let out = write(Format::Csv);
+        assert!(out.starts_with(
+            "\"/images/a, b.png\",\"/images/\"\"quoted\"\".png\","
+        ));
+        assert!(out.contains(
+            "\n\"/images/\"\"quoted\"\".png\",\"/images/two\nlines.png\","
+        ));
+        assert!(
+            out.contains(
+                "\n\"/images/two\nlines.png\",/images/tab\there\\.png,"
+            )
+        );
+        assert!(out.contains("\n/images/tab\there\\.png,/images/plain.png,"));
+    }
+
+    #[test]
+    fn plain_escaping() {
+        let out = write(Format::Plain);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "/images/a, b.png\t/images/\"quoted\".png",
+                "/images/\"quoted\".png\t/images/two\\nlines.png",
+                "/images/two\\nlines.png\t/images/tab\\there\\\\.png",
+                "/images/tab\\there\\\\.png\t/images/plain.png",
+            ]
+        );
+    }
+
+    #[test]
+    fn plain_distances() {
+        let mut hashdb = HashDB::new();
+        for (name, byte) in [("a", 0), ("b", 1), ("c", 7)] {
+            let hash = image_hasher::ImageHash::from_bytes(&[byte; 8]).unwrap();
+            hashdb
+                .insert(format!("/images/{name}.png"), hash.into())
+                .unwrap();
+        }
+        let pair =
+            |a: &str, b: &str| (Path::new(a).into(), Path::new(b).into());
+        let duplicates = [
+            pair("/images/a.png", "/images/b.png"),
+            pair("/images/a.png", "/images/c.png"),
+            pair("/images/a.png", "/images/unknown.png"),
+        ];
+        assert_eq!(
+            write_pairs(Format::Plain, &hashdb, &duplicates),
+            "/images/a.png\t/images/b.png\t8\n\
+             /images/a.png\t/images/c.png\t24\n\
+             /images/a.png\t/images/unknown.png\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn plain_escaping_not_utf8() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let latin1 = Path::new(OsStr::from_bytes(b"/images/caf\xe9.png"));
+        let pair = (latin1.into(), Path::new("/images/cafe.png").into());
+        assert_eq!(
+            write_pairs(Format::Plain, &HashDB::new(), &[pair]),
+            "/images/caf\\xe9.png\t/images/cafe.png\n"
+        );
+    }
+
+    #[test]
+    fn json_escaping() {
+        let doc: serde_json::Value =
+            serde_json::from_str(&write(Format::Json)).unwrap();
+        let pairs = doc["pairs"].as_array().unwrap();
+        assert_eq!(pairs.len(), AWKWARD.len() - 1);
+        for (pair, names) in pairs.iter().zip(AWKWARD.windows(2)) {
+            assert_eq!(pair["files"][0]["path"], names[0]);
+            assert_eq!(pair["files"][1]["path"], names[1]);
+        }
+    }
+}
@@ -0,0 +1,963 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The scan pipeline: find the database file, load or create the database,
+//! scan the directory, dump the database, and search it for similar images.
+//! [`scan_and_find`] forms the main interface.
+
+use crate::{
+    UsageError,
+    hashdb::{
+        Algorithm, Depth, HashDB, HashDBError, Header, Pair, ScanFilter,
+        ScanReport, ScanSettings, Threshold,
+    },
+    progress::{Phase, ScanObserver, Summary},
+};
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, File},
+    io,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Default image similarity threshold.
+pub const DEFAULT_THRESHOLD: u32 = 9;
+
+/// Check that a similarity threshold can match some pairs but not all of
+/// them, for hashes of `bits` bits. Pairs match when their distance is below
+/// the threshold, so 0 matches nothing, and anything above the number of bits
+/// in a hash matches everything.
+pub fn check_threshold(threshold: u32, bits: u32) -> Result<(), String> {
+    match threshold {
+        0 => Err(format!(
+            "a threshold of 0 matches nothing, since pairs match when their \
+             distance is below it; use 1 to {} (1 finds identical hashes)",
+            bits - 1
+        )),
+        x if x >= bits => Err(format!(
+            "a threshold of {x} matches nearly every pair, since hashes have \
+             only {bits} bits; use 1 to {}",
+            bits - 1
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Environment variable naming the directory for databases kept outside of the
+/// scanned directory.
+const DB_DIR_VAR: &str = "IMAGE_DUPLICATE_DB_DIR";
+
+/// Where to keep a database file that was not given explicitly.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum DbLocation {
+    /// In the scanned directory if possible, otherwise in the data directory
+    #[default]
+    Auto,
+    /// In the scanned directory
+    Local,
+    /// In the data directory
+    Xdg,
+}
+
+/// Options for [`scan_and_find`].
+#[derive(Clone, Debug)]
+pub struct ScanOptions {
+    /// Directories to scan for images. The first one decides where the
+    /// database is kept if `db` is `None`.
+    pub roots: Vec<PathBuf>,
+    /// Database file, or `None` to choose one according to `db_location`.
+    /// `-` reads the database from stdin or writes it to stdout, but not
+    /// both.
+    pub db: Option<PathBuf>,
+    /// Where to keep the database file if `db` is `None`.
+    pub db_location: DbLocation,
+    /// Read-only database to search along with the database file. Its
+    /// images are not hashed again unless their files changed, and its
+    /// entries are never written to the database file or pruned; entries of
+    /// the database file, including those of images hashed again, win over
+    /// its entries for the same image.
+    pub seed_db: Option<PathBuf>,
+    /// Databases whose entries are added to the database for good, without
+    /// hashing their images again.
+    pub merge: Vec<PathBuf>,
+    /// Scan the directory recursively.
+    pub recursive: bool,
+    /// With `recursive`, most levels of directories to look into under each
+    /// of `roots`, where 1 is only the images directly in it, or `None` for
+    /// no limit. Entries for images deeper down are kept in the database but
+    /// left out of the search.
+    pub max_depth: Option<usize>,
+    /// Do not descend into other filesystems when scanning recursively.
+    pub one_file_system: bool,
+    /// Glob patterns of paths to leave out of the scan, relative to `root`.
+    /// Entries for images they match are removed from the database.
+    pub exclude: Vec<String>,
+    /// Extensions of image files, as given to
+    /// [`ScanFilter::with_extensions`], or `None` for the usual ones.
+    pub extensions: Option<Vec<String>>,
+    /// Start from an empty database instead of the database file.
+    pub rebuild: bool,
+    /// Hash the images already in the database again.
+    pub rehash: bool,
+    /// Crop near-uniform borders before hashing. A database keeps trimming
+    /// once it has been hashed with it, until it is hashed again without.
+    pub trim_borders: bool,
+    /// Also hash images rotated and mirrored, so that turned copies match.
+    /// Like trimming, a database keeps this once it has been hashed with it.
+    pub match_rotations: bool,
+    /// Hash algorithm, or `None` for that of the database, or the default
+    /// for a new one. Changing the algorithm of a database takes a rehash.
+    pub algorithm: Option<Algorithm>,
+    /// Width and height of hashes, or `None` for those of the database, or
+    /// the default for a new one. Changing them takes a rehash.
+    pub hash_size: Option<u32>,
+    /// Hash new images and drop entries for missing ones.
+    pub update: bool,
+    /// While updating, also hash images again whose modification time or
+    /// size changed since they were hashed.
+    pub check_changes: bool,
+    /// Most images to decode at once while hashing, or `None` for one per
+    /// hashing thread.
+    pub max_decodes: Option<NonZeroUsize>,
+    /// Threads to hash with, or `None` for one per core.
+    pub jobs: Option<NonZeroUsize>,
+    /// Largest image file to hash, in bytes; larger ones are reported as
+    /// errors and skipped.
+    pub max_file_size: Option<u64>,
+    /// Write the database after every so many images hashed, so that an
+    /// interrupted scan resumes where it stopped. Only done if `dump` is set
+    /// and the database is not on stdout.
+    pub checkpoint: Option<NonZeroUsize>,
+    /// Write the database back to its file.
+    pub dump: bool,
+    /// Fail if the database cannot be written back, rather than warning and
+    /// carrying on without it.
+    pub require_dump: bool,
+    /// Image similarity threshold, or `None` to skip searching. A similarity
+    /// is worked out for the size of the hashes of the database.
+    pub threshold: Option<Threshold>,
+    /// Also pair entries that name the same file, such as hardlinks.
+    pub include_hardlinks: bool,
+    /// Directory to search `roots` against, such as an archive that new
+    /// images may already be in. It is scanned along with them, and only
+    /// pairs of an image under `roots` with one under it are found, with the
+    /// latter on the right. If it has a database file of its own and there is
+    /// no `seed_db`, that is used as the seed database.
+    pub against: Option<PathBuf>,
+    /// Store the names of the database relative to the first of `roots`, so
+    /// that it still applies once that directory is mounted elsewhere. The
+    /// other directories must be under it. A database keeps this once it
+    /// has been written with it.
+    pub relative_db: bool,
+}
+
+impl ScanOptions {
+    /// Options for a non-recursive scan of `root` that keeps the database
+    /// next to the images and searches with [`DEFAULT_THRESHOLD`].
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        ScanOptions {
+            roots: vec![root.into()],
+            db: None,
+            db_location: DbLocation::default(),
+            seed_db: None,
+            merge: Vec::new(),
+            recursive: false,
+            max_depth: None,
+            one_file_system: false,
+            exclude: Vec::new(),
+            extensions: None,
+            rebuild: false,
+            rehash: false,
+            trim_borders: false,
+            match_rotations: false,
+            algorithm: None,
+            hash_size: None,
+            update: true,
+            check_changes: true,
+            max_decodes: None,
+            jobs: None,
+            max_file_size: None,
+            checkpoint: None,
+            dump: true,
+            require_dump: false,
+            threshold: Some(Threshold::Distance(DEFAULT_THRESHOLD)),
+            include_hardlinks: false,
+            against: None,
+            relative_db: false,
+        }
+    }
+}
+
+/// Results of [`scan_and_find`].
+#[derive(Debug)]
+pub struct ScanOutcome {
+    /// Database file used.
+    pub db_file: PathBuf,
+    /// The updated database.
+    pub hashdb: HashDB,
+    /// Pairs of similar images. Empty if the search was skipped.
+    pub duplicates: Vec<Pair>,
+    /// What the scan changed in the database.
+    pub report: ScanReport,
+    /// Whether the search ran.
+    pub searched: bool,
+    /// Distance the search ran with, as worked out from the threshold for
+    /// the hashes of the database. `None` if the search was skipped.
+    pub threshold: Option<u32>,
+}
+
+impl ScanOutcome {
+    /// Totals for progress reporting.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            images: self.hashdb.len(),
+            hashed: self.report.hashed,
+            errors: self.report.errors,
+            removed: self.report.removed,
+            unreadable: self.report.unreadable,
+            pairs: self.searched.then_some(self.duplicates.len()),
+            timings: None,
+        }
+    }
+}
+
+/// 64-bit FNV-1a hash. Unlike [`std::hash::DefaultHasher`], its output is
+/// guaranteed not to change between builds.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Whether files can be created in a directory. This actually creates (and
+/// removes) a file, since permissions do not tell about read-only mounts.
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".image_hash.{}.tmp", std::process::id()));
+    match File::create_new(&probe) {
+        Ok(_) => fs::remove_file(&probe).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Database file for a directory in the data directory.
+fn xdg_db_file(root: &Path) -> Result<PathBuf> {
+    let dir = match env::var_os(DB_DIR_VAR) {
+        Some(dir) => PathBuf::from(dir),
+        None => dirs::data_dir()
+            .ok_or(anyhow!("Could not find the data directory"))?
+            .join("image-duplicate"),
+    };
+    let root = root
+        .canonicalize()
+        .map_err(|e| anyhow!("Could not resolve {root:?}: {e}"))?;
+    let hash = fnv1a(root.as_os_str().as_encoded_bytes());
+    Ok(dir.join(format!("{hash:016x}.db")))
+}
+
+/// Location of the database file for a scan. Without `db`, it goes by the
+/// first directory to scan.
+pub fn db_file(options: &ScanOptions) -> Result<PathBuf> {
+    if let Some(path) = &options.db {
+        return Ok(path.clone());
+    }
+    let Some(root) = options.roots.first() else {
+        Err(UsageError("No directory to scan".into()))?
+    };
+    let local = root.join(".image_hash.db");
+    match options.db_location {
+        DbLocation::Local => Ok(local),
+        DbLocation::Xdg => xdg_db_file(root),
+        DbLocation::Auto => {
+            match env::var_os(DB_DIR_VAR).is_none()
+                && (local.is_file() || is_writable(root))
+            {
+                true => Ok(local),
+                false => xdg_db_file(root),
+            }
+        }
+    }
+}
+
+/// Write a database to its file, creating the directory for it if needed.
+fn save(hashdb: &HashDB, db_file: &Path) -> Result<()> {
+    if let Some(dir) = db_file.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| anyhow!("Could not create {dir:?}: {e}"))?;
+    }
+    hashdb.to_file(db_file)?;
+    Ok(())
+}
+
+/// Observer that writes the database to `file` at each checkpoint, without
+/// the entries of the seed database, and passes everything on to `inner`.
+struct Checkpoints<'a> {
+    inner: &'a dyn ScanObserver,
+    file: &'a Path,
+    seeded: &'a HashSet<PathBuf>,
+}
+
+impl ScanObserver for Checkpoints<'_> {
+    fn database(&self, file: &Path, existing: bool) {
+        self.inner.database(file, existing);
+    }
+
+    fn phase(&self, phase: Phase) {
+        self.inner.phase(phase);
+    }
+
+    fn phase_finished(&self, phase: Phase, elapsed: Duration) {
+        self.inner.phase_finished(phase, elapsed);
+    }
+
+    fn scanned(&self, images: usize, new: usize) {
+        self.inner.scanned(images, new);
+    }
+
+    fn hashing(&self, file: &Path, done: usize, total: usize) {
+        self.inner.hashing(file, done, total);
+    }
+
+    fn hashed(&self, file: &Path, elapsed: Duration) {
+        self.inner.hashed(file, elapsed);
+    }
+
+    fn checkpoint(&self, hashdb: &HashDB) {
+        let result = match self.seeded.is_empty() {
+            true => save(hashdb, self.file),
+            false => {
+                let mut hashdb = hashdb.clone();
+                hashdb.remove_all(self.seeded);
+                save(&hashdb, self.file)
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Could not write a checkpoint of the database: {e}");
+        }
+        self.inner.checkpoint(hashdb);
+    }
+
+    fn error(&self, file: &Path, error: &HashDBError) {
+        self.inner.error(file, error);
+    }
+
+    fn pruned(&self, removed: usize) {
+        self.inner.pruned(removed);
+    }
+
+    fn unreadable(&self, dir: &Path, error: &HashDBError) {
+        self.inner.unreadable(dir, error);
+    }
+
+    fn matching(&self, done: u64, total: u64, pairs: usize) {
+        self.inner.matching(done, total, pairs);
+    }
+
+    fn pair(&self, img_1: &Path, img_2: &Path, distance: u32) {
+        self.inner.pair(img_1, img_2, distance);
+    }
+
+    fn finished(&self, summary: &Summary) {
+        self.inner.finished(summary);
+    }
+
+    fn cancelled(&self) -> bool {
+        self.inner.cancelled()
+    }
+}
+
+/// Paths quoted and separated by commas, for messages.
+fn quoted<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> String {
+    let paths: Vec<String> = paths
+        .into_iter()
+        .map(|x| format!("{:?}", x.as_ref()))
+        .collect();
+    paths.join(", ")
+}
+
+/// Read the database in `file`, falling back to its backup with a warning
+/// if the file itself is damaged.
+pub(crate) fn load(file: &Path) -> Result<HashDB, HashDBError> {
+    let (hashdb, error) = HashDB::from_file_or_backup(file)?;
+    if let Some(e) = error {
+        eprintln!(
+            "Warning: {e}; using the backup {:?} from before the last write",
+            HashDB::backup_file(file)
+        );
+    }
+    Ok(hashdb)
+}
+
+/// Name to move a damaged database `file` aside to: `file` with `.corrupt-`
+/// and the current Unix time added.
+fn corrupt_file(file: &Path) -> PathBuf {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs());
+    let mut name = file.as_os_str().to_owned();
+    name.push(format!(".corrupt-{time}"));
+    PathBuf::from(name)
+}
+
+/// Start over from a database `file` that neither it nor its backup could be
+/// decoded from, failing with `error`: move it aside with [`corrupt_file`],
+/// so that it is not written over, and keep the entries that can still be
+/// read from it. Without `dump`, nothing is written, so the file is left
+/// where it is. If it cannot be moved, that is an error, since a database
+/// that cannot replace it would only be rebuilt again on every run.
+fn recover(file: &Path, error: HashDBError, dump: bool) -> Result<HashDB> {
+    let hashdb = HashDB::salvage(file).unwrap_or_default();
+    let kept = match hashdb.len() {
+        0 => "a new database".into(),
+        1 => "the one entry that could be read from it".into(),
+        n => format!("the {n} entries that could be read from it"),
+    };
+    if !dump {
+        eprintln!("Warning: {error}; carrying on with {kept}");
+        return Ok(hashdb);
+    }
+    let aside = corrupt_file(file);
+    fs::rename(file, &aside).map_err(|e| {
+        anyhow!(
+            "{error}; it could not be moved aside to {aside:?} ({e}), so it \
+             was left alone; give --db to keep the database elsewhere"
+        )
+    })?;
+    eprintln!(
+        "Warning: {error}; moved it to {aside:?} and carrying on with {kept}"
+    );
+    Ok(hashdb)
+}
+
+/// Resolve the names of a database read from `file`, if it stores them
+/// relative, against the directory of the file, for when no scan tells where
+/// its images are. That is right for a database kept in the directory it is
+/// scanned from, as it is by default.
+pub(crate) fn resolve(mut hashdb: HashDB, file: &Path) -> HashDB {
+    if hashdb.header().relative {
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        hashdb.set_root(dir.canonicalize().unwrap_or_else(|_| dir.into()));
+    }
+    hashdb
+}
+
+/// Load a seed database, or one to merge, and check that its hashes can be
+/// compared with those of `hashdb` and of images hashed now. `what` names it
+/// in errors.
+fn load_other(file: &Path, hashdb: &HashDB, what: &str) -> Result<HashDB> {
+    let seed = resolve(HashDB::from_file(file)?, file);
+    let header = hashdb.header();
+    match seed.hash_size() {
+        Some(_) if seed.header().hash_size != header.hash_size => {
+            Err(HashDBError::Mismatch(
+                seed.header().hash_bits(),
+                header.hash_bits(),
+            ))
+        }
+        Some(_) if seed.header().algorithm != hashdb.header().algorithm => {
+            Err(HashDBError::AlgorithmMismatch(
+                seed.header().algorithm,
+                hashdb.header().algorithm,
+            ))
+        }
+        Some(_)
+            if seed.header().trim_borders != hashdb.header().trim_borders =>
+        {
+            Err(HashDBError::TrimMismatch)
+        }
+        Some(_)
+            if seed.header().match_rotations
+                != hashdb.header().match_rotations =>
+        {
+            Err(HashDBError::RotationMismatch)
+        }
+        _ => hashdb.check_compatible(&seed),
+    }
+    .map_err(|e| anyhow!("{what} {file:?}: {e}"))?;
+    Ok(seed)
+}
+
+/// The database file of the directory searched against, loaded as a seed
+/// database if there is one apart from `db_file`. One that cannot be used
+/// only costs hashing its images again, so it is passed over with a warning.
+fn against_db(
+    options: &ScanOptions,
+    against: &Path,
+    db_file: &Path,
+    hashdb: &HashDB,
+) -> Option<HashDB> {
+    // Only an existing file will do, so there is no need to probe where a
+    // new one would go.
+    let local = (options.db_location != DbLocation::Xdg)
+        .then(|| against.join(".image_hash.db"));
+    let xdg = (options.db_location != DbLocation::Local)
+        .then(|| xdg_db_file(against).ok())
+        .flatten();
+    let file = [local, xdg]
+        .into_iter()
+        .flatten()
+        .find(|x| x.is_file() && x != db_file)?;
+    match load_other(&file, hashdb, "Database") {
+        Ok(mut seed) => {
+            // Relative names go by the directory searched against, wherever
+            // its database is kept.
+            if let Ok(dir) = against.canonicalize() {
+                seed.set_root(dir);
+            }
+            eprintln!("Using the hashes in {file:?} for {against:?}");
+            Some(seed)
+        }
+        Err(e) => {
+            eprintln!("Warning: {e}; hashing the images again instead");
+            None
+        }
+    }
+}
+
+/// Run the scan pipeline: load the database for `options.roots` (or create a
+/// new one if it does not exist or a rebuild was requested), hash its entries
+/// again if a rehash was requested, update it from the filesystem, write it
+/// back, and search it for similar images, each step as far as `options` asks
+/// for. With a seed database, only the entries of the database file are pruned
+/// and written back, along with seed entries whose images changed and were
+/// hashed again, but the search covers both. A database file that neither it
+/// nor its backup can be decoded from is moved aside, and the run starts over
+/// with the entries that can still be read from it. If the database cannot be
+/// written, a warning is printed and the run carries on, unless
+/// `options.require_dump` or the file was moved aside. Progress is reported to
+/// `observer`, ending with [`ScanObserver::finished`]. If `observer` cancels
+/// the run, the hashes computed so far are still written back, but the search
+/// is skipped.
+pub fn scan_and_find(
+    options: &ScanOptions,
+    observer: &dyn ScanObserver,
+) -> Result<ScanOutcome> {
+    if options.roots.is_empty() {
+        Err(UsageError("No directory to scan".into()))?;
+    }
+    for root in options.roots.iter().chain(&options.against) {
+        if !root.is_dir() {
+            Err(UsageError(format!("Directory not found: {root:?}")))?;
+        }
+    }
+    let mut filter = ScanFilter::new(&options.exclude)
+        .map_err(|e| UsageError(format!("Invalid --exclude pattern: {e}")))?;
+    if let Some(extensions) = &options.extensions {
+        filter = filter.with_extensions(extensions);
+    }
+
+    let db_file = db_file(options)?;
+    let stdio = db_file == Path::new("-");
+    if stdio && options.dump && !options.rebuild {
+        Err(UsageError(
+            "--db - cannot both read and write the database; add --rebuild \
+             to only write it, or --no-dump to only read it"
+                .into(),
+        ))?;
+    }
+    let existing = (stdio || db_file.is_file()) && !options.rebuild;
+    observer.database(&db_file, existing);
+    let start = Instant::now();
+    if existing {
+        observer.phase(Phase::Load);
+    }
+    let loaded = match (existing, stdio) {
+        (true, true) => HashDB::from_reader(io::stdin().lock()),
+        (true, false) => load(&db_file),
+        (false, _) => Ok(HashDB::new()),
+    };
+    // A damaged database is rebuilt rather than stopping every run, which
+    // then must be able to write the new one.
+    let mut recovered = false;
+    let mut hashdb = match loaded {
+        Ok(hashdb) => hashdb,
+        Err(e @ (HashDBError::DecodeError(..) | HashDBError::Corrupt(..)))
+            if !stdio =>
+        {
+            recovered = true;
+            recover(&db_file, e, options.dump)?
+        }
+        Err(e @ HashDBError::Version { .. }) => Err(anyhow!(
+            "{e}; add --rebuild to replace it with a new database, hashing \
+             the images again"
+        ))?,
+        Err(e) => Err(e)?,
+    };
+    if existing {
+        observer.phase_finished(Phase::Load, start.elapsed());
+    }
+    hashdb.set_jobs(options.jobs);
+    hashdb.set_max_file_size(options.max_file_size);
+
+    // Settings that change hashes only change along with a rehash, so that
+    // the entries of a database are always comparable.
+    let trim_borders = options.trim_borders
+        || (hashdb.header().trim_borders && !options.rehash);
+    if trim_borders != hashdb.header().trim_borders
+        && !options.rehash
+        && !hashdb.is_empty()
+    {
+        Err(UsageError(format!(
+            "{db_file:?} was hashed without --trim-borders; add --rehash to \
+             hash its images again with it"
+        )))?;
+    }
+    let match_rotations = options.match_rotations
+        || (hashdb.header().match_rotations && !options.rehash);
+    if match_rotations != hashdb.header().match_rotations
+        && !options.rehash
+        && !hashdb.is_empty()
+    {
+        Err(UsageError(format!(
+            "{db_file:?} was hashed without --match-rotations; add --rehash \
+             to hash its images again with it"
+        )))?;
+    }
+    let hash_size = options.hash_size.unwrap_or(hashdb.header().hash_size);
+    if hash_size != hashdb.header().hash_size
+        && !options.rehash
+        && !hashdb.is_empty()
+    {
+        Err(UsageError(format!(
+            "{db_file:?} was hashed with a hash size of {}; add --rehash to \
+             hash its images again with {hash_size}",
+            hashdb.header().hash_size
+        )))?;
+    }
+    let algorithm = options.algorithm.unwrap_or(hashdb.header().algorithm);
+    if algorithm != hashdb.header().algorithm
+        && !options.rehash
+        && !hashdb.is_empty()
+    {
+        Err(UsageError(format!(
+            "{db_file:?} was hashed with the {} algorithm; add --rehash to \
+             hash its images again with {algorithm}",
+            hashdb.header().algorithm
+        )))?;
+    }
+    let roots = options
+        .roots
+        .iter()
+        .chain(&options.against)
+        .map(|root| {
+            root.canonicalize()
+                .map_err(|e| anyhow!("Could not resolve {root:?}: {e}"))
+        })
+        .collect::<Result<Vec<PathBuf>>>()?;
+
+    // Relative names go by the first directory, wherever it is mounted now,
+    // so the scan must not reach outside of it.
+    let relative = options.relative_db || hashdb.header().relative;
+    if relative {
+        if let Some(root) = roots.iter().find(|x| !x.starts_with(&roots[0])) {
+            Err(UsageError(format!(
+                "{} stores paths relative to {:?}, and {root:?} is not under \
+                 it",
+                match hashdb.header().relative {
+                    true => format!("{db_file:?}"),
+                    false => "--relative-db".into(),
+                },
+                roots[0]
+            )))?;
+        }
+        hashdb.set_root(&roots[0]);
+    }
+    // A relative database is expected to be found elsewhere than where it
+    // was last scanned. A shallower scan than the last one keeps the entries
+    // below its depth, so only other directories are worth a warning.
+    if options.update
+        && let Some(scan) = &hashdb.header().scan
+        && !relative
+        && scan.roots().any(|x| !roots.contains(x))
+    {
+        eprintln!(
+            "Warning: {db_file:?} was last scanned from {}, but this scan is \
+             of {}; entries for images outside of this scan will be removed \
+             from it",
+            quoted(scan.roots()),
+            quoted(&roots),
+        );
+    }
+    if roots.len() > 1 && options.db.is_none() && !stdio {
+        eprintln!(
+            "Warning: keeping the database of all {} directories at \
+             {db_file:?}, chosen by the first; give --db to keep it \
+             elsewhere",
+            roots.len()
+        );
+    }
+    hashdb.set_header(Header {
+        trim_borders,
+        algorithm,
+        hash_size,
+        match_rotations,
+        relative,
+        ..hashdb.header().clone()
+    });
+
+    // The threshold only makes sense against the number of bits in a hash,
+    // which the entries tell best unless they are about to be hashed again.
+    // A similarity is only valid from 0% to 100%, which it is checked for
+    // when parsed.
+    let mut distance = None;
+    if let Some(threshold) = options.threshold {
+        let bits = match options.rehash {
+            true => None,
+            false => hashdb.hash_size(),
+        }
+        .unwrap_or(hashdb.header().hash_bits());
+        if let Threshold::Distance(x) = threshold {
+            check_threshold(x, bits)
+                .map_err(|e| UsageError(format!("Invalid threshold: {e}")))?;
+        }
+        let x = threshold.distance(bits);
+        if x > bits / 2 {
+            eprintln!(
+                "Warning: a threshold of {threshold} lets pairs differ in \
+                 over half of the {bits} bits in a hash, where the hashes of \
+                 unrelated images tend to differ, so most pairs will match; \
+                 try about {}",
+                match threshold {
+                    Threshold::Distance(_) => Threshold::Distance(
+                        (bits * DEFAULT_THRESHOLD).div_ceil(64)
+                    ),
+                    Threshold::Similarity(_) => Threshold::Similarity(
+                        100.0 - f64::from(DEFAULT_THRESHOLD - 1) * 100.0 / 64.0
+                    ),
+                }
+            );
+        }
+        distance = Some(x);
+    }
+
+    let seed = match (&options.seed_db, &options.against) {
+        (Some(file), _) => Some(load_other(file, &hashdb, "Seed database")?),
+        (None, Some(against)) => {
+            against_db(options, against, &db_file, &hashdb)
+        }
+        (None, None) => None,
+    };
+    let merged = options
+        .merge
+        .iter()
+        .map(|file| load_other(file, &hashdb, "Database to merge"))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut report = match options.rehash {
+        true => hashdb.rehash(options.max_decodes, observer),
+        false => ScanReport::default(),
+    };
+    // Merged entries are not hashed again, but an update checks them
+    // against the files like any others.
+    for (file, other) in options.merge.iter().zip(merged) {
+        let merge = hashdb.merge(other);
+        eprintln!(
+            "Merged {file:?}: {} entries added, {} replaced by newer ones, {} \
+             kept",
+            merge.added, merge.replaced, merge.kept
+        );
+    }
+    let mut seeded = match &seed {
+        Some(seed) => hashdb.overlay(seed),
+        None => HashSet::new(),
+    };
+    hashdb
+        .set_checkpoint(options.checkpoint.filter(|_| options.dump && !stdio));
+    let checkpoints = Checkpoints {
+        inner: observer,
+        file: &db_file,
+        seeded: &seeded,
+    };
+    let depth = match options.recursive {
+        true => Depth {
+            max_depth: options.max_depth,
+            one_file_system: options.one_file_system,
+        },
+        false => Depth::FLAT,
+    };
+    if options.update {
+        let roots: Vec<&PathBuf> =
+            options.roots.iter().chain(&options.against).collect();
+        report += hashdb.read_dirs(
+            &roots,
+            depth,
+            &filter,
+            options.max_decodes,
+            options.check_changes,
+            &checkpoints,
+        )?;
+    }
+    // Seed entries hashed again are newer than the seed, so they go into the
+    // database file. Those for images outside of the scan count as removed.
+    for name in &report.changed {
+        seeded.remove(name);
+    }
+    let kept = hashdb.remove_all(&seeded);
+    report.removed = report.removed.saturating_sub(seeded.len() - kept);
+
+    if options.dump {
+        if options.update {
+            let threshold =
+                hashdb.header().scan.as_ref().and_then(|x| x.threshold);
+            hashdb.set_header(Header {
+                scan: Some(ScanSettings {
+                    root: roots[0].clone(),
+                    more_roots: roots[1..options.roots.len()].to_vec(),
+                    against: options
+                        .against
+                        .as_ref()
+                        .map(|_| roots[options.roots.len()].clone()),
+                    recursive: options.recursive,
+                    max_depth: options.max_depth.filter(|_| options.recursive),
+                    one_file_system: options.one_file_system,
+                    threshold: distance.or(threshold),
+                    exclude: options.exclude.clone(),
+                    extensions: options.extensions.clone(),
+                }),
+                ..hashdb.header().clone()
+            });
+        }
+        let start = Instant::now();
+        observer.phase(Phase::Save);
+        match stdio {
+            true => hashdb.to_writer(io::stdout().lock())?,
+            false => match save(&hashdb, &db_file) {
+                Ok(()) => (),
+                Err(e) if options.require_dump || recovered => Err(e)?,
+                Err(e) => eprintln!(
+                    "Could not save the database ({e}); carrying on without \
+                     it. Use --db to keep it somewhere writable, or \
+                     --require-dump to stop instead."
+                ),
+            },
+        }
+        observer.phase_finished(Phase::Save, start.elapsed());
+    }
+
+    // Entries below the depth of the scan stay in the file, but were not
+    // scanned, so they are left out of the search like those of other
+    // directories.
+    hashdb.remove_all(&report.too_deep.iter().cloned().collect());
+    // The search covers the seed, but the entries of the database file win.
+    if let Some(seed) = seed {
+        hashdb.overlay(&seed);
+    }
+
+    let threshold = distance.filter(|_| !observer.cancelled());
+    let (left, right) = roots.split_at(options.roots.len());
+    let duplicates = match (threshold, options.against.is_some()) {
+        (Some(threshold), false) => hashdb.find_duplicates(
+            threshold,
+            options.include_hardlinks,
+            observer,
+        ),
+        (Some(threshold), true) => hashdb
+            .find_across(
+                left,
+                right,
+                threshold,
+                options.include_hardlinks,
+                observer,
+            )
+            .into_iter()
+            .map(|(a, b, _)| (a, b))
+            .collect(),
+        (None, _) => Vec::new(),
+    };
+
+    let outcome = ScanOutcome {
+        db_file,
+        hashdb,
+        duplicates,
+        report,
+        searched: threshold.is_some(),
+        threshold,
+    };
+    observer.finished(&outcome.summary());
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_zero_matches_nothing() {
+        let err = check_threshold(0, 64).unwrap_err();
+        assert!(err.contains("matches nothing"), "{err}");
+        assert!(err.contains("1 to 63"), "{err}");
+    }
+
+    #[test]
+    fn threshold_within_hash_bits() {
+        assert!(check_threshold(1, 64).is_ok());
+        assert!(check_threshold(DEFAULT_THRESHOLD, 64).is_ok());
+        assert!(check_threshold(63, 64).is_ok());
+        for (threshold, bits) in [(64, 64), (900, 64), (64, 16)] {
+            let err = check_threshold(threshold, bits).unwrap_err();
+            assert!(err.contains(&format!("only {bits} bits")), "{err}");
+            assert!(err.contains(&format!("1 to {}", bits - 1)), "{err}");
+        }
+        // Larger hashes allow larger thresholds.
+        assert!(check_threshold(200, 256).is_ok());
+    }
+
+    /// Seed entries whose images changed are hashed again, written to the
+    /// database file, and searched with instead of those of the seed.
+    #[test]
+    fn changed_seed_entries_are_kept() {
+        let dir = std::env::temp_dir()
+            .join(format!("image-duplicate-seed-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let images = dir.join("images");
+        fs::create_dir_all(&images).unwrap();
+        let image = images.join("a.png");
+        let save = |f: fn(u32) -> bool| {
+            image::GrayImage::from_fn(64, 64, |x, _| [255 * f(x) as u8].into())
+                .save(&image)
+                .unwrap();
+        };
+        save(|x| x < 32);
+
+        let options = |db: &str| ScanOptions {
+            db: Some(dir.join(db)),
+            threshold: None,
+            ..ScanOptions::new(&images)
+        };
+        let seed = scan_and_find(&options("seed.db"), &()).unwrap().hashdb;
+
+        // Another image, of another size, in place of the first one.
+        save(|x| x % 2 == 0);
+        let mut options = options("local.db");
+        options.seed_db = Some(dir.join("seed.db"));
+        let outcome = scan_and_find(&options, &()).unwrap();
+        assert_eq!(outcome.report.changed, [image.canonicalize().unwrap()]);
+
+        let local = HashDB::from_file(dir.join("local.db")).unwrap();
+        let hash = |x: &HashDB| x.iter().next().map(|(_, h)| h.clone());
+        assert_eq!(local.len(), 1);
+        assert_ne!(hash(&local), hash(&seed));
+        assert_eq!(hash(&outcome.hashdb), hash(&local));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Lowering the scheduling priority of the process, so that long scans stay
+//! out of the way of interactive programs. [`lower`] forms the main interface.
+
+use std::io;
+
+/// Lower the CPU priority as far as `nice` would, and put disk access in the
+/// idle class as `ionice -c 3` would.
+#[cfg(target_os = "linux")]
+fn lower_platform() -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+    // SAFETY: Plain system calls on the calling process, without pointers.
+    unsafe {
+        if libc::setpriority(libc::PRIO_PROCESS, 0, 19) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Put the process in the background band, which lowers both its CPU and its
+/// disk priority.
+#[cfg(target_os = "macos")]
+fn lower_platform() -> io::Result<()> {
+    // SAFETY: A plain system call on the calling process, without pointers.
+    match unsafe {
+        libc::setpriority(libc::PRIO_DARWIN_PROCESS, 0, libc::PRIO_DARWIN_BG)
+    } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Lower the CPU priority as far as `nice` would.
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+fn lower_platform() -> io::Result<()> {
+    // SAFETY: A plain system call on the calling process, without pointers.
+    match unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Move the process to the below normal priority class.
+#[cfg(windows)]
+fn lower_platform() -> io::Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        BELOW_NORMAL_PRIORITY_CLASS, GetCurrentProcess, SetPriorityClass,
+    };
+
+    // SAFETY: The pseudo handle of the current process is always valid.
+    match unsafe {
+        SetPriorityClass(GetCurrentProcess(), BELOW_NORMAL_PRIORITY_CLASS)
+    } {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn lower_platform() -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Lower the priority of the process. This should happen before the first
+/// parallel work, so that every worker thread inherits it. Failure is not
+/// fatal; it is reported as a warning and the program carries on.
+pub fn lower() {
+    if let Err(e) = lower_platform() {
+        eprintln!("Warning: could not lower the process priority: {e}");
+    }
+}
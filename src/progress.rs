@@ -0,0 +1,563 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Reporting on the progress of a scan. [`ScanObserver`] forms the main
+//! interface; [`HashDB`][crate::hashdb::HashDB] calls it while hashing and
+//! searching, and the program calls it between phases.
+
+use crate::hashdb::{ErrorCategory, HashDB, HashDBError};
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Version of the NDJSON event schema. Bumped only for incompatible changes.
+const SCHEMA: u32 = 1;
+
+/// Minimum time between two NDJSON progress events of the same kind.
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// Phases of a run, in the order they happen.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Reading the database file.
+    Load,
+    /// Listing image files in the directory.
+    Scan,
+    /// Hashing new images.
+    Hash,
+    /// Writing the database file.
+    Save,
+    /// Comparing hashes.
+    Match,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Phase::Load => "load",
+            Phase::Scan => "scan",
+            Phase::Hash => "hash",
+            Phase::Save => "save",
+            Phase::Match => "match",
+        })
+    }
+}
+
+/// Time spent in a phase.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct PhaseTime {
+    pub phase: Phase,
+    /// Wall-clock time, in seconds.
+    pub seconds: f64,
+}
+
+/// Totals for a finished run.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Summary {
+    /// Entries in the database.
+    pub images: usize,
+    /// Images hashed during this run.
+    pub hashed: usize,
+    /// Images that could not be hashed.
+    pub errors: usize,
+    /// Entries removed because their images no longer exist.
+    pub removed: usize,
+    /// Directories that could not be read.
+    pub unreadable: usize,
+    /// Similar pairs found, if the run searched for them.
+    pub pairs: Option<usize>,
+    /// Time spent in each phase, if it was measured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Vec<PhaseTime>>,
+}
+
+/// Receiver of progress reports. Every method does nothing by default, and
+/// is called only where the information is at hand anyway, so that ignoring
+/// reports costs next to nothing. Methods may be called from several threads
+/// at once.
+pub trait ScanObserver: Send + Sync {
+    /// The database file was chosen. `existing` tells whether it will be read
+    /// or a new database created.
+    fn database(&self, _file: &Path, _existing: bool) {}
+
+    /// A phase started.
+    fn phase(&self, _phase: Phase) {}
+
+    /// A phase ended, `elapsed` after it started.
+    fn phase_finished(&self, _phase: Phase, _elapsed: Duration) {}
+
+    /// The scan found `images` image files, `new` of which are not in the
+    /// database yet or have changed since they were hashed, and are about to
+    /// be hashed.
+    fn scanned(&self, _images: usize, _new: usize) {}
+
+    /// `done` of `total` new images have been processed, the latest being
+    /// `file`.
+    fn hashing(&self, _file: &Path, _done: usize, _total: usize) {}
+
+    /// `file` was hashed, which took `elapsed`, including any wait for a
+    /// turn to decode. Called before [`hashing`][ScanObserver::hashing]
+    /// counts it.
+    fn hashed(&self, _file: &Path, _elapsed: Duration) {}
+
+    /// Another batch of images was hashed into `hashdb`, as set with
+    /// [`HashDB::set_checkpoint`], and more are to come. Writing it out now
+    /// lets an interrupted scan resume from here; images deleted since the
+    /// last scan still have entries until it ends.
+    fn checkpoint(&self, _hashdb: &HashDB) {}
+
+    /// `file` could not be hashed and was skipped.
+    fn error(&self, _file: &Path, _error: &HashDBError) {}
+
+    /// `removed` entries were dropped because their images no longer exist.
+    fn pruned(&self, _removed: usize) {}
+
+    /// `dir` could not be read, so its contents were not scanned.
+    fn unreadable(&self, _dir: &Path, _error: &HashDBError) {}
+
+    /// `done` of `total` pairs of hashes have been compared, and `pairs` of
+    /// them were similar.
+    fn matching(&self, _done: u64, _total: u64, _pairs: usize) {}
+
+    /// `img_1` and `img_2` were found similar, at `distance`. Pairs are
+    /// reported as they are found, before they are sorted.
+    fn pair(&self, _img_1: &Path, _img_2: &Path, _distance: u32) {}
+
+    /// The run finished.
+    fn finished(&self, _summary: &Summary) {}
+
+    /// Whether the run should stop early. Hashing skips the images it has
+    /// not started on, and the search for similar images is skipped, but the
+    /// database is still written with the hashes computed so far.
+    fn cancelled(&self) -> bool {
+        false
+    }
+}
+
+impl ScanObserver for () {}
+
+/// Prints status messages and skipped files on stderr.
+pub struct Stderr;
+
+impl ScanObserver for Stderr {
+    fn database(&self, file: &Path, existing: bool) {
+        eprintln!("Database file is {file:?}");
+        match existing {
+            true => eprintln!("Reading database file..."),
+            false => eprintln!("Creating new database..."),
+        }
+    }
+
+    fn phase(&self, phase: Phase) {
+        match phase {
+            Phase::Load => (),
+            Phase::Scan => eprintln!("Scanning for images..."),
+            Phase::Hash => eprintln!("Hashing new images..."),
+            Phase::Save => eprintln!("Dumping database..."),
+            Phase::Match => eprintln!("Finding duplicate images..."),
+        }
+    }
+
+    fn error(&self, file: &Path, error: &HashDBError) {
+        eprintln!("Skipping {file:?}: {error}");
+    }
+
+    fn unreadable(&self, dir: &Path, error: &HashDBError) {
+        eprintln!("Skipping directory {dir:?}: {error}");
+    }
+
+    fn finished(&self, summary: &Summary) {
+        if let Some(pairs) = summary.pairs {
+            eprintln!(
+                "Found {pairs} duplicate {} among {} images",
+                match pairs {
+                    1 => "pair",
+                    _ => "pairs",
+                },
+                summary.images
+            );
+        }
+        if summary.errors > 0 {
+            eprintln!(
+                "Skipped {} images that could not be read",
+                summary.errors
+            );
+        }
+        if summary.unreadable > 0 {
+            eprintln!(
+                "Skipped {} directories that could not be read; entries \
+                 under them were kept",
+                summary.unreadable
+            );
+        }
+    }
+}
+
+/// Collects how long each phase takes, passing every report on to another
+/// observer. The times are added to the [`Summary`] and printed as a table on
+/// stderr when the run finishes.
+pub struct Timed {
+    inner: Box<dyn ScanObserver>,
+    state: Mutex<TimedState>,
+}
+
+/// The phases finished so far.
+#[derive(Default)]
+struct TimedState {
+    /// Images processed in the phase in progress.
+    images: usize,
+    times: Vec<PhaseTime>,
+    /// Images processed in each finished phase.
+    counts: Vec<usize>,
+}
+
+impl Timed {
+    pub fn new(inner: Box<dyn ScanObserver>) -> Self {
+        Timed {
+            inner,
+            state: Mutex::default(),
+        }
+    }
+}
+
+/// Print a table of phase times on stderr.
+fn print_timings(times: &[PhaseTime], counts: &[usize]) {
+    let mut total = 0.0;
+    eprintln!("{:<8}{:>10}", "Phase", "Time");
+    for (time, &images) in times.iter().zip(counts) {
+        total += time.seconds;
+        let name = time.phase.to_string();
+        match (time.phase, images) {
+            (Phase::Hash, 1..) => eprintln!(
+                "{name:<8}{:>9.3}s  ({:.1} ms per image)",
+                time.seconds,
+                time.seconds * 1000.0 / images as f64
+            ),
+            _ => eprintln!("{name:<8}{:>9.3}s", time.seconds),
+        }
+    }
+    eprintln!("{:<8}{total:>9.3}s", "total");
+}
+
+impl ScanObserver for Timed {
+    fn database(&self, file: &Path, existing: bool) {
+        self.inner.database(file, existing);
+    }
+
+    fn phase(&self, phase: Phase) {
+        self.inner.phase(phase);
+    }
+
+    fn phase_finished(&self, phase: Phase, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let seconds = elapsed.as_secs_f64();
+        state.times.push(PhaseTime { phase, seconds });
+        let images = std::mem::take(&mut state.images);
+        state.counts.push(images);
+        drop(state);
+        self.inner.phase_finished(phase, elapsed);
+    }
+
+    fn scanned(&self, images: usize, new: usize) {
+        self.inner.scanned(images, new);
+    }
+
+    fn hashing(&self, file: &Path, done: usize, total: usize) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).images = total;
+        self.inner.hashing(file, done, total);
+    }
+
+    fn hashed(&self, file: &Path, elapsed: Duration) {
+        self.inner.hashed(file, elapsed);
+    }
+
+    fn checkpoint(&self, hashdb: &HashDB) {
+        self.inner.checkpoint(hashdb);
+    }
+
+    fn error(&self, file: &Path, error: &HashDBError) {
+        self.inner.error(file, error);
+    }
+
+    fn pruned(&self, removed: usize) {
+        self.inner.pruned(removed);
+    }
+
+    fn unreadable(&self, dir: &Path, error: &HashDBError) {
+        self.inner.unreadable(dir, error);
+    }
+
+    fn cancelled(&self) -> bool {
+        self.inner.cancelled()
+    }
+
+    fn matching(&self, done: u64, total: u64, pairs: usize) {
+        self.inner.matching(done, total, pairs);
+    }
+
+    fn pair(&self, img_1: &Path, img_2: &Path, distance: u32) {
+        self.inner.pair(img_1, img_2, distance);
+    }
+
+    fn finished(&self, summary: &Summary) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (times, counts) = (
+            std::mem::take(&mut state.times),
+            std::mem::take(&mut state.counts),
+        );
+        drop(state);
+        self.inner.finished(&Summary {
+            timings: Some(times.clone()),
+            ..summary.clone()
+        });
+        print_timings(&times, &counts);
+    }
+}
+
+/// One file that could not be hashed.
+#[derive(Debug, Serialize)]
+struct Failure {
+    path: String,
+    category: ErrorCategory,
+    message: String,
+}
+
+/// Collects the files that could not be hashed, passing every report on to
+/// another observer, and writes them to a file as a JSON array when the run
+/// finishes. The array is written even if it is empty.
+pub struct ErrorLog {
+    inner: Box<dyn ScanObserver>,
+    file: PathBuf,
+    failures: Mutex<Vec<Failure>>,
+}
+
+impl ErrorLog {
+    pub fn new(inner: Box<dyn ScanObserver>, file: PathBuf) -> Self {
+        ErrorLog {
+            inner,
+            file,
+            failures: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn write(&self, failures: &[Failure]) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(&self.file)?);
+        serde_json::to_writer_pretty(&mut out, failures)?;
+        writeln!(out)?;
+        out.flush()
+    }
+}
+
+impl ScanObserver for ErrorLog {
+    fn database(&self, file: &Path, existing: bool) {
+        self.inner.database(file, existing);
+    }
+
+    fn phase(&self, phase: Phase) {
+        self.inner.phase(phase);
+    }
+
+    fn phase_finished(&self, phase: Phase, elapsed: Duration) {
+        self.inner.phase_finished(phase, elapsed);
+    }
+
+    fn scanned(&self, images: usize, new: usize) {
+        self.inner.scanned(images, new);
+    }
+
+    fn hashing(&self, file: &Path, done: usize, total: usize) {
+        self.inner.hashing(file, done, total);
+    }
+
+    fn hashed(&self, file: &Path, elapsed: Duration) {
+        self.inner.hashed(file, elapsed);
+    }
+
+    fn checkpoint(&self, hashdb: &HashDB) {
+        self.inner.checkpoint(hashdb);
+    }
+
+    fn pruned(&self, removed: usize) {
+        self.inner.pruned(removed);
+    }
+
+    fn pair(&self, img_1: &Path, img_2: &Path, distance: u32) {
+        self.inner.pair(img_1, img_2, distance);
+    }
+
+    fn error(&self, file: &Path, error: &HashDBError) {
+        self.failures
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Failure {
+                path: file.to_string_lossy().into_owned(),
+                category: error.category(),
+                message: error.to_string(),
+            });
+        self.inner.error(file, error);
+    }
+
+    fn unreadable(&self, dir: &Path, error: &HashDBError) {
+        self.inner.unreadable(dir, error);
+    }
+
+    fn cancelled(&self) -> bool {
+        self.inner.cancelled()
+    }
+
+    fn matching(&self, done: u64, total: u64, pairs: usize) {
+        self.inner.matching(done, total, pairs);
+    }
+
+    fn finished(&self, summary: &Summary) {
+        self.inner.finished(summary);
+        let mut failures = std::mem::take(
+            &mut *self.failures.lock().unwrap_or_else(|e| e.into_inner()),
+        );
+        failures.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        if let Err(e) = self.write(&failures) {
+            eprintln!("Could not write {:?}: {e}", self.file);
+        }
+    }
+}
+
+/// An event of the NDJSON progress stream.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Event<'a> {
+    Start {
+        schema: u32,
+    },
+    Phase {
+        phase: Phase,
+    },
+    Hash {
+        done: usize,
+        total: usize,
+        file: Cow<'a, str>,
+    },
+    Error {
+        file: Cow<'a, str>,
+        message: String,
+    },
+    Unreadable {
+        dir: String,
+        message: String,
+    },
+    Match {
+        done: u64,
+        total: u64,
+        pairs: usize,
+    },
+    Summary(&'a Summary),
+}
+
+/// Writes one JSON object per event to stdout, in addition to the messages of
+/// [`Stderr`]. Hashing and matching progress is limited to one event per
+/// [`INTERVAL`], plus the final one.
+pub struct Ndjson {
+    last: Mutex<Option<Instant>>,
+}
+
+impl Ndjson {
+    /// Start the stream.
+    pub fn new() -> Self {
+        let ndjson = Ndjson {
+            last: Mutex::new(None),
+        };
+        ndjson.emit(&Event::Start { schema: SCHEMA });
+        ndjson
+    }
+
+    /// Whether a progress event should be written now.
+    fn due(&self, last: bool) -> bool {
+        let mut prev = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        match last || prev.is_none_or(|x| now - x >= INTERVAL) {
+            true => {
+                *prev = Some(now);
+                true
+            }
+            false => false,
+        }
+    }
+
+    /// Write an event. Progress is best effort, so failures are ignored.
+    fn emit(&self, event: &Event) {
+        let mut out = io::stdout().lock();
+        if serde_json::to_writer(&mut out, event).is_ok() {
+            let _ = writeln!(out);
+            let _ = out.flush();
+        }
+    }
+}
+
+impl ScanObserver for Ndjson {
+    fn database(&self, file: &Path, existing: bool) {
+        Stderr.database(file, existing);
+    }
+
+    fn phase(&self, phase: Phase) {
+        Stderr.phase(phase);
+        *self.last.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        self.emit(&Event::Phase { phase });
+    }
+
+    fn hashing(&self, file: &Path, done: usize, total: usize) {
+        if self.due(done == total) {
+            self.emit(&Event::Hash {
+                done,
+                total,
+                file: file.to_string_lossy(),
+            });
+        }
+    }
+
+    fn error(&self, file: &Path, error: &HashDBError) {
+        Stderr.error(file, error);
+        self.emit(&Event::Error {
+            file: file.to_string_lossy(),
+            message: error.to_string(),
+        });
+    }
+
+    fn unreadable(&self, dir: &Path, error: &HashDBError) {
+        Stderr.unreadable(dir, error);
+        self.emit(&Event::Unreadable {
+            dir: dir.to_string_lossy().into_owned(),
+            message: error.to_string(),
+        });
+    }
+
+    fn matching(&self, done: u64, total: u64, pairs: usize) {
+        if self.due(done == total) {
+            self.emit(&Event::Match { done, total, pairs });
+        }
+    }
+
+    fn finished(&self, summary: &Summary) {
+        Stderr.finished(summary);
+        self.emit(&Event::Summary(summary));
+    }
+}
@@ -0,0 +1,645 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The queue of pairs under review, shared by the GUI and scripted reviews:
+//! which pair is current, the decisions taken so far, and undoing them.
+//! [`Review`] forms the main interface, [`run_script`] drives it from lines
+//! of text, and [`read_pairs`] fills it from a list of pairs instead of a
+//! scan.
+
+use crate::{
+    actions::{ActionError, Reason, Remover},
+    hashdb::{self, Pair},
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    io::{self, BufRead},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use thiserror::Error;
+
+/// A decision on a pair under review.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Decision {
+    /// Keep the first image and remove the second.
+    KeepLeft,
+    /// Keep the second image and remove the first.
+    KeepRight,
+    /// Keep both images.
+    KeepBoth,
+    /// Move on without deciding.
+    Skip,
+}
+
+impl Decision {
+    /// Whether an image is removed.
+    pub fn removes(self) -> bool {
+        matches!(self, Decision::KeepLeft | Decision::KeepRight)
+    }
+}
+
+/// The names used in scripts: `keep-left`, `keep-right`, `keep-both`, and
+/// `skip`.
+impl Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Decision::KeepLeft => write!(f, "keep-left"),
+            Decision::KeepRight => write!(f, "keep-right"),
+            Decision::KeepBoth => write!(f, "keep-both"),
+            Decision::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+impl FromStr for Decision {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-left" => Ok(Decision::KeepLeft),
+            "keep-right" => Ok(Decision::KeepRight),
+            "keep-both" => Ok(Decision::KeepBoth),
+            "skip" => Ok(Decision::Skip),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Counts of the decisions taken in a review.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ReviewSummary {
+    /// Pairs in the queue.
+    pub pairs: usize,
+    /// Pairs decided on, including skipped ones.
+    pub reviewed: usize,
+    /// Images removed.
+    pub removed: usize,
+    /// Pairs where both images were kept.
+    pub kept_both: usize,
+    /// Pairs skipped.
+    pub skipped: usize,
+}
+
+impl Display for ReviewSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Reviewed {} of {} pairs (removed {}, kept both {}, skipped {})",
+            self.reviewed,
+            self.pairs,
+            self.removed,
+            self.kept_both,
+            self.skipped
+        )
+    }
+}
+
+/// The queue of pairs under review. Decisions are carried out by the caller,
+/// which tells the queue about them with [`decide`][Review::decide] to move
+/// on to the next pair.
+#[derive(Debug, Default)]
+pub struct Review {
+    duplicates: Vec<Pair>,
+    idx: usize,
+    /// Pairs decided on so far, and the decisions.
+    history: Vec<(usize, Decision)>,
+}
+
+impl Review {
+    /// Start reviewing `duplicates` from the first pair.
+    pub fn new(duplicates: Vec<Pair>) -> Self {
+        Review {
+            duplicates,
+            idx: 0,
+            history: Vec::new(),
+        }
+    }
+
+    /// The pair under review, or `None` once all have been decided on.
+    pub fn current(&self) -> Option<&Pair> {
+        self.duplicates.get(self.idx)
+    }
+
+    /// Position of the pair under review in the queue, which tells results
+    /// for it from those for other pairs.
+    pub fn index(&self) -> usize {
+        self.idx
+    }
+
+    /// The image to remove and the image to keep for a decision on the pair
+    /// under review, or `None` if it removes nothing.
+    pub fn removal(&self, decision: Decision) -> Option<(&Path, &Path)> {
+        let (img_1, img_2) = self.current()?;
+        match decision {
+            Decision::KeepLeft => Some((img_2, img_1)),
+            Decision::KeepRight => Some((img_1, img_2)),
+            Decision::KeepBoth | Decision::Skip => None,
+        }
+    }
+
+    /// Record a decision on the pair under review, which the caller has
+    /// carried out, and move on to the next pair whose images both still
+    /// exist. Returns that pair, or `None` if there are no more.
+    pub fn decide(
+        &mut self,
+        decision: Decision,
+    ) -> Result<Option<&Pair>, ReviewError> {
+        match decision {
+            Decision::KeepBoth => eprintln!("Keeping both images"),
+            Decision::Skip => eprintln!("Skipping pair"),
+            Decision::KeepLeft | Decision::KeepRight => (),
+        }
+        self.history.push((self.idx, decision));
+
+        let exists = |x: &Path| {
+            fs::exists(x).map_err(|source| ReviewError::Io {
+                path: x.into(),
+                source,
+            })
+        };
+        self.idx += 1;
+        while let Some((img_1, img_2)) = self.duplicates.get(self.idx) {
+            if exists(img_1)? && exists(img_2)? {
+                break;
+            }
+            self.idx += 1;
+        }
+        Ok(self.current())
+    }
+
+    /// The last decision taken, if any, which [`undo`][Review::undo] would
+    /// take back. A removal must be undone by the caller first.
+    pub fn last(&self) -> Option<Decision> {
+        self.history.last().map(|&(_, decision)| decision)
+    }
+
+    /// Go back to the pair of the last decision, forgetting the decision.
+    /// Returns that pair, or `None` if nothing has been decided yet.
+    pub fn undo(&mut self) -> Option<&Pair> {
+        let (idx, _) = self.history.pop()?;
+        self.idx = idx;
+        self.current()
+    }
+
+    /// Counts of the decisions taken so far.
+    pub fn summary(&self) -> ReviewSummary {
+        let count =
+            |x: Decision| self.history.iter().filter(|(_, d)| *d == x).count();
+        ReviewSummary {
+            pairs: self.duplicates.len(),
+            reviewed: self.history.len(),
+            removed: count(Decision::KeepLeft) + count(Decision::KeepRight),
+            kept_both: count(Decision::KeepBoth),
+            skipped: count(Decision::Skip),
+        }
+    }
+}
+
+/// Take decisions from `input`, one [`Decision`] per line, on the pairs of
+/// `review` in order, removing images with `remover` as the GUI would. Blank
+/// lines and lines starting with `#` are ignored. Stops at the first line
+/// that is not a decision, or whose removal fails. Running out of input
+/// leaves the remaining pairs untouched; input left over once every pair is
+/// decided on is ignored with a warning.
+pub fn run_script<R: BufRead>(
+    review: &mut Review,
+    remover: &mut dyn Remover,
+    input: R,
+) -> Result<(), ReviewError> {
+    for (i, line) in input.lines().enumerate() {
+        let number = i + 1;
+        let line = line.map_err(ReviewError::Read)?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let decision = line.parse().map_err(|_| ReviewError::Parse {
+            line: number,
+            text: line.to_owned(),
+        })?;
+        if review.current().is_none() {
+            eprintln!("All pairs decided; ignoring line {number} and the rest");
+            break;
+        }
+        if let Some((file, kept)) = review.removal(decision) {
+            remover
+                .remove(file, kept, Reason::Manual)
+                .map_err(|source| ReviewError::Action {
+                    line: number,
+                    source,
+                })?;
+            if let Some(note) = remover.take_note() {
+                eprintln!("{note}");
+            }
+        }
+        review.decide(decision)?;
+    }
+    Ok(())
+}
+
+/// A line of a pairs file in JSON.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PairLine {
+    left: PathBuf,
+    right: PathBuf,
+    #[serde(default)]
+    distance: Option<u32>,
+}
+
+/// The document that `list --format json` writes, as far as pairs files go.
+#[derive(Deserialize)]
+struct ListedPairs {
+    pairs: Vec<ListedPair>,
+}
+
+/// A pair of [`ListedPairs`].
+#[derive(Deserialize)]
+struct ListedPair {
+    distance: Option<u32>,
+    files: [ListedFile; 2],
+}
+
+/// A file of a [`ListedPair`].
+#[derive(Deserialize)]
+struct ListedFile {
+    path: PathBuf,
+}
+
+/// Parse a line of a pairs file: either two paths and an optional distance
+/// separated by tabs, with paths escaped as in `list` output, or a JSON
+/// object with `left`, `right`, and an optional `distance`.
+fn parse_pair(line: &str) -> Result<PairLine, String> {
+    if line.starts_with('{') {
+        return serde_json::from_str(line).map_err(|e| e.to_string());
+    }
+    let fields: Vec<&str> = line.split('\t').collect();
+    let (left, right, distance) = match fields[..] {
+        [left, right] => (left, right, None),
+        [left, right, distance] => match distance.trim().parse() {
+            Ok(x) => (left, right, Some(x)),
+            Err(_) => Err(format!("{distance:?} is not a distance"))?,
+        },
+        _ => Err(
+            "expected two paths and an optional distance separated by tabs, \
+             or a JSON object"
+                .to_owned(),
+        )?,
+    };
+    if left.is_empty() || right.is_empty() {
+        return Err("empty path".to_owned());
+    }
+    let path = |x: &str| {
+        hashdb::unescape_path(x)
+            .ok_or_else(|| format!("{x:?} is not a path on this system"))
+    };
+    Ok(PairLine {
+        left: path(left)?,
+        right: path(right)?,
+        distance,
+    })
+}
+
+/// Read pairs to review from `input`, in the plain or JSON format of `list`
+/// output or as JSON lines. In the plain format, each line holds two paths
+/// and an optional distance separated by tabs, with paths escaped as `list`
+/// escapes them. As JSON lines, each line is an object `{"left": ...,
+/// "right": ..., "distance": ...}`. Blank lines and lines starting with `#`
+/// are ignored in both. Other formats of `list`, such as CSV, are not read.
+/// Pairs come out closest first, like those of a scan, followed by those
+/// without a distance in the order they were read. Paths are made absolute;
+/// pairs with an image that cannot be found are left out with a warning, as
+/// are pairs of an image with itself.
+pub fn read_pairs<R: BufRead>(mut input: R) -> Result<Vec<Pair>, ReviewError> {
+    let mut text = String::new();
+    input
+        .read_to_string(&mut text)
+        .map_err(ReviewError::ReadPairs)?;
+    let lines = match text.trim_start().starts_with('{') {
+        true => serde_json::from_str::<ListedPairs>(&text).ok(),
+        false => None,
+    };
+    let lines = match lines {
+        Some(doc) => doc
+            .pairs
+            .into_iter()
+            .map(|ListedPair { distance, files }| {
+                let [left, right] = files.map(|x| x.path);
+                PairLine {
+                    left,
+                    right,
+                    distance,
+                }
+            })
+            .collect(),
+        None => {
+            let mut lines = Vec::new();
+            for (i, line) in text.lines().enumerate() {
+                let line = line.trim_end_matches('\r');
+                if line.trim().is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                lines.push(parse_pair(line).map_err(|reason| {
+                    ReviewError::Pairs {
+                        line: i + 1,
+                        reason,
+                    }
+                })?);
+            }
+            lines
+        }
+    };
+
+    // Paths shared between pairs, or None for images not found.
+    let mut paths: HashMap<PathBuf, Option<Arc<Path>>> = HashMap::new();
+    let mut path = |x: PathBuf| {
+        paths
+            .entry(x)
+            .or_insert_with_key(|x| match fs::canonicalize(x) {
+                Ok(p) => Some(Arc::from(p)),
+                Err(e) => {
+                    eprintln!("Could not find {x:?} ({e}); skipping its pairs");
+                    None
+                }
+            })
+            .clone()
+    };
+
+    let mut pairs = Vec::new();
+    for pair in lines {
+        let (Some(left), Some(right)) = (path(pair.left), path(pair.right))
+        else {
+            continue;
+        };
+        if left == right {
+            eprintln!("Skipping pair of {left:?} with itself");
+            continue;
+        }
+        pairs.push((pair.distance, (left, right)));
+    }
+    pairs.sort_by_key(|&(distance, _)| (distance.is_none(), distance));
+    Ok(pairs.into_iter().map(|(_, pair)| pair).collect())
+}
+
+/// Errors that can happen while reviewing.
+#[derive(Debug, Error)]
+pub enum ReviewError {
+    /// A line of a script is not a decision.
+    #[error(
+        "Line {line}: expected keep-left, keep-right, keep-both, or skip, \
+         not {text:?}"
+    )]
+    Parse { line: usize, text: String },
+
+    /// A removal asked for by a line of a script failed.
+    #[error("Line {line}: {source}")]
+    Action { line: usize, source: ActionError },
+
+    /// The script could not be read.
+    #[error("Could not read decisions: {0}")]
+    Read(io::Error),
+
+    /// A line of a pairs file is not a pair.
+    #[error("Line {line} of the pairs: {reason}")]
+    Pairs { line: usize, reason: String },
+
+    /// The pairs file could not be read.
+    #[error("Could not read pairs: {0}")]
+    ReadPairs(io::Error),
+
+    /// Wrapper around [`std::io::Error`], with the path involved.
+    #[error("IO error: {path:?}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HashDB, output::Listing};
+
+    /// An empty directory for test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("image-duplicate-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn pairs_round_trip_through_list() {
+        let dir = temp_dir("pairs-round-trip");
+        let mut names = vec![
+            dir.join("plain.png"),
+            dir.join("tab\there.png"),
+            dir.join("two\nlines.png"),
+            dir.join("back\\slash.png"),
+        ];
+        #[cfg(unix)]
+        {
+            use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+            names.push(dir.join(OsStr::from_bytes(b"caf\xe9.png")));
+        }
+        let mut hashdb = HashDB::new();
+        for (i, name) in names.iter().enumerate() {
+            fs::write(name, b"image").unwrap();
+            // Hash i has i bits set, so the distances of the pairs rise.
+            let mut hash = [0; 8];
+            hash[0] = (1u8 << i) - 1;
+            let hash = image_hasher::ImageHash::from_bytes(&hash);
+            hashdb.insert(name, hash.unwrap().into()).unwrap();
+        }
+        let duplicates: Vec<Pair> = names
+            .iter()
+            .skip(1)
+            .map(|x| (Arc::from(names[0].as_path()), Arc::from(x.as_path())))
+            .collect();
+        let listing = Listing {
+            threshold: 64,
+            roots: std::slice::from_ref(&dir),
+            hashdb: &hashdb,
+            duplicates: &duplicates,
+            header: false,
+            max_pairs: 100,
+            savings: false,
+            color: false,
+        };
+
+        let mut plain = Vec::new();
+        listing.write(&mut plain, crate::Format::Plain).unwrap();
+        assert_eq!(read_pairs(&plain[..]).unwrap(), duplicates);
+
+        // JSON output holds paths as text, so only UTF-8 names survive it.
+        let mut json = Vec::new();
+        listing.write(&mut json, crate::Format::Json).unwrap();
+        let utf8: Vec<Pair> = duplicates
+            .iter()
+            .filter(|(_, x)| x.to_str().is_some())
+            .cloned()
+            .collect();
+        assert_eq!(read_pairs(&json[..]).unwrap(), utf8);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pairs_file_lines() {
+        let dir = temp_dir("pairs-lines");
+        for name in ["a.png", "b.png", "c.png"] {
+            fs::write(dir.join(name), b"image").unwrap();
+        }
+        let path = |x: &str| Arc::from(dir.join(x));
+        let text = format!(
+            "# comment\n\n\
+             {0}/a.png\t{0}/c.png\r\n\
+             {{\"left\": \"{0}/a.png\", \"right\": \"{0}/b.png\", \
+             \"distance\": 3}}\n\
+             {0}/b.png\t{0}/c.png\t1\n\
+             {0}/a.png\t{0}/missing.png\t0\n\
+             {0}/a.png\t{0}/./a.png\t0\n",
+            dir.display()
+        );
+        assert_eq!(
+            read_pairs(text.as_bytes()).unwrap(),
+            [
+                (path("b.png"), path("c.png")),
+                (path("a.png"), path("b.png")),
+                (path("a.png"), path("c.png")),
+            ]
+        );
+
+        for (text, line) in [
+            ("a.png\n", 1),
+            ("# comment\na.png\tb.png\tfar\n", 2),
+            ("a.png\tb.png\t1\textra\n", 1),
+            ("\tb.png\n", 1),
+        ] {
+            match read_pairs(text.as_bytes()) {
+                Err(ReviewError::Pairs { line: x, .. }) => assert_eq!(x, line),
+                x => panic!("{text:?}: {x:?}"),
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// A remover that only records what it is asked to remove, and fails
+    /// for `fail`.
+    #[derive(Debug, Default)]
+    struct MockRemover {
+        removed: Vec<(PathBuf, PathBuf)>,
+        fail: Option<PathBuf>,
+    }
+
+    impl Remover for MockRemover {
+        fn remove(
+            &mut self,
+            file: &Path,
+            kept: &Path,
+            _reason: Reason,
+        ) -> Result<(), ActionError> {
+            if self.fail.as_deref() == Some(file) {
+                return Err(ActionError::Io {
+                    path: file.to_path_buf(),
+                    source: io::ErrorKind::PermissionDenied.into(),
+                });
+            }
+            self.removed.push((file.to_path_buf(), kept.to_path_buf()));
+            Ok(())
+        }
+    }
+
+    /// A review of three pairs of existing files in `dir`: a and b, c and d,
+    /// then e and f.
+    fn review(dir: &Path) -> Review {
+        let files: Vec<Arc<Path>> = ["a", "b", "c", "d", "e", "f"]
+            .iter()
+            .map(|x| {
+                let path = dir.join(format!("{x}.png"));
+                fs::write(&path, b"image").unwrap();
+                Arc::from(path)
+            })
+            .collect();
+        Review::new(
+            files
+                .chunks(2)
+                .map(|x| (x[0].clone(), x[1].clone()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn script_decisions() {
+        let dir = temp_dir("script-decisions");
+        let mut review = review(&dir);
+        let mut remover = MockRemover::default();
+        let script = "# first pair\n\n  keep-left  \n\
+                      keep-both\n# last\nkeep-right\nkeep-left\nskip\n";
+        // The last two lines come once every pair is decided, and do nothing.
+        run_script(&mut review, &mut remover, script.as_bytes()).unwrap();
+        assert_eq!(
+            remover.removed,
+            [
+                (dir.join("b.png"), dir.join("a.png")),
+                (dir.join("e.png"), dir.join("f.png")),
+            ]
+        );
+        assert_eq!(review.current(), None);
+        let summary = review.summary();
+        assert_eq!((summary.reviewed, summary.removed), (3, 2));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn script_stops_at_bad_line() {
+        let dir = temp_dir("script-bad-line");
+        let mut review = review(&dir);
+        let mut remover = MockRemover::default();
+        let script = "keep-left\n\n# comment\nkeep-lfet\nkeep-left\n";
+        match run_script(&mut review, &mut remover, script.as_bytes()) {
+            Err(ReviewError::Parse { line: 4, text }) => {
+                assert_eq!(text, "keep-lfet")
+            }
+            x => panic!("{x:?}"),
+        }
+        assert_eq!(remover.removed.len(), 1);
+        assert_eq!(review.index(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn script_stops_at_failed_removal() {
+        let dir = temp_dir("script-failed-removal");
+        let mut review = review(&dir);
+        let mut remover = MockRemover {
+            fail: Some(dir.join("c.png")),
+            ..Default::default()
+        };
+        let script = "skip\nkeep-right\nkeep-left\n";
+        match run_script(&mut review, &mut remover, script.as_bytes()) {
+            Err(ReviewError::Action { line: 2, .. }) => (),
+            x => panic!("{x:?}"),
+        }
+        assert!(remover.removed.is_empty());
+        // The failed pair is still the one under review.
+        assert_eq!(review.index(), 1);
+        assert_eq!(review.summary().reviewed, 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
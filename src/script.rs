@@ -0,0 +1,456 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Writing planned removals and links to a shell script instead of carrying
+//! them out, so that they can be reviewed, edited, and run later.
+//! [`ScriptRemover`] forms the main interface.
+
+use crate::{
+    actions::{ActionError, Reason, Remover},
+    hashdb::{HashDB, escape_path},
+};
+use clap::ValueEnum;
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Language of a script.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ScriptFormat {
+    /// POSIX shell
+    #[default]
+    Sh,
+    /// PowerShell
+    Powershell,
+}
+
+/// A planned action.
+#[derive(Debug)]
+enum Step {
+    /// Delete `file`.
+    Delete { file: PathBuf, kept: PathBuf },
+    /// Move `file` to `target`.
+    Move {
+        file: PathBuf,
+        target: PathBuf,
+        kept: PathBuf,
+    },
+    /// Replace `file` with a hardlink to `kept`.
+    Link { file: PathBuf, kept: PathBuf },
+}
+
+/// A [`Remover`] that plans removals instead of carrying them out. The plan
+/// is written out with [`write`][ScriptRemover::write].
+#[derive(Debug)]
+pub struct ScriptRemover {
+    format: ScriptFormat,
+    /// Where removed files go: `None` to delete them, or a directory to move
+    /// them into.
+    move_to: Option<PathBuf>,
+    steps: Vec<Step>,
+}
+
+impl ScriptRemover {
+    /// Create a remover that plans deleting files, or moving them into
+    /// `move_to`. The trash cannot be reached from a script.
+    pub fn new(format: ScriptFormat, move_to: Option<PathBuf>) -> Self {
+        ScriptRemover {
+            format,
+            move_to,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Plan replacing `file` with a hardlink to `kept`. The script checks that
+    /// the two are still identical before linking them.
+    pub fn link(&mut self, file: &Path, kept: &Path) {
+        self.steps.push(Step::Link {
+            file: file.to_path_buf(),
+            kept: kept.to_path_buf(),
+        });
+    }
+
+    /// Number of planned actions.
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Whether nothing is planned.
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Write the script. It starts by asking for confirmation and stops at
+    /// the first action that fails. Each action is preceded by a comment with
+    /// the kept file and its distance in `hashdb`.
+    pub fn write<W: Write>(
+        &self,
+        mut out: W,
+        hashdb: &HashDB,
+    ) -> io::Result<()> {
+        let quote = match self.format {
+            ScriptFormat::Sh => sh_quote,
+            ScriptFormat::Powershell => powershell_quote,
+        };
+        let quote_path = match self.format {
+            ScriptFormat::Sh => sh_quote_path,
+            ScriptFormat::Powershell => powershell_quote_path,
+        };
+        let header = match self.format {
+            ScriptFormat::Sh => SH_HEADER,
+            ScriptFormat::Powershell => POWERSHELL_HEADER,
+        };
+        let prompt = format!("Carry out {} actions? [y/N]", self.steps.len());
+        write!(out, "{}", header.replace("PROMPT", &quote(&prompt)))?;
+
+        if let Some(dir) = &self.move_to
+            && self.steps.iter().any(|x| matches!(x, Step::Move { .. }))
+        {
+            let dir = quote_path(dir);
+            writeln!(out)?;
+            match self.format {
+                ScriptFormat::Sh => writeln!(out, "mkdir -p -- {dir}")?,
+                ScriptFormat::Powershell => writeln!(
+                    out,
+                    "New-Item -ItemType Directory -Force -Path {dir} | Out-Null"
+                )?,
+            }
+        }
+
+        for step in &self.steps {
+            let (Step::Delete { file, kept }
+            | Step::Move { file, kept, .. }
+            | Step::Link { file, kept }) = step;
+            writeln!(out)?;
+            match hashdb.distance(file, kept) {
+                Some(dist) => writeln!(
+                    out,
+                    "# keeping {}, distance {dist}",
+                    escape_path(kept)
+                )?,
+                None => writeln!(out, "# keeping {}", escape_path(kept))?,
+            }
+            let (file, kept) = (quote_path(file), quote_path(kept));
+            match (step, self.format) {
+                (Step::Delete { .. }, ScriptFormat::Sh) => {
+                    writeln!(out, "rm -- {file}")?
+                }
+                (Step::Delete { .. }, ScriptFormat::Powershell) => {
+                    writeln!(out, "Remove-Item -LiteralPath {file}")?
+                }
+                (Step::Move { target, .. }, ScriptFormat::Sh) => {
+                    let target = quote_path(target);
+                    writeln!(out, "move {file} {target}")?
+                }
+                (Step::Move { target, .. }, ScriptFormat::Powershell) => {
+                    let target = quote_path(target);
+                    writeln!(out, "Move-File {file} {target}")?
+                }
+                (Step::Link { .. }, ScriptFormat::Sh) => {
+                    writeln!(out, "link {kept} {file}")?
+                }
+                (Step::Link { .. }, ScriptFormat::Powershell) => {
+                    writeln!(out, "Link-File {kept} {file}")?
+                }
+            }
+        }
+        out.flush()
+    }
+}
+
+impl Remover for ScriptRemover {
+    fn remove(
+        &mut self,
+        file: &Path,
+        kept: &Path,
+        _reason: Reason,
+    ) -> Result<(), ActionError> {
+        // PowerShell strings cannot hold the unpaired surrogates a Windows
+        // path may have, and a script must not name some other file.
+        if self.format == ScriptFormat::Powershell
+            && (file.to_str().is_none() || kept.to_str().is_none())
+        {
+            Err(ActionError::Io {
+                path: file.to_path_buf(),
+                source: io::ErrorKind::InvalidData.into(),
+            })?;
+        }
+        let (file, kept) = (file.to_path_buf(), kept.to_path_buf());
+        let step = match &self.move_to {
+            None => Step::Delete { file, kept },
+            Some(dir) => match file.file_name() {
+                Some(name) => Step::Move {
+                    target: dir.join(name),
+                    file,
+                    kept,
+                },
+                None => Err(ActionError::Io {
+                    path: file,
+                    source: io::ErrorKind::InvalidInput.into(),
+                })?,
+            },
+        };
+        self.steps.push(step);
+        Ok(())
+    }
+}
+
+/// Start of a POSIX shell script, with `PROMPT` standing for the quoted
+/// confirmation prompt.
+const SH_HEADER: &str = r#"#!/bin/sh
+# Actions planned by image-duplicate. Nothing has been changed yet; review
+# and edit this script, then run it to carry them out.
+set -eu
+
+# Move a file, refusing to overwrite another.
+move() {
+    if [ -e "$2" ]; then
+        echo "Not overwriting $2" >&2
+        return 1
+    fi
+    mv -- "$1" "$2"
+}
+
+# Replace a file with a hardlink to another, if they are still identical.
+link() {
+    if ! cmp -s -- "$1" "$2"; then
+        echo "Skipping $2: no longer identical to $1" >&2
+        return 0
+    fi
+    ln -- "$1" "$2.$$.link.tmp"
+    mv -f -- "$2.$$.link.tmp" "$2"
+}
+
+printf '%s ' PROMPT
+read -r answer || answer=
+case $answer in
+[yY]*) ;;
+*)
+    echo 'Aborted' >&2
+    exit 1
+    ;;
+esac
+"#;
+
+/// Start of a PowerShell script, with `PROMPT` standing for the quoted
+/// confirmation prompt.
+const POWERSHELL_HEADER: &str = r#"# Actions planned by image-duplicate.
+# Nothing has been changed yet; review and edit this script, then run it to
+# carry them out.
+$ErrorActionPreference = 'Stop'
+
+# Move a file, refusing to overwrite another.
+function Move-File($From, $To) {
+    if (Test-Path -LiteralPath $To) {
+        throw "Not overwriting $To"
+    }
+    Move-Item -LiteralPath $From -Destination $To
+}
+
+# Replace a file with a hardlink to another, if they are still identical.
+# New-Item has no -LiteralPath and takes wildcards in its paths, so names with
+# wildcard characters are skipped rather than risk linking some other file.
+function Link-File($Kept, $File) {
+    if ("$Kept$File" -match '[\[\]*?`]') {
+        Write-Warning "Skipping ${File}: wildcard characters in name"
+        return
+    }
+    $a = (Get-FileHash -LiteralPath $Kept).Hash
+    $b = (Get-FileHash -LiteralPath $File).Hash
+    if ($a -ne $b) {
+        Write-Warning "Skipping ${File}: no longer identical to $Kept"
+        return
+    }
+    $tmp = "$File.$PID.link.tmp"
+    New-Item -ItemType HardLink -Path $tmp -Target $Kept | Out-Null
+    Move-Item -LiteralPath $tmp -Destination $File -Force
+}
+
+$answer = Read-Host -Prompt PROMPT
+if ($answer -notmatch '^[yY]') {
+    Write-Error 'Aborted'
+}
+"#;
+
+/// Quote a string for a POSIX shell. Nothing is special inside single
+/// quotes, including line breaks, so only single quotes need care.
+fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Quote a path for a POSIX shell. Bytes that are not UTF-8 are spliced in
+/// with `printf`, so that the script stays text yet names the very file.
+fn sh_quote_path(path: &Path) -> String {
+    let mut quoted = String::new();
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        if !chunk.valid().is_empty() {
+            quoted.push_str(&sh_quote(chunk.valid()));
+        }
+        if !chunk.invalid().is_empty() {
+            let octal: String = chunk
+                .invalid()
+                .iter()
+                .map(|b| format!("\\{b:03o}"))
+                .collect();
+            quoted.push_str(&format!("\"$(printf '{octal}')\""));
+        }
+    }
+    quoted
+}
+
+/// Quote a path for PowerShell. [`ScriptRemover`] refuses paths that are not
+/// valid Unicode for PowerShell, so nothing is lost here.
+fn powershell_quote_path(path: &Path) -> String {
+    powershell_quote(&path.to_string_lossy())
+}
+
+/// Quote a string for PowerShell. Single-quoted strings are literal except
+/// for single quotes, which are doubled; PowerShell also takes typographic
+/// single quotes as quotes.
+fn powershell_quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('\'');
+    for c in s.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}')
+        {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sh_quoting() {
+        assert_eq!(sh_quote("it's"), r"'it'\''s'");
+        assert_eq!(sh_quote("two\nlines"), "'two\nlines'");
+        assert_eq!(sh_quote("-rf"), "'-rf'");
+        assert_eq!(sh_quote("$HOME `x` \\"), "'$HOME `x` \\'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sh_quoting_not_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"caf\xe9\xff.png"));
+        assert_eq!(
+            sh_quote_path(path),
+            r#"'caf'"$(printf '\351')""$(printf '\377')"'.png'"#
+        );
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"\xe9"));
+        assert_eq!(sh_quote_path(path), r#""$(printf '\351')""#);
+    }
+
+    #[test]
+    fn powershell_quoting() {
+        assert_eq!(powershell_quote("it's"), "'it''s'");
+        for q in ['\u{2018}', '\u{2019}', '\u{201a}', '\u{201b}'] {
+            assert_eq!(
+                powershell_quote(&format!("a{q}b")),
+                format!("'a{q}{q}b'")
+            );
+        }
+        assert_eq!(powershell_quote("$x `n \"y\""), "'$x `n \"y\"'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn powershell_refuses_not_unicode() {
+        use std::os::unix::ffi::OsStrExt;
+        let odd = Path::new(std::ffi::OsStr::from_bytes(b"caf\xe9.png"));
+        let kept = Path::new("kept.png");
+
+        let mut remover = ScriptRemover::new(ScriptFormat::Powershell, None);
+        assert!(remover.remove(odd, kept, Reason::Manual).is_err());
+        assert!(remover.remove(kept, odd, Reason::Manual).is_err());
+        assert!(remover.is_empty());
+
+        let mut remover = ScriptRemover::new(ScriptFormat::Sh, None);
+        remover.remove(odd, kept, Reason::Manual).unwrap();
+        assert_eq!(remover.len(), 1);
+    }
+
+    /// An empty directory for test `name`.
+    #[cfg(unix)]
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("image-duplicate-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Running a script removes exactly the files with awkward names that
+    /// it was written for.
+    #[cfg(unix)]
+    #[test]
+    fn sh_script_runs() {
+        use std::os::unix::ffi::OsStrExt;
+        use std::process::{Command, Stdio};
+
+        let dir = temp_dir("sh-script");
+        let kept = dir.join("kept.png");
+        let linked = dir.join("[linked] it's.png");
+        let names: [&[u8]; 5] = [
+            b"it's.png",
+            b"-rf.png",
+            b"two\nlines.png",
+            b"caf\xe9.png",
+            b"$(touch pwned).png",
+        ];
+        let files: Vec<_> = names
+            .iter()
+            .map(|x| dir.join(std::ffi::OsStr::from_bytes(x)))
+            .collect();
+        for file in files.iter().chain([&kept, &linked]) {
+            std::fs::write(file, "same").unwrap();
+        }
+
+        let mut remover = ScriptRemover::new(ScriptFormat::Sh, None);
+        for file in &files {
+            remover.remove(file, &kept, Reason::Manual).unwrap();
+        }
+        remover.link(&linked, &kept);
+        let script = dir.join("script.sh");
+        let out = std::fs::File::create(&script).unwrap();
+        remover.write(out, &HashDB::new()).unwrap();
+
+        let mut child = Command::new("sh")
+            .arg(&script)
+            .current_dir(&dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"y\n").unwrap();
+        assert!(child.wait().unwrap().success());
+
+        for file in &files {
+            assert!(!file.exists(), "{file:?} not removed");
+        }
+        assert!(!dir.join("pwned").exists());
+        use std::os::unix::fs::MetadataExt;
+        let ino = |x: &Path| std::fs::metadata(x).unwrap().ino();
+        assert_eq!(ino(&linked), ino(&kept));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
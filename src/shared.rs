@@ -0,0 +1,88 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A [`HashDB`] that can be queried from many threads while one of them keeps
+//! updating it. [`SharedDB`] forms the main interface.
+//!
+//! Readers take a snapshot, which is a cheap handle to an immutable database.
+//! Writers work on a copy of the current database and publish it as a whole
+//! when done, so a query never sees half of an update. Queries may therefore
+//! lag behind an update in progress: a snapshot keeps showing the database as
+//! it was when taken, and new snapshots show the previous generation until
+//! the update is published.
+
+use crate::hashdb::HashDB;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A [`HashDB`] shared between threads, published in generations.
+#[derive(Debug, Default)]
+pub struct SharedDB {
+    /// The current generation.
+    current: RwLock<Arc<HashDB>>,
+    /// Held while preparing a generation, so that concurrent updates are
+    /// applied one after another rather than one overwriting the other.
+    writer: Mutex<()>,
+}
+
+impl SharedDB {
+    /// Share a database.
+    pub fn new(hashdb: HashDB) -> Self {
+        SharedDB {
+            current: RwLock::new(Arc::new(hashdb)),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// The current generation of the database. Updates published later do
+    /// not change it.
+    pub fn snapshot(&self) -> Arc<HashDB> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Replace the database with a new generation.
+    pub fn publish(&self, hashdb: HashDB) {
+        let _writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        self.swap(hashdb);
+    }
+
+    /// Apply `f` to a copy of the current generation and publish the result.
+    /// Queries carry on against the previous generation meanwhile, so `f` may
+    /// take as long as a whole scan. Since every update copies the database,
+    /// batch changes into few updates rather than making one per image.
+    pub fn update<T>(&self, f: impl FnOnce(&mut HashDB) -> T) -> T {
+        let _writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let mut next = HashDB::clone(&self.snapshot());
+        let result = f(&mut next);
+        self.swap(next);
+        result
+    }
+
+    fn swap(&self, hashdb: HashDB) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) =
+            Arc::new(hashdb);
+    }
+
+    /// The current generation of the database, copied if snapshots of it are
+    /// still held.
+    pub fn into_inner(self) -> HashDB {
+        let current =
+            self.current.into_inner().unwrap_or_else(|e| e.into_inner());
+        Arc::unwrap_or_clone(current)
+    }
+}
@@ -0,0 +1,165 @@
+// image-dupicate - GUI for handling visually similar images in a directory
+// Copyright (C) 2024 Cameron Norton
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Exit status of the binary run without the GUI, on directories of
+//! generated images.
+
+use image::GrayImage;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+/// A fresh directory for test `name`, with a data directory for databases
+/// and one of images, holding an image white on the left and one white on
+/// the top, which are far from similar.
+fn setup(name: &str) -> PathBuf {
+    let dir = env::temp_dir()
+        .join(format!("image-duplicate-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("data")).unwrap();
+    fs::create_dir_all(dir.join("config")).unwrap();
+    fs::create_dir_all(dir.join("images")).unwrap();
+    GrayImage::from_fn(64, 64, |x, _| [if x < 32 { 255 } else { 0 }].into())
+        .save(dir.join("images/left.png"))
+        .unwrap();
+    GrayImage::from_fn(64, 64, |_, y| [if y < 32 { 255 } else { 0 }].into())
+        .save(dir.join("images/top.png"))
+        .unwrap();
+    dir
+}
+
+/// The binary with `args`, keeping its databases and configuration under
+/// `dir` and clear of the environment the tests run in.
+fn command(dir: &Path, args: &[&str]) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_image-duplicate"));
+    for (key, _) in env::vars_os() {
+        if key.to_string_lossy().starts_with("IMAGE_DUPLICATE_") {
+            cmd.env_remove(key);
+        }
+    }
+    cmd.args(args)
+        .current_dir(dir)
+        .env("XDG_DATA_HOME", dir.join("data"))
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("HOME", dir)
+        .env_remove("DISPLAY")
+        .env_remove("WAYLAND_DISPLAY");
+    cmd
+}
+
+/// Run the binary with `args`, as set up by [`command`].
+fn run(dir: &Path, args: &[&str]) -> Output {
+    command(dir, args).output().unwrap()
+}
+
+/// Whether the database of the images has been written, where it goes by
+/// default for a writable directory.
+fn dumped(dir: &Path) -> bool {
+    dir.join("images/.image_hash.db").exists()
+}
+
+#[test]
+fn no_duplicates() {
+    let dir = setup("none");
+    for args in [&["list", "images"][..], &["review", "--no-gui", "images"]] {
+        let out = run(&dir, args);
+        assert_eq!(out.status.code(), Some(0), "{args:?}: {out:?}");
+        assert!(out.stdout.is_empty());
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn duplicates() {
+    let dir = setup("found");
+    fs::copy(dir.join("images/left.png"), dir.join("images/copy.png")).unwrap();
+    for args in [
+        &["list", "images"][..],
+        &["review", "--no-gui", "images"],
+        &["review", "--list", "images"],
+        // Without a command, as the original interface took it.
+        &["--no-gui", "images"],
+    ] {
+        let out = run(&dir, args);
+        assert_eq!(out.status.code(), Some(2), "{args:?}: {out:?}");
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        assert!(stdout.contains("copy.png") && stdout.contains("left.png"));
+        assert!(!stdout.contains("top.png"));
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("Found 1 duplicate pair among 3 images"));
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// A reader that stops reading, like `head`, does not make a failure.
+#[test]
+fn closed_stdout() {
+    let dir = setup("closed");
+    fs::copy(dir.join("images/left.png"), dir.join("images/copy.png")).unwrap();
+    let mut child = command(&dir, &["list", "--no-dump", "images"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    drop(child.stdout.take());
+    let out = child.wait_with_output().unwrap();
+    assert_eq!(out.status.code(), Some(2), "{out:?}");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn dump_and_update() {
+    let dir = setup("dump");
+    fs::copy(dir.join("images/left.png"), dir.join("images/copy.png")).unwrap();
+    let out = run(&dir, &["list", "--no-dump", "images"]);
+    assert_eq!(out.status.code(), Some(2), "{out:?}");
+    assert!(!dumped(&dir));
+
+    let out = run(&dir, &["list", "images"]);
+    assert_eq!(out.status.code(), Some(2), "{out:?}");
+    assert!(dumped(&dir));
+
+    // Without updating, the database still holds the copy.
+    fs::remove_file(dir.join("images/copy.png")).unwrap();
+    let out = run(&dir, &["list", "--no-update", "images"]);
+    assert_eq!(out.status.code(), Some(2), "{out:?}");
+    let out = run(&dir, &["list", "images"]);
+    assert_eq!(out.status.code(), Some(0), "{out:?}");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn failures() {
+    let dir = setup("fail");
+    let out = run(&dir, &["list", "--threshold", "many", "images"]);
+    assert_eq!(out.status.code(), Some(1), "{out:?}");
+    let out = run(&dir, &["review", "--no-gui", "--print-db", "images"]);
+    assert_eq!(out.status.code(), Some(1), "{out:?}");
+    let out = run(&dir, &["list", "missing"]);
+    assert_eq!(out.status.code(), Some(1), "{out:?}");
+
+    // The database cannot be written where a directory is.
+    let out = run(&dir, &["list", "--require-dump", "--db", "data", "images"]);
+    assert_eq!(out.status.code(), Some(1), "{out:?}");
+
+    // Help is not a failure.
+    let out = run(&dir, &["list", "--help"]);
+    assert_eq!(out.status.code(), Some(0), "{out:?}");
+    assert!(!out.stdout.is_empty());
+    fs::remove_dir_all(dir).unwrap();
+}